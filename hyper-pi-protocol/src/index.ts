@@ -147,8 +147,44 @@ export interface NodeInfo {
   port: number;
   status: "active" | "offline";
   pid?: number;
+  /** Set on agents started by `spawn_agent`; matches its `spawn_id` result. */
+  spawn_id?: string;
+  /** Last activity the agent reported with `report_status`. */
+  agent_status?: NodeAgentStatus;
+  /** Unix seconds when `agent_status` last changed. */
+  agent_status_since?: number;
+  session_name?: string;
+  model?: string;
+  provider?: string;
+  pi_version?: string;
+  git_branch?: string;
+  labels?: Record<string, string>;
+  /** Fields the hypivisor does not know are passed through unchanged. */
+  [field: string]: unknown;
+}
+
+/** An entry in an agent card's `tools`, `skills` or `commands` */
+export interface AgentCardItem {
+  name: string;
+  description?: string;
+}
+
+/**
+ * What an agent can do and what it is working on. Published on register
+ * or with `set_agent_card`, served by `get_agent_card`.
+ */
+export interface AgentCard {
+  tools: AgentCardItem[];
+  skills: AgentCardItem[];
+  /** Slash commands, without the leading `/`. */
+  commands: AgentCardItem[];
+  current_task?: string;
+  project?: string;
 }
 
+/** What an agent is doing, as reported by pi-socket */
+export type NodeAgentStatus = "idle" | "working" | "waiting_for_input" | "error";
+
 /** Hypivisor WebSocket connection status */
 export type HypivisorStatus = "connecting" | "connected" | "disconnected" | "error";
 
@@ -160,8 +196,28 @@ export type AgentStatus = "connecting" | "connected" | "disconnected" | "offline
 export type HypivisorEvent =
   | { event: "init"; nodes: NodeInfo[]; protocol_version: string }
   | { event: "node_joined"; node: NodeInfo }
+  | { event: "node_updated"; node: NodeInfo }
+  | { event: "agent_card_updated"; id: string }
+  | {
+      event: "node_status_changed";
+      id: string;
+      agent_status: NodeAgentStatus;
+      agent_status_since: number;
+    }
   | { event: "node_offline"; id: string }
-  | { event: "node_removed"; id: string };
+  | { event: "node_removed"; id: string }
+  | {
+      /** A spawned agent exited before registering (e.g. bad pi config). */
+      event: "spawn_exited";
+      pid: number;
+      spawn_id: string;
+      command: string;
+      cwd: string;
+      code: number | null;
+      signal: number | null;
+      /** Last lines the process wrote to stderr. */
+      stderr: string[];
+    };
 
 // ── JSON-RPC ──────────────────────────────────────────────────
 
@@ -0,0 +1,206 @@
+//! Agent cards: what a node can do and what it is working on.
+//!
+//! An agent publishes its card on `register` (as `card`) or later with
+//! `set_agent_card`. Cards are checked against a fixed schema, kept on the
+//! node and served by `get_agent_card`; `list_nodes` can filter on them.
+//! They are left out of `NodeInfo` events so the roster stays small.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Most entries in each of `tools`, `skills` and `commands`.
+pub const MAX_ITEMS: usize = 1000;
+const MAX_NAME_CHARS: usize = 200;
+const MAX_TEXT_CHARS: usize = 4096;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentCard {
+    pub tools: Vec<CardItem>,
+    pub skills: Vec<CardItem>,
+    /// Slash commands the agent accepts, without the `/`.
+    pub commands: Vec<CardItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_task: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CardItem {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl AgentCard {
+    /// Parse and check a card as sent by an agent.
+    pub fn from_value(value: Value) -> Result<AgentCard, String> {
+        let card: AgentCard =
+            serde_json::from_value(value).map_err(|e| format!("Invalid agent card: {e}"))?;
+        card.validate()
+            .map_err(|e| format!("Invalid agent card: {e}"))?;
+        Ok(card)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let lists = [
+            ("tools", &self.tools),
+            ("skills", &self.skills),
+            ("commands", &self.commands),
+        ];
+        for (field, items) in lists {
+            if items.len() > MAX_ITEMS {
+                return Err(format!("{field} has more than {MAX_ITEMS} entries"));
+            }
+            for (i, item) in items.iter().enumerate() {
+                if item.name.trim().is_empty() {
+                    return Err(format!("{field}[{i}].name must not be empty"));
+                }
+                check_length(&format!("{field}[{i}].name"), &item.name, MAX_NAME_CHARS)?;
+                if let Some(description) = &item.description {
+                    check_length(
+                        &format!("{field}[{i}].description"),
+                        description,
+                        MAX_TEXT_CHARS,
+                    )?;
+                }
+            }
+        }
+        if let Some(task) = &self.current_task {
+            check_length("current_task", task, MAX_TEXT_CHARS)?;
+        }
+        if let Some(project) = &self.project {
+            check_length("project", project, MAX_TEXT_CHARS)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_length(field: &str, value: &str, max: usize) -> Result<(), String> {
+    if value.chars().count() > max {
+        return Err(format!("{field} is longer than {max} characters"));
+    }
+    Ok(())
+}
+
+/// `list_nodes` filters. All given ones must match, and nodes without a
+/// card match none.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CardFilter {
+    /// Has a tool with this name.
+    pub tool: Option<String>,
+    /// Has a skill with this name.
+    pub skill: Option<String>,
+    /// Has a command with this name.
+    pub command: Option<String>,
+    /// `project` is exactly this.
+    pub project: Option<String>,
+    /// `current_task` contains this, ignoring case.
+    pub task: Option<String>,
+}
+
+impl CardFilter {
+    pub fn from_params(params: Option<Value>) -> Result<CardFilter, String> {
+        match params {
+            None | Some(Value::Null) => Ok(CardFilter::default()),
+            Some(params) => serde_json::from_value(params)
+                .map_err(|e| format!("Invalid list_nodes filter: {e}")),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tool.is_none()
+            && self.skill.is_none()
+            && self.command.is_none()
+            && self.project.is_none()
+            && self.task.is_none()
+    }
+
+    pub fn matches(&self, card: Option<&AgentCard>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(card) = card else {
+            return false;
+        };
+        let has = |items: &[CardItem], name: &Option<String>| {
+            name.as_ref()
+                .is_none_or(|name| items.iter().any(|item| &item.name == name))
+        };
+        let task = self.task.as_ref().map(|t| t.to_lowercase());
+        has(&card.tools, &self.tool)
+            && has(&card.skills, &self.skill)
+            && has(&card.commands, &self.command)
+            && self
+                .project
+                .as_ref()
+                .is_none_or(|project| card.project.as_ref() == Some(project))
+            && task.is_none_or(|task| {
+                card.current_task
+                    .as_ref()
+                    .is_some_and(|current| current.to_lowercase().contains(&task))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn card() -> AgentCard {
+        AgentCard::from_value(json!({
+            "tools": [{ "name": "bash", "description": "Run a command" }, { "name": "read" }],
+            "skills": [{ "name": "harden" }],
+            "commands": [{ "name": "reload" }],
+            "current_task": "Fix the Login form",
+            "project": "site"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_cards_against_the_schema() {
+        assert_eq!(card().tools[1].name, "read");
+        assert_eq!(
+            AgentCard::from_value(json!({})).unwrap(),
+            AgentCard::default()
+        );
+
+        let error = |value: Value| AgentCard::from_value(value).unwrap_err();
+        assert!(error(json!({ "tools": "bash" })).starts_with("Invalid agent card"));
+        assert!(error(json!({ "owner": "me" })).contains("unknown field"));
+        assert!(error(json!({ "skills": [{ "name": "x", "level": 3 }] })).contains("unknown field"));
+        assert_eq!(
+            error(json!({ "commands": [{ "name": " " }] })),
+            "Invalid agent card: commands[0].name must not be empty"
+        );
+        assert!(error(json!({ "project": "p".repeat(MAX_TEXT_CHARS + 1) }))
+            .contains("project is longer than"));
+        let many: Vec<Value> = (0..=MAX_ITEMS)
+            .map(|i| json!({ "name": i.to_string() }))
+            .collect();
+        assert!(error(json!({ "tools": many })).contains("more than"));
+    }
+
+    #[test]
+    fn filters_on_card_fields() {
+        let card = card();
+        let filter = |params: Value| CardFilter::from_params(Some(params)).unwrap();
+
+        assert!(filter(json!({})).matches(None));
+        assert!(filter(json!({ "tool": "bash", "skill": "harden" })).matches(Some(&card)));
+        assert!(filter(json!({ "command": "reload", "project": "site" })).matches(Some(&card)));
+        assert!(filter(json!({ "task": "login" })).matches(Some(&card)));
+        assert!(!filter(json!({ "tool": "edit" })).matches(Some(&card)));
+        assert!(!filter(json!({ "project": "Site" })).matches(Some(&card)));
+        assert!(!filter(json!({ "tool": "bash" })).matches(None));
+
+        assert!(CardFilter::from_params(Some(json!({ "tools": "bash" })))
+            .unwrap_err()
+            .starts_with("Invalid list_nodes filter"));
+    }
+}
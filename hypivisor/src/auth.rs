@@ -15,6 +15,12 @@ pub fn is_authorized(token: Option<&str>, secret: &str) -> bool {
     }) == Some(true)
 }
 
+/// Returns true if the token matches any accepted secret, or if the set is
+/// empty (auth disabled).
+pub fn is_authorized_any(token: Option<&str>, secrets: &[String]) -> bool {
+    secrets.is_empty() || secrets.iter().any(|s| !s.is_empty() && is_authorized(token, s))
+}
+
 /// Extract token from query string (e.g., "token=abc123&foo=bar" → Some("abc123"))
 pub fn extract_token_from_query(uri: &str) -> Option<String> {
     let query = uri.split('?').nth(1)?;
@@ -68,6 +74,20 @@ mod tests {
         assert!(is_authorized(Some(encoded_token), "my secret token"));
     }
 
+    #[test]
+    fn empty_secret_set_allows_all() {
+        assert!(is_authorized_any(None, &[]));
+    }
+
+    #[test]
+    fn any_matching_secret_allowed() {
+        let secrets = vec!["old".to_string(), "new".to_string()];
+        assert!(is_authorized_any(Some("old"), &secrets));
+        assert!(is_authorized_any(Some("new"), &secrets));
+        assert!(!is_authorized_any(Some("other"), &secrets));
+        assert!(!is_authorized_any(None, &secrets));
+    }
+
     #[test]
     fn extract_token_from_query_string() {
         assert_eq!(
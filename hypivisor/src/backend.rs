@@ -0,0 +1,413 @@
+//! Spawn backends: how an agent process is started.
+//!
+//! Pi is a TUI. The `direct` backend runs it as a plain child with no
+//! terminal, which is fine for agents driven only through pi-socket. The
+//! others give it a terminal a human can attach to afterwards:
+//!
+//! - `tmux`: a detached session named after the folder, or a new window in
+//!   it if the session already exists.
+//! - `screen`: a detached screen session named after the folder.
+//! - `pty`: a terminal owned by the hypivisor itself (see [`crate::pty`]).
+
+use crate::limits::ResourceLimits;
+#[cfg(unix)]
+use crate::pty::{self, PtySession, WindowSize};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::{
+    fmt,
+    path::Path,
+    process::{Child, Command, Stdio},
+    str::FromStr,
+};
+use tracing::warn;
+
+/// Which backend to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Direct,
+    Tmux,
+    Screen,
+    Pty,
+}
+
+impl BackendKind {
+    pub fn backend(self) -> &'static dyn SpawnBackend {
+        match self {
+            BackendKind::Direct => &DirectBackend,
+            BackendKind::Tmux => &TmuxBackend,
+            BackendKind::Screen => &ScreenBackend,
+            BackendKind::Pty => &PtyBackend,
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(BackendKind::Direct),
+            "tmux" => Ok(BackendKind::Tmux),
+            "screen" => Ok(BackendKind::Screen),
+            "pty" => Ok(BackendKind::Pty),
+            other => Err(format!(
+                "Unknown spawn backend '{other}' (expected direct, tmux, screen or pty)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::Direct => "direct",
+            BackendKind::Tmux => "tmux",
+            BackendKind::Screen => "screen",
+            BackendKind::Pty => "pty",
+        })
+    }
+}
+
+/// What to run and where.
+pub struct LaunchSpec<'a> {
+    pub program: &'a str,
+    pub args: &'a [String],
+    pub env: &'a [(String, String)],
+    pub cwd: &'a Path,
+    /// Human-readable name, used for tmux and screen sessions.
+    pub name: &'a str,
+    pub rlimits: &'a ResourceLimits,
+}
+
+/// A started agent.
+#[derive(Debug)]
+pub enum Launched {
+    /// Our own child, reaped by us. `stderr` is piped unless the child is
+    /// on a terminal.
+    Child {
+        child: Child,
+        #[cfg(unix)]
+        pty: Option<Arc<PtySession>>,
+        /// Command a human can run to attach, if any.
+        attach: Option<String>,
+    },
+    /// Started under another supervisor and known only by PID.
+    External { pid: u32, attach: Option<String> },
+}
+
+impl Launched {
+    pub fn pid(&self) -> u32 {
+        match self {
+            Launched::Child { child, .. } => child.id(),
+            Launched::External { pid, .. } => *pid,
+        }
+    }
+
+    pub fn attach(&self) -> Option<&str> {
+        match self {
+            Launched::Child { attach, .. } | Launched::External { attach, .. } => attach.as_deref(),
+        }
+    }
+}
+
+/// A way of starting agent processes.
+pub trait SpawnBackend: Sync {
+    fn launch(&self, spec: &LaunchSpec) -> Result<Launched, String>;
+}
+
+/// A plain child process without a terminal.
+pub struct DirectBackend;
+
+impl SpawnBackend for DirectBackend {
+    fn launch(&self, spec: &LaunchSpec) -> Result<Launched, String> {
+        let mut command = Command::new(spec.program);
+        command
+            .args(spec.args)
+            .envs(spec.env.iter().cloned())
+            .current_dir(spec.cwd)
+            .stderr(Stdio::piped());
+        spec.rlimits.apply(&mut command);
+        let child = command
+            .spawn()
+            .map_err(|e| format!("Failed to spawn: {e}"))?;
+        Ok(Launched::Child {
+            child,
+            #[cfg(unix)]
+            pty: None,
+            attach: None,
+        })
+    }
+}
+
+/// A window in a detached tmux session.
+pub struct TmuxBackend;
+
+impl TmuxBackend {
+    fn command(session: &str, session_exists: bool, spec: &LaunchSpec) -> Command {
+        let mut command = Command::new("tmux");
+        if session_exists {
+            command.args(["new-window", "-d", "-t", &format!("={session}:")]);
+        } else {
+            command.args(["new-session", "-d", "-s", session]);
+        }
+        command.args(["-n", session, "-P", "-F", "#{pane_pid}", "-c"]);
+        command.arg(spec.cwd);
+        for (key, value) in spec.env {
+            command.arg("-e").arg(format!("{key}={value}"));
+        }
+        command.arg(spec.program).args(spec.args);
+        command
+    }
+}
+
+impl SpawnBackend for TmuxBackend {
+    fn launch(&self, spec: &LaunchSpec) -> Result<Launched, String> {
+        let session = session_name(spec.name);
+        if !spec.rlimits.is_empty() {
+            warn!("spawn_rlimits are not applied to agents started under tmux");
+        }
+        let exists = Command::new("tmux")
+            .args(["has-session", "-t", &format!("={session}")])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| format!("Failed to run tmux: {e}"))?
+            .success();
+        let output = Self::command(&session, exists, spec)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Failed to run tmux: {e}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "tmux failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let pid = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .map_err(|_| "tmux did not report the pane PID".to_string())?;
+        Ok(Launched::External {
+            pid,
+            attach: Some(format!("tmux attach -t '={session}'")),
+        })
+    }
+}
+
+/// A detached screen session. `screen -D -m` stays in the foreground, so
+/// the screen process is our child and exits with the agent.
+pub struct ScreenBackend;
+
+impl ScreenBackend {
+    fn command(session: &str, spec: &LaunchSpec) -> Command {
+        let mut command = Command::new("screen");
+        command
+            .args(["-D", "-m", "-S", session])
+            .arg(spec.program)
+            .args(spec.args)
+            .envs(spec.env.iter().cloned())
+            .current_dir(spec.cwd);
+        spec.rlimits.apply(&mut command);
+        command
+    }
+}
+
+impl SpawnBackend for ScreenBackend {
+    fn launch(&self, spec: &LaunchSpec) -> Result<Launched, String> {
+        let session = session_name(spec.name);
+        let child = Self::command(&session, spec)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run screen: {e}"))?;
+        Ok(Launched::Child {
+            child,
+            #[cfg(unix)]
+            pty: None,
+            attach: Some(format!("screen -r {session}")),
+        })
+    }
+}
+
+/// A terminal owned by the hypivisor.
+pub struct PtyBackend;
+
+impl SpawnBackend for PtyBackend {
+    #[cfg(unix)]
+    fn launch(&self, spec: &LaunchSpec) -> Result<Launched, String> {
+        let (master, slave) =
+            pty::open(WindowSize::default()).map_err(|e| format!("Failed to open PTY: {e}"))?;
+        let mut command = Command::new(spec.program);
+        command
+            .args(spec.args)
+            .env("TERM", "xterm-256color")
+            .envs(spec.env.iter().cloned())
+            .current_dir(spec.cwd);
+        pty::attach(&mut command, slave).map_err(|e| format!("Failed to open PTY: {e}"))?;
+        spec.rlimits.apply(&mut command);
+        let child = command
+            .spawn()
+            .map_err(|e| format!("Failed to spawn: {e}"))?;
+        // Drop our copies of the terminal so it hangs up when the agent exits.
+        drop(command);
+        let pty = PtySession::start(master).map_err(|e| format!("Failed to open PTY: {e}"))?;
+        Ok(Launched::Child {
+            child,
+            pty: Some(pty),
+            attach: None,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn launch(&self, _spec: &LaunchSpec) -> Result<Launched, String> {
+        Err("The pty backend requires Unix".into())
+    }
+}
+
+/// tmux and screen treat `.` and `:` specially in session names.
+fn session_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "pi".into()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec<'a>(env: &'a [(String, String)], args: &'a [String]) -> LaunchSpec<'a> {
+        LaunchSpec {
+            program: "pi",
+            args,
+            env,
+            cwd: Path::new("/work/my.app"),
+            name: "my.app",
+            // Promoted to a static, unlike `&ResourceLimits::default()`.
+            rlimits: &ResourceLimits {
+                nofile: None,
+                nproc: None,
+                cpu_secs: None,
+                memory_mb: None,
+            },
+        }
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parses_backend_names() {
+        for kind in [
+            BackendKind::Direct,
+            BackendKind::Tmux,
+            BackendKind::Screen,
+            BackendKind::Pty,
+        ] {
+            assert_eq!(kind.to_string().parse::<BackendKind>(), Ok(kind));
+        }
+        assert!("docker".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn session_names_are_sanitized() {
+        assert_eq!(session_name("my.app"), "my_app");
+        assert_eq!(session_name("a:b c"), "a_b_c");
+        assert_eq!(session_name(""), "pi");
+    }
+
+    #[test]
+    fn tmux_creates_session_or_window() {
+        let env = [("HYPI_SPAWN_ID".to_string(), "sp-1".to_string())];
+        let extra = ["--model".to_string(), "x".to_string()];
+        let spec = spec(&env, &extra);
+
+        let new_session = args(&TmuxBackend::command("my_app", false, &spec));
+        assert_eq!(
+            new_session,
+            [
+                "new-session",
+                "-d",
+                "-s",
+                "my_app",
+                "-n",
+                "my_app",
+                "-P",
+                "-F",
+                "#{pane_pid}",
+                "-c",
+                "/work/my.app",
+                "-e",
+                "HYPI_SPAWN_ID=sp-1",
+                "pi",
+                "--model",
+                "x"
+            ]
+        );
+
+        let new_window = args(&TmuxBackend::command("my_app", true, &spec));
+        assert_eq!(new_window[..4], ["new-window", "-d", "-t", "=my_app:"]);
+    }
+
+    #[test]
+    fn screen_runs_in_foreground() {
+        let env = [("PI_X".to_string(), "1".to_string())];
+        let command = ScreenBackend::command("my_app", &spec(&env, &[]));
+        assert_eq!(args(&command), ["-D", "-m", "-S", "my_app", "pi"]);
+        assert_eq!(command.get_current_dir(), Some(Path::new("/work/my.app")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pty_backend_gives_agent_a_terminal() {
+        let env = [("PI_X".to_string(), "from-env".to_string())];
+        let script = [
+            "-c".to_string(),
+            "test -t 1 && echo \"$TERM $PI_X\"".to_string(),
+        ];
+        let spec = LaunchSpec {
+            program: "sh",
+            args: &script,
+            env: &env,
+            cwd: Path::new("/"),
+            name: "root",
+            rlimits: &ResourceLimits::default(),
+        };
+        let Launched::Child {
+            mut child,
+            pty: Some(pty),
+            ..
+        } = PtyBackend.launch(&spec).unwrap()
+        else {
+            panic!("expected a child on a PTY");
+        };
+        assert!(child.wait().unwrap().success());
+        for _ in 0..100 {
+            if String::from_utf8_lossy(&pty.scrollback()).contains("xterm-256color from-env") {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("agent output never reached the scrollback");
+    }
+}
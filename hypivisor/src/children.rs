@@ -0,0 +1,977 @@
+//! Tracking and reaping of agent processes started by `spawn_agent`.
+//!
+//! Every spawned child gets a reaper thread that waits on it, so exited
+//! agents never linger as zombies. Its stderr is drained continuously and
+//! the last few lines kept. If the process exits before any node registers
+//! with its spawn ID, which usually means pi failed at startup, a
+//! `spawn_exited` event carrying that stderr tail is broadcast so the
+//! dashboard can say why.
+//!
+//! Only processes tracked here can be stopped or restarted; agents the
+//! hypivisor did not start are never signalled.
+
+use crate::backend::{BackendKind, Launched};
+use crate::spawn::SpawnOptions;
+use crate::state::{NodeInfo, Registry};
+use chrono::Utc;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read},
+    process::ExitStatus,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Number of stderr lines kept per child.
+pub const STDERR_TAIL_LINES: usize = 20;
+
+/// Longest stderr line kept, in bytes; the rest of a longer line is dropped.
+const STDERR_LINE_MAX_BYTES: usize = 4096;
+
+/// How long to wait for buffered stderr after the child exits.
+const STDERR_DRAIN_GRACE: Duration = Duration::from_millis(200);
+
+/// How often agents started under tmux are checked for exit.
+const EXTERNAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default time a stopped agent gets to exit before `SIGKILL`.
+pub const DEFAULT_STOP_GRACE_MS: u64 = 5_000;
+/// Longest grace period a caller may ask for.
+pub const MAX_STOP_GRACE_MS: u64 = 60_000;
+
+/// A child process started by the hypivisor.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpawnedProcess {
+    pub pid: u32,
+    /// ID passed to the agent so its registration can be matched.
+    pub spawn_id: String,
+    pub backend: BackendKind,
+    /// Command line, for display only.
+    pub command: String,
+    pub cwd: String,
+    /// Unix timestamp (seconds) of the spawn.
+    pub started_at: i64,
+    /// Node ID the process registered as, once it has.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// Options it was spawned with, reused by `restart_agent`.
+    #[serde(skip)]
+    pub options: SpawnOptions,
+    /// Set once `stop_agent` has signalled it.
+    #[serde(skip)]
+    pub stopping: bool,
+    /// How to tell the PID still belongs to this process.
+    #[serde(skip)]
+    pub ownership: Ownership,
+    /// The agent's spawn secret, proving its `spawn_id` on register.
+    #[serde(skip)]
+    pub secret: String,
+}
+
+/// How a tracked PID is tied to the process we started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Our child: its PID cannot be reused until we reap it.
+    #[default]
+    Child,
+    /// Not our child (a tmux pane), only polled. Its PID can be reused
+    /// before the poller notices the exit, so it is identified by its start
+    /// time, when that can be read.
+    External { started: Option<u64> },
+}
+
+/// What a `spawn_agent` caller waiting on registration learns.
+#[derive(Debug)]
+pub enum SpawnOutcome {
+    Registered(Box<NodeInfo>),
+    Exited {
+        /// `None` when the agent was not our child, e.g. under tmux.
+        status: Option<ExitStatus>,
+        stderr: Vec<String>,
+    },
+}
+
+impl SpawnedProcess {
+    pub fn new(
+        pid: u32,
+        spawn_id: String,
+        backend: BackendKind,
+        command: String,
+        cwd: String,
+    ) -> SpawnedProcess {
+        SpawnedProcess {
+            pid,
+            spawn_id,
+            backend,
+            command,
+            cwd,
+            started_at: Utc::now().timestamp(),
+            node_id: None,
+            options: SpawnOptions::default(),
+            stopping: false,
+            ownership: Ownership::Child,
+            secret: String::new(),
+        }
+    }
+
+    /// Whether the PID still belongs to this process, or `None` if that
+    /// cannot be told (an external process without a known start time).
+    fn is_current(&self) -> Option<bool> {
+        match self.ownership {
+            Ownership::Child => Some(true),
+            Ownership::External {
+                started: Some(started),
+            } => Some(process_start_time(self.pid) == Some(started)),
+            Ownership::External { started: None } => None,
+        }
+    }
+}
+
+/// Start tracking `launched` and reap it in the background. Processes that
+/// are not our children are polled until they disappear instead.
+pub fn track(state: &Registry, launched: Launched, mut record: SpawnedProcess) -> SpawnedProcess {
+    if let Launched::External { pid, .. } = launched {
+        record.ownership = Ownership::External {
+            started: process_start_time(pid),
+        };
+    }
+    state
+        .children
+        .lock()
+        .expect("children lock poisoned")
+        .insert(record.pid, record.clone());
+
+    let reap_state = state.clone();
+    let pid = record.pid;
+    match launched {
+        Launched::Child {
+            mut child,
+            #[cfg(unix)]
+            pty,
+            ..
+        } => {
+            #[cfg(unix)]
+            if let Some(pty) = pty {
+                state
+                    .ptys
+                    .lock()
+                    .expect("ptys lock poisoned")
+                    .insert(record.spawn_id.clone(), pty);
+            }
+            let stderr = child.stderr.take().map(StderrTail::spawn);
+            std::thread::spawn(move || {
+                let status = child.wait();
+                let tail = stderr.map(StderrTail::finish).unwrap_or_default();
+                match status {
+                    Ok(status) => on_exit(&reap_state, pid, Some(status), tail),
+                    Err(e) => {
+                        warn!(pid, error = %e, "Failed to wait on spawned agent");
+                        forget(&reap_state, pid);
+                    }
+                }
+            });
+        }
+        Launched::External { .. } => {
+            std::thread::spawn(move || {
+                while process_alive(pid) {
+                    std::thread::sleep(EXTERNAL_POLL_INTERVAL);
+                }
+                on_exit(&reap_state, pid, None, Vec::new());
+            });
+        }
+    }
+    record
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists.
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+/// When `pid` started, in clock ticks since boot, or `None` if it is not
+/// running or this is not Linux.
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in parentheses may contain spaces; `starttime` is
+    // the 20th field after it.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The tracked child `node` belongs to, by its spawn ID. `register` only
+/// keeps a spawn ID that [`verify_spawn`] accepted.
+fn find_child<'a>(
+    children: &'a mut HashMap<u32, SpawnedProcess>,
+    node: &NodeInfo,
+) -> Option<&'a mut SpawnedProcess> {
+    let spawn_id = node.spawn_id.as_ref()?;
+    children.values_mut().find(|c| &c.spawn_id == spawn_id)
+}
+
+/// Whether `secret` is the secret of the tracked child `spawn_id`, i.e. the
+/// registrant claiming that spawn ID really is the agent we started.
+pub fn verify_spawn(state: &Registry, spawn_id: &str, secret: Option<&str>) -> bool {
+    let Some(secret) = secret else {
+        return false;
+    };
+    let children = state.children.lock().expect("children lock poisoned");
+    children.values().any(|c| {
+        c.spawn_id == spawn_id
+            && !c.secret.is_empty()
+            && c.secret.len() == secret.len()
+            // Compared in constant time.
+            && c.secret.bytes().zip(secret.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    })
+}
+
+/// Spawn ID of the tracked child `node` belongs to, if any.
+pub fn spawn_id_for(state: &Registry, node: &NodeInfo) -> Option<String> {
+    let mut children = state.children.lock().expect("children lock poisoned");
+    find_child(&mut children, node).map(|c| c.spawn_id.clone())
+}
+
+/// Record that `node` registered and wake any caller waiting on its spawn.
+/// Returns false if it is not a tracked child.
+pub fn mark_registered(state: &Registry, node: &NodeInfo) -> bool {
+    let tracked = {
+        let mut children = state.children.lock().expect("children lock poisoned");
+        find_child(&mut children, node).map(|child| {
+            child.node_id = Some(node.id.clone());
+            child.spawn_id.clone()
+        })
+    };
+    if let Some(spawn_id) = &tracked {
+        notify_waiter(
+            state,
+            spawn_id,
+            SpawnOutcome::Registered(Box::new(node.clone())),
+        );
+    }
+    tracked.is_some()
+}
+
+/// Register interest in `spawn_id`'s outcome. Must be called before the
+/// child starts so a fast registration is not missed.
+pub fn wait_for(state: &Registry, spawn_id: &str) -> mpsc::Receiver<SpawnOutcome> {
+    let (tx, rx) = mpsc::channel();
+    state
+        .spawn_waiters
+        .lock()
+        .expect("spawn waiters lock poisoned")
+        .insert(spawn_id.to_string(), tx);
+    rx
+}
+
+/// Drop the waiter for `spawn_id`, e.g. after a timeout or failed spawn.
+pub fn stop_waiting(state: &Registry, spawn_id: &str) {
+    state
+        .spawn_waiters
+        .lock()
+        .expect("spawn waiters lock poisoned")
+        .remove(spawn_id);
+}
+
+fn notify_waiter(state: &Registry, spawn_id: &str, outcome: SpawnOutcome) {
+    let waiter = state
+        .spawn_waiters
+        .lock()
+        .expect("spawn waiters lock poisoned")
+        .remove(spawn_id);
+    if let Some(tx) = waiter {
+        let _ = tx.send(outcome);
+    }
+}
+
+/// The tracked child a stop or restart request refers to: the node `node_id`
+/// or the spawn `spawn_id`, exactly one of which must be given.
+///
+/// A node only counts as ours if it is still registered and its current
+/// registration matches the child that claimed it. A node ID that was
+/// re-registered by another process, or any agent the hypivisor did not
+/// start, is refused.
+pub fn owned_child(
+    state: &Registry,
+    node_id: Option<&str>,
+    spawn_id: Option<&str>,
+) -> Result<SpawnedProcess, String> {
+    match (node_id, spawn_id) {
+        (Some(_), Some(_)) => Err("Pass either id or spawn_id, not both".into()),
+        (None, None) => Err("Missing id or spawn_id".into()),
+        (Some(node_id), None) => {
+            let node = state
+                .nodes
+                .read()
+                .expect("nodes lock poisoned")
+                .get(node_id)
+                .cloned()
+                .ok_or_else(|| format!("Node not found: {node_id}"))?;
+            let mut children = state.children.lock().expect("children lock poisoned");
+            find_child(&mut children, &node)
+                .filter(|c| c.node_id.as_deref() == Some(node_id))
+                .map(|c| c.clone())
+                .ok_or_else(|| format!("Node {node_id} was not spawned by the hypivisor"))
+        }
+        (None, Some(spawn_id)) => state
+            .children
+            .lock()
+            .expect("children lock poisoned")
+            .values()
+            .find(|c| c.spawn_id == spawn_id)
+            .cloned()
+            .ok_or_else(|| format!("No running agent with spawn ID {spawn_id}")),
+    }
+}
+
+/// Ask a tracked child to exit with `SIGTERM`, then `SIGKILL` it if it is
+/// still running after `grace`. Returns without waiting for it to exit.
+pub fn stop(state: &Registry, pid: u32, grace: Duration) -> Result<(), String> {
+    {
+        let mut children = state.children.lock().expect("children lock poisoned");
+        let child = children
+            .get_mut(&pid)
+            .ok_or_else(|| format!("Agent {pid} has already exited"))?;
+        // An external process we cannot identify gets SIGTERM as long as
+        // its PID is in use, but is never killed.
+        if !child.is_current().unwrap_or_else(|| process_alive(pid)) {
+            return Err(format!("Agent {pid} has already exited"));
+        }
+        child.stopping = true;
+        signal(pid, Signal::Term)?;
+    }
+    info!(
+        pid,
+        grace_ms = grace.as_millis() as u64,
+        "Stopping spawned agent"
+    );
+    let state = state.clone();
+    std::thread::spawn(move || {
+        if wait_untracked(&state, pid, grace) {
+            return;
+        }
+        // Still tracked. A child keeps its PID until reaped, but an
+        // external process may have exited and had its PID reused since
+        // the last poll, so it is only killed if it is provably the same.
+        let children = state.children.lock().expect("children lock poisoned");
+        match children.get(&pid).map(SpawnedProcess::is_current) {
+            Some(Some(true)) => {
+                warn!(pid, "Spawned agent ignored SIGTERM, killing it");
+                let _ = signal(pid, Signal::Kill);
+            }
+            Some(None) => {
+                warn!(
+                    pid,
+                    "Spawned agent ignored SIGTERM; cannot confirm its PID, not killing it"
+                );
+            }
+            Some(Some(false)) | None => {}
+        }
+    });
+    Ok(())
+}
+
+/// Stop every tracked child as [`stop`] does, and wait until they have all
+/// exited or been killed.
+pub fn stop_all(state: &Registry, grace: Duration) {
+    let pids: Vec<u32> = state
+        .children
+        .lock()
+        .expect("children lock poisoned")
+        .keys()
+        .copied()
+        .collect();
+    let stopping: Vec<u32> = pids
+        .into_iter()
+        .filter(|&pid| stop(state, pid, grace).is_ok())
+        .collect();
+    // Past the grace period the stop threads kill what is left; allow a
+    // moment for that and for the reapers to notice.
+    let deadline = Instant::now() + grace + Duration::from_secs(1);
+    for pid in stopping {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if !wait_untracked(state, pid, left) {
+            warn!(pid, "Spawned agent still running at shutdown");
+        }
+    }
+}
+
+/// Wait up to `timeout` for `pid` to exit and be forgotten. Returns whether
+/// it did.
+pub fn wait_untracked(state: &Registry, pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !state
+            .children
+            .lock()
+            .expect("children lock poisoned")
+            .contains_key(&pid)
+        {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+enum Signal {
+    Term,
+    Kill,
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, signal: Signal) -> Result<(), String> {
+    let signo = match signal {
+        Signal::Term => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    // SAFETY: plain kill(2) on a PID we spawned and have not yet reaped.
+    if unsafe { libc::kill(pid as libc::pid_t, signo) } == -1 {
+        return Err(format!(
+            "Failed to signal agent {pid}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn signal(_pid: u32, _signal: Signal) -> Result<(), String> {
+    Err("Stopping agents requires Unix".into())
+}
+
+/// The most recent stderr lines of a child, filled by a reader thread.
+struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    /// Disconnects when the reader reaches EOF.
+    done: mpsc::Receiver<()>,
+}
+
+impl StderrTail {
+    /// Drain `stderr` on a dedicated thread until EOF so a chatty child
+    /// never blocks on a full pipe. Only the last [`STDERR_TAIL_LINES`]
+    /// lines are kept, each cut to [`STDERR_LINE_MAX_BYTES`], and invalid
+    /// UTF-8 is replaced rather than ending the drain.
+    fn spawn(stderr: impl Read + Send + 'static) -> StderrTail {
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL_LINES)));
+        let (done_tx, done) = mpsc::channel::<()>();
+        let reader_lines = lines.clone();
+        std::thread::spawn(move || {
+            let _done = done_tx;
+            let mut reader = BufReader::new(stderr);
+            let mut line = Vec::new();
+            while let Ok(true) = read_capped_line(&mut reader, &mut line) {
+                let text = String::from_utf8_lossy(&line).into_owned();
+                push_bounded(&mut reader_lines.lock().unwrap(), text);
+            }
+        });
+        StderrTail { lines, done }
+    }
+
+    /// Snapshot the tail once the reader finishes, or after a short grace
+    /// period, since grandchildren may hold stderr open indefinitely.
+    fn finish(self) -> Vec<String> {
+        let _ = self.done.recv_timeout(STDERR_DRAIN_GRACE);
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Read the next line into `line` without its line ending, keeping at
+/// most [`STDERR_LINE_MAX_BYTES`] but consuming all of it. Returns `false`
+/// at EOF.
+fn read_capped_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    let mut read_any = false;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            return Ok(read_any);
+        }
+        read_any = true;
+        let newline = buf.iter().position(|&b| b == b'\n');
+        let chunk = &buf[..newline.unwrap_or(buf.len())];
+        let room = STDERR_LINE_MAX_BYTES.saturating_sub(line.len());
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let used = newline.map_or(buf.len(), |i| i + 1);
+        reader.consume(used);
+        if newline.is_some() {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(true);
+        }
+    }
+}
+
+fn push_bounded(tail: &mut VecDeque<String>, line: String) {
+    if tail.len() == STDERR_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+/// Remove `pid` from tracking, along with anything queued for it.
+fn forget(state: &Registry, pid: u32) -> Option<SpawnedProcess> {
+    let record = state
+        .children
+        .lock()
+        .expect("children lock poisoned")
+        .remove(&pid)?;
+    state
+        .pending_prompts
+        .lock()
+        .expect("pending prompts lock poisoned")
+        .remove(&record.spawn_id);
+    #[cfg(unix)]
+    state
+        .ptys
+        .lock()
+        .expect("ptys lock poisoned")
+        .remove(&record.spawn_id);
+    Some(record)
+}
+
+/// Build the `spawn_exited` event for a child that never registered.
+pub fn build_exit_event(
+    record: &SpawnedProcess,
+    status: Option<ExitStatus>,
+    stderr: &[String],
+) -> String {
+    #[cfg(unix)]
+    let signal = status.and_then(|s| std::os::unix::process::ExitStatusExt::signal(&s));
+    #[cfg(not(unix))]
+    let signal: Option<i32> = None;
+
+    serde_json::json!({
+        "event": "spawn_exited",
+        "pid": record.pid,
+        "spawn_id": record.spawn_id,
+        "command": record.command,
+        "cwd": record.cwd,
+        "code": status.and_then(|s| s.code()),
+        "signal": signal,
+        "stderr": stderr,
+    })
+    .to_string()
+}
+
+/// Human-readable exit status, if we know it.
+pub fn describe_exit(status: Option<ExitStatus>) -> String {
+    status.map_or_else(|| "exit status unknown".into(), |s| s.to_string())
+}
+
+fn on_exit(state: &Registry, pid: u32, status: Option<ExitStatus>, stderr: Vec<String>) {
+    let Some(record) = forget(state, pid) else {
+        return;
+    };
+    let described = describe_exit(status);
+    if record.stopping {
+        info!(pid, status = %described, "Stopped agent exited");
+        notify_waiter(
+            state,
+            &record.spawn_id,
+            SpawnOutcome::Exited { status, stderr },
+        );
+        return;
+    }
+    if let Some(node_id) = &record.node_id {
+        info!(pid, node_id = %node_id, status = %described, "Spawned agent exited");
+        return;
+    }
+
+    warn!(pid, cwd = %record.cwd, status = %described, "Spawned agent exited before registering");
+    crate::log::warn(
+        "spawn.exit",
+        &format!(
+            "Spawned agent {pid} in {} exited before registering ({described})",
+            record.cwd
+        ),
+    );
+    let event = build_exit_event(&record, status, &stderr);
+    notify_waiter(
+        state,
+        &record.spawn_id,
+        SpawnOutcome::Exited { status, stderr },
+    );
+    let cx = crate::ephemeral_cx();
+    if state.tx.send(&cx, event).is_err() {
+        warn!("No receivers for spawn_exited broadcast");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::state::NodeMeta;
+    use std::process::{Child, Command, Stdio};
+
+    fn make_registry() -> Registry {
+        crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            ..Default::default()
+        })
+    }
+
+    fn spawn_sh(script: &str) -> Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    fn track_sh(state: &Registry, child: Child, spawn_id: &str) -> SpawnedProcess {
+        let record = SpawnedProcess::new(
+            child.id(),
+            spawn_id.into(),
+            BackendKind::Direct,
+            "sh".into(),
+            "/tmp".into(),
+        );
+        let launched = Launched::Child {
+            child,
+            #[cfg(unix)]
+            pty: None,
+            attach: None,
+        };
+        track(state, launched, record)
+    }
+
+    fn wait_until_untracked(state: &Registry, pid: u32) {
+        for _ in 0..250 {
+            if !state.children.lock().unwrap().contains_key(&pid) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("child {pid} was never reaped");
+    }
+
+    #[test]
+    fn stderr_tail_keeps_last_lines() {
+        let input: String = (0..STDERR_TAIL_LINES + 5)
+            .map(|i| format!("line {i}\n"))
+            .collect();
+        let tail = StderrTail::spawn(std::io::Cursor::new(input.into_bytes())).finish();
+        assert_eq!(tail.len(), STDERR_TAIL_LINES);
+        assert_eq!(tail[0], "line 5");
+        assert_eq!(
+            tail.last().unwrap(),
+            &format!("line {}", STDERR_TAIL_LINES + 4)
+        );
+    }
+
+    #[test]
+    fn stderr_tail_survives_bad_utf8_and_long_lines() {
+        let mut input = b"bad \xff byte\r\n".to_vec();
+        input.extend(vec![b'x'; STDERR_LINE_MAX_BYTES * 3]);
+        input.extend(b"\nlast words");
+        let tail = StderrTail::spawn(std::io::Cursor::new(input)).finish();
+        assert_eq!(tail.len(), 3);
+        assert_eq!(tail[0], "bad \u{fffd} byte");
+        assert_eq!(tail[1], "x".repeat(STDERR_LINE_MAX_BYTES));
+        assert_eq!(tail[2], "last words");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_event_reports_code_and_stderr() {
+        let record = SpawnedProcess {
+            pid: 7,
+            spawn_id: "sp-7".into(),
+            backend: BackendKind::Direct,
+            command: "pi --model x".into(),
+            cwd: "/tmp".into(),
+            started_at: 0,
+            node_id: None,
+            options: SpawnOptions::default(),
+            stopping: false,
+            ownership: Ownership::Child,
+            secret: String::new(),
+        };
+        let status = spawn_sh("exit 3").wait().unwrap();
+        let event: serde_json::Value =
+            serde_json::from_str(&build_exit_event(&record, Some(status), &["boom".into()]))
+                .unwrap();
+        assert_eq!(event["event"], "spawn_exited");
+        assert_eq!(event["pid"], 7);
+        assert_eq!(event["spawn_id"], "sp-7");
+        assert_eq!(event["code"], 3);
+        assert!(event["signal"].is_null());
+        assert_eq!(event["stderr"][0], "boom");
+    }
+
+    #[test]
+    fn reaps_child_and_clears_pending_prompt() {
+        let state = make_registry();
+        let child = spawn_sh("echo 'bad config' >&2; exit 1");
+        let pid = child.id();
+        state
+            .pending_prompts
+            .lock()
+            .unwrap()
+            .insert("sp-a".into(), "hi".into());
+        let waiter = wait_for(&state, "sp-a");
+
+        let record = track_sh(&state, child, "sp-a");
+        assert_eq!(record.pid, pid);
+        match waiter.recv_timeout(Duration::from_secs(5)).unwrap() {
+            SpawnOutcome::Exited { status, stderr } => {
+                assert_eq!(status.unwrap().code(), Some(1));
+                assert_eq!(stderr, vec!["bad config".to_string()]);
+            }
+            other => panic!("unexpected outcome: {other:?}"),
+        }
+        wait_until_untracked(&state, pid);
+        assert!(state.pending_prompts.lock().unwrap().is_empty());
+        assert!(state.spawn_waiters.lock().unwrap().is_empty());
+    }
+
+    fn node(id: &str, pid: Option<u32>, spawn_id: Option<&str>) -> NodeInfo {
+        NodeInfo {
+            id: id.into(),
+            machine: "localhost".into(),
+            cwd: "/tmp".into(),
+            port: 8080,
+            status: crate::state::NodeStatus::Active,
+            offline_since: None,
+            last_seen: None,
+            pid,
+            spawn_id: spawn_id.map(String::from),
+            agent_status: None,
+            agent_status_since: None,
+            card: None,
+            meta: NodeMeta::default(),
+        }
+    }
+
+    #[test]
+    fn mark_registered_only_for_tracked_children() {
+        let state = make_registry();
+        let child = spawn_sh("sleep 0.2");
+        let pid = child.id();
+        track_sh(&state, child, "sp-b");
+
+        // A PID alone proves nothing: anyone can report one.
+        assert!(!mark_registered(&state, &node("node-0", Some(pid), None)));
+        assert!(mark_registered(&state, &node("node-1", None, Some("sp-b"))));
+        assert_eq!(
+            state.children.lock().unwrap()[&pid].node_id.as_deref(),
+            Some("node-1")
+        );
+        assert!(!mark_registered(
+            &state,
+            &node("node-2", None, Some("sp-x"))
+        ));
+        wait_until_untracked(&state, pid);
+    }
+
+    #[test]
+    fn spawn_ids_are_verified_by_secret() {
+        let state = make_registry();
+        let child = spawn_sh("sleep 0.2");
+        let pid = child.id();
+        let mut record = SpawnedProcess::new(
+            pid,
+            "sp-s".into(),
+            BackendKind::Direct,
+            "sh".into(),
+            "/tmp".into(),
+        );
+        record.secret = "0123abcd".into();
+        let launched = Launched::Child {
+            child,
+            #[cfg(unix)]
+            pty: None,
+            attach: None,
+        };
+        track(&state, launched, record);
+
+        assert!(verify_spawn(&state, "sp-s", Some("0123abcd")));
+        assert!(!verify_spawn(&state, "sp-s", Some("0123abce")));
+        assert!(!verify_spawn(&state, "sp-s", Some("0123")));
+        assert!(!verify_spawn(&state, "sp-s", None));
+        assert!(!verify_spawn(&state, "sp-other", Some("0123abcd")));
+        // Children without a secret cannot be claimed at all.
+        let other = spawn_sh("sleep 0.2");
+        let other_pid = other.id();
+        track_sh(&state, other, "sp-t");
+        assert!(!verify_spawn(&state, "sp-t", Some("")));
+        wait_until_untracked(&state, pid);
+        wait_until_untracked(&state, other_pid);
+    }
+
+    #[test]
+    fn registration_matches_spawn_id_and_wakes_waiter() {
+        let state = make_registry();
+        let child = spawn_sh("sleep 0.2");
+        let pid = child.id();
+        let waiter = wait_for(&state, "sp-c");
+        track_sh(&state, child, "sp-c");
+
+        // A wrapper may report a different PID; the spawn ID still matches.
+        let joined = node("node-3", Some(pid + 100_000), Some("sp-c"));
+        assert_eq!(spawn_id_for(&state, &joined).as_deref(), Some("sp-c"));
+        assert!(mark_registered(&state, &joined));
+        match waiter.recv_timeout(Duration::from_secs(1)).unwrap() {
+            SpawnOutcome::Registered(n) => assert_eq!(n.id, "node-3"),
+            other => panic!("unexpected outcome: {other:?}"),
+        }
+        assert!(!mark_registered(
+            &state,
+            &node("node-4", Some(pid), Some("sp-other"))
+        ));
+        wait_until_untracked(&state, pid);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn external_process_is_forgotten_when_it_exits() {
+        let state = make_registry();
+        let mut child = spawn_sh("sleep 0.3");
+        let pid = child.id();
+        // Someone else (tmux, in practice) reaps it.
+        std::thread::spawn(move || child.wait());
+        let waiter = wait_for(&state, "sp-d");
+        let record = SpawnedProcess::new(
+            pid,
+            "sp-d".into(),
+            BackendKind::Tmux,
+            "pi".into(),
+            "/tmp".into(),
+        );
+        track(&state, Launched::External { pid, attach: None }, record);
+
+        match waiter.recv_timeout(Duration::from_secs(5)).unwrap() {
+            SpawnOutcome::Exited { status, .. } => assert!(status.is_none()),
+            other => panic!("unexpected outcome: {other:?}"),
+        }
+        wait_until_untracked(&state, pid);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn external_process_is_identified_by_start_time() {
+        let state = make_registry();
+        let mut child = spawn_sh("sleep 5");
+        let pid = child.id();
+        let record = SpawnedProcess::new(
+            pid,
+            "sp-f".into(),
+            BackendKind::Tmux,
+            "pi".into(),
+            "/tmp".into(),
+        );
+        let tracked = track(&state, Launched::External { pid, attach: None }, record);
+        let Ownership::External {
+            started: Some(started),
+        } = tracked.ownership
+        else {
+            panic!("no start time for {pid}: {:?}", tracked.ownership);
+        };
+        assert_eq!(tracked.is_current(), Some(true));
+
+        // Pretend the agent exited and its PID went to a newer process.
+        let reused = Ownership::External {
+            started: Some(started + 1),
+        };
+        state
+            .children
+            .lock()
+            .unwrap()
+            .get_mut(&pid)
+            .unwrap()
+            .ownership = reused;
+        assert_eq!(
+            stop(&state, pid, Duration::ZERO).unwrap_err(),
+            format!("Agent {pid} has already exited")
+        );
+        assert!(child.try_wait().unwrap().is_none());
+
+        let _ = child.kill();
+        let _ = child.wait();
+        wait_until_untracked(&state, pid);
+    }
+
+    #[test]
+    fn only_registered_children_are_owned() {
+        let state = make_registry();
+        let child = spawn_sh("sleep 0.5");
+        let pid = child.id();
+        track_sh(&state, child, "sp-e");
+        let ours = node("ours", Some(pid), Some("sp-e"));
+        let stranger = node("stranger", Some(pid + 100_000), None);
+        for n in [&ours, &stranger] {
+            state.nodes.write().unwrap().insert(n.id.clone(), n.clone());
+        }
+
+        // Not yet claimed by a registration.
+        assert!(owned_child(&state, Some("ours"), None).is_err());
+        assert!(mark_registered(&state, &ours));
+        assert_eq!(owned_child(&state, Some("ours"), None).unwrap().pid, pid);
+        assert_eq!(owned_child(&state, None, Some("sp-e")).unwrap().pid, pid);
+
+        assert_eq!(
+            owned_child(&state, Some("stranger"), None).unwrap_err(),
+            "Node stranger was not spawned by the hypivisor"
+        );
+        assert!(owned_child(&state, Some("missing"), None).is_err());
+        assert!(owned_child(&state, None, Some("sp-missing")).is_err());
+        assert!(owned_child(&state, Some("ours"), Some("sp-e")).is_err());
+        assert!(owned_child(&state, None, None).is_err());
+
+        // Another process taking over the node ID does not inherit ownership.
+        let imposter = node("ours", Some(pid + 100_000), None);
+        state.nodes.write().unwrap().insert("ours".into(), imposter);
+        assert!(owned_child(&state, Some("ours"), None).is_err());
+        wait_until_untracked(&state, pid);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_terminates_then_kills() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let state = make_registry();
+        let polite = spawn_sh("exec sleep 30");
+        let stubborn = spawn_sh("trap '' TERM; exec sleep 30");
+        let (polite_pid, stubborn_pid) = (polite.id(), stubborn.id());
+        let polite_waiter = wait_for(&state, "sp-f");
+        let stubborn_waiter = wait_for(&state, "sp-g");
+        track_sh(&state, polite, "sp-f");
+        track_sh(&state, stubborn, "sp-g");
+        // Let the shell install its trap.
+        std::thread::sleep(Duration::from_millis(200));
+
+        stop(&state, polite_pid, Duration::from_secs(5)).unwrap();
+        stop(&state, stubborn_pid, Duration::from_millis(200)).unwrap();
+        for (waiter, signal) in [
+            (polite_waiter, libc::SIGTERM),
+            (stubborn_waiter, libc::SIGKILL),
+        ] {
+            match waiter.recv_timeout(Duration::from_secs(5)).unwrap() {
+                SpawnOutcome::Exited { status, .. } => {
+                    assert_eq!(status.unwrap().signal(), Some(signal));
+                }
+                other => panic!("unexpected outcome: {other:?}"),
+            }
+        }
+        assert!(wait_untracked(&state, polite_pid, Duration::from_secs(5)));
+        assert!(wait_untracked(&state, stubborn_pid, Duration::from_secs(5)));
+        assert!(stop(&state, polite_pid, Duration::ZERO).is_err());
+    }
+}
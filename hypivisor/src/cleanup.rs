@@ -7,7 +7,7 @@ use tracing::{info, warn};
 /// heartbeat (last_seen) is older than 3× TTL (i.e. 3 missed heartbeat windows).
 pub fn cleanup_stale_nodes(cx: &Cx, state: &Registry) {
     let now = Utc::now().timestamp();
-    let ttl = state.node_ttl as i64;
+    let ttl = state.live().node_ttl as i64;
     let active_ttl = ttl * 3; // active ghosts get more grace (3 missed heartbeats)
     let mut to_remove = vec![];
 
@@ -52,22 +52,15 @@ pub fn cleanup_stale_nodes(cx: &Cx, state: &Registry) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state::{AppState, NodeInfo, NodeStatus};
-    use asupersync::channel::broadcast;
-    use std::{
-        collections::HashMap,
-        path::PathBuf,
-        sync::{Arc, RwLock},
-    };
+    use crate::config::ServerConfig;
+    use crate::state::{NodeInfo, NodeMeta, NodeStatus};
+    use std::path::PathBuf;
 
     fn make_registry(ttl: u64) -> Registry {
-        let (tx, _) = broadcast::channel::<String>(16);
-        Arc::new(AppState {
-            nodes: RwLock::new(HashMap::new()),
-            tx,
-            secret_token: String::new(),
-            home_dir: PathBuf::from("/tmp"),
+        crate::create_state(&ServerConfig {
             node_ttl: ttl,
+            allowed_roots: vec![PathBuf::from("/tmp")],
+            ..Default::default()
         })
     }
 
@@ -86,6 +79,11 @@ mod tests {
                 offline_since: None,
                 last_seen: Some(Utc::now().timestamp()),
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
         cleanup_stale_nodes(&cx, &reg);
@@ -108,6 +106,11 @@ mod tests {
                 offline_since: None,
                 last_seen: None,
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
         cleanup_stale_nodes(&cx, &reg);
@@ -129,6 +132,11 @@ mod tests {
                 offline_since: None,
                 last_seen: Some(Utc::now().timestamp() - 200), // well past 3×TTL
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
         cleanup_stale_nodes(&cx, &reg);
@@ -150,6 +158,11 @@ mod tests {
                 offline_since: Some(Utc::now().timestamp()),
                 last_seen: None,
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
         cleanup_stale_nodes(&cx, &reg);
@@ -171,6 +184,11 @@ mod tests {
                 offline_since: Some(Utc::now().timestamp() - 120),
                 last_seen: None,
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
         cleanup_stale_nodes(&cx, &reg);
@@ -192,6 +210,11 @@ mod tests {
                 offline_since: Some(Utc::now().timestamp() - 120),
                 last_seen: None,
                 pid: None,
+                spawn_id: None,
+                agent_status: None,
+                agent_status_since: None,
+                card: None,
+                meta: NodeMeta::default(),
             },
         );
 
@@ -0,0 +1,911 @@
+//! Configuration: TOML file, environment and CLI, plus hot reload.
+//!
+//! Every setting can come from four places. Precedence is
+//! CLI > environment > config file > built-in defaults. Each source is
+//! parsed into a [`ConfigLayer`] (all fields optional) and the layers are
+//! merged into a fully-resolved [`ServerConfig`].
+//!
+//! Settings in [`LiveConfig`] are re-read on SIGHUP or via the admin
+//! `reload_config` RPC, so changing the node TTL or rotating tokens no
+//! longer requires a restart (which would drop every proxy session). The
+//! remaining settings only take effect on restart.
+//!
+//! Auth is only checked at WebSocket upgrade time, so connections that are
+//! already open keep working across a reload, even if their token was removed.
+
+use crate::auth::is_authorized;
+use crate::backend::BackendKind;
+use crate::fs_browser::Roots;
+use crate::limits::{self, ResourceLimits, SpawnLimits};
+use crate::listener::{ListenerRole, ListenerSpec};
+use crate::search;
+use crate::state::AppState;
+use crate::template::SpawnTemplate;
+use crate::watch;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+pub const DEFAULT_PORT: u16 = 31415;
+pub const DEFAULT_NODE_TTL: u64 = 30;
+pub const DEFAULT_CLEANUP_INTERVAL: u64 = 15;
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 256;
+
+/// One source of settings (file, environment or CLI).
+/// Unset fields fall through to the layer below.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    /// Address to bind when `listen` is not set.
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// Listeners as `ADDR[:PORT][=ROLE]`. Replaces `bind` when set.
+    pub listen: Option<Vec<ListenerSpec>>,
+    /// Unix domain socket for local agents, served in addition to `listen`.
+    /// Its file permissions stand in for the token.
+    pub unix_socket: Option<PathBuf>,
+    /// Seconds before offline nodes are removed from the registry.
+    pub node_ttl: Option<u64>,
+    /// Seconds between stale-node cleanup passes.
+    pub cleanup_interval: Option<u64>,
+    /// Executable launched by `spawn_agent`.
+    pub spawn_command: Option<String>,
+    /// Default spawn backend: `direct`, `tmux`, `screen` or `pty`.
+    pub spawn_backend: Option<BackendKind>,
+    /// Environment variables `spawn_agent` callers may set. Entries are
+    /// exact names or prefixes ending in `*`.
+    pub spawn_env_allowlist: Option<Vec<String>>,
+    /// Directories `list_directories` and `spawn_agent` are confined to.
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    /// Directories inside `allowed_roots` that are off limits.
+    pub denied_paths: Option<Vec<PathBuf>>,
+    /// Directory names `search_directories` does not descend into.
+    pub search_skip: Option<Vec<String>>,
+    /// Names `watch_directory` ignores at any depth.
+    pub watch_ignore: Option<Vec<String>>,
+    /// Most spawned agents running at once. 0 disables the limit.
+    pub max_spawned_agents: Option<usize>,
+    /// Most spawned agents running in one directory. 0 disables the limit.
+    pub max_agents_per_dir: Option<usize>,
+    /// Most spawns in any 60 seconds. 0 disables the limit.
+    pub spawn_rate_per_minute: Option<usize>,
+    /// Resource limits for spawned agents (`[spawn_rlimits]` table).
+    pub spawn_rlimits: Option<ResourceLimits>,
+    /// Stop spawned agents when the hypivisor shuts down on SIGTERM or
+    /// SIGINT. By default they keep running.
+    pub stop_agents_on_exit: Option<bool>,
+    /// Named spawn templates (`[templates.NAME]` tables). File only.
+    pub templates: Option<BTreeMap<String, SpawnTemplate>>,
+    /// JSONL log file.
+    pub log_path: Option<PathBuf>,
+    /// Directory for state kept across restarts (recent directories).
+    pub data_dir: Option<PathBuf>,
+    /// Accepted pre-shared keys. Tokens from every layer are accepted.
+    pub tokens: Option<Vec<String>>,
+    /// Tokens that may also call admin methods such as `reload_config`.
+    /// They are accepted wherever `tokens` are.
+    pub admin_tokens: Option<Vec<String>>,
+    /// Capacity of the registry event broadcast channel.
+    pub broadcast_capacity: Option<usize>,
+}
+
+impl ConfigLayer {
+    /// Overlay `higher` on top of `self`. Set fields in `higher` win,
+    /// except `tokens` and `admin_tokens`, which are combined.
+    pub fn merge(self, higher: ConfigLayer) -> ConfigLayer {
+        let combine = |lower: Option<Vec<String>>, upper| match (lower, upper) {
+            (Some(mut lower), Some(upper)) => {
+                lower.extend(upper);
+                Some(lower)
+            }
+            (lower, upper) => upper.or(lower),
+        };
+        ConfigLayer {
+            bind: higher.bind.or(self.bind),
+            port: higher.port.or(self.port),
+            listen: higher.listen.or(self.listen),
+            unix_socket: higher.unix_socket.or(self.unix_socket),
+            node_ttl: higher.node_ttl.or(self.node_ttl),
+            cleanup_interval: higher.cleanup_interval.or(self.cleanup_interval),
+            spawn_command: higher.spawn_command.or(self.spawn_command),
+            spawn_backend: higher.spawn_backend.or(self.spawn_backend),
+            spawn_env_allowlist: higher.spawn_env_allowlist.or(self.spawn_env_allowlist),
+            allowed_roots: higher.allowed_roots.or(self.allowed_roots),
+            denied_paths: higher.denied_paths.or(self.denied_paths),
+            search_skip: higher.search_skip.or(self.search_skip),
+            watch_ignore: higher.watch_ignore.or(self.watch_ignore),
+            max_spawned_agents: higher.max_spawned_agents.or(self.max_spawned_agents),
+            max_agents_per_dir: higher.max_agents_per_dir.or(self.max_agents_per_dir),
+            spawn_rate_per_minute: higher.spawn_rate_per_minute.or(self.spawn_rate_per_minute),
+            spawn_rlimits: higher.spawn_rlimits.or(self.spawn_rlimits),
+            stop_agents_on_exit: higher.stop_agents_on_exit.or(self.stop_agents_on_exit),
+            templates: higher.templates.or(self.templates),
+            log_path: higher.log_path.or(self.log_path),
+            data_dir: higher.data_dir.or(self.data_dir),
+            tokens: combine(self.tokens, higher.tokens),
+            admin_tokens: combine(self.admin_tokens, higher.admin_tokens),
+            broadcast_capacity: higher.broadcast_capacity.or(self.broadcast_capacity),
+        }
+    }
+
+    /// Build a layer from environment variables, using `lookup` to read them.
+    ///
+    /// Recognised: `HYPI_TOKEN`, `HYPIVISOR_BIND`, `HYPIVISOR_PORT`,
+    /// `HYPIVISOR_LISTEN` (comma-separated), `HYPIVISOR_UNIX_SOCKET`,
+    /// `HYPIVISOR_NODE_TTL`, `HYPIVISOR_SPAWN_COMMAND`,
+    /// `HYPIVISOR_SPAWN_BACKEND`, `HYPIVISOR_LOG`, `HYPIVISOR_DATA_DIR`.
+    pub fn from_env_with(lookup: impl Fn(&str) -> Option<String>) -> Result<ConfigLayer, String> {
+        let lookup = |key: &str| lookup(key).filter(|v| !v.is_empty());
+        let parse_num = |key: &str| -> Result<Option<u64>, String> {
+            lookup(key)
+                .map(|v| v.parse::<u64>().map_err(|e| format!("Invalid {key}: {e}")))
+                .transpose()
+        };
+        let port = parse_num("HYPIVISOR_PORT")?
+            .map(|p| u16::try_from(p).map_err(|_| format!("Invalid HYPIVISOR_PORT: {p}")))
+            .transpose()?;
+        let bind = lookup("HYPIVISOR_BIND")
+            .map(|v| v.parse::<IpAddr>().map_err(|e| format!("Invalid HYPIVISOR_BIND: {e}")))
+            .transpose()?;
+        let listen = lookup("HYPIVISOR_LISTEN")
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().parse::<ListenerSpec>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Invalid HYPIVISOR_LISTEN: {e}"))
+            })
+            .transpose()?;
+        let spawn_backend = lookup("HYPIVISOR_SPAWN_BACKEND")
+            .map(|v| {
+                v.parse::<BackendKind>()
+                    .map_err(|e| format!("Invalid HYPIVISOR_SPAWN_BACKEND: {e}"))
+            })
+            .transpose()?;
+        Ok(ConfigLayer {
+            bind,
+            port,
+            listen,
+            unix_socket: lookup("HYPIVISOR_UNIX_SOCKET").map(PathBuf::from),
+            node_ttl: parse_num("HYPIVISOR_NODE_TTL")?,
+            spawn_command: lookup("HYPIVISOR_SPAWN_COMMAND"),
+            spawn_backend,
+            log_path: lookup("HYPIVISOR_LOG").map(PathBuf::from),
+            data_dir: lookup("HYPIVISOR_DATA_DIR").map(PathBuf::from),
+            tokens: lookup("HYPI_TOKEN").map(|t| vec![t]),
+            ..Default::default()
+        })
+    }
+
+    /// Build a layer from the process environment.
+    pub fn from_env() -> Result<ConfigLayer, String> {
+        Self::from_env_with(|key| env::var(key).ok())
+    }
+}
+
+/// Where the resolved config comes from, kept so it can be re-resolved on reload.
+#[derive(Debug, Default, Clone)]
+pub struct ConfigSource {
+    /// Config file, if any.
+    pub path: Option<PathBuf>,
+    /// CLI and environment settings, already merged. Always win over the file.
+    pub overrides: ConfigLayer,
+}
+
+/// Fully-resolved server configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub listen: Vec<ListenerSpec>,
+    pub unix_socket: Option<PathBuf>,
+    pub node_ttl: u64,
+    pub cleanup_interval: u64,
+    pub spawn_command: String,
+    pub spawn_backend: BackendKind,
+    pub spawn_env_allowlist: Vec<String>,
+    pub allowed_roots: Vec<PathBuf>,
+    pub denied_paths: Vec<PathBuf>,
+    pub search_skip: Vec<String>,
+    pub watch_ignore: Vec<String>,
+    pub max_spawned_agents: usize,
+    pub max_agents_per_dir: usize,
+    pub spawn_rate_per_minute: usize,
+    pub spawn_rlimits: ResourceLimits,
+    pub stop_agents_on_exit: bool,
+    pub templates: BTreeMap<String, SpawnTemplate>,
+    pub log_path: PathBuf,
+    /// `None` keeps state in memory only. `resolve` fills in
+    /// `~/.hyper-pi/state` when nothing is configured.
+    pub data_dir: Option<PathBuf>,
+    /// Left out of the printed config, which only counts them.
+    #[serde(skip)]
+    pub tokens: Vec<String>,
+    #[serde(skip)]
+    pub admin_tokens: Vec<String>,
+    pub broadcast_capacity: usize,
+    /// Where this config was resolved from. Not part of the printed config.
+    #[serde(skip)]
+    pub source: ConfigSource,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let home_dir = dirs::home_dir().unwrap_or_else(|| {
+            warn!("Could not determine home directory, falling back to '.'");
+            ".".into()
+        });
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            listen: Vec::new(),
+            unix_socket: None,
+            node_ttl: DEFAULT_NODE_TTL,
+            cleanup_interval: DEFAULT_CLEANUP_INTERVAL,
+            spawn_command: "pi".into(),
+            spawn_backend: BackendKind::Direct,
+            spawn_env_allowlist: vec!["PI_*".into()],
+            log_path: home_dir.join(".pi").join("logs").join("hyper-pi.jsonl"),
+            data_dir: None,
+            allowed_roots: vec![home_dir],
+            denied_paths: Vec::new(),
+            search_skip: search::DEFAULT_SKIP.iter().map(|s| s.to_string()).collect(),
+            watch_ignore: watch::DEFAULT_IGNORE
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            max_spawned_agents: limits::DEFAULT_MAX_SPAWNED_AGENTS,
+            max_agents_per_dir: limits::DEFAULT_MAX_AGENTS_PER_DIR,
+            spawn_rate_per_minute: limits::DEFAULT_SPAWN_RATE_PER_MINUTE,
+            spawn_rlimits: ResourceLimits::default(),
+            stop_agents_on_exit: false,
+            templates: BTreeMap::new(),
+            tokens: Vec::new(),
+            admin_tokens: Vec::new(),
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+            source: ConfigSource::default(),
+        }
+    }
+}
+
+/// Drop empty and repeated tokens, keeping the first occurrence.
+fn clean_tokens(tokens: Option<Vec<String>>) -> Vec<String> {
+    let mut cleaned: Vec<String> = Vec::new();
+    for token in tokens.unwrap_or_default() {
+        if !token.is_empty() && !cleaned.contains(&token) {
+            cleaned.push(token);
+        }
+    }
+    cleaned
+}
+
+impl ServerConfig {
+    /// Apply a merged layer on top of the defaults and validate the result.
+    /// Empty tokens are dropped so a blank entry can never disable auth.
+    pub fn resolve(layer: ConfigLayer) -> Result<ServerConfig, String> {
+        let defaults = ServerConfig::default();
+        let config = ServerConfig {
+            bind: layer.bind.unwrap_or(defaults.bind),
+            port: layer.port.unwrap_or(defaults.port),
+            listen: layer.listen.unwrap_or(defaults.listen),
+            unix_socket: layer.unix_socket.or(defaults.unix_socket),
+            node_ttl: layer.node_ttl.unwrap_or(defaults.node_ttl),
+            cleanup_interval: layer.cleanup_interval.unwrap_or(defaults.cleanup_interval),
+            spawn_command: layer.spawn_command.unwrap_or(defaults.spawn_command),
+            spawn_backend: layer.spawn_backend.unwrap_or(defaults.spawn_backend),
+            spawn_env_allowlist: layer
+                .spawn_env_allowlist
+                .unwrap_or(defaults.spawn_env_allowlist),
+            allowed_roots: layer.allowed_roots.unwrap_or(defaults.allowed_roots),
+            denied_paths: layer.denied_paths.unwrap_or(defaults.denied_paths),
+            search_skip: layer.search_skip.unwrap_or(defaults.search_skip),
+            watch_ignore: layer.watch_ignore.unwrap_or(defaults.watch_ignore),
+            max_spawned_agents: layer
+                .max_spawned_agents
+                .unwrap_or(defaults.max_spawned_agents),
+            max_agents_per_dir: layer
+                .max_agents_per_dir
+                .unwrap_or(defaults.max_agents_per_dir),
+            spawn_rate_per_minute: layer
+                .spawn_rate_per_minute
+                .unwrap_or(defaults.spawn_rate_per_minute),
+            spawn_rlimits: layer.spawn_rlimits.unwrap_or(defaults.spawn_rlimits),
+            stop_agents_on_exit: layer
+                .stop_agents_on_exit
+                .unwrap_or(defaults.stop_agents_on_exit),
+            templates: layer.templates.unwrap_or(defaults.templates),
+            log_path: layer.log_path.unwrap_or(defaults.log_path),
+            data_dir: layer
+                .data_dir
+                .or(defaults.data_dir)
+                .or_else(|| dirs::home_dir().map(|h| h.join(".hyper-pi").join("state"))),
+            tokens: clean_tokens(layer.tokens),
+            admin_tokens: clean_tokens(layer.admin_tokens),
+            broadcast_capacity: layer.broadcast_capacity.unwrap_or(defaults.broadcast_capacity),
+            source: ConfigSource::default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Resolve the config described by `source`: defaults, then the file,
+    /// then the CLI/environment overrides.
+    pub fn load(source: &ConfigSource) -> Result<ServerConfig, String> {
+        let file = match &source.path {
+            Some(path) => load_file(path)?,
+            None => ConfigLayer::default(),
+        };
+        let mut config = Self::resolve(file.merge(source.overrides.clone()))?;
+        config.source = source.clone();
+        Ok(config)
+    }
+
+    /// Listeners to bind: `listen` if set, otherwise a single `bind:port`
+    /// listener serving everything.
+    pub fn listeners(&self) -> Vec<ListenerSpec> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        vec![ListenerSpec {
+            ip: self.bind,
+            port: Some(self.port),
+            role: ListenerRole::All,
+        }]
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.cleanup_interval == 0 {
+            return Err("cleanup_interval must be greater than 0".into());
+        }
+        if self.broadcast_capacity == 0 {
+            return Err("broadcast_capacity must be greater than 0".into());
+        }
+        if self.spawn_command.trim().is_empty() {
+            return Err("spawn_command must not be empty".into());
+        }
+        if self.allowed_roots.is_empty() {
+            return Err("allowed_roots must not be empty".into());
+        }
+        if cfg!(not(unix)) && self.unix_socket.is_some() {
+            return Err("unix_socket is only supported on Unix".into());
+        }
+        for (key, paths) in [
+            ("allowed_roots", &self.allowed_roots),
+            ("denied_paths", &self.denied_paths),
+        ] {
+            if let Some(path) = paths.iter().find(|p| !p.is_absolute()) {
+                return Err(format!("{key} must be absolute paths: {}", path.display()));
+            }
+        }
+        for (name, template) in &self.templates {
+            template.validate(name)?;
+        }
+        Ok(())
+    }
+
+    /// Render the effective config as TOML, for `--print-config`. Tokens are
+    /// only counted in a comment, so the output can be reused as a config
+    /// file without turning a placeholder into a valid token.
+    pub fn to_toml(&self) -> String {
+        let body =
+            toml::to_string(self).unwrap_or_else(|e| format!("# failed to render config: {e}\n"));
+        format!(
+            "# tokens: {} configured, admin_tokens: {} configured (not shown)\n{body}",
+            self.tokens.len(),
+            self.admin_tokens.len()
+        )
+    }
+
+    /// The allowed roots and denied paths in this config.
+    pub fn roots(&self) -> Roots {
+        Roots::new(self.allowed_roots.clone(), self.denied_paths.clone())
+    }
+
+    /// The spawn limits in this config.
+    pub fn spawn_limits(&self) -> SpawnLimits {
+        SpawnLimits {
+            max_agents: self.max_spawned_agents,
+            max_per_dir: self.max_agents_per_dir,
+            rate_per_minute: self.spawn_rate_per_minute,
+        }
+    }
+
+    /// The hot-reloadable subset of this config.
+    pub fn live(&self) -> LiveConfig {
+        LiveConfig {
+            tokens: self.tokens.clone(),
+            admin_tokens: self.admin_tokens.clone(),
+            node_ttl: self.node_ttl,
+            cleanup_interval: self.cleanup_interval,
+        }
+    }
+
+    /// Settings that differ in `newer` but only take effect on restart.
+    pub fn restart_only_changes(&self, newer: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        let mut check = |name, differs: bool| {
+            if differs {
+                changed.push(name);
+            }
+        };
+        check("bind", self.bind != newer.bind);
+        check("port", self.port != newer.port);
+        check("listen", self.listen != newer.listen);
+        check("unix_socket", self.unix_socket != newer.unix_socket);
+        check("spawn_command", self.spawn_command != newer.spawn_command);
+        check("spawn_backend", self.spawn_backend != newer.spawn_backend);
+        check(
+            "spawn_env_allowlist",
+            self.spawn_env_allowlist != newer.spawn_env_allowlist,
+        );
+        check("allowed_roots", self.allowed_roots != newer.allowed_roots);
+        check("denied_paths", self.denied_paths != newer.denied_paths);
+        check("search_skip", self.search_skip != newer.search_skip);
+        check("watch_ignore", self.watch_ignore != newer.watch_ignore);
+        check(
+            "max_spawned_agents",
+            self.max_spawned_agents != newer.max_spawned_agents,
+        );
+        check(
+            "max_agents_per_dir",
+            self.max_agents_per_dir != newer.max_agents_per_dir,
+        );
+        check(
+            "spawn_rate_per_minute",
+            self.spawn_rate_per_minute != newer.spawn_rate_per_minute,
+        );
+        check("spawn_rlimits", self.spawn_rlimits != newer.spawn_rlimits);
+        check(
+            "stop_agents_on_exit",
+            self.stop_agents_on_exit != newer.stop_agents_on_exit,
+        );
+        check("templates", self.templates != newer.templates);
+        check("log_path", self.log_path != newer.log_path);
+        check("data_dir", self.data_dir != newer.data_dir);
+        check(
+            "broadcast_capacity",
+            self.broadcast_capacity != newer.broadcast_capacity,
+        );
+        changed
+    }
+}
+
+/// Settings that can change while the server is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveConfig {
+    /// Accepted tokens. Empty means authentication is disabled.
+    pub tokens: Vec<String>,
+    /// Tokens that may also call admin methods.
+    pub admin_tokens: Vec<String>,
+    pub node_ttl: u64,
+    pub cleanup_interval: u64,
+}
+
+impl LiveConfig {
+    /// Whether a client presenting `token` may connect. With no tokens of
+    /// either kind configured, authentication is disabled.
+    pub fn accepts(&self, token: Option<&str>) -> bool {
+        if self.tokens.is_empty() && self.admin_tokens.is_empty() {
+            return true;
+        }
+        self.is_admin_token(token) || self.tokens.iter().any(|s| is_authorized(token, s))
+    }
+
+    /// Whether a client presenting `token` may call admin methods such as
+    /// `reload_config`. Only admin tokens qualify, unless authentication
+    /// is disabled altogether.
+    pub fn is_admin(&self, token: Option<&str>) -> bool {
+        if self.tokens.is_empty() && self.admin_tokens.is_empty() {
+            return true;
+        }
+        self.is_admin_token(token)
+    }
+
+    fn is_admin_token(&self, token: Option<&str>) -> bool {
+        self.admin_tokens.iter().any(|s| is_authorized(token, s))
+    }
+}
+
+/// The hot-reloadable settings in effect and the config they were taken
+/// from, which the next reload is compared against.
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    pub live: LiveConfig,
+    pub config: ServerConfig,
+}
+
+/// What a reload changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reloaded {
+    pub live: LiveConfig,
+    /// Settings changed since the last load that need a restart.
+    pub ignored: Vec<&'static str>,
+}
+
+/// Read and parse a TOML config file.
+pub fn load_file(path: &Path) -> Result<ConfigLayer, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+}
+
+/// Re-read the config file and swap in the new hot-reloadable settings.
+/// On error the current settings are left untouched. Changes to settings
+/// that need a restart are logged and ignored; each is reported once, by
+/// the first reload that sees it change.
+pub fn reload(state: &AppState) -> Result<Reloaded, String> {
+    if state.config.source.path.is_none() {
+        return Err("No config file configured".into());
+    }
+    // Held throughout, so concurrent reloads compare against each other.
+    let mut loaded = state.live.write().expect("config lock poisoned in reload");
+    let config = ServerConfig::load(&state.config.source)?;
+    let ignored = loaded.config.restart_only_changes(&config);
+    if !ignored.is_empty() {
+        let ignored = ignored.join(", ");
+        warn!(settings = %ignored, "Config reload: these changes require a restart");
+        crate::log::warn(
+            "config.reload",
+            &format!("Config reload ignored changes that require a restart: {ignored}"),
+        );
+    }
+    let live = config.live();
+    *loaded = Loaded {
+        live: live.clone(),
+        config,
+    };
+    info!(node_ttl = live.node_ttl, tokens = live.tokens.len(), "Config reloaded");
+    Ok(Reloaded { live, ignored })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_full_file() {
+        let layer: ConfigLayer = toml::from_str(
+            r#"
+            bind = "127.0.0.1"
+            port = 4000
+            listen = ["127.0.0.1=dashboard", "[::]:5000=agents"]
+            unix_socket = "/run/user/1000/hypivisor.sock"
+            node_ttl = 90
+            cleanup_interval = 5
+            spawn_command = "/usr/local/bin/pi"
+            spawn_backend = "tmux"
+            spawn_env_allowlist = ["PI_*", "OPENAI_BASE_URL"]
+            allowed_roots = ["/srv/work"]
+            denied_paths = ["/srv/work/secrets"]
+            search_skip = ["node_modules"]
+            watch_ignore = [".git", "target"]
+            log_path = "/var/log/hypivisor.jsonl"
+            data_dir = "/var/lib/hypivisor"
+            tokens = ["a", "b"]
+            admin_tokens = ["root"]
+            broadcast_capacity = 1024
+            max_spawned_agents = 8
+            max_agents_per_dir = 0
+            spawn_rate_per_minute = 5
+
+            [spawn_rlimits]
+            nofile = 1024
+            memory_mb = 4096
+
+            [templates.reviewer]
+            path = "/srv/work/api"
+            model = "sonnet"
+            prompt = "Review {branch}"
+
+            [templates.reviewer.sandbox]
+            network = false
+            "#,
+        )
+        .unwrap();
+        let config = ServerConfig::resolve(layer).unwrap();
+        assert_eq!(config.bind.to_string(), "127.0.0.1");
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.listen[1].role, ListenerRole::Agents);
+        assert_eq!(
+            config.unix_socket,
+            Some(PathBuf::from("/run/user/1000/hypivisor.sock"))
+        );
+        assert_eq!(config.node_ttl, 90);
+        assert_eq!(config.cleanup_interval, 5);
+        assert_eq!(config.spawn_command, "/usr/local/bin/pi");
+        assert_eq!(config.spawn_backend, BackendKind::Tmux);
+        assert_eq!(config.spawn_env_allowlist, vec!["PI_*", "OPENAI_BASE_URL"]);
+        assert_eq!(config.allowed_roots, vec![PathBuf::from("/srv/work")]);
+        assert_eq!(
+            config.denied_paths,
+            vec![PathBuf::from("/srv/work/secrets")]
+        );
+        assert_eq!(config.search_skip, vec!["node_modules"]);
+        assert_eq!(config.watch_ignore, vec![".git", "target"]);
+        assert_eq!(config.log_path, PathBuf::from("/var/log/hypivisor.jsonl"));
+        assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/hypivisor")));
+        assert_eq!(config.tokens, vec!["a", "b"]);
+        assert_eq!(config.admin_tokens, vec!["root"]);
+        assert_eq!(config.broadcast_capacity, 1024);
+        assert_eq!(
+            config.spawn_limits(),
+            SpawnLimits {
+                max_agents: 8,
+                max_per_dir: 0,
+                rate_per_minute: 5,
+            }
+        );
+        assert_eq!(config.spawn_rlimits.nofile, Some(1024));
+        assert_eq!(config.spawn_rlimits.memory_mb, Some(4096));
+        let reviewer = &config.templates["reviewer"];
+        assert_eq!(reviewer.path.as_deref(), Some("/srv/work/api"));
+        assert_eq!(reviewer.vars(), ["branch"]);
+        let sandbox = reviewer.sandbox.as_ref().unwrap();
+        assert!(!sandbox.network);
+        assert_eq!(sandbox.writable, ["~/.pi"]);
+        assert!(config.to_toml().contains("[templates.reviewer]"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let layer: ConfigLayer = toml::from_str(
+            r#"
+            [templates.bad]
+            env = { "1X" = "y" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            ServerConfig::resolve(layer).unwrap_err(),
+            "Invalid template 'bad': Invalid environment variable name: 1X"
+        );
+        assert!(toml::from_str::<ConfigLayer>("[templates.t]\nmodle = \"x\"").is_err());
+        let layer: ConfigLayer =
+            toml::from_str("[templates.t.sandbox]\nwritable = [\"cache\"]").unwrap();
+        assert_eq!(
+            ServerConfig::resolve(layer).unwrap_err(),
+            "Invalid template 't': sandbox writable paths must be absolute or start with ~/: cache"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<ConfigLayer>("nodettl = 90").is_err());
+    }
+
+    #[test]
+    fn empty_layer_resolves_to_defaults() {
+        let config = ServerConfig::resolve(ConfigLayer::default()).unwrap();
+        assert_eq!(config.bind.to_string(), "0.0.0.0");
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.node_ttl, DEFAULT_NODE_TTL);
+        assert_eq!(config.broadcast_capacity, DEFAULT_BROADCAST_CAPACITY);
+        assert_eq!(config.spawn_command, "pi");
+        assert!(config.tokens.is_empty());
+    }
+
+    #[test]
+    fn listeners_default_to_bind_and_port() {
+        let config = ServerConfig::resolve(ConfigLayer {
+            port: Some(4000),
+            ..Default::default()
+        })
+        .unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].socket_addr(0).to_string(), "0.0.0.0:4000");
+        assert_eq!(listeners[0].role, ListenerRole::All);
+    }
+
+    #[test]
+    fn rejects_invalid_listen_entry() {
+        assert!(toml::from_str::<ConfigLayer>("listen = [\"localhost=all\"]").is_err());
+    }
+
+    #[test]
+    fn higher_layer_wins() {
+        let file = ConfigLayer {
+            port: Some(1000),
+            node_ttl: Some(10),
+            ..Default::default()
+        };
+        let cli = ConfigLayer {
+            port: Some(2000),
+            ..Default::default()
+        };
+        let config = ServerConfig::resolve(file.merge(cli)).unwrap();
+        assert_eq!(config.port, 2000);
+        assert_eq!(config.node_ttl, 10);
+    }
+
+    #[test]
+    fn tokens_from_all_layers_are_combined() {
+        let file = ConfigLayer {
+            tokens: Some(vec!["file".into(), "env".into(), String::new()]),
+            ..Default::default()
+        };
+        let env = ConfigLayer {
+            tokens: Some(vec!["env".into()]),
+            ..Default::default()
+        };
+        let config = ServerConfig::resolve(file.merge(env)).unwrap();
+        assert_eq!(config.tokens, vec!["file", "env"]);
+    }
+
+    #[test]
+    fn admin_tokens_gate_admin_methods() {
+        let live = |tokens: &[&str], admin_tokens: &[&str]| LiveConfig {
+            tokens: tokens.iter().map(|t| t.to_string()).collect(),
+            admin_tokens: admin_tokens.iter().map(|t| t.to_string()).collect(),
+            node_ttl: DEFAULT_NODE_TTL,
+            cleanup_interval: DEFAULT_CLEANUP_INTERVAL,
+        };
+
+        let open = live(&[], &[]);
+        assert!(open.accepts(None));
+        assert!(open.is_admin(None));
+
+        let user_only = live(&["user"], &[]);
+        assert!(user_only.accepts(Some("user")));
+        assert!(!user_only.is_admin(Some("user")));
+
+        let both = live(&["user"], &["root"]);
+        assert!(both.accepts(Some("root")));
+        assert!(both.is_admin(Some("root")));
+        assert!(!both.is_admin(Some("user")));
+        assert!(!both.accepts(Some("guest")));
+
+        let admin_only = live(&[], &["root"]);
+        assert!(!admin_only.accepts(None));
+        assert!(admin_only.is_admin(Some("root")));
+    }
+
+    #[test]
+    fn restart_only_changes_lists_ignored_settings() {
+        let current = ServerConfig::default();
+        let newer = ServerConfig {
+            node_ttl: 99,
+            tokens: vec!["rotated".into()],
+            unix_socket: Some("/tmp/hypi.sock".into()),
+            max_spawned_agents: 1,
+            log_path: "/tmp/hypi.jsonl".into(),
+            ..ServerConfig::default()
+        };
+        assert!(current.restart_only_changes(&current.clone()).is_empty());
+        assert_eq!(
+            current.restart_only_changes(&newer),
+            ["unix_socket", "max_spawned_agents", "log_path"]
+        );
+    }
+
+    #[test]
+    fn env_layer_reads_known_variables() {
+        let vars: HashMap<&str, &str> = [
+            ("HYPI_TOKEN", "secret"),
+            ("HYPIVISOR_PORT", "5000"),
+            ("HYPIVISOR_NODE_TTL", "60"),
+            ("HYPIVISOR_BIND", ""),
+            ("HYPIVISOR_LISTEN", "127.0.0.1=dashboard, [::1]=agents"),
+            ("HYPIVISOR_UNIX_SOCKET", "/tmp/hypi.sock"),
+            ("HYPIVISOR_SPAWN_BACKEND", "pty"),
+        ]
+        .into_iter()
+        .collect();
+        let layer = ConfigLayer::from_env_with(|k| vars.get(k).map(|v| v.to_string())).unwrap();
+        assert_eq!(layer.port, Some(5000));
+        assert_eq!(layer.node_ttl, Some(60));
+        assert_eq!(layer.tokens, Some(vec!["secret".to_string()]));
+        assert!(layer.bind.is_none());
+        assert_eq!(layer.listen.unwrap().len(), 2);
+        assert_eq!(layer.unix_socket, Some(PathBuf::from("/tmp/hypi.sock")));
+        assert_eq!(layer.spawn_backend, Some(BackendKind::Pty));
+    }
+
+    #[test]
+    fn env_layer_rejects_bad_numbers() {
+        let err = ConfigLayer::from_env_with(|k| {
+            (k == "HYPIVISOR_PORT").then(|| "99999".to_string())
+        })
+        .unwrap_err();
+        assert!(err.contains("HYPIVISOR_PORT"));
+    }
+
+    #[test]
+    fn rejects_zero_broadcast_capacity() {
+        let layer = ConfigLayer {
+            broadcast_capacity: Some(0),
+            ..Default::default()
+        };
+        assert!(ServerConfig::resolve(layer).is_err());
+    }
+
+    #[test]
+    fn rejects_relative_roots() {
+        let layer = ConfigLayer {
+            denied_paths: Some(vec!["secrets".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            ServerConfig::resolve(layer).unwrap_err(),
+            "denied_paths must be absolute paths: secrets"
+        );
+    }
+
+    #[test]
+    fn load_applies_overrides_over_file() {
+        let path = std::env::temp_dir().join("hypi_test_config_load.toml");
+        fs::write(&path, "port = 1111\nnode_ttl = 120\n").unwrap();
+        let source = ConfigSource {
+            path: Some(path.clone()),
+            overrides: ConfigLayer {
+                port: Some(2222),
+                ..Default::default()
+            },
+        };
+        let config = ServerConfig::load(&source).unwrap();
+        assert_eq!(config.port, 2222);
+        assert_eq!(config.node_ttl, 120);
+        assert_eq!(config.source.path, Some(path.clone()));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reload_reports_each_restart_only_change_once() {
+        let dir = std::env::temp_dir().join(format!("hypi_test_reload_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hypivisor.toml");
+        let write = |extra: &str| {
+            let text = format!("data_dir = {:?}\n{extra}", dir.join("data"));
+            fs::write(&path, text).unwrap();
+        };
+        write("node_ttl = 30\n");
+        let source = ConfigSource {
+            path: Some(path.clone()),
+            overrides: ConfigLayer::default(),
+        };
+        let state = crate::create_state(&ServerConfig::load(&source).unwrap());
+
+        write("max_spawned_agents = 3\nnode_ttl = 60\n");
+        let first = reload(&state).unwrap();
+        assert_eq!(first.ignored, ["max_spawned_agents"]);
+        assert_eq!(first.live.node_ttl, 60);
+        let second = reload(&state).unwrap();
+        assert!(second.ignored.is_empty());
+        write("node_ttl = 60\n");
+        assert_eq!(reload(&state).unwrap().ignored, ["max_spawned_agents"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_missing_file_returns_error() {
+        let source = ConfigSource {
+            path: Some(std::env::temp_dir().join("hypi_missing_config.toml")),
+            overrides: ConfigLayer::default(),
+        };
+        let err = ServerConfig::load(&source).unwrap_err();
+        assert!(err.contains("Cannot read config"));
+    }
+
+    #[test]
+    fn printed_config_leaves_out_tokens_and_round_trips() {
+        let config = ServerConfig {
+            tokens: vec!["hunter2".into()],
+            admin_tokens: vec!["root-pw".into()],
+            ..Default::default()
+        };
+        let printed = config.to_toml();
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("root-pw"));
+        assert!(printed.starts_with("# tokens: 1 configured, admin_tokens: 1 configured"));
+        let reparsed: ConfigLayer = toml::from_str(&printed).unwrap();
+        assert_eq!(reparsed.port, Some(DEFAULT_PORT));
+        assert_eq!(reparsed.tokens, None);
+        assert_eq!(reparsed.admin_tokens, None);
+    }
+}
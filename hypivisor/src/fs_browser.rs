@@ -1,51 +1,357 @@
-use std::{fs, path::PathBuf};
+use crate::worktree::git;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
-/// List visible (non-hidden) subdirectories at `target`, enforcing
-/// that the resolved path stays within `home_dir`.
-pub fn list_directories(
-    target: &PathBuf,
-    home_dir: &PathBuf,
-) -> Result<(String, Vec<String>), String> {
-    let canonical = fs::canonicalize(target).map_err(|e| format!("Invalid path: {}", e))?;
+/// Where `list_directories` and `spawn_agent` may go: anywhere inside an
+/// allowed root, except inside a denied path. Both are canonicalized when
+/// they exist, so they compare against canonical paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Roots {
+    allowed: Vec<PathBuf>,
+    denied: Vec<PathBuf>,
+}
+
+fn canonical_or_given(path: PathBuf) -> PathBuf {
+    fs::canonicalize(&path).unwrap_or(path)
+}
+
+impl Roots {
+    pub fn new(allowed: Vec<PathBuf>, denied: Vec<PathBuf>) -> Roots {
+        Roots {
+            allowed: allowed.into_iter().map(canonical_or_given).collect(),
+            denied: denied.into_iter().map(canonical_or_given).collect(),
+        }
+    }
+
+    pub fn allowed(&self) -> &[PathBuf] {
+        &self.allowed
+    }
+
+    pub fn denied(&self) -> &[PathBuf] {
+        &self.denied
+    }
+
+    /// The most specific allowed root containing `path`, unless `path` is
+    /// inside a denied path.
+    pub fn root_of(&self, path: &Path) -> Option<&Path> {
+        if self.denied.iter().any(|d| path.starts_with(d)) {
+            return None;
+        }
+        self.allowed
+            .iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .map(PathBuf::as_path)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.root_of(path).is_some()
+    }
+
+    /// Like `root_of`, with an error saying why `path` is not allowed.
+    pub fn check(&self, path: &Path) -> Result<&Path, String> {
+        self.root_of(path).ok_or_else(|| {
+            if self.denied.iter().any(|d| path.starts_with(d)) {
+                "Path is inside a denied directory".to_string()
+            } else {
+                "Path resolves outside allowed roots".to_string()
+            }
+        })
+    }
+}
+
+/// Most entries one `list_directories` call returns.
+pub const MAX_LIST_LIMIT: usize = 5000;
+const DEFAULT_LIST_LIMIT: usize = 500;
+/// Most repositories one listing runs `git status` in, and the time it may
+/// spend on them. Repositories past either limit are reported without
+/// branch or dirty state.
+const MAX_GIT_STATUS: usize = 50;
+const GIT_STATUS_BUDGET: Duration = Duration::from_secs(2);
+
+/// How to order a listing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    /// Most recently modified first.
+    Mtime,
+}
+
+/// `list_directories` options besides the path.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ListOptions {
+    pub sort: SortBy,
+    /// Include directories whose names start with a dot.
+    pub show_hidden: bool,
+    pub offset: usize,
+    pub limit: usize,
+    /// Report the git state of repositories. Off by default, since it runs
+    /// `git status` in each one.
+    pub git: bool,
+}
 
-    if !canonical.starts_with(home_dir) {
-        return Err("Path resolves outside home directory".into());
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            sort: SortBy::Name,
+            show_hidden: false,
+            offset: 0,
+            limit: DEFAULT_LIST_LIMIT,
+            git: false,
+        }
+    }
+}
+
+impl ListOptions {
+    /// Read the options from `list_directories` params.
+    pub fn from_params(params: &Value) -> Result<ListOptions, String> {
+        let options: ListOptions = serde_json::from_value(params.clone())
+            .map_err(|e| format!("Invalid list options: {e}"))?;
+        if options.limit == 0 || options.limit > MAX_LIST_LIMIT {
+            return Err(format!("limit must be between 1 and {MAX_LIST_LIMIT}"));
+        }
+        Ok(options)
+    }
+}
+
+/// Git state of a directory that is the top of a repository or worktree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GitInfo {
+    /// Checked-out branch; `None` when detached or unknown.
+    pub branch: Option<String>,
+    /// Tracked files have uncommitted changes. `None` if git failed or was
+    /// not run.
+    pub dirty: Option<bool>,
+}
+
+impl GitInfo {
+    const UNKNOWN: GitInfo = GitInfo {
+        branch: None,
+        dirty: None,
+    };
+}
+
+/// One subdirectory in a listing.
+#[derive(Debug, Clone, Serialize)]
+pub struct DirEntry {
+    pub name: String,
+    /// Last modification, in Unix seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitInfo>,
+    /// Has a `.pi` directory (project-local pi settings).
+    pub pi: bool,
+    /// Active agents running in or below it. Filled in by the caller.
+    pub agents: usize,
+}
+
+/// A directory listing.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    /// Canonical path that was listed.
+    pub current: String,
+    /// The allowed root it is in.
+    pub root: String,
+    /// Names of the entries on this page, as in `entries`.
+    pub directories: Vec<String>,
+    pub entries: Vec<DirEntry>,
+    /// Matching subdirectories across all pages.
+    pub total: usize,
+    pub offset: usize,
+}
+
+/// Branch and dirty state of the repository at `dir`.
+fn git_info(dir: &Path) -> GitInfo {
+    let Ok(status) = git(dir, &["status", "--porcelain=v2", "--branch", "-uno"]) else {
+        return GitInfo::UNKNOWN;
+    };
+    let branch = status
+        .lines()
+        .find_map(|l| l.strip_prefix("# branch.head "))
+        .filter(|b| *b != "(detached)")
+        .map(String::from);
+    GitInfo {
+        branch,
+        dirty: Some(status.lines().any(|l| !l.starts_with('#'))),
     }
+}
+
+/// List subdirectories at `target`, enforcing that the resolved path, and
+/// every listed entry, stays within `roots`. Hidden directories are skipped
+/// unless asked for.
+pub fn list_directories(
+    target: &Path,
+    roots: &Roots,
+    options: &ListOptions,
+) -> Result<Listing, String> {
+    let canonical = fs::canonicalize(target).map_err(|e| format!("Invalid path: {}", e))?;
+    let root = roots.check(&canonical)?.to_string_lossy().to_string();
 
-    let mut directories = Vec::new();
+    let mut found = Vec::new();
     let entries = fs::read_dir(&canonical).map_err(|e| format!("Cannot read directory: {}", e))?;
 
     for entry in entries.flatten() {
         let name = entry.file_name();
         let name_str = name.to_string_lossy();
 
-        if name_str.starts_with('.') {
+        if name_str.starts_with('.') && !options.show_hidden {
             continue;
         }
 
-        let Ok(metadata) = entry.metadata() else {
+        // Follows symlinks, so links to directories are listed too.
+        let Ok(metadata) = fs::metadata(entry.path()) else {
             continue;
         };
         if !metadata.is_dir() {
             continue;
         }
 
-        // Symlink safety: verify target is within $HOME
+        // Symlink safety: verify target is within an allowed root
         let Ok(ft) = entry.file_type() else { continue };
-        if ft.is_symlink() {
+        let resolved = if ft.is_symlink() {
             let Ok(resolved) = fs::canonicalize(entry.path()) else {
                 continue;
             };
-            if !resolved.starts_with(home_dir) {
-                continue;
-            }
+            resolved
+        } else {
+            entry.path()
+        };
+        if !roots.contains(&resolved) {
+            continue;
         }
 
-        directories.push(name_str.to_string());
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+        found.push((name_str.to_string(), mtime));
+    }
+    match options.sort {
+        SortBy::Name => found.sort(),
+        SortBy::Mtime => found.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
     }
-    directories.sort();
 
-    Ok((canonical.to_string_lossy().to_string(), directories))
+    let total = found.len();
+    let git_deadline = Instant::now() + GIT_STATUS_BUDGET;
+    let mut git_runs = 0;
+    let entries: Vec<DirEntry> = found
+        .into_iter()
+        .skip(options.offset)
+        .take(options.limit)
+        .map(|(name, mtime)| {
+            let path = canonical.join(&name);
+            let git = (options.git && path.join(".git").exists()).then(|| {
+                git_runs += 1;
+                if git_runs > MAX_GIT_STATUS || Instant::now() >= git_deadline {
+                    GitInfo::UNKNOWN
+                } else {
+                    git_info(&path)
+                }
+            });
+            DirEntry {
+                git,
+                pi: path.join(".pi").is_dir(),
+                name,
+                mtime,
+                agents: 0,
+            }
+        })
+        .collect();
+
+    Ok(Listing {
+        current: canonical.to_string_lossy().to_string(),
+        root,
+        directories: entries.iter().map(|e| e.name.clone()).collect(),
+        entries,
+        total,
+        offset: options.offset,
+    })
+}
+
+/// Largest `max_bytes` a `read_file` call may ask for.
+pub const MAX_READ_BYTES: u64 = 1024 * 1024;
+pub const DEFAULT_READ_BYTES: u64 = 256 * 1024;
+/// Bytes checked for NULs when deciding whether a file is binary.
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+/// A file preview.
+#[derive(Debug, Serialize)]
+pub struct FileContent {
+    /// Canonical path that was read.
+    pub path: String,
+    /// The allowed root it is in.
+    pub root: String,
+    /// Size on disk, in bytes.
+    pub size: u64,
+    /// Last modification, in Unix seconds.
+    pub mtime: Option<i64>,
+    /// Not UTF-8 text; `content` is then `None`.
+    pub binary: bool,
+    /// Only the first `max_bytes` were read.
+    pub truncated: bool,
+    pub content: Option<String>,
+}
+
+/// Read up to `max_bytes` of the text file at `target`, which must resolve
+/// to a regular file inside `roots`. Files that contain NULs or are not
+/// UTF-8 are reported as binary, without content.
+pub fn read_file(target: &Path, roots: &Roots, max_bytes: u64) -> Result<FileContent, String> {
+    let canonical = fs::canonicalize(target).map_err(|e| format!("Invalid path: {}", e))?;
+    let root = roots.check(&canonical)?.to_string_lossy().to_string();
+    let metadata = fs::metadata(&canonical).map_err(|e| format!("Cannot read file: {}", e))?;
+    if !metadata.is_file() {
+        return Err("Path is not a regular file".into());
+    }
+
+    let file = fs::File::open(&canonical).map_err(|e| format!("Cannot read file: {}", e))?;
+    let mut bytes = Vec::new();
+    file.take(max_bytes + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Cannot read file: {}", e))?;
+    let truncated = bytes.len() as u64 > max_bytes;
+    bytes.truncate(max_bytes as usize);
+
+    let sniffed = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    let content = if sniffed.contains(&0) {
+        None
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(text) => Some(text),
+            // A multi-byte character cut off by the size cap.
+            Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+                let valid = e.utf8_error().valid_up_to();
+                let mut bytes = e.into_bytes();
+                bytes.truncate(valid);
+                String::from_utf8(bytes).ok()
+            }
+            Err(_) => None,
+        }
+    };
+
+    Ok(FileContent {
+        path: canonical.to_string_lossy().to_string(),
+        root,
+        size: metadata.len(),
+        mtime: metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64),
+        binary: content.is_none(),
+        truncated,
+        content,
+    })
 }
 
 #[cfg(test)]
@@ -53,10 +359,19 @@ mod tests {
     use super::*;
     use std::fs;
 
+    /// List `target` with `root` as the only allowed root.
+    fn list(target: &Path, root: PathBuf) -> Result<Listing, String> {
+        list_directories(
+            target,
+            &Roots::new(vec![root], vec![]),
+            &ListOptions::default(),
+        )
+    }
+
     /// Create a uniquely-named test directory under $HOME to avoid collisions
     /// when tests run in parallel.
     fn unique_test_dir(suffix: &str) -> (PathBuf, PathBuf) {
-        let home = dirs::home_dir().unwrap_or_else(|| std::env::temp_dir());
+        let home = dirs::home_dir().unwrap_or_else(std::env::temp_dir);
         let dir = home.join(format!(".hypi_test_fb_{}", suffix));
         let _ = fs::remove_dir_all(&dir);
         fs::create_dir_all(&dir).unwrap();
@@ -70,7 +385,8 @@ mod tests {
         fs::create_dir_all(tmp.join(".hidden")).unwrap();
         fs::write(tmp.join("file.txt"), "hello").unwrap();
 
-        let (current, dirs) = list_directories(&tmp, &home).unwrap();
+        let listing = list(&tmp, home).unwrap();
+        let (current, dirs) = (listing.current, listing.directories);
         assert!(current.contains(".hypi_test_fb_list"));
         assert!(dirs.contains(&"visible".to_string()));
         assert!(!dirs.contains(&".hidden".to_string()));
@@ -79,13 +395,56 @@ mod tests {
         let _ = fs::remove_dir_all(&tmp);
     }
 
+    #[test]
+    fn matches_most_specific_root_outside_denied() {
+        let roots = Roots::new(
+            vec![
+                PathBuf::from("/srv/work"),
+                PathBuf::from("/mnt/projects"),
+                PathBuf::from("/srv/work/big"),
+            ],
+            vec![PathBuf::from("/srv/work/secrets")],
+        );
+        assert_eq!(
+            roots.root_of(Path::new("/srv/work/repo")),
+            Some(Path::new("/srv/work"))
+        );
+        assert_eq!(
+            roots.root_of(Path::new("/srv/work/big/repo")),
+            Some(Path::new("/srv/work/big"))
+        );
+        assert!(roots.contains(Path::new("/mnt/projects")));
+        assert!(!roots.contains(Path::new("/srv/workshop")));
+        assert!(!roots.contains(Path::new("/etc")));
+        assert_eq!(
+            roots.check(Path::new("/srv/work/secrets/x")).unwrap_err(),
+            "Path is inside a denied directory"
+        );
+        assert!(roots.contains(Path::new("/srv/work/secrets2")));
+    }
+
+    #[test]
+    fn hides_denied_directories() {
+        let (_, tmp) = unique_test_dir("denied");
+        fs::create_dir_all(tmp.join("open")).unwrap();
+        fs::create_dir_all(tmp.join("private")).unwrap();
+        let roots = Roots::new(vec![tmp.clone()], vec![tmp.join("private")]);
+
+        let listing = list_directories(&tmp, &roots, &ListOptions::default()).unwrap();
+        assert_eq!(listing.directories, vec!["open"]);
+        assert_eq!(listing.root, listing.current);
+        assert!(list_directories(&tmp.join("private"), &roots, &ListOptions::default()).is_err());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
     #[test]
     fn rejects_path_outside_home() {
         let home = PathBuf::from("/tmp/fakehome");
         let target = PathBuf::from("/usr");
-        let result = list_directories(&target, &home);
+        let result = list(&target, home);
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("outside home"));
+        assert!(result.unwrap_err().contains("outside allowed roots"));
     }
 
     #[test]
@@ -95,7 +454,7 @@ mod tests {
         fs::create_dir_all(tmp.join("alpha")).unwrap();
         fs::create_dir_all(tmp.join("middle")).unwrap();
 
-        let (_, dirs) = list_directories(&tmp, &home).unwrap();
+        let dirs = list(&tmp, home).unwrap().directories;
         assert_eq!(dirs, vec!["alpha", "middle", "zebra"]);
 
         let _ = fs::remove_dir_all(&tmp);
@@ -105,7 +464,7 @@ mod tests {
     fn empty_directory_returns_empty_vec() {
         let (home, tmp) = unique_test_dir("empty");
 
-        let (_, dirs) = list_directories(&tmp, &home).unwrap();
+        let dirs = list(&tmp, home).unwrap().directories;
         assert!(dirs.is_empty());
 
         let _ = fs::remove_dir_all(&tmp);
@@ -113,9 +472,9 @@ mod tests {
 
     #[test]
     fn nonexistent_path_returns_error() {
-        let home = dirs::home_dir().unwrap_or_else(|| std::env::temp_dir());
+        let home = dirs::home_dir().unwrap_or_else(std::env::temp_dir);
         let target = home.join(".hypi_nonexistent_path_test");
-        let result = list_directories(&target, &home);
+        let result = list(&target, home);
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("Invalid path"));
     }
@@ -127,7 +486,7 @@ mod tests {
         fs::write(tmp.join("file2.rs"), "content").unwrap();
         fs::create_dir_all(tmp.join("realdir")).unwrap();
 
-        let (_, dirs) = list_directories(&tmp, &home).unwrap();
+        let dirs = list(&tmp, home).unwrap().directories;
         assert_eq!(dirs, vec!["realdir"]);
 
         let _ = fs::remove_dir_all(&tmp);
@@ -139,7 +498,7 @@ mod tests {
         // Use a path with /./
         let non_canonical = tmp.join(".");
 
-        let (current, _) = list_directories(&non_canonical, &home).unwrap();
+        let current = list(&non_canonical, home).unwrap().current;
         // Should not contain /./
         assert!(!current.contains("/./"));
 
@@ -154,9 +513,132 @@ mod tests {
         fs::create_dir_all(tmp.join(".pi")).unwrap();
         fs::create_dir_all(tmp.join("src")).unwrap();
 
-        let (_, dirs) = list_directories(&tmp, &home).unwrap();
+        let dirs = list(&tmp, home).unwrap().directories;
         assert_eq!(dirs, vec!["src"]);
 
         let _ = fs::remove_dir_all(&tmp);
     }
+
+    #[test]
+    fn sorts_pages_and_shows_hidden() {
+        let (_, tmp) = unique_test_dir("options");
+        for (name, age) in [("old", 300), ("new", 0), ("mid", 100), (".dot", 200)] {
+            fs::create_dir_all(tmp.join(name)).unwrap();
+            let when = std::time::SystemTime::now() - std::time::Duration::from_secs(age);
+            fs::File::open(tmp.join(name))
+                .unwrap()
+                .set_modified(when)
+                .unwrap();
+        }
+        let roots = Roots::new(vec![tmp.clone()], vec![]);
+        let by_mtime = ListOptions::from_params(&serde_json::json!({
+            "sort": "mtime",
+            "show_hidden": true,
+            "offset": 1,
+            "limit": 2
+        }))
+        .unwrap();
+
+        let listing = list_directories(&tmp, &roots, &by_mtime).unwrap();
+        assert_eq!(listing.directories, vec!["mid", ".dot"]);
+        assert_eq!((listing.total, listing.offset), (4, 1));
+        assert!(listing.entries[0].mtime > listing.entries[1].mtime);
+
+        let listing = list_directories(&tmp, &roots, &ListOptions::default()).unwrap();
+        assert_eq!(listing.directories, vec!["mid", "new", "old"]);
+        assert!(ListOptions::from_params(&serde_json::json!({ "limit": 0 })).is_err());
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn detects_git_and_pi_projects() {
+        let (_, tmp) = unique_test_dir("projects");
+        let repo = tmp.join("repo");
+        fs::create_dir_all(repo.join(".pi")).unwrap();
+        fs::create_dir_all(tmp.join("plain")).unwrap();
+        git(&repo, &["init", "-q", "-b", "trunk"]).unwrap();
+        fs::write(repo.join("file"), "a").unwrap();
+        git(&repo, &["add", "file"]).unwrap();
+        let commit = [
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "-q",
+            "-m",
+            "init",
+        ];
+        git(&repo, &commit).unwrap();
+
+        let roots = Roots::new(vec![tmp.clone()], vec![]);
+        let listing = list_directories(&tmp, &roots, &ListOptions::default()).unwrap();
+        assert!(listing.entries[1].git.is_none());
+        assert!(listing.entries[1].pi);
+
+        let with_git = ListOptions {
+            git: true,
+            ..Default::default()
+        };
+        let listing = list_directories(&tmp, &roots, &with_git).unwrap();
+        let (plain, repo_entry) = (&listing.entries[0], &listing.entries[1]);
+        assert_eq!((plain.git.as_ref(), plain.pi), (None, false));
+        assert!(repo_entry.pi);
+        assert_eq!(
+            repo_entry.git,
+            Some(GitInfo {
+                branch: Some("trunk".into()),
+                dirty: Some(false),
+            })
+        );
+
+        fs::write(repo.join("file"), "b").unwrap();
+        let listing = list_directories(&tmp, &roots, &with_git).unwrap();
+        assert_eq!(listing.entries[1].git.as_ref().unwrap().dirty, Some(true));
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
+
+    #[test]
+    fn reads_text_files_within_roots() {
+        let (_, tmp) = unique_test_dir("read");
+        fs::create_dir_all(tmp.join("private")).unwrap();
+        fs::write(tmp.join("README.md"), "# Hello\n").unwrap();
+        fs::write(tmp.join("private/key"), "secret").unwrap();
+        fs::write(tmp.join("blob"), [0x7f, b'E', b'L', b'F', 0, 1]).unwrap();
+        fs::write(tmp.join("accent"), "ab\u{e9}").unwrap();
+        let roots = Roots::new(vec![tmp.clone()], vec![tmp.join("private")]);
+
+        let file = read_file(&tmp.join("./README.md"), &roots, DEFAULT_READ_BYTES).unwrap();
+        assert_eq!(file.content.as_deref(), Some("# Hello\n"));
+        assert_eq!((file.size, file.binary, file.truncated), (8, false, false));
+        assert!(!file.path.contains("/./"));
+
+        let blob = read_file(&tmp.join("blob"), &roots, DEFAULT_READ_BYTES).unwrap();
+        assert!(blob.binary && blob.content.is_none());
+
+        // The cap falls inside the two-byte "\u{e9}".
+        let cut = read_file(&tmp.join("accent"), &roots, 3).unwrap();
+        assert_eq!(cut.content.as_deref(), Some("ab"));
+        assert!(cut.truncated && !cut.binary);
+
+        assert_eq!(
+            read_file(&tmp.join("private/key"), &roots, 100).unwrap_err(),
+            "Path is inside a denied directory"
+        );
+        assert_eq!(
+            read_file(&tmp, &roots, 100).unwrap_err(),
+            "Path is not a regular file"
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/", tmp.join("link")).unwrap();
+            assert!(read_file(&tmp.join("link/etc/passwd"), &roots, 100)
+                .unwrap_err()
+                .contains("outside allowed roots"));
+        }
+
+        let _ = fs::remove_dir_all(&tmp);
+    }
 }
@@ -0,0 +1,385 @@
+//! `node_git_status`: what an agent has changed in its working directory.
+//!
+//! git runs in the node's cwd, which must be inside the allowed roots just
+//! like a `list_directories` path, and so must the repository's top level.
+//! The result holds the branch, how far it is ahead of or behind its
+//! upstream, the changed files and, when asked for, the diff against `HEAD`
+//! cut off at `max_diff_bytes`.
+
+use crate::fs_browser::Roots;
+use crate::worktree::git;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+pub const MAX_DIFF_BYTES: usize = 1024 * 1024;
+/// Most files listed; the rest are counted in `total_files`.
+const MAX_FILES: usize = 1000;
+/// What `HEAD` is diffed as before the first commit.
+const EMPTY_TREE: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
+/// `node_git_status` options besides the node.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusOptions {
+    /// Include the diff of tracked files against `HEAD`.
+    pub diff: bool,
+    pub max_diff_bytes: usize,
+}
+
+impl Default for StatusOptions {
+    fn default() -> Self {
+        StatusOptions {
+            diff: false,
+            max_diff_bytes: 256 * 1024,
+        }
+    }
+}
+
+impl StatusOptions {
+    pub fn from_params(params: &Value) -> Result<StatusOptions, String> {
+        let options: StatusOptions = serde_json::from_value(params.clone())
+            .map_err(|e| format!("Invalid git status options: {e}"))?;
+        if options.max_diff_bytes == 0 || options.max_diff_bytes > MAX_DIFF_BYTES {
+            return Err(format!(
+                "max_diff_bytes must be between 1 and {MAX_DIFF_BYTES}"
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// A changed file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileStatus {
+    /// Relative to the repository. A wholly untracked directory is listed
+    /// once, with a trailing `/`.
+    pub path: String,
+    /// Where a renamed or copied file came from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orig_path: Option<String>,
+    /// `modified`, `added`, `deleted`, `renamed`, `copied`, `type_changed`,
+    /// `untracked` or `conflicted`.
+    pub status: &'static str,
+    /// Has changes in the index.
+    pub staged: bool,
+    /// Has changes in the working tree not yet staged.
+    pub unstaged: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GitStatus {
+    /// Top level of the repository.
+    pub repo: String,
+    /// `None` when detached.
+    pub branch: Option<String>,
+    /// Commit checked out; `None` before the first commit.
+    pub head: Option<String>,
+    pub upstream: Option<String>,
+    /// Commits not on the upstream, and upstream commits not here.
+    /// `None` without an upstream.
+    pub ahead: Option<u64>,
+    pub behind: Option<u64>,
+    pub files: Vec<FileStatus>,
+    pub total_files: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    /// The diff was cut off at `max_diff_bytes`.
+    pub diff_truncated: bool,
+}
+
+fn status_name(code: char) -> Option<&'static str> {
+    Some(match code {
+        'M' => "modified",
+        'A' => "added",
+        'D' => "deleted",
+        'R' => "renamed",
+        'C' => "copied",
+        'T' => "type_changed",
+        _ => return None,
+    })
+}
+
+/// Parse `git status --porcelain=v2 --branch -z` output.
+fn parse_status(output: &str, status: &mut GitStatus) {
+    let mut entries = output.split('\0');
+    while let Some(entry) = entries.next() {
+        if let Some(header) = entry.strip_prefix("# ") {
+            let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+            match key {
+                "branch.oid" if value != "(initial)" => status.head = Some(value.into()),
+                "branch.head" if value != "(detached)" => status.branch = Some(value.into()),
+                "branch.upstream" => status.upstream = Some(value.into()),
+                "branch.ab" => {
+                    let mut counts = value
+                        .split(' ')
+                        .map(|n| n.trim_start_matches(['+', '-']).parse().ok());
+                    status.ahead = counts.next().flatten();
+                    status.behind = counts.next().flatten();
+                }
+                _ => {}
+            }
+            continue;
+        }
+        let (kind, rest) = entry.split_once(' ').unwrap_or((entry, ""));
+        let file = match kind {
+            "?" => Some(FileStatus {
+                path: rest.into(),
+                orig_path: None,
+                status: "untracked",
+                staged: false,
+                unstaged: true,
+            }),
+            "1" | "2" | "u" => {
+                // Fields before the path, starting with XY.
+                let fields = match kind {
+                    "1" => 7,
+                    "2" => 8,
+                    _ => 9,
+                };
+                let mut parts = rest.splitn(fields + 1, ' ');
+                let xy: Vec<char> = parts.next().unwrap_or("").chars().collect();
+                let path = parts.nth(fields - 1).unwrap_or("").to_string();
+                let (x, y) = (
+                    xy.first().copied().unwrap_or('.'),
+                    xy.get(1).copied().unwrap_or('.'),
+                );
+                let orig_path = (kind == "2").then(|| entries.next().unwrap_or("").to_string());
+                let status = if kind == "u" {
+                    "conflicted"
+                } else {
+                    status_name(x).or(status_name(y)).unwrap_or("modified")
+                };
+                Some(FileStatus {
+                    path,
+                    orig_path,
+                    status,
+                    staged: kind != "u" && x != '.',
+                    unstaged: kind == "u" || y != '.',
+                })
+            }
+            _ => None,
+        };
+        if let Some(file) = file {
+            status.total_files += 1;
+            if status.files.len() < MAX_FILES {
+                status.files.push(file);
+            }
+        }
+    }
+}
+
+/// Run git in `dir` and keep at most `max_bytes` of its output. Returns the
+/// output and whether it was cut off.
+fn git_capped(dir: &Path, args: &[&str], max_bytes: usize) -> Result<(String, bool), String> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    let mut bytes = Vec::new();
+    let read = child
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut bytes);
+    let truncated = bytes.len() > max_bytes;
+    if truncated {
+        let _ = child.kill();
+    }
+    let exit = child.wait();
+    read.map_err(|e| format!("Failed to read git output: {e}"))?;
+    if !truncated && !exit.is_ok_and(|s| s.success()) {
+        return Err(format!(
+            "git {} failed",
+            args.first().copied().unwrap_or_default()
+        ));
+    }
+    bytes.truncate(max_bytes);
+    // Cutting may split a multi-byte character; lossy decoding keeps the rest.
+    Ok((String::from_utf8_lossy(&bytes).into_owned(), truncated))
+}
+
+/// Git status of the repository containing `cwd`, which must be inside
+/// `roots` along with the repository's top level.
+pub fn status(cwd: &str, roots: &Roots, options: &StatusOptions) -> Result<GitStatus, String> {
+    let canonical = fs::canonicalize(cwd).map_err(|e| format!("Invalid path: {e}"))?;
+    roots.check(&canonical)?;
+    let top = git(&canonical, &["rev-parse", "--show-toplevel"])
+        .map_err(|_| "Path is not inside a git repository".to_string())?;
+    let top = fs::canonicalize(top.trim()).map_err(|e| format!("Invalid path: {e}"))?;
+    if !roots.contains(&top) {
+        return Err("Repository resolves outside allowed roots".into());
+    }
+
+    let mut status = GitStatus {
+        repo: top.to_string_lossy().into_owned(),
+        branch: None,
+        head: None,
+        upstream: None,
+        ahead: None,
+        behind: None,
+        files: Vec::new(),
+        total_files: 0,
+        diff: None,
+        diff_truncated: false,
+    };
+    let output = git(&top, &["status", "--porcelain=v2", "--branch", "-z"])?;
+    parse_status(&output, &mut status);
+
+    if options.diff {
+        let base = if status.head.is_some() {
+            "HEAD"
+        } else {
+            EMPTY_TREE
+        };
+        let args = ["diff", "--no-color", "--no-ext-diff", "--no-textconv", base];
+        let (diff, truncated) = git_capped(&top, &args, options.max_diff_bytes)?;
+        status.diff = Some(diff);
+        status.diff_truncated = truncated;
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn commit(repo: &Path, message: &str) {
+        let args = [
+            "-c",
+            "user.name=t",
+            "-c",
+            "user.email=t@t",
+            "commit",
+            "-q",
+            "-am",
+            message,
+        ];
+        git(repo, &args).unwrap();
+    }
+
+    fn temp_repo(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_gitst_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("repo/src")).unwrap();
+        let repo = dir.join("repo");
+        git(&repo, &["init", "-q", "-b", "main"]).unwrap();
+        fs::write(repo.join("a.txt"), "one\n").unwrap();
+        fs::write(repo.join("old.txt"), "old\n").unwrap();
+        git(&repo, &["add", "."]).unwrap();
+        commit(&repo, "init");
+        dir
+    }
+
+    #[test]
+    fn parses_branch_counts_and_renames() {
+        let mut status = GitStatus {
+            repo: String::new(),
+            branch: None,
+            head: None,
+            upstream: None,
+            ahead: None,
+            behind: None,
+            files: Vec::new(),
+            total_files: 0,
+            diff: None,
+            diff_truncated: false,
+        };
+        let output = [
+            "# branch.oid 1234abcd",
+            "# branch.head feature",
+            "# branch.upstream origin/feature",
+            "# branch.ab +2 -5",
+            "2 R. N... 100644 100644 100644 aaaa bbbb R100 new name.rs",
+            "old name.rs",
+            "u UU N... 100644 100644 100644 100644 aaaa bbbb cccc both.rs",
+            "? notes.md",
+            "",
+        ]
+        .join("\0");
+        parse_status(&output, &mut status);
+        assert_eq!(status.branch.as_deref(), Some("feature"));
+        assert_eq!(status.head.as_deref(), Some("1234abcd"));
+        assert_eq!((status.ahead, status.behind), (Some(2), Some(5)));
+        assert_eq!(
+            status.files[0],
+            FileStatus {
+                path: "new name.rs".into(),
+                orig_path: Some("old name.rs".into()),
+                status: "renamed",
+                staged: true,
+                unstaged: false,
+            }
+        );
+        assert_eq!(status.files[1].status, "conflicted");
+        assert_eq!(status.files[2].status, "untracked");
+        assert_eq!(status.total_files, 3);
+    }
+
+    #[test]
+    fn reports_changes_and_capped_diff() {
+        let dir = temp_repo("changes");
+        let repo = dir.join("repo");
+        fs::write(repo.join("a.txt"), "one\ntwo\n").unwrap();
+        git(&repo, &["rm", "-q", "old.txt"]).unwrap();
+        fs::write(repo.join("src/new.rs"), "").unwrap();
+        let roots = Roots::new(vec![dir.clone()], vec![]);
+
+        let options = StatusOptions {
+            diff: true,
+            ..Default::default()
+        };
+        let cwd = repo.join("src").to_string_lossy().into_owned();
+        let found = status(&cwd, &roots, &options).unwrap();
+        assert_eq!(found.repo, repo.to_string_lossy());
+        assert_eq!(found.branch.as_deref(), Some("main"));
+        assert_eq!(found.upstream, None);
+        let files: Vec<_> = found
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status, f.staged, f.unstaged))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("a.txt", "modified", false, true),
+                ("old.txt", "deleted", true, false),
+                ("src/", "untracked", false, true),
+            ]
+        );
+        let diff = found.diff.unwrap();
+        assert!(diff.contains("+two"), "{diff}");
+        assert!(diff.contains("-old"), "{diff}");
+        assert!(!found.diff_truncated);
+
+        let capped = StatusOptions {
+            diff: true,
+            max_diff_bytes: 10,
+        };
+        let found = status(&cwd, &roots, &capped).unwrap();
+        assert_eq!(found.diff.unwrap().len(), 10);
+        assert!(found.diff_truncated);
+
+        let outside = Roots::new(vec![repo.join("src")], vec![]);
+        assert_eq!(
+            status(&cwd, &outside, &options).unwrap_err(),
+            "Repository resolves outside allowed roots"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
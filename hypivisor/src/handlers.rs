@@ -1,4 +1,6 @@
-use crate::rpc::{self, RpcRequest};
+use crate::children;
+use crate::listener::ListenerRole;
+use crate::rpc::{self, Connection, RpcRequest, RpcResponse};
 use crate::state::{NodeInfo, NodeStatus, Registry};
 use asupersync::Cx;
 use chrono::Utc;
@@ -15,6 +17,8 @@ pub enum RouteMatch<'a> {
     Registry,
     /// Proxy relay to a specific agent (/ws/agent/{nodeId})
     AgentProxy(&'a str),
+    /// Terminal of an agent spawned on a hypivisor PTY (/ws/pty/{spawnId})
+    Terminal(&'a str),
     /// No matching route
     NotFound,
     /// Bad request (e.g. /ws/agent/ with empty node ID)
@@ -40,6 +44,12 @@ pub fn match_route<'a>(path: &'a str) -> RouteMatch<'a> {
         } else {
             RouteMatch::AgentProxy(node_id)
         }
+    } else if let Some(spawn_id) = path.strip_prefix("/ws/pty/") {
+        if spawn_id.is_empty() {
+            RouteMatch::BadRequest("Missing spawn ID")
+        } else {
+            RouteMatch::Terminal(spawn_id)
+        }
     } else {
         RouteMatch::NotFound
     }
@@ -61,19 +71,35 @@ pub fn build_init_event(nodes: &[NodeInfo]) -> String {
 ///
 /// Parses the JSON as an RPC request, dispatches it, and returns:
 /// - `Some((response_json, maybe_new_node_id))` on success
-/// - `None` if the text is not valid JSON-RPC
+/// - `None` if the text is not valid JSON-RPC, or if the response will be
+///   sent through `connection.reply` later
+///
+/// If the RPC method is "register" and the node was registered, its ID is
+/// returned so the caller can track which node this connection represents.
 ///
-/// If the RPC method is "register" and the params contain a valid node,
-/// the node's ID is returned so the caller can track which node this
-/// connection represents.
+/// Methods the listener's `role` does not allow are answered with an error.
 pub fn process_registry_message(
     cx: &Cx,
     text: &str,
     state: &Registry,
     registered_node_id: Option<&str>,
+    connection: Connection,
+    role: ListenerRole,
 ) -> Option<(String, Option<String>)> {
     let req: RpcRequest = serde_json::from_str(text).ok()?;
 
+    if !role.allows_method(&req.method) {
+        let response = RpcResponse {
+            id: req.id,
+            result: None,
+            error: Some(format!(
+                "Method not allowed on this listener: {}",
+                req.method
+            )),
+        };
+        return Some((serde_json::to_string(&response).unwrap(), None));
+    }
+
     let new_node_id = if req.method == "register" {
         req.params
             .as_ref()
@@ -83,7 +109,8 @@ pub fn process_registry_message(
         None
     };
 
-    let response = rpc::dispatch(cx, req, state, registered_node_id);
+    let response = rpc::dispatch_on(cx, req, state, registered_node_id, connection)?;
+    let new_node_id = new_node_id.filter(|_| response.error.is_none());
     let json = serde_json::to_string(&response).unwrap();
     Some((json, new_node_id))
 }
@@ -117,6 +144,37 @@ pub fn update_heartbeat(state: &Registry, node_id: &str) {
     }
 }
 
+/// An initial prompt ready to be sent to a freshly registered agent.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PromptDelivery {
+    pub node_id: String,
+    pub host: String,
+    pub port: u16,
+    pub prompt: String,
+}
+
+/// If `node_id` was spawned with an initial prompt, claim it for delivery.
+/// Spawned agents are matched by spawn ID or PID; each prompt is handed out
+/// once.
+pub fn take_pending_prompt(state: &Registry, node_id: &str) -> Option<PromptDelivery> {
+    let nodes = state.nodes.read().expect("nodes lock poisoned");
+    let spawn_id = children::spawn_id_for(state, nodes.get(node_id)?)?;
+    let ProxyLookup::Found { host, port } = lookup_proxy_target(&nodes, node_id) else {
+        return None;
+    };
+    let prompt = state
+        .pending_prompts
+        .lock()
+        .expect("pending prompts lock poisoned")
+        .remove(&spawn_id)?;
+    Some(PromptDelivery {
+        node_id: node_id.to_string(),
+        host,
+        port,
+        prompt,
+    })
+}
+
 // ── Proxy handler logic ──────────────────────────────────────────────────────
 
 /// Result of looking up a proxy target node.
@@ -220,19 +278,16 @@ pub fn build_agent_handshake_request(agent_addr: &str, ws_key: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state::AppState;
-    use asupersync::channel::broadcast;
+    use crate::backend::BackendKind;
+    use crate::config::ServerConfig;
+    use crate::state::NodeMeta;
     use std::path::PathBuf;
-    use std::sync::{Arc, RwLock};
 
     fn make_registry() -> Registry {
-        let (tx, _) = broadcast::channel::<String>(16);
-        Arc::new(AppState {
-            nodes: RwLock::new(HashMap::new()),
-            tx,
-            secret_token: String::new(),
-            home_dir: PathBuf::from("/tmp"),
+        crate::create_state(&ServerConfig {
             node_ttl: 3600,
+            allowed_roots: vec![PathBuf::from("/tmp")],
+            ..Default::default()
         })
     }
 
@@ -246,6 +301,11 @@ mod tests {
             offline_since: None,
             last_seen: Some(Utc::now().timestamp()),
             pid: None,
+            spawn_id: None,
+            agent_status: None,
+            agent_status_since: None,
+            card: None,
+            meta: NodeMeta::default(),
         }
     }
 
@@ -312,6 +372,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn route_terminal() {
+        assert_eq!(match_route("/ws/pty/sp-1"), RouteMatch::Terminal("sp-1"));
+        assert_eq!(
+            match_route("/ws/pty/"),
+            RouteMatch::BadRequest("Missing spawn ID")
+        );
+    }
+
     #[test]
     fn route_not_found() {
         assert_eq!(match_route("/"), RouteMatch::NotFound);
@@ -434,7 +503,15 @@ mod tests {
         })
         .to_string();
 
-        let (json, new_id) = process_registry_message(&cx, &msg, &reg, None).unwrap();
+        let (json, new_id) = process_registry_message(
+            &cx,
+            &msg,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::All,
+        )
+        .unwrap();
         assert!(new_id.is_some());
         assert_eq!(new_id.unwrap(), "node-42");
         // Response should contain "registered"
@@ -451,7 +528,15 @@ mod tests {
         })
         .to_string();
 
-        let (_, new_id) = process_registry_message(&cx, &msg, &reg, None).unwrap();
+        let (_, new_id) = process_registry_message(
+            &cx,
+            &msg,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::All,
+        )
+        .unwrap();
         assert!(new_id.is_none());
     }
 
@@ -459,7 +544,14 @@ mod tests {
     fn process_invalid_json_returns_none() {
         let cx = crate::ephemeral_cx();
         let reg = make_registry();
-        let result = process_registry_message(&cx, "not json", &reg, None);
+        let result = process_registry_message(
+            &cx,
+            "not json",
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::All,
+        );
         assert!(result.is_none());
     }
 
@@ -478,7 +570,14 @@ mod tests {
             }
         })
         .to_string();
-        process_registry_message(&cx, &msg1, &reg, None);
+        process_registry_message(
+            &cx,
+            &msg1,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::All,
+        );
 
         // Now list_nodes as node-1
         let msg2 = serde_json::json!({
@@ -486,10 +585,104 @@ mod tests {
             "method": "list_nodes"
         })
         .to_string();
-        let (json, _) = process_registry_message(&cx, &msg2, &reg, Some("node-1")).unwrap();
+        let (json, _) = process_registry_message(
+            &cx,
+            &msg2,
+            &reg,
+            Some("node-1"),
+            Connection::default(),
+            ListenerRole::All,
+        )
+        .unwrap();
         assert!(json.contains("node-1"));
     }
 
+    #[test]
+    fn process_disallowed_method_returns_error() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let msg = serde_json::json!({
+            "id": "req-1",
+            "method": "spawn_agent",
+            "params": { "path": "/tmp" }
+        })
+        .to_string();
+
+        let (json, new_id) = process_registry_message(
+            &cx,
+            &msg,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::Agents,
+        )
+        .unwrap();
+        assert!(new_id.is_none());
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed["id"], "req-1");
+        assert!(parsed["error"]
+            .as_str()
+            .unwrap()
+            .contains("not allowed on this listener"));
+    }
+
+    #[test]
+    fn process_register_refused_on_dashboard_listener() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let msg = serde_json::json!({
+            "id": "req-1",
+            "method": "register",
+            "params": {
+                "id": "node-42", "machine": "host", "cwd": "/tmp",
+                "port": 8080, "status": "active"
+            }
+        })
+        .to_string();
+
+        let (_, new_id) = process_registry_message(
+            &cx,
+            &msg,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::Dashboard,
+        )
+        .unwrap();
+        assert!(new_id.is_none());
+        assert!(reg.nodes.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn process_register_with_invalid_card_returns_no_node_id() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let msg = serde_json::json!({
+            "id": "req-1",
+            "method": "register",
+            "params": {
+                "id": "node-42", "machine": "host", "cwd": "/tmp",
+                "port": 8080, "status": "active",
+                "card": { "tools": [], "unknown": true }
+            }
+        })
+        .to_string();
+
+        let (json, new_id) = process_registry_message(
+            &cx,
+            &msg,
+            &reg,
+            None,
+            Connection::default(),
+            ListenerRole::All,
+        )
+        .unwrap();
+        assert!(new_id.is_none());
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(parsed["error"].is_string(), "{json}");
+        assert!(reg.nodes.read().unwrap().is_empty());
+    }
+
     // ── mark_node_offline tests ──
 
     #[test]
@@ -546,6 +739,72 @@ mod tests {
         update_heartbeat(&reg, "ghost"); // Should not panic
     }
 
+    // ── take_pending_prompt tests ──
+
+    fn track_fake_child(reg: &Registry, pid: u32, spawn_id: &str) {
+        reg.children.lock().unwrap().insert(
+            pid,
+            children::SpawnedProcess::new(
+                pid,
+                spawn_id.into(),
+                BackendKind::Direct,
+                "pi".into(),
+                "/tmp".into(),
+            ),
+        );
+        reg.pending_prompts
+            .lock()
+            .unwrap()
+            .insert(spawn_id.into(), "fix the tests".into());
+    }
+
+    #[test]
+    fn pending_prompt_is_claimed_once_and_not_by_pid() {
+        let reg = make_registry();
+        let mut node = make_node("spawned", NodeStatus::Active);
+        node.pid = Some(4242);
+        reg.nodes
+            .write()
+            .unwrap()
+            .insert("spawned".into(), node.clone());
+        track_fake_child(&reg, 4242, "sp-1");
+        assert!(take_pending_prompt(&reg, "spawned").is_none());
+
+        node.spawn_id = Some("sp-1".into());
+        reg.nodes.write().unwrap().insert("spawned".into(), node);
+        let delivery = take_pending_prompt(&reg, "spawned").unwrap();
+        assert_eq!(delivery.prompt, "fix the tests");
+        assert_eq!(delivery.host, "localhost");
+        assert_eq!(delivery.port, 8080);
+        assert!(take_pending_prompt(&reg, "spawned").is_none());
+    }
+
+    #[test]
+    fn pending_prompt_is_claimed_by_spawn_id() {
+        let reg = make_registry();
+        let mut node = make_node("wrapped", NodeStatus::Active);
+        node.pid = Some(1);
+        node.spawn_id = Some("sp-2".into());
+        reg.nodes.write().unwrap().insert("wrapped".into(), node);
+        track_fake_child(&reg, 4242, "sp-2");
+
+        let delivery = take_pending_prompt(&reg, "wrapped").unwrap();
+        assert_eq!(delivery.prompt, "fix the tests");
+    }
+
+    #[test]
+    fn pending_prompt_ignores_other_pids() {
+        let reg = make_registry();
+        let mut node = make_node("other", NodeStatus::Active);
+        node.pid = Some(1);
+        reg.nodes.write().unwrap().insert("other".into(), node);
+        track_fake_child(&reg, 4242, "sp-3");
+
+        assert!(take_pending_prompt(&reg, "other").is_none());
+        assert!(take_pending_prompt(&reg, "missing").is_none());
+        assert_eq!(reg.pending_prompts.lock().unwrap().len(), 1);
+    }
+
     // ── base64_ws_key tests ──
 
     #[test]
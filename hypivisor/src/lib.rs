@@ -1,13 +1,32 @@
+pub mod agent_card;
 pub mod auth;
+pub mod backend;
+pub mod children;
 pub mod cleanup;
+pub mod config;
 pub mod fs_browser;
+pub mod git_status;
 pub mod handlers;
+pub mod limits;
+pub mod listener;
 pub mod log;
+#[cfg(unix)]
+pub mod pty;
+pub mod recent;
 pub mod rpc;
+pub mod sandbox;
+pub mod search;
 pub mod spawn;
 pub mod state;
+pub mod stream;
+pub mod systemd;
+pub mod template;
+pub mod watch;
+pub mod worktree;
 
-use auth::{extract_token_from_query, is_authorized};
+pub use config::ServerConfig;
+
+use auth::extract_token_from_query;
 use asupersync::channel::broadcast;
 use asupersync::net::websocket::{
     Frame, FrameCodec, HttpRequest, Message, Opcode, ServerHandshake,
@@ -15,6 +34,7 @@ use asupersync::net::websocket::{
 use asupersync::runtime::builder::RuntimeBuilder;
 use asupersync::types::{Budget, RegionId, TaskId};
 use asupersync::Cx;
+use listener::ListenerRole;
 use state::{AppState, NodeInfo, Registry};
 use std::{
     collections::HashMap,
@@ -23,51 +43,9 @@ use std::{
     sync::{Arc, Mutex, RwLock},
     time::Duration,
 };
+use stream::{Listener, Stream};
 use tracing::{error, info, warn};
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn create_state_with_default_config() {
-        let config = ServerConfig {
-            port: 0,
-            node_ttl: 30,
-            secret_token: "test".to_string(),
-        };
-        let state = create_state(&config);
-        assert_eq!(state.secret_token, "test");
-        assert_eq!(state.node_ttl, 30);
-        assert!(state.nodes.read().unwrap().is_empty());
-    }
-
-    #[test]
-    fn create_state_empty_token() {
-        let config = ServerConfig {
-            port: 0,
-            node_ttl: 60,
-            secret_token: String::new(),
-        };
-        let state = create_state(&config);
-        assert!(state.secret_token.is_empty());
-    }
-
-    #[test]
-    fn bind_random_port() {
-        let listener = bind(0);
-        let addr = listener.local_addr().unwrap();
-        assert!(addr.port() > 0);
-    }
-
-    #[test]
-    fn ephemeral_cx_is_valid() {
-        let cx = ephemeral_cx();
-        // Just verify it doesn't panic
-        let _ = cx;
-    }
-}
-
 /// Create an ephemeral Cx for use outside the runtime's region system.
 pub fn ephemeral_cx() -> Cx {
     Cx::new(
@@ -77,75 +55,176 @@ pub fn ephemeral_cx() -> Cx {
     )
 }
 
-/// Server configuration.
-pub struct ServerConfig {
-    pub port: u16,
-    pub node_ttl: u64,
-    pub secret_token: String,
-}
-
-/// Create app state from config.
+/// Create app state from a resolved config.
 pub fn create_state(config: &ServerConfig) -> Registry {
-    let home_dir = dirs::home_dir().unwrap_or_else(|| {
-        warn!("Could not determine home directory, falling back to '.'");
-        ".".into()
-    });
-
-    let (tx, _rx) = broadcast::channel::<String>(256);
+    let (tx, _rx) = broadcast::channel::<String>(config.broadcast_capacity);
     Arc::new(AppState {
         nodes: RwLock::new(HashMap::new()),
         tx,
-        secret_token: config.secret_token.clone(),
-        home_dir,
-        node_ttl: config.node_ttl,
+        roots: config.roots(),
+        search_skip: config.search_skip.clone(),
+        directories: Mutex::new(recent::DirectoryHistory::load(config.data_dir.as_deref())),
+        watch_ignore: config.watch_ignore.clone(),
+        watches: watch::Watches::default(),
+        spawn_command: config.spawn_command.clone(),
+        spawn_backend: config.spawn_backend,
+        spawn_env_allowlist: config.spawn_env_allowlist.clone(),
+        spawn_templates: config.templates.clone(),
+        spawn_limits: config.spawn_limits(),
+        spawn_window: Mutex::new(limits::SpawnWindow::default()),
+        spawn_rlimits: config.spawn_rlimits,
+        pending_prompts: Mutex::new(HashMap::new()),
+        spawn_waiters: Mutex::new(HashMap::new()),
+        children: Mutex::new(HashMap::new()),
+        #[cfg(unix)]
+        ptys: Mutex::new(HashMap::new()),
+        live: RwLock::new(config::Loaded {
+            live: config.live(),
+            config: config.clone(),
+        }),
+        config: config.clone(),
+        socket_files: Mutex::new(Vec::new()),
     })
 }
 
-/// Bind to the given port and return the listener.
-pub fn bind(port: u16) -> TcpListener {
-    let addr = format!("0.0.0.0:{port}");
-    TcpListener::bind(&addr).unwrap_or_else(|e| panic!("Failed to bind {addr}: {e}"))
+/// Bind to the given address and return the listener.
+pub fn bind(addr: std::net::SocketAddr) -> Result<TcpListener, String> {
+    TcpListener::bind(addr).map_err(|e| format!("Failed to bind {addr}: {e}"))
 }
 
-/// Start the cleanup thread.
+/// Start the cleanup thread. The interval is re-read every pass so it
+/// follows config reloads.
+///
+/// Under a systemd watchdog the loop also wakes at half the watchdog
+/// deadline and sends `WATCHDOG=1`, so the keep-alive stops as soon as
+/// cleanup stops running.
 pub fn start_cleanup_thread(state: &Registry) {
     let cleanup_state = state.clone();
-    std::thread::spawn(move || loop {
-        std::thread::sleep(Duration::from_secs(15));
-        let cx = ephemeral_cx();
-        cleanup::cleanup_stale_nodes(&cx, &cleanup_state);
+    let watchdog = systemd::watchdog_interval();
+    std::thread::spawn(move || {
+        let mut since_cleanup = Duration::ZERO;
+        loop {
+            let interval = Duration::from_secs(cleanup_state.live().cleanup_interval);
+            let tick = watchdog.map_or(interval, |wd| interval.min(wd / 2));
+            std::thread::sleep(tick);
+            since_cleanup += tick;
+            if since_cleanup >= interval {
+                let cx = ephemeral_cx();
+                cleanup::cleanup_stale_nodes(&cx, &cleanup_state);
+                since_cleanup = Duration::ZERO;
+            }
+            if watchdog.is_some() {
+                if let Err(e) = systemd::notify("WATCHDOG=1") {
+                    warn!(error = %e, "Failed to send watchdog keep-alive");
+                }
+            }
+        }
+    });
+}
+
+/// Start a thread that reloads the config file whenever SIGHUP arrives and
+/// runs [`shutdown`] on SIGTERM or SIGINT.
+#[cfg(unix)]
+pub fn start_signal_thread(state: &Registry) -> io::Result<()> {
+    use signal_hook::{
+        consts::{SIGHUP, SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT])?;
+    let reload_state = state.clone();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal != SIGHUP {
+                info!(signal, "Shutting down");
+                log::info("hypivisor", &format!("Shutting down on signal {signal}"));
+                shutdown(&reload_state);
+            }
+            match config::reload(&reload_state) {
+                Ok(_) => log::info("hypivisor", "Config reloaded on SIGHUP"),
+                Err(e) => {
+                    warn!(error = %e, "Config reload on SIGHUP failed");
+                    log::warn(
+                        "config.reload",
+                        &format!("Config reload on SIGHUP failed: {e}"),
+                    );
+                }
+            }
+        }
     });
+    Ok(())
+}
+
+/// Shut down and exit: send `STOPPING=1`, stop spawned agents if
+/// `stop_agents_on_exit` is set (otherwise they keep running, as after a
+/// crash), and remove the socket files this process created.
+pub fn shutdown(state: &Registry) -> ! {
+    let _ = systemd::notify("STOPPING=1");
+    if state.config.stop_agents_on_exit {
+        children::stop_all(
+            state,
+            Duration::from_millis(children::DEFAULT_STOP_GRACE_MS),
+        );
+    }
+    let sockets = std::mem::take(&mut *state.socket_files.lock().unwrap());
+    for path in sockets {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!(path = %path.display(), error = %e, "Failed to remove socket file");
+        }
+    }
+    info!("Hypivisor stopped");
+    std::process::exit(0)
+}
+
+/// Signals are Unix-only; elsewhere the config is reloaded via RPC only.
+#[cfg(not(unix))]
+pub fn start_signal_thread(_state: &Registry) -> io::Result<()> {
+    Ok(())
 }
 
 /// Run the server accept loop. Blocks forever unless the listener is closed.
-pub fn serve(listener: TcpListener, state: Registry) {
-    for incoming in listener.incoming() {
-        let stream = match incoming {
+pub fn serve(listener: impl Into<Listener>, state: Registry) {
+    serve_with_role(listener, state, ListenerRole::All);
+}
+
+/// Serve several listeners, one accept thread each. Blocks until all exit.
+pub fn serve_all(listeners: Vec<(Listener, ListenerRole)>, state: Registry) {
+    let handles: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, role)| {
+            let state = state.clone();
+            std::thread::spawn(move || serve_with_role(listener, state, role))
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+/// Run the accept loop for a listener restricted to `role`.
+pub fn serve_with_role(listener: impl Into<Listener>, state: Registry, role: ListenerRole) {
+    let listener = listener.into();
+    loop {
+        let stream = match listener.accept() {
             Ok(s) => s,
             Err(e) => {
-                let msg = format!("TCP accept failed: {e}");
+                let msg = format!("Accept failed on {}: {e}", listener.local_label());
                 error!(error = %e, "Accept failed");
                 log::error("tcp.accept", &msg);
                 continue;
             }
         };
 
-        let peer_addr = stream
-            .peer_addr()
-            .unwrap_or_else(|_| "0.0.0.0:0".parse().unwrap());
+        let peer_addr = stream.peer_label();
         let state = state.clone();
 
         std::thread::spawn(move || {
-            handle_connection(stream, peer_addr, state);
+            handle_connection(stream, peer_addr, state, role);
         });
     }
 }
 
-fn handle_connection(
-    mut stream: StdTcpStream,
-    peer_addr: std::net::SocketAddr,
-    state: Registry,
-) {
+fn handle_connection(mut stream: Stream, peer_addr: String, state: Registry, role: ListenerRole) {
     use io::{Read, Write};
 
     let mut buf = [0u8; 8192];
@@ -162,31 +241,51 @@ fn handle_connection(
     let request_str = String::from_utf8_lossy(request_bytes);
     let (uri, path) = handlers::parse_request_uri(&request_str);
 
-    // Auth check (applies to all WebSocket paths)
+    // Auth check (applies to all WebSocket paths). Clients of our own
+    // owner-only Unix socket were already vetted by its file permissions,
+    // and may also call admin methods.
     let token = extract_token_from_query(uri);
-    if !is_authorized(token.as_deref(), &state.secret_token) {
+    let live = state.live();
+    if !stream.is_trusted() && !live.accepts(token.as_deref()) {
         let _ = stream
             .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 12\r\n\r\nUnauthorized");
         return;
     }
 
-    // Route: /ws = registry, /ws/agent/{nodeId} = proxy
-    match handlers::match_route(path) {
+    // Route: /ws = registry, /ws/agent/{nodeId} = proxy,
+    // /ws/pty/{spawnId} = agent terminal.
+    // Routes the listener's role does not serve look like unknown paths.
+    let route = handlers::match_route(path);
+    let route = if role.allows_route(&route) {
+        route
+    } else {
+        handlers::RouteMatch::NotFound
+    };
+    match route {
         handlers::RouteMatch::Registry => {
-            if let Some(ws_stream) = upgrade_websocket(&mut stream, request_bytes, peer_addr) {
-                handle_registry_ws(ws_stream, peer_addr, state);
+            if let Some(ws_stream) = upgrade_websocket(&mut stream, request_bytes, &peer_addr) {
+                let admin = stream.is_trusted() || live.is_admin(token.as_deref());
+                handle_registry_ws(ws_stream, &peer_addr, state, role, admin);
             }
         }
         handlers::RouteMatch::AgentProxy(node_id) => {
             let node_id = node_id.to_string();
-            if let Some(ws_stream) = upgrade_websocket(&mut stream, request_bytes, peer_addr) {
-                handle_proxy_ws(ws_stream, peer_addr, &node_id, &state);
+            if let Some(ws_stream) = upgrade_websocket(&mut stream, request_bytes, &peer_addr) {
+                handle_proxy_ws(ws_stream, &peer_addr, &node_id, &state);
+            }
+        }
+        handlers::RouteMatch::Terminal(spawn_id) => {
+            let spawn_id = spawn_id.to_string();
+            if let Some(ws_stream) = upgrade_websocket(&mut stream, request_bytes, &peer_addr) {
+                handle_terminal_ws(ws_stream, &peer_addr, &spawn_id, &state);
             }
         }
-        handlers::RouteMatch::BadRequest(_) => {
-            let _ = stream.write_all(
-                b"HTTP/1.1 400 Bad Request\r\nContent-Length: 15\r\n\r\nMissing node ID",
+        handlers::RouteMatch::BadRequest(msg) => {
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\n\r\n{msg}",
+                msg.len()
             );
+            let _ = stream.write_all(response.as_bytes());
         }
         handlers::RouteMatch::NotFound => {
             let _ = stream
@@ -197,10 +296,10 @@ fn handle_connection(
 
 /// Perform WebSocket upgrade handshake, returning the stream on success.
 fn upgrade_websocket(
-    stream: &mut StdTcpStream,
+    stream: &mut Stream,
     request_bytes: &[u8],
-    peer_addr: std::net::SocketAddr,
-) -> Option<StdTcpStream> {
+    peer_addr: &str,
+) -> Option<Stream> {
     use io::Write;
 
     let http_req = match HttpRequest::parse(request_bytes) {
@@ -242,12 +341,12 @@ fn upgrade_websocket(
 // ── Shared WebSocket writer ──────────────────────────────────────────────────
 
 struct WsWriter {
-    stream: StdTcpStream,
+    stream: Stream,
     codec: FrameCodec,
 }
 
 impl WsWriter {
-    fn new(stream: StdTcpStream) -> Self {
+    fn new(stream: Stream) -> Self {
         Self {
             stream,
             codec: FrameCodec::server(),
@@ -280,6 +379,19 @@ impl WsWriter {
         self.stream.write_all(&buf)
     }
 
+    fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        use asupersync::bytes::BytesMut;
+        use asupersync::codec::Encoder;
+        use io::Write;
+
+        let frame = Frame::binary(data.to_vec());
+        let mut buf = BytesMut::with_capacity(data.len() + 14);
+        self.codec
+            .encode(frame, &mut buf)
+            .map_err(|e| io::Error::other(format!("WS encode: {e}")))?;
+        self.stream.write_all(&buf)
+    }
+
     fn send_raw_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
         use io::Write;
         self.stream.write_all(bytes)
@@ -299,7 +411,7 @@ enum ReadResult {
 }
 
 fn ws_read(
-    stream: &mut StdTcpStream,
+    stream: &mut Stream,
     codec: &mut FrameCodec,
     read_buf: &mut asupersync::bytes::BytesMut,
 ) -> io::Result<Option<ReadResult>> {
@@ -333,7 +445,13 @@ fn ws_read(
 
 // ── Registry WebSocket handler (/ws) ─────────────────────────────────────────
 
-fn handle_registry_ws(stream: StdTcpStream, peer_addr: std::net::SocketAddr, state: Registry) {
+fn handle_registry_ws(
+    stream: Stream,
+    peer_addr: &str,
+    state: Registry,
+    role: ListenerRole,
+    admin: bool,
+) {
     let writer = Arc::new(Mutex::new(WsWriter::new(
         stream.try_clone().expect("clone for writer"),
     )));
@@ -343,8 +461,12 @@ fn handle_registry_ws(stream: StdTcpStream, peer_addr: std::net::SocketAddr, sta
     let mut read_codec = FrameCodec::server();
     let mut read_buf = asupersync::bytes::BytesMut::with_capacity(8192);
 
-    // Send init event
-    {
+    // Subscribe before taking the roster snapshot so no event falls between
+    // the two. A node may then appear in both, which clients tolerate.
+    let subscription = role.receives_events().then(|| state.tx.subscribe());
+
+    // Send init event (agents-only listeners get no roster)
+    if role.receives_events() {
         let nodes: Vec<NodeInfo> = state
             .nodes
             .read()
@@ -363,31 +485,50 @@ fn handle_registry_ws(stream: StdTcpStream, peer_addr: std::net::SocketAddr, sta
     }
 
     // Broadcast forwarder thread
-    let mut rx = state.tx.subscribe();
     let broadcast_writer = writer.clone();
     let broadcast_running = Arc::new(std::sync::atomic::AtomicBool::new(true));
     let br = broadcast_running.clone();
 
-    let broadcast_handle = std::thread::spawn(move || {
-        let rt = RuntimeBuilder::new()
-            .worker_threads(1)
-            .build()
-            .expect("broadcast runtime");
-
-        rt.block_on(async {
-            let cx = ephemeral_cx();
-            while let Ok(event) = rx.recv(&cx).await {
-                if !br.load(std::sync::atomic::Ordering::Relaxed) {
-                    break;
-                }
-                let mut w = broadcast_writer.lock().unwrap();
-                if w.send_text(&event).is_err() {
-                    break;
+    let broadcast_handle = subscription.map(|mut rx| {
+        std::thread::spawn(move || {
+            let rt = RuntimeBuilder::new()
+                .worker_threads(1)
+                .build()
+                .expect("broadcast runtime");
+
+            rt.block_on(async {
+                let cx = ephemeral_cx();
+                while let Ok(event) = rx.recv(&cx).await {
+                    if !br.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut w = broadcast_writer.lock().unwrap();
+                    if w.send_text(&event).is_err() {
+                        break;
+                    }
                 }
-            }
-        });
+            });
+        })
+    });
+
+    // Directory watches send their events straight to this connection.
+    let watch_client = role.receives_events().then(|| {
+        let watch_writer = writer.clone();
+        state.watches.client(Arc::new(move |event: &str| {
+            watch_writer.lock().unwrap().send_text(event).is_ok()
+        }))
     });
 
+    // Responses to slow calls arrive later, from their own threads.
+    let reply_writer = writer.clone();
+    let reply: watch::EventSink =
+        Arc::new(move |response: &str| reply_writer.lock().unwrap().send_text(response).is_ok());
+    let connection = rpc::Connection {
+        watch_client: watch_client.as_ref(),
+        reply: Some(&reply),
+        admin,
+    };
+
     // Read loop
     let mut registered_node_id: Option<String> = None;
     let cx = ephemeral_cx();
@@ -400,8 +541,13 @@ fn handle_registry_ws(stream: StdTcpStream, peer_addr: std::net::SocketAddr, sta
                     &text,
                     &state,
                     registered_node_id.as_deref(),
+                    connection,
+                    role,
                 ) {
                     if let Some(nid) = new_node_id {
+                        if let Some(delivery) = handlers::take_pending_prompt(&state, &nid) {
+                            std::thread::spawn(move || deliver_prompt(delivery));
+                        }
                         registered_node_id = Some(nid);
                     }
                     let mut w = writer.lock().unwrap();
@@ -436,109 +582,241 @@ fn handle_registry_ws(stream: StdTcpStream, peer_addr: std::net::SocketAddr, sta
         handlers::mark_node_offline(&cx, &state, node_id);
     }
 
+    if let Some(client) = &watch_client {
+        state.watches.drop_client(client.id());
+    }
     broadcast_running.store(false, std::sync::atomic::Ordering::Relaxed);
     writer.lock().unwrap().shutdown();
-    let _ = broadcast_handle.join();
+    if let Some(handle) = broadcast_handle {
+        let _ = handle.join();
+    }
 }
 
-// ── Agent proxy WebSocket handler (/ws/agent/{nodeId}) ───────────────────────
+// ── Terminal WebSocket handler (/ws/pty/{spawnId}) ───────────────────────────
+
+/// Attach a viewer to the PTY of an agent spawned with the `pty` backend.
+/// Terminal output goes out as binary frames, starting with the scrollback.
+/// Binary frames from the viewer are keystrokes; text frames are
+/// [`pty::ViewerMessage`]s.
+#[cfg(unix)]
+fn handle_terminal_ws(stream: Stream, peer_addr: &str, spawn_id: &str, state: &Registry) {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let session = state
+        .ptys
+        .lock()
+        .expect("ptys lock poisoned")
+        .get(spawn_id)
+        .cloned();
+    let writer = Arc::new(Mutex::new(WsWriter::new(
+        stream.try_clone().expect("clone for writer"),
+    )));
+    let Some(session) = session else {
+        let err = serde_json::json!({ "error": "No terminal for this spawn ID" });
+        let _ = writer.lock().unwrap().send_text(&err.to_string());
+        return;
+    };
 
-fn handle_proxy_ws(
-    stream: StdTcpStream,
-    peer_addr: std::net::SocketAddr,
-    node_id: &str,
-    state: &Registry,
-) {
-    // Look up the node's local address
-    let (agent_host, agent_port) = {
-        let nodes = state.nodes.read().expect("nodes lock poisoned");
-        let lookup = handlers::lookup_proxy_target(&nodes, node_id);
-        match lookup {
-            handlers::ProxyLookup::Found { host, port } => (host, port),
-            _ => {
-                drop(nodes);
-                let mut w = WsWriter::new(stream);
-                let err = handlers::proxy_error_json(&lookup).unwrap();
-                let _ = w.send_text(&err);
-                return;
+    let subscription = session.subscribe();
+    if writer
+        .lock()
+        .unwrap()
+        .send_binary(&subscription.scrollback)
+        .is_err()
+    {
+        return;
+    }
+    info!(peer = %peer_addr, spawn_id, "Terminal viewer attached");
+
+    // Thread: terminal → viewer. Closes the connection when the agent exits.
+    let running = Arc::new(AtomicBool::new(true));
+    let output_running = running.clone();
+    let output_writer = writer.clone();
+    let output = std::thread::spawn(move || {
+        while output_running.load(Ordering::Relaxed) {
+            match subscription.live.recv_timeout(Duration::from_millis(500)) {
+                Ok(chunk) => {
+                    if output_writer.lock().unwrap().send_binary(&chunk).is_err() {
+                        break;
+                    }
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
             }
         }
-    };
+        output_writer.lock().unwrap().shutdown();
+    });
+
+    // This thread: viewer → terminal
+    let mut read_stream = stream;
+    let _ = read_stream.set_read_timeout(Some(Duration::from_millis(2000)));
+    let mut codec = FrameCodec::server();
+    let mut buf = asupersync::bytes::BytesMut::with_capacity(8192);
+    loop {
+        let result = match ws_read(&mut read_stream, &mut codec, &mut buf) {
+            Ok(Some(ReadResult::Binary(data))) => session.write_input(&data),
+            Ok(Some(ReadResult::Text(text))) => match serde_json::from_str(&text) {
+                Ok(message) => session.handle_message(message),
+                Err(e) => {
+                    warn!(spawn_id, error = %e, "Ignoring invalid terminal message");
+                    Ok(())
+                }
+            },
+            Ok(Some(ReadResult::Ping(payload))) => {
+                if writer.lock().unwrap().send_pong(payload).is_err() {
+                    break;
+                }
+                Ok(())
+            }
+            Ok(None) => break,
+            Err(e) => {
+                let idle =
+                    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut;
+                if !idle || output.is_finished() {
+                    break;
+                }
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            warn!(spawn_id, error = %e, "Failed to write to terminal");
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+    writer.lock().unwrap().shutdown();
+    let _ = output.join();
+    info!(peer = %peer_addr, spawn_id, "Terminal viewer detached");
+}
+
+#[cfg(not(unix))]
+fn handle_terminal_ws(stream: Stream, _peer_addr: &str, _spawn_id: &str, _state: &Registry) {
+    let err = serde_json::json!({ "error": "Terminals require Unix" });
+    let _ = WsWriter::new(stream).send_text(&err.to_string());
+}
+
+// ── Agent connections ────────────────────────────────────────────────────────
+
+/// Open a client WebSocket to an agent's pi-socket server. Failures are
+/// logged and returned as a message suitable for the dashboard.
+fn connect_agent_ws(node_id: &str, agent_host: &str, agent_port: u16) -> Result<Stream, String> {
+    use io::{Read, Write};
 
-    // Connect to the agent's local WebSocket
     let agent_addr = format!("{agent_host}:{agent_port}");
     let socket_addr = match agent_addr.to_socket_addrs() {
         Ok(mut addrs) => match addrs.next() {
             Some(addr) => addr,
             None => {
                 warn!(node_id, addr = %agent_addr, "No addresses resolved for agent");
-                let mut w = WsWriter::new(stream);
-                let err =
-                    serde_json::json!({ "error": "Cannot resolve agent address" }).to_string();
-                let _ = w.send_text(&err);
-                return;
+                return Err("Cannot resolve agent address".into());
             }
         },
         Err(e) => {
             warn!(node_id, addr = %agent_addr, error = %e, "Failed to resolve agent address");
-            let mut w = WsWriter::new(stream);
-            let err = serde_json::json!({ "error": format!("Cannot resolve agent: {e}") })
-                .to_string();
-            let _ = w.send_text(&err);
-            return;
+            return Err(format!("Cannot resolve agent: {e}"));
         }
     };
-    let mut agent_stream =
-        match StdTcpStream::connect_timeout(&socket_addr, Duration::from_secs(5)) {
-            Ok(s) => s,
-            Err(e) => {
-                warn!(node_id, error = %e, "Failed to connect to agent");
-                let mut w = WsWriter::new(stream);
-                let err =
-                    serde_json::json!({ "error": format!("Cannot reach agent: {e}") }).to_string();
-                let _ = w.send_text(&err);
-                return;
-            }
-        };
-
-    // Perform client-side WebSocket handshake to the agent
+    let mut agent_stream = match StdTcpStream::connect_timeout(&socket_addr, Duration::from_secs(5))
     {
-        use io::{Read, Write};
-        let key = handlers::base64_ws_key();
-        let req = handlers::build_agent_handshake_request(&agent_addr, &key);
-        if let Err(e) = agent_stream.write_all(req.as_bytes()) {
-            let msg = format!("Failed to send agent handshake request for {node_id}: {e}");
-            warn!(node_id, error = %e, "Failed to send agent handshake request");
-            log::warn("proxy.handshake", &msg);
+        Ok(s) => Stream::Tcp(s),
+        Err(e) => {
+            warn!(node_id, error = %e, "Failed to connect to agent");
+            return Err(format!("Cannot reach agent: {e}"));
+        }
+    };
+
+    // Client-side WebSocket handshake
+    let key = handlers::base64_ws_key();
+    let req = handlers::build_agent_handshake_request(&agent_addr, &key);
+    if let Err(e) = agent_stream.write_all(req.as_bytes()) {
+        let msg = format!("Failed to send agent handshake request for {node_id}: {e}");
+        warn!(node_id, error = %e, "Failed to send agent handshake request");
+        log::warn("proxy.handshake", &msg);
+        return Err(format!("Agent handshake failed: {e}"));
+    }
+    let mut resp_buf = [0u8; 1024];
+    let n = match agent_stream.read(&mut resp_buf) {
+        Ok(n) if n > 0 => n,
+        _ => return Err("Agent handshake failed: no response".into()),
+    };
+
+    let resp_str = String::from_utf8_lossy(&resp_buf[..n]);
+    if !handlers::validate_agent_handshake(&resp_str) {
+        warn!(
+            node_id,
+            "Agent handshake validation failed: response does not contain 101"
+        );
+        return Err("Agent handshake failed: invalid response".into());
+    }
+    Ok(agent_stream)
+}
+
+/// Send a spawned agent its initial prompt as a plain text message, the
+/// same way a dashboard would, then disconnect.
+fn deliver_prompt(delivery: handlers::PromptDelivery) {
+    use asupersync::codec::Encoder;
+
+    let handlers::PromptDelivery {
+        node_id,
+        host,
+        port,
+        prompt,
+    } = delivery;
+    let mut stream = match connect_agent_ws(&node_id, &host, port) {
+        Ok(s) => s,
+        Err(e) => {
+            let msg = format!("Failed to deliver initial prompt to {node_id}: {e}");
+            warn!(node_id = %node_id, error = %e, "Failed to deliver initial prompt");
+            log::warn("spawn.prompt", &msg);
             return;
         }
-        let mut resp_buf = [0u8; 1024];
-        let n = match agent_stream.read(&mut resp_buf) {
-            Ok(n) if n > 0 => n,
+    };
+
+    let mut codec = FrameCodec::client();
+    let mut out = asupersync::bytes::BytesMut::with_capacity(prompt.len() + 14);
+    let sent = codec
+        .encode(Frame::from(Message::text(&prompt)), &mut out)
+        .map_err(|e| io::Error::other(format!("WS encode: {e}")))
+        .and_then(|()| io::Write::write_all(&mut stream, &out));
+    match sent {
+        Ok(()) => info!(node_id = %node_id, "Initial prompt delivered"),
+        Err(e) => {
+            let msg = format!("Failed to send initial prompt to {node_id}: {e}");
+            warn!(node_id = %node_id, error = %e, "Failed to send initial prompt");
+            log::warn("spawn.prompt", &msg);
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+// ── Agent proxy WebSocket handler (/ws/agent/{nodeId}) ───────────────────────
+
+fn handle_proxy_ws(stream: Stream, peer_addr: &str, node_id: &str, state: &Registry) {
+    // Look up the node's local address
+    let (agent_host, agent_port) = {
+        let nodes = state.nodes.read().expect("nodes lock poisoned");
+        let lookup = handlers::lookup_proxy_target(&nodes, node_id);
+        match lookup {
+            handlers::ProxyLookup::Found { host, port } => (host, port),
             _ => {
+                drop(nodes);
                 let mut w = WsWriter::new(stream);
-                let err =
-                    serde_json::json!({ "error": "Agent handshake failed: no response" })
-                        .to_string();
+                let err = handlers::proxy_error_json(&lookup).unwrap();
                 let _ = w.send_text(&err);
                 return;
             }
-        };
+        }
+    };
 
-        let resp_str = String::from_utf8_lossy(&resp_buf[..n]);
-        if !handlers::validate_agent_handshake(&resp_str) {
-            warn!(
-                node_id,
-                "Agent handshake validation failed: response does not contain 101"
-            );
+    // Connect to the agent's local WebSocket
+    let mut agent_stream = match connect_agent_ws(node_id, &agent_host, agent_port) {
+        Ok(s) => s,
+        Err(e) => {
             let mut w = WsWriter::new(stream);
-            let err =
-                serde_json::json!({ "error": "Agent handshake failed: invalid response" })
-                    .to_string();
-            let _ = w.send_text(&err);
+            let _ = w.send_text(&serde_json::json!({ "error": e }).to_string());
             return;
         }
-    }
+    };
 
     // Bidirectional relay: dashboard ↔ agent
     let dashboard_read = stream;
@@ -663,3 +941,72 @@ fn handle_proxy_ws(
     let _ = agent_shutdown_handle.shutdown(Shutdown::Both);
     let _ = agent_to_dash.join();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_state_with_default_config() {
+        let config = ServerConfig {
+            node_ttl: 30,
+            tokens: vec!["test".to_string()],
+            ..Default::default()
+        };
+        let state = create_state(&config);
+        assert_eq!(state.live().tokens, vec!["test"]);
+        assert_eq!(state.live().node_ttl, 30);
+        assert_eq!(state.spawn_command, "pi");
+        assert!(state.nodes.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn create_state_empty_token() {
+        let config = ServerConfig {
+            node_ttl: 60,
+            ..Default::default()
+        };
+        let state = create_state(&config);
+        assert!(state.live().tokens.is_empty());
+    }
+
+    #[test]
+    fn create_state_uses_configured_roots() {
+        let config = ServerConfig {
+            allowed_roots: vec!["/srv/work".into()],
+            denied_paths: vec!["/srv/work/secrets".into()],
+            ..Default::default()
+        };
+        let state = create_state(&config);
+        assert_eq!(
+            state.roots.allowed(),
+            [std::path::PathBuf::from("/srv/work")]
+        );
+        assert_eq!(
+            state.roots.denied(),
+            [std::path::PathBuf::from("/srv/work/secrets")]
+        );
+    }
+
+    #[test]
+    fn bind_random_port() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.port() > 0);
+    }
+
+    #[test]
+    fn bind_ipv6_loopback() {
+        // Hosts without IPv6 are allowed to fail, but must not panic
+        if let Ok(listener) = bind("[::1]:0".parse().unwrap()) {
+            assert!(listener.local_addr().unwrap().is_ipv6());
+        }
+    }
+
+    #[test]
+    fn ephemeral_cx_is_valid() {
+        let cx = ephemeral_cx();
+        // Just verify it doesn't panic
+        let _ = cx;
+    }
+}
@@ -0,0 +1,277 @@
+//! Limits on spawning: how many agents may run, how fast they may be
+//! started, and optional resource limits for each agent process.
+//!
+//! Errors from exceeding a limit start with a stable code (see the
+//! constants below) followed by `": "` and a human-readable message, so
+//! clients can tell them apart without parsing prose.
+
+use crate::children::SpawnedProcess;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// Too many spawned agents running.
+pub const AGENT_LIMIT: &str = "spawn_limit_agents";
+/// Too many spawned agents running in the target directory.
+pub const DIRECTORY_LIMIT: &str = "spawn_limit_directory";
+/// Too many spawns in the last minute.
+pub const RATE_LIMIT: &str = "spawn_limit_rate";
+
+pub const DEFAULT_MAX_SPAWNED_AGENTS: usize = 16;
+pub const DEFAULT_MAX_AGENTS_PER_DIR: usize = 4;
+pub const DEFAULT_SPAWN_RATE_PER_MINUTE: usize = 20;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Spawn limits. Zero disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawnLimits {
+    pub max_agents: usize,
+    pub max_per_dir: usize,
+    pub rate_per_minute: usize,
+}
+
+impl Default for SpawnLimits {
+    fn default() -> Self {
+        SpawnLimits {
+            max_agents: DEFAULT_MAX_SPAWNED_AGENTS,
+            max_per_dir: DEFAULT_MAX_AGENTS_PER_DIR,
+            rate_per_minute: DEFAULT_SPAWN_RATE_PER_MINUTE,
+        }
+    }
+}
+
+/// Recent spawn times, for the rate limit. Callers hold its lock from the
+/// check until the new agent is tracked, so concurrent spawns cannot both
+/// squeeze under a limit.
+#[derive(Debug, Default)]
+pub struct SpawnWindow {
+    recent: VecDeque<Instant>,
+}
+
+impl SpawnWindow {
+    /// Check whether one more agent may be spawned in `dir`, given the
+    /// agents already running, and if so count it against the rate limit.
+    pub fn admit(
+        &mut self,
+        limits: &SpawnLimits,
+        children: &HashMap<u32, SpawnedProcess>,
+        dir: Option<&Path>,
+        now: Instant,
+    ) -> Result<(), String> {
+        self.check(limits, children, dir, now)?;
+        self.recent.push_back(now);
+        Ok(())
+    }
+
+    /// Like [`SpawnWindow::admit`], but without counting the spawn.
+    pub fn check(
+        &mut self,
+        limits: &SpawnLimits,
+        children: &HashMap<u32, SpawnedProcess>,
+        dir: Option<&Path>,
+        now: Instant,
+    ) -> Result<(), String> {
+        if limits.max_agents > 0 && children.len() >= limits.max_agents {
+            return Err(format!(
+                "{AGENT_LIMIT}: Too many spawned agents running (max {})",
+                limits.max_agents
+            ));
+        }
+        if let Some(dir) = dir.filter(|_| limits.max_per_dir > 0) {
+            let here = children
+                .values()
+                .filter(|c| Path::new(&c.cwd) == dir)
+                .count();
+            if here >= limits.max_per_dir {
+                return Err(format!(
+                    "{DIRECTORY_LIMIT}: Too many spawned agents in {} (max {})",
+                    dir.display(),
+                    limits.max_per_dir
+                ));
+            }
+        }
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        if limits.rate_per_minute > 0 && self.recent.len() >= limits.rate_per_minute {
+            return Err(format!(
+                "{RATE_LIMIT}: Too many spawns (max {} per minute)",
+                limits.rate_per_minute
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Resource limits applied to each agent process. Not applied to agents
+/// started under tmux, which launches them itself.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Open file descriptors (`RLIMIT_NOFILE`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nofile: Option<u64>,
+    /// Processes for the agent's user (`RLIMIT_NPROC`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nproc: Option<u64>,
+    /// CPU seconds (`RLIMIT_CPU`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_secs: Option<u64>,
+    /// Address space in MiB (`RLIMIT_AS`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Set the limits in the child between fork and exec.
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // SAFETY: setrlimit is async-signal-safe and only affects the child.
+        unsafe {
+            command.pre_exec(move || limits.set());
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _command: &mut Command) {}
+
+    #[cfg(unix)]
+    fn set(&self) -> std::io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_NOFILE, self.nofile),
+            (libc::RLIMIT_NPROC, self.nproc),
+            (libc::RLIMIT_CPU, self.cpu_secs),
+            (
+                libc::RLIMIT_AS,
+                self.memory_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+            ),
+        ];
+        for (resource, value) in limits {
+            let Some(value) = value else { continue };
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: value as libc::rlim_t,
+            };
+            // SAFETY: plain setrlimit with a valid struct.
+            if unsafe { libc::setrlimit(resource, &limit) } == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendKind;
+
+    fn running(cwds: &[&str]) -> HashMap<u32, SpawnedProcess> {
+        cwds.iter()
+            .enumerate()
+            .map(|(i, cwd)| {
+                let pid = i as u32 + 1;
+                let record = SpawnedProcess::new(
+                    pid,
+                    format!("sp-{pid}"),
+                    BackendKind::Direct,
+                    "pi".into(),
+                    cwd.to_string(),
+                );
+                (pid, record)
+            })
+            .collect()
+    }
+
+    fn code(err: String) -> String {
+        err.split(": ").next().unwrap().to_string()
+    }
+
+    #[test]
+    fn enforces_agent_and_directory_limits() {
+        let limits = SpawnLimits {
+            max_agents: 3,
+            max_per_dir: 2,
+            rate_per_minute: 0,
+        };
+        let mut window = SpawnWindow::default();
+        let now = Instant::now();
+        let children = running(&["/a", "/a"]);
+        assert_eq!(
+            code(
+                window
+                    .admit(&limits, &children, Some(Path::new("/a")), now)
+                    .unwrap_err()
+            ),
+            DIRECTORY_LIMIT
+        );
+        window
+            .admit(&limits, &children, Some(Path::new("/b")), now)
+            .unwrap();
+        window.admit(&limits, &children, None, now).unwrap();
+
+        let children = running(&["/a", "/b", "/c"]);
+        assert_eq!(
+            code(window.admit(&limits, &children, None, now).unwrap_err()),
+            AGENT_LIMIT
+        );
+    }
+
+    #[test]
+    fn rate_limit_slides() {
+        let limits = SpawnLimits {
+            max_agents: 0,
+            max_per_dir: 0,
+            rate_per_minute: 2,
+        };
+        let mut window = SpawnWindow::default();
+        let none = HashMap::new();
+        let start = Instant::now();
+        window.admit(&limits, &none, None, start).unwrap();
+        window
+            .admit(&limits, &none, None, start + Duration::from_secs(30))
+            .unwrap();
+        let err = window
+            .admit(&limits, &none, None, start + Duration::from_secs(59))
+            .unwrap_err();
+        assert_eq!(code(err), RATE_LIMIT);
+        // The first spawn has left the window.
+        window
+            .admit(&limits, &none, None, start + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn applies_resource_limits() {
+        let limits = ResourceLimits {
+            nofile: Some(64),
+            cpu_secs: Some(100),
+            ..Default::default()
+        };
+        let mut command = Command::new("sh");
+        command.args(["-c", "ulimit -n; ulimit -t"]);
+        limits.apply(&mut command);
+        let output = command.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n100\n");
+    }
+}
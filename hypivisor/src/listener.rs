@@ -0,0 +1,370 @@
+//! Listener specs: which addresses to bind and what each one may serve.
+//!
+//! A listener is written as `ADDR[:PORT][=ROLE]`, e.g. `127.0.0.1=dashboard`,
+//! `[::]:31415` or `100.64.0.7:31415=agents`. A missing port falls back to
+//! the configured `port`; a missing role means `all`.
+//!
+//! A Unix domain socket can be added alongside the TCP listeners for agents
+//! on the same host. It serves the full protocol without a token; the
+//! socket file is created owner-only, so its permissions decide who may
+//! connect. Next to it, an agents-only socket (`<path>.agents`) serves
+//! just the agent methods; sandboxed agents are pointed at that one. Unix
+//! sockets inherited from systemd are not created by us and still need a
+//! token.
+
+use crate::handlers::RouteMatch;
+use crate::stream::Listener;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, TcpListener},
+    str::FromStr,
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+/// RPC methods pi-socket agents need. These are the only methods served on
+/// an agents-only listener, and the only ones refused on dashboard-only ones.
+const AGENT_METHODS: &[&str] = &[
+    "register",
+    "deregister",
+    "update_node",
+    "report_status",
+    "set_agent_card",
+    "ping",
+];
+
+/// What a listener is allowed to serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListenerRole {
+    /// Agent registration only: `/ws` with the agent RPC methods, no roster
+    /// push events, no proxy and no terminals.
+    Agents,
+    /// Dashboard only: `/ws` without `register`, plus `/ws/agent/{id}` and
+    /// `/ws/pty/{spawn_id}`.
+    Dashboard,
+    /// Everything.
+    #[default]
+    All,
+}
+
+impl ListenerRole {
+    /// Whether a request path may be served on this listener.
+    pub fn allows_route(self, route: &RouteMatch) -> bool {
+        match self {
+            ListenerRole::All | ListenerRole::Dashboard => true,
+            ListenerRole::Agents => {
+                !matches!(route, RouteMatch::AgentProxy(_) | RouteMatch::Terminal(_))
+            }
+        }
+    }
+
+    /// Whether an RPC method may be called on this listener.
+    pub fn allows_method(self, method: &str) -> bool {
+        match self {
+            ListenerRole::All => true,
+            ListenerRole::Agents => AGENT_METHODS.contains(&method),
+            ListenerRole::Dashboard => method != "register",
+        }
+    }
+
+    /// Whether registry clients get the `init` roster and push events.
+    pub fn receives_events(self) -> bool {
+        self != ListenerRole::Agents
+    }
+}
+
+impl FromStr for ListenerRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "agents" => Ok(ListenerRole::Agents),
+            "dashboard" => Ok(ListenerRole::Dashboard),
+            "all" => Ok(ListenerRole::All),
+            other => Err(format!(
+                "Unknown listener role '{other}' (expected agents, dashboard or all)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ListenerRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ListenerRole::Agents => "agents",
+            ListenerRole::Dashboard => "dashboard",
+            ListenerRole::All => "all",
+        })
+    }
+}
+
+/// One address to listen on, with its role.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ListenerSpec {
+    pub ip: IpAddr,
+    /// `None` means "use the configured port".
+    pub port: Option<u16>,
+    pub role: ListenerRole,
+}
+
+impl ListenerSpec {
+    /// The socket address to bind, filling in `default_port` if needed.
+    pub fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.unwrap_or(default_port))
+    }
+}
+
+impl FromStr for ListenerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, role) = match s.rsplit_once('=') {
+            Some((addr, role)) => (addr, role.parse()?),
+            None => (s, ListenerRole::All),
+        };
+        if let Ok(sock) = addr.parse::<SocketAddr>() {
+            return Ok(ListenerSpec {
+                ip: sock.ip(),
+                port: Some(sock.port()),
+                role,
+            });
+        }
+        let bare = addr.trim_start_matches('[').trim_end_matches(']');
+        let ip = bare
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid listen address '{addr}' (expected IP[:PORT])"))?;
+        Ok(ListenerSpec {
+            ip,
+            port: None,
+            role,
+        })
+    }
+}
+
+impl TryFrom<String> for ListenerSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.ip, self.port) {
+            (IpAddr::V6(ip), Some(port)) => write!(f, "[{ip}]:{port}")?,
+            (IpAddr::V6(ip), None) => write!(f, "[{ip}]")?,
+            (ip, Some(port)) => write!(f, "{ip}:{port}")?,
+            (ip, None) => write!(f, "{ip}")?,
+        }
+        write!(f, "={}", self.role)
+    }
+}
+
+impl From<ListenerSpec> for String {
+    fn from(spec: ListenerSpec) -> String {
+        spec.to_string()
+    }
+}
+
+/// Bind every listener, failing with a readable message on the first error.
+pub fn bind_all(
+    specs: &[ListenerSpec],
+    default_port: u16,
+) -> Result<Vec<(Listener, ListenerRole)>, String> {
+    specs
+        .iter()
+        .map(|spec| {
+            let addr = spec.socket_addr(default_port);
+            TcpListener::bind(addr)
+                .map(|l| (l.into(), spec.role))
+                .map_err(|e| format!("Failed to bind {addr}: {e}"))
+        })
+        .collect()
+}
+
+/// The agents-only socket that goes with the Unix socket at `path`.
+pub fn agents_socket_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut agents = path.as_os_str().to_owned();
+    agents.push(".agents");
+    agents.into()
+}
+
+/// Bind a Unix domain socket at `path` and restrict it to the owner. The
+/// listener is marked trusted: its clients skip the token check.
+///
+/// A socket file left behind by a previous run is removed, but only if
+/// nothing is accepting on it; a live socket means another hypivisor owns
+/// the path. Any other kind of file at `path` is left alone.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> Result<Listener, String> {
+    let shown = path.display();
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(format!(
+                "Failed to bind unix:{shown}: path exists and is not a socket"
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "Failed to bind unix:{shown}: another process is listening"
+            ));
+        }
+        fs::remove_file(path)
+            .map_err(|e| format!("Failed to remove stale socket unix:{shown}: {e}"))?;
+    }
+    // Create the socket owner-only, so others cannot connect in the window
+    // before the chmod below. The umask is process-wide: a file another
+    // thread creates meanwhile also comes out owner-only, which is harmless.
+    let previous = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    let listener = bound.map_err(|e| format!("Failed to bind unix:{shown}: {e}"))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to set permissions on unix:{shown}: {e}"))?;
+    Ok(Listener::Unix {
+        listener,
+        trusted: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ipv4_with_port_and_role() {
+        let spec: ListenerSpec = "127.0.0.1:4000=dashboard".parse().unwrap();
+        assert_eq!(spec.ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.port, Some(4000));
+        assert_eq!(spec.role, ListenerRole::Dashboard);
+    }
+
+    #[test]
+    fn parses_bare_ipv6_without_port() {
+        let spec: ListenerSpec = "[::]=agents".parse().unwrap();
+        assert_eq!(spec.ip, "::".parse::<IpAddr>().unwrap());
+        assert_eq!(spec.port, None);
+        assert_eq!(spec.role, ListenerRole::Agents);
+        assert_eq!(spec.socket_addr(31415).to_string(), "[::]:31415");
+    }
+
+    #[test]
+    fn parses_ipv6_with_port_default_role() {
+        let spec: ListenerSpec = "[::1]:9000".parse().unwrap();
+        assert_eq!(spec.port, Some(9000));
+        assert_eq!(spec.role, ListenerRole::All);
+    }
+
+    #[test]
+    fn rejects_hostnames_and_bad_roles() {
+        assert!("localhost:31415".parse::<ListenerSpec>().is_err());
+        let err = "127.0.0.1=admin".parse::<ListenerSpec>().unwrap_err();
+        assert!(err.contains("Unknown listener role"));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["127.0.0.1:80=all", "[::]=agents", "10.0.0.1=dashboard"] {
+            let spec: ListenerSpec = s.parse().unwrap();
+            assert_eq!(spec.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn agents_role_restrictions() {
+        let role = ListenerRole::Agents;
+        assert!(role.allows_route(&RouteMatch::Registry));
+        assert!(!role.allows_route(&RouteMatch::AgentProxy("n1")));
+        assert!(!role.allows_route(&RouteMatch::Terminal("sp-1")));
+        assert!(role.allows_method("register"));
+        assert!(!role.allows_method("spawn_agent"));
+        assert!(!role.allows_method("list_nodes"));
+        assert!(!role.receives_events());
+    }
+
+    #[test]
+    fn dashboard_role_restrictions() {
+        let role = ListenerRole::Dashboard;
+        assert!(role.allows_route(&RouteMatch::AgentProxy("n1")));
+        assert!(role.allows_route(&RouteMatch::Terminal("sp-1")));
+        assert!(!role.allows_method("register"));
+        assert!(role.allows_method("spawn_agent"));
+        assert!(role.receives_events());
+    }
+
+    #[test]
+    fn bind_all_reports_address_in_use() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let spec: ListenerSpec = format!("127.0.0.1:{port}").parse().unwrap();
+        let err = bind_all(&[spec], 0).unwrap_err();
+        assert!(err.contains(&format!("Failed to bind 127.0.0.1:{port}")));
+    }
+
+    #[cfg(unix)]
+    fn socket_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("hypi-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_is_owner_only() {
+        let dir = socket_dir("perm");
+        let path = dir.join("hypivisor.sock");
+        let _listener = bind_unix(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn agents_socket_sits_next_to_the_main_one() {
+        assert_eq!(
+            agents_socket_path(Path::new("/run/user/1000/hypivisor.sock")),
+            Path::new("/run/user/1000/hypivisor.sock.agents")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_replaces_stale_socket() {
+        let dir = socket_dir("stale");
+        let path = dir.join("hypivisor.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        assert!(bind_unix(&path).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_refuses_live_socket_and_regular_files() {
+        let dir = socket_dir("live");
+        let path = dir.join("hypivisor.sock");
+        let _live = bind_unix(&path).unwrap();
+        let err = bind_unix(&path).unwrap_err();
+        assert!(err.contains("another process is listening"));
+
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "data").unwrap();
+        let err = bind_unix(&file).unwrap_err();
+        assert!(err.contains("not a socket"));
+        assert!(file.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
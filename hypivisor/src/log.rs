@@ -1,9 +1,10 @@
 //! Structured JSONL logger for hypivisor.
 //!
 //! Writes to `~/.pi/logs/hyper-pi.jsonl` — the same unified log that
-//! pi-socket uses. Each entry includes a `component` field to distinguish
-//! the source. Entries with `level: "error"` are marked `needsHardening: true`
-//! so the harden skill can process them.
+//! pi-socket uses — unless `log_path` is set in the config. Each entry
+//! includes a `component` field to distinguish the source. Entries with
+//! `level: "error"` are marked `needsHardening: true` so the harden skill
+//! can process them.
 //!
 //! This is **in addition to** the `tracing` macros that go to stderr.
 //! stderr gives real-time visibility; the JSONL file gives persistent
@@ -16,21 +17,30 @@ use std::io::Write;
 use std::path::PathBuf;
 use std::sync::OnceLock;
 
-fn log_dir() -> &'static PathBuf {
-    static DIR: OnceLock<PathBuf> = OnceLock::new();
-    DIR.get_or_init(|| {
+static LOG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the log file path. Must be called before the first log entry;
+/// later calls are ignored.
+pub fn init(path: PathBuf) {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = LOG_PATH.set(path);
+}
+
+fn log_path() -> &'static PathBuf {
+    LOG_PATH.get_or_init(|| {
         let dir = dirs::home_dir()
             .unwrap_or_else(|| ".".into())
             .join(".pi")
             .join("logs");
         let _ = fs::create_dir_all(&dir);
-        dir
+        dir.join("hyper-pi.jsonl")
     })
 }
 
 fn write_entry(entry: serde_json::Value) {
-    let path = log_dir().join("hyper-pi.jsonl");
-    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(log_path()) {
         // Each line is well under PIPE_BUF (4096), so append is atomic
         let _ = writeln!(file, "{}", entry);
     }
@@ -1,16 +1,62 @@
 use clap::Parser;
-use std::env;
-use tracing::{info, warn};
+use hypivisor::config::{ConfigLayer, ConfigSource, ServerConfig};
+use hypivisor::listener::{self, ListenerRole, ListenerSpec};
+use hypivisor::state::Registry;
+use hypivisor::stream::Listener;
+use std::{net::IpAddr, path::PathBuf, process};
+use tracing::{error, info, warn};
 
+/// Command-line settings. Anything left unset falls back to the
+/// environment, then the config file, then the built-in defaults.
 #[derive(Parser, Debug)]
 #[command(name = "hypivisor", version, about = "Hyper-Pi central registry")]
 struct Args {
-    #[arg(short, long, default_value_t = 31415)]
-    port: u16,
+    /// Port to listen on [default: 31415]
+    #[arg(short, long)]
+    port: Option<u16>,
 
-    /// Seconds before offline nodes are removed from the registry
-    #[arg(short = 't', long, default_value_t = 30)]
-    node_ttl: u64,
+    /// Address to bind to when no --listen is given [default: 0.0.0.0]
+    #[arg(short, long)]
+    bind: Option<IpAddr>,
+
+    /// Listener as ADDR[:PORT][=agents|dashboard|all]; repeatable.
+    /// e.g. --listen 127.0.0.1=dashboard --listen 100.64.0.7=agents --listen [::]
+    #[arg(short, long = "listen", value_name = "ADDR[:PORT][=ROLE]")]
+    listen: Vec<ListenerSpec>,
+
+    /// Also serve on this Unix domain socket (owner-only, no token needed)
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
+
+    /// Seconds before offline nodes are removed from the registry [default: 30]
+    #[arg(short = 't', long)]
+    node_ttl: Option<u64>,
+
+    /// Directory for state kept across restarts [default: ~/.hyper-pi/state]
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// TOML config file (hot-reloadable settings are re-read on SIGHUP)
+    #[arg(short, long, env = "HYPIVISOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+impl Args {
+    fn layer(&self) -> ConfigLayer {
+        ConfigLayer {
+            port: self.port,
+            bind: self.bind,
+            listen: (!self.listen.is_empty()).then(|| self.listen.clone()),
+            unix_socket: self.unix_socket.clone(),
+            node_ttl: self.node_ttl,
+            data_dir: self.data_dir.clone(),
+            ..Default::default()
+        }
+    }
 }
 
 fn main() {
@@ -23,24 +69,89 @@ fn main() {
         .init();
 
     let args = Args::parse();
-    let secret_token = env::var("HYPI_TOKEN").unwrap_or_default();
+    let config = ConfigLayer::from_env()
+        .map(|env_layer| ConfigSource {
+            path: args.config.clone(),
+            overrides: env_layer.merge(args.layer()),
+        })
+        .and_then(|source| ServerConfig::load(&source))
+        .unwrap_or_else(|e| {
+            error!(error = %e, "Failed to load config");
+            process::exit(1);
+        });
 
-    if secret_token.is_empty() {
-        warn!("HYPI_TOKEN not set — running without authentication");
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return;
     }
 
-    let config = hypivisor::ServerConfig {
-        port: args.port,
-        node_ttl: args.node_ttl,
-        secret_token,
-    };
+    // Claim systemd-passed sockets before any other thread starts
+    let inherited = hypivisor::systemd::take_listeners().unwrap_or_else(|e| {
+        error!(error = %e, "Failed to use sockets from systemd");
+        process::exit(1);
+    });
+
+    hypivisor::log::init(config.log_path.clone());
+    if config.tokens.is_empty() && config.admin_tokens.is_empty() {
+        warn!("No HYPI_TOKEN or config tokens set — running without authentication");
+    }
 
     let state = hypivisor::create_state(&config);
     hypivisor::start_cleanup_thread(&state);
+    if let Err(e) = hypivisor::start_signal_thread(&state) {
+        warn!(error = %e, "Failed to install signal handlers, config reload via RPC only");
+    }
+
+    if config.templates.values().any(|t| t.sandbox.is_some())
+        && hypivisor::sandbox::available_method().is_none()
+    {
+        warn!("Sandboxing is not available on this host; sandboxed templates will fail to spawn");
+    }
+
+    let listeners = if inherited.is_empty() {
+        bind_listeners(&config, &state).unwrap_or_else(|e| {
+            error!(error = %e, "Failed to start listeners");
+            hypivisor::log::error("tcp.bind", &e);
+            process::exit(1);
+        })
+    } else {
+        info!(
+            count = inherited.len(),
+            "Using sockets from systemd, ignoring configured listeners"
+        );
+        inherited
+    };
+    for (l, role) in &listeners {
+        let addr = l.local_label();
+        info!(addr = %addr, role = %role, "Hypivisor listening");
+        hypivisor::log::info("hypivisor", &format!("Hypivisor online on {addr} ({role})"));
+    }
 
-    let listener = hypivisor::bind(config.port);
-    info!(port = config.port, "Hypivisor online");
-    hypivisor::log::info("hypivisor", &format!("Hypivisor online on port {}", config.port));
+    if let Err(e) = hypivisor::systemd::notify("READY=1") {
+        warn!(error = %e, "Failed to notify systemd of readiness");
+    }
+    hypivisor::serve_all(listeners, state);
+}
 
-    hypivisor::serve(listener, state);
+/// Bind the TCP listeners and, if configured, the Unix socket and its
+/// agents-only companion, noting their files for removal on shutdown.
+fn bind_listeners(
+    config: &ServerConfig,
+    state: &Registry,
+) -> Result<Vec<(Listener, ListenerRole)>, String> {
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut listeners = listener::bind_all(&config.listeners(), config.port)?;
+    // Config validation refuses `unix_socket` elsewhere.
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let agents = listener::agents_socket_path(path);
+        for (path, role) in [
+            (path.clone(), ListenerRole::All),
+            (agents, ListenerRole::Agents),
+        ] {
+            listeners.push((listener::bind_unix(&path)?, role));
+            state.socket_files.lock().unwrap().push(path);
+        }
+    }
+    Ok(listeners)
 }
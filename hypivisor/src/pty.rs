@@ -0,0 +1,353 @@
+//! Pseudo-terminals owned by the hypivisor.
+//!
+//! Agents spawned with the `pty` backend run on a terminal whose master side
+//! stays here, so pi's TUI works and a human can attach to it later. Output
+//! is drained continuously into a bounded scrollback so the agent never
+//! blocks on a full terminal.
+
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, OwnedFd},
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    ptr,
+    sync::{mpsc, Arc, Mutex},
+};
+
+/// Bytes of terminal output kept per session.
+pub const SCROLLBACK_BYTES: usize = 256 * 1024;
+
+/// Terminal size in character cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        WindowSize {
+            rows: 40,
+            cols: 120,
+        }
+    }
+}
+
+impl WindowSize {
+    fn to_winsize(self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+/// Open a PTY pair. Both ends are close-on-exec.
+pub fn open(size: WindowSize) -> io::Result<(OwnedFd, OwnedFd)> {
+    use std::os::fd::FromRawFd;
+
+    let (mut master, mut slave) = (-1, -1);
+    let mut winsize = size.to_winsize();
+    // SAFETY: the out-pointers are valid for the call; name and termios may
+    // be null.
+    let rc = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            // Mutable for platforms that declare it so.
+            ptr::addr_of_mut!(winsize),
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: openpty returned two fresh descriptors nobody else owns.
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    set_cloexec(&master)?;
+    set_cloexec(&slave)?;
+    Ok((master, slave))
+}
+
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: plain fcntl on a descriptor we own.
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Run `command` on the terminal `slave`: it becomes stdin, stdout, stderr
+/// and the controlling terminal of a new session.
+pub fn attach(command: &mut Command, slave: OwnedFd) -> io::Result<()> {
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    // SAFETY: setsid and ioctl are async-signal-safe and touch no state
+    // shared with the parent.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Output chunks queued per viewer before it counts as stalled.
+const VIEWER_QUEUE: usize = 256;
+
+/// The hypivisor's side of an agent's terminal. Output goes to the
+/// scrollback and to every attached viewer.
+pub struct PtySession {
+    master: Mutex<File>,
+    output: Arc<Mutex<Output>>,
+}
+
+#[derive(Default)]
+struct Output {
+    scrollback: VecDeque<u8>,
+    viewers: Vec<mpsc::SyncSender<Vec<u8>>>,
+    /// Set once the terminal hangs up.
+    closed: bool,
+}
+
+/// A viewer's feed: the scrollback at the time it attached, then live
+/// output. `live` disconnects when the agent exits.
+pub struct Subscription {
+    pub scrollback: Vec<u8>,
+    pub live: mpsc::Receiver<Vec<u8>>,
+}
+
+/// Control messages viewers send as text frames. Keystrokes may also be
+/// sent as raw binary frames.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ViewerMessage {
+    Input { data: String },
+    Resize { rows: u16, cols: u16 },
+}
+
+impl std::fmt::Debug for PtySession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PtySession").finish_non_exhaustive()
+    }
+}
+
+impl PtySession {
+    /// Take ownership of `master` and start draining its output. The
+    /// terminal stays open until the reader sees it hang up.
+    pub fn start(master: OwnedFd) -> io::Result<Arc<PtySession>> {
+        let mut reader = File::from(master.try_clone()?);
+        let output = Arc::new(Mutex::new(Output::default()));
+        let sink = output.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            // Reads fail with EIO once every process has closed the terminal.
+            while let Ok(n) = reader.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let mut output = sink.lock().unwrap();
+                push_bounded(&mut output.scrollback, &buf[..n]);
+                // Stalled viewers are dropped; they can reattach and replay
+                // the scrollback.
+                output
+                    .viewers
+                    .retain(|viewer| viewer.try_send(buf[..n].to_vec()).is_ok());
+            }
+            let mut output = sink.lock().unwrap();
+            output.closed = true;
+            output.viewers.clear();
+        });
+        Ok(Arc::new(PtySession {
+            master: Mutex::new(File::from(master)),
+            output,
+        }))
+    }
+
+    /// Everything still in the scrollback.
+    pub fn scrollback(&self) -> Vec<u8> {
+        let output = self.output.lock().unwrap();
+        output.scrollback.iter().copied().collect()
+    }
+
+    /// Attach a viewer. No output is lost or repeated between the
+    /// scrollback snapshot and the live feed.
+    pub fn subscribe(&self) -> Subscription {
+        let mut output = self.output.lock().unwrap();
+        let (tx, live) = mpsc::sync_channel(VIEWER_QUEUE);
+        if !output.closed {
+            output.viewers.push(tx);
+        }
+        Subscription {
+            scrollback: output.scrollback.iter().copied().collect(),
+            live,
+        }
+    }
+
+    /// Send keystrokes to the agent.
+    pub fn write_input(&self, data: &[u8]) -> io::Result<()> {
+        self.master.lock().unwrap().write_all(data)
+    }
+
+    /// Resize the terminal; the agent gets `SIGWINCH`.
+    pub fn resize(&self, size: WindowSize) -> io::Result<()> {
+        if size.rows == 0 || size.cols == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rows and cols must be greater than 0",
+            ));
+        }
+        let winsize = size.to_winsize();
+        let master = self.master.lock().unwrap();
+        // SAFETY: TIOCSWINSZ reads a winsize that outlives the call.
+        if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Apply a viewer's control message.
+    pub fn handle_message(&self, message: ViewerMessage) -> io::Result<()> {
+        match message {
+            ViewerMessage::Input { data } => self.write_input(data.as_bytes()),
+            ViewerMessage::Resize { rows, cols } => self.resize(WindowSize { rows, cols }),
+        }
+    }
+}
+
+fn push_bounded(scrollback: &mut VecDeque<u8>, data: &[u8]) {
+    scrollback.extend(data);
+    let excess = scrollback.len().saturating_sub(SCROLLBACK_BYTES);
+    scrollback.drain(..excess);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn scrollback_keeps_newest_bytes() {
+        let mut scrollback = VecDeque::new();
+        push_bounded(&mut scrollback, &vec![b'a'; SCROLLBACK_BYTES]);
+        push_bounded(&mut scrollback, b"tail");
+        assert_eq!(scrollback.len(), SCROLLBACK_BYTES);
+        assert!(scrollback.iter().rev().take(4).eq(b"liat".iter()));
+    }
+
+    #[test]
+    fn child_runs_on_terminal() {
+        let (master, slave) = open(WindowSize::default()).unwrap();
+        let mut command = Command::new("sh");
+        command.args(["-c", "test -t 0 && stty size"]);
+        attach(&mut command, slave).unwrap();
+        let mut child = command.spawn().unwrap();
+        drop(command);
+        let session = PtySession::start(master).unwrap();
+
+        assert!(child.wait().unwrap().success());
+        for _ in 0..100 {
+            if String::from_utf8_lossy(&session.scrollback()).contains("40 120") {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!(
+            "unexpected output: {:?}",
+            String::from_utf8_lossy(&session.scrollback())
+        );
+    }
+
+    fn spawn_on_pty(script: &str) -> (std::process::Child, Arc<PtySession>) {
+        let (master, slave) = open(WindowSize::default()).unwrap();
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        attach(&mut command, slave).unwrap();
+        let child = command.spawn().unwrap();
+        drop(command);
+        (child, PtySession::start(master).unwrap())
+    }
+
+    fn read_until(live: &mpsc::Receiver<Vec<u8>>, needle: &str) -> String {
+        let mut seen = Vec::new();
+        while let Ok(chunk) = live.recv_timeout(Duration::from_secs(5)) {
+            seen.extend(chunk);
+            if String::from_utf8_lossy(&seen).contains(needle) {
+                return String::from_utf8_lossy(&seen).into_owned();
+            }
+        }
+        panic!(
+            "never saw {needle:?} in {:?}",
+            String::from_utf8_lossy(&seen)
+        );
+    }
+
+    #[test]
+    fn viewers_send_input_and_resize() {
+        let (mut child, session) =
+            spawn_on_pty("echo ready; read line; echo \"got $line\"; stty size");
+        let first = session.subscribe();
+        read_until(&first.live, "ready");
+
+        // A second viewer replays what it missed, then follows along.
+        let second = session.subscribe();
+        assert!(String::from_utf8_lossy(&second.scrollback).contains("ready"));
+
+        session
+            .handle_message(ViewerMessage::Resize {
+                rows: 30,
+                cols: 100,
+            })
+            .unwrap();
+        session
+            .handle_message(ViewerMessage::Input {
+                data: "hello\n".into(),
+            })
+            .unwrap();
+        read_until(&first.live, "30 100");
+        let seen = read_until(&second.live, "30 100");
+        assert!(seen.contains("got hello"));
+
+        assert!(child.wait().unwrap().success());
+        // The feed ends once the agent has gone.
+        while first.live.recv_timeout(Duration::from_secs(5)).is_ok() {}
+        assert!(session.subscribe().live.recv().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_size() {
+        let (mut child, session) = spawn_on_pty("true");
+        assert!(session.resize(WindowSize { rows: 0, cols: 80 }).is_err());
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn parses_viewer_messages() {
+        let input: ViewerMessage =
+            serde_json::from_str(r#"{"type":"input","data":"ls\r"}"#).unwrap();
+        assert_eq!(
+            input,
+            ViewerMessage::Input {
+                data: "ls\r".into()
+            }
+        );
+        let resize: ViewerMessage =
+            serde_json::from_str(r#"{"type":"resize","rows":24,"cols":80}"#).unwrap();
+        assert_eq!(resize, ViewerMessage::Resize { rows: 24, cols: 80 });
+        assert!(serde_json::from_str::<ViewerMessage>(r#"{"type":"paste"}"#).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Recently used and favorite project directories.
+//!
+//! Every `spawn_agent` path and every cwd a new node registers with is
+//! counted, and `list_recent_directories` ranks them by use count weighted
+//! by how recently they were used. Favorites are pinned by hand. Only
+//! directories inside the allowed roots are kept.
+//!
+//! With a `data_dir`, both lists are saved to `directories.json` there after
+//! every change (written to a temporary file, then renamed), so they
+//! survive restarts.
+
+use crate::fs_browser::Roots;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// Most recently used directories remembered.
+pub const MAX_RECENT: usize = 100;
+/// Most favorites allowed.
+pub const MAX_FAVORITES: usize = 100;
+const FILE_NAME: &str = "directories.json";
+
+/// Use count and last use of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub count: u64,
+    /// Unix seconds.
+    pub last_used: i64,
+}
+
+impl Usage {
+    /// Use count weighted by age: recent uses count for more.
+    pub fn score(&self, now: i64) -> f64 {
+        const HOUR: i64 = 3600;
+        let weight = match now - self.last_used {
+            age if age < HOUR => 4.0,
+            age if age < 24 * HOUR => 2.0,
+            age if age < 7 * 24 * HOUR => 1.0,
+            age if age < 30 * 24 * HOUR => 0.5,
+            _ => 0.25,
+        };
+        self.count as f64 * weight
+    }
+}
+
+/// What is saved to disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    #[serde(default)]
+    recent: BTreeMap<String, Usage>,
+    #[serde(default)]
+    favorites: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct DirectoryHistory {
+    /// File to save to; `None` keeps everything in memory.
+    file: Option<PathBuf>,
+    saved: Saved,
+}
+
+impl DirectoryHistory {
+    /// Load the history from `data_dir`, starting empty if there is none
+    /// yet or it cannot be read.
+    pub fn load(data_dir: Option<&Path>) -> DirectoryHistory {
+        let file = data_dir.map(|dir| dir.join(FILE_NAME));
+        let saved = match file.as_deref().map(fs::read_to_string) {
+            Some(Ok(text)) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                warn!(error = %e, "Ignoring unreadable directory history");
+                Saved::default()
+            }),
+            _ => Saved::default(),
+        };
+        DirectoryHistory { file, saved }
+    }
+
+    fn save(&self) {
+        let Some(file) = &self.file else { return };
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = file.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = file.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_vec_pretty(&self.saved)?)?;
+            fs::rename(&tmp, file)
+        };
+        if let Err(e) = write() {
+            warn!(error = %e, file = %file.display(), "Failed to save directory history");
+        }
+    }
+
+    /// Count a use of `dir`, if it is inside `roots`.
+    pub fn record(&mut self, dir: &Path, roots: &Roots, now: i64) {
+        let Ok(dir) = fs::canonicalize(dir) else {
+            return;
+        };
+        if !roots.contains(&dir) {
+            return;
+        }
+        let key = dir.to_string_lossy().into_owned();
+        let usage = self.saved.recent.entry(key.clone()).or_insert(Usage {
+            count: 0,
+            last_used: now,
+        });
+        usage.count += 1;
+        usage.last_used = now;
+        if self.saved.recent.len() > MAX_RECENT {
+            // A first use scores lowest of all; evicting it would make the
+            // directory just used impossible to remember once the list is full.
+            let weakest = self
+                .saved
+                .recent
+                .iter()
+                .filter(|(path, _)| **path != key)
+                .min_by(|a, b| a.1.score(now).total_cmp(&b.1.score(now)))
+                .map(|(path, _)| path.clone());
+            if let Some(weakest) = weakest {
+                self.saved.recent.remove(&weakest);
+            }
+        }
+        self.save();
+    }
+
+    /// The `limit` best recent directories, best first.
+    pub fn recent(&self, now: i64, limit: usize) -> Vec<(String, Usage)> {
+        let mut recent: Vec<(String, Usage)> = self
+            .saved
+            .recent
+            .iter()
+            .map(|(path, usage)| (path.clone(), *usage))
+            .collect();
+        recent.sort_by(|a, b| {
+            b.1.score(now)
+                .total_cmp(&a.1.score(now))
+                .then_with(|| b.1.last_used.cmp(&a.1.last_used))
+        });
+        recent.truncate(limit);
+        recent
+    }
+
+    pub fn favorites(&self) -> &[String] {
+        &self.saved.favorites
+    }
+
+    /// Pin `path`, which must be a directory inside `roots`. Returns the
+    /// canonical path.
+    pub fn add_favorite(&mut self, path: &str, roots: &Roots) -> Result<String, String> {
+        let canonical = fs::canonicalize(path).map_err(|e| format!("Invalid path: {e}"))?;
+        if !canonical.is_dir() {
+            return Err("Path is not a directory".into());
+        }
+        roots.check(&canonical)?;
+        let canonical = canonical.to_string_lossy().into_owned();
+        if !self.saved.favorites.contains(&canonical) {
+            if self.saved.favorites.len() >= MAX_FAVORITES {
+                return Err(format!("Too many favorites (max {MAX_FAVORITES})"));
+            }
+            self.saved.favorites.push(canonical.clone());
+            self.save();
+        }
+        Ok(canonical)
+    }
+
+    /// Unpin `path`, given as listed or as any path resolving to it.
+    pub fn remove_favorite(&mut self, path: &str) -> Result<String, String> {
+        let canonical = fs::canonicalize(path)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| path.to_string());
+        let index = self
+            .saved
+            .favorites
+            .iter()
+            .position(|f| f == path || *f == canonical)
+            .ok_or("Not a favorite")?;
+        let removed = self.saved.favorites.remove(index);
+        self.save();
+        Ok(removed)
+    }
+}
+
+/// Unix seconds, for callers of `record` and `recent`.
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_recent_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for sub in ["a", "b", "data"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn ranks_by_weighted_frequency() {
+        let dir = temp_dir("rank");
+        let roots = Roots::new(vec![dir.clone()], vec![]);
+        let mut history = DirectoryHistory::load(None);
+        let now = 10_000_000;
+        // Used three times a month ago, versus once just now.
+        for _ in 0..3 {
+            history.record(&dir.join("a"), &roots, now - 40 * 24 * 3600);
+        }
+        history.record(&dir.join("b"), &roots, now);
+        history.record(Path::new("/"), &roots, now);
+
+        let recent = history.recent(now, 10);
+        let paths: Vec<_> = recent.iter().map(|(p, _)| p.clone()).collect();
+        assert_eq!(
+            paths,
+            [dir.join("b"), dir.join("a")].map(|p| p.to_string_lossy().into_owned())
+        );
+        assert_eq!(recent[1].1.count, 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn favorites_persist() {
+        let dir = temp_dir("fav");
+        let roots = Roots::new(vec![dir.join("a"), dir.join("b")], vec![]);
+        let data = dir.join("data");
+        let a = dir.join("a").to_string_lossy().into_owned();
+
+        let mut history = DirectoryHistory::load(Some(&data));
+        assert_eq!(history.add_favorite(&format!("{a}/."), &roots).unwrap(), a);
+        history.add_favorite(&a, &roots).unwrap();
+        history.record(&dir.join("b"), &roots, 1);
+        assert!(history
+            .add_favorite(dir.to_str().unwrap(), &roots)
+            .unwrap_err()
+            .contains("outside allowed roots"));
+
+        let mut reloaded = DirectoryHistory::load(Some(&data));
+        assert_eq!(reloaded.favorites(), std::slice::from_ref(&a));
+        assert_eq!(reloaded.recent(1, 10).len(), 1);
+        assert_eq!(reloaded.remove_favorite(&a).unwrap(), a);
+        assert_eq!(reloaded.remove_favorite(&a).unwrap_err(), "Not a favorite");
+        assert!(DirectoryHistory::load(Some(&data)).favorites().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn full_history_keeps_the_directory_just_used() {
+        let dir = temp_dir("full");
+        let roots = Roots::new(vec![dir.clone()], vec![]);
+        let mut history = DirectoryHistory::load(None);
+        let now = 10_000_000;
+        for i in 0..MAX_RECENT {
+            let sub = dir.join(format!("old{i}"));
+            fs::create_dir_all(&sub).unwrap();
+            history.record(&sub, &roots, now);
+            history.record(&sub, &roots, now);
+        }
+        history.record(&dir.join("a"), &roots, now);
+
+        let recent = history.recent(now, MAX_RECENT + 1);
+        assert_eq!(recent.len(), MAX_RECENT);
+        let a = dir.join("a").to_string_lossy().into_owned();
+        assert!(recent.iter().any(|(path, _)| *path == a));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
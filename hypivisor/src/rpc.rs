@@ -1,10 +1,15 @@
-use crate::state::{NodeInfo, NodeStatus, Registry};
-use crate::{fs_browser, spawn};
+use crate::agent_card::{AgentCard, CardFilter};
+use crate::state::{AgentStatus, NodeInfo, NodeStatus, Registry, FIXED_NODE_FIELDS};
+use crate::watch::{EventSink, WatchClient};
+use crate::{children, config, fs_browser, git_status, recent, search, spawn, template, worktree};
 use asupersync::Cx;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 use tracing::{info, warn};
 
 #[derive(Deserialize)]
@@ -24,22 +29,77 @@ pub struct RpcResponse {
     pub error: Option<String>,
 }
 
+/// The registry connection a request arrived on.
+#[derive(Default, Clone, Copy)]
+pub struct Connection<'a> {
+    /// Holds the connection's directory watches.
+    pub watch_client: Option<&'a WatchClient>,
+    /// Sends responses that are only ready later (`spawn_agent` with
+    /// `wait_ms`, `restart_agent`), so the connection keeps serving other
+    /// requests meanwhile.
+    pub reply: Option<&'a EventSink>,
+    /// Whether the client may call admin methods (`reload_config`).
+    pub admin: bool,
+}
+
 /// Dispatch an RPC request to the appropriate handler.
 /// `registered_node_id` is the ID of the node making the request (None for dashboard/admin).
+/// The caller is treated as an in-process admin without directory watches,
+/// and slow calls block until they are answered.
 pub fn dispatch(
     cx: &Cx,
     req: RpcRequest,
     state: &Registry,
     registered_node_id: Option<&str>,
 ) -> RpcResponse {
+    let connection = Connection {
+        admin: true,
+        ..Default::default()
+    };
+    dispatch_on(cx, req, state, registered_node_id, connection)
+        .expect("requests without a reply sink are answered inline")
+}
+
+/// Like [`dispatch`], for a request that arrived on `connection`. Returns
+/// `None` when the response will be sent through `connection.reply` later.
+pub fn dispatch_on(
+    cx: &Cx,
+    req: RpcRequest,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+    connection: Connection,
+) -> Option<RpcResponse> {
+    let watch_client = connection.watch_client;
+    let reply = connection.reply;
     let id = req.id.clone();
-    match req.method.as_str() {
+    let response = match req.method.as_str() {
         "register" => handle_register(cx, id, req.params, state),
         "deregister" => handle_deregister(cx, id, req.params, state, registered_node_id),
-        "list_nodes" => handle_list_nodes(id, state),
+        "update_node" => handle_update_node(cx, id, req.params, state, registered_node_id),
+        "report_status" => handle_report_status(cx, id, req.params, state, registered_node_id),
+        "set_agent_card" => handle_set_agent_card(cx, id, req.params, state, registered_node_id),
+        "get_agent_card" => handle_get_agent_card(id, req.params, state),
+        "list_nodes" => handle_list_nodes(id, req.params, state),
+        "list_roots" => handle_list_roots(id, state),
         "list_directories" => handle_list_directories(id, req.params, state),
-        "spawn_agent" => handle_spawn_agent(id, req.params, state),
+        "search_directories" => handle_search_directories(id, req.params, state),
+        "read_file" => handle_read_file(id, req.params, state),
+        "watch_directory" => handle_watch_directory(id, req.params, state, watch_client),
+        "unwatch_directory" => handle_unwatch_directory(id, req.params, state, watch_client),
+        "list_recent_directories" => handle_list_recent_directories(id, req.params, state),
+        "add_favorite" => handle_favorite(id, req.params, state, true),
+        "remove_favorite" => handle_favorite(id, req.params, state, false),
+        "spawn_agent" => return handle_spawn_agent(id, req.params, state, reply),
+        "list_spawn_templates" => handle_list_spawn_templates(id, state),
+        "list_worktrees" => handle_list_worktrees(id, req.params, state),
+        "node_git_status" => handle_node_git_status(id, req.params, state),
+        "remove_worktree" => handle_remove_worktree(id, req.params, state, registered_node_id),
+        "stop_agent" => handle_stop_agent(id, req.params, state, registered_node_id),
+        "restart_agent" => {
+            return handle_restart_agent(id, req.params, state, registered_node_id, reply)
+        }
         "ping" => handle_ping(id, state),
+        "reload_config" => handle_reload_config(id, state, registered_node_id, connection.admin),
         other => {
             warn!(method = other, "Unknown RPC method");
             RpcResponse {
@@ -48,22 +108,73 @@ pub fn dispatch(
                 error: Some(format!("Method not found: {}", other)),
             }
         }
+    };
+    Some(response)
+}
+
+fn into_response(id: Option<String>, result: Result<Value, String>) -> RpcResponse {
+    match result {
+        Ok(result) => RpcResponse {
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
     }
 }
 
+/// Run `work` on its own thread and send its response through `reply`, so
+/// a slow call does not hold up the other requests on its connection.
+/// Without a reply sink `work` runs inline.
+fn respond_later(
+    id: Option<String>,
+    reply: Option<&EventSink>,
+    work: impl FnOnce() -> Result<Value, String> + Send + 'static,
+) -> Option<RpcResponse> {
+    let Some(reply) = reply.cloned() else {
+        return Some(into_response(id, work()));
+    };
+    std::thread::spawn(move || {
+        let response = into_response(id, work());
+        reply(&serde_json::to_string(&response).unwrap());
+    });
+    None
+}
+
 fn handle_register(
     cx: &Cx,
     id: Option<String>,
     params: Option<Value>,
     state: &Registry,
 ) -> RpcResponse {
-    let Some(params) = params else {
+    let Some(mut params) = params else {
         return RpcResponse {
             id,
             result: None,
             error: Some("Missing params".into()),
         };
     };
+    let card = match params.as_object_mut().and_then(|p| p.remove("card")) {
+        None | Some(Value::Null) => None,
+        Some(card) => match AgentCard::from_value(card) {
+            Ok(card) => Some(card),
+            Err(e) => {
+                return RpcResponse {
+                    id,
+                    result: None,
+                    error: Some(e),
+                }
+            }
+        },
+    };
+    // Proves the `spawn_id` claim below; never stored or passed on.
+    let spawn_secret = params
+        .as_object_mut()
+        .and_then(|p| p.remove("spawn_secret"));
     let Ok(mut node) = serde_json::from_value::<NodeInfo>(params) else {
         return RpcResponse {
             id,
@@ -74,7 +185,17 @@ fn handle_register(
     node.status = NodeStatus::Active;
     node.offline_since = None;
     node.last_seen = Some(Utc::now().timestamp());
+    node.agent_status_since = node.agent_status.and(node.last_seen);
+    node.card = card;
+    if let Some(spawn_id) = &node.spawn_id {
+        let secret = spawn_secret.as_ref().and_then(Value::as_str);
+        if !children::verify_spawn(state, spawn_id, secret) {
+            warn!(node_id = %node.id, %spawn_id, "Ignoring spawn_id without a matching spawn secret");
+            node.spawn_id = None;
+        }
+    }
     let evicted: Vec<String>;
+    let new_use: bool;
     {
         let mut nodes = state
             .nodes
@@ -96,8 +217,26 @@ fn handle_register(
         for id in &evicted {
             nodes.remove(id);
         }
-        nodes.insert(node.id.clone(), node.clone());
+        if let Some(previous) = nodes.get(&node.id) {
+            node.meta.inherit(&previous.meta);
+            if node.card.is_none() {
+                node.card.clone_from(&previous.card);
+            }
+        }
+        let previous = nodes.insert(node.id.clone(), node.clone());
+        // Reconnects and spawned agents (counted at spawn) are not new uses.
+        new_use = node.spawn_id.is_none() && previous.is_none_or(|p| p.cwd != node.cwd);
     }
+    // Recorded after releasing the nodes lock: recording saves the history
+    // to disk, which must not hold up every other registry call.
+    if new_use {
+        state
+            .directories
+            .lock()
+            .expect("directories lock poisoned")
+            .record(std::path::Path::new(&node.cwd), &state.roots, recent::now());
+    }
+    children::mark_registered(state, &node);
     for id in &evicted {
         info!(node_id = %id, "Evicted stale node (same machine:port)");
         let event =
@@ -164,12 +303,215 @@ fn handle_deregister(
     }
 }
 
-fn handle_list_nodes(id: Option<String>, state: &Registry) -> RpcResponse {
+fn handle_update_node(
+    cx: &Cx,
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+) -> RpcResponse {
+    let error = |e: String| RpcResponse {
+        id: id.clone(),
+        result: None,
+        error: Some(e),
+    };
+    let Some(Value::Object(mut patch)) = params else {
+        return error("Missing params".into());
+    };
+    let Some(node_id) = patch
+        .remove("id")
+        .and_then(|v| v.as_str().map(String::from))
+    else {
+        return error("Missing params.id".into());
+    };
+    // Agents may only describe themselves; dashboards may update any node.
+    if registered_node_id.is_some_and(|caller| caller != node_id) {
+        return error("Unauthorized: cannot update another node".into());
+    }
+    if let Some(field) = patch
+        .keys()
+        .find(|k| FIXED_NODE_FIELDS.contains(&k.as_str()))
+    {
+        return error(format!("Cannot change {field} with update_node"));
+    }
+
+    let updated = {
+        let mut nodes = state.nodes.write().expect("nodes lock poisoned");
+        let Some(node) = nodes.get_mut(&node_id) else {
+            return error(format!("Node not found: {node_id}"));
+        };
+        if let Err(e) = node.meta.apply(&patch) {
+            return error(e);
+        }
+        node.clone()
+    };
+    let event = serde_json::json!({ "event": "node_updated", "node": updated }).to_string();
+    let _ = state.tx.send(cx, event);
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "node": updated })),
+        error: None,
+    }
+}
+
+fn handle_report_status(
+    cx: &Cx,
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+) -> RpcResponse {
+    let error = |e: String| RpcResponse {
+        id: id.clone(),
+        result: None,
+        error: Some(e),
+    };
+    let params = params.unwrap_or(Value::Null);
+    let Some(node_id) = params.get("id").and_then(|v| v.as_str()) else {
+        return error("Missing params.id".into());
+    };
+    if registered_node_id.is_some_and(|caller| caller != node_id) {
+        return error("Unauthorized: cannot report status for another node".into());
+    }
+    let Some(status) = params.get("status").filter(|v| !v.is_null()) else {
+        return error("Missing params.status".into());
+    };
+    let Ok(status) = serde_json::from_value::<AgentStatus>(status.clone()) else {
+        return error(format!(
+            "Invalid status: {status} (expected idle, working, waiting_for_input or error)"
+        ));
+    };
+
+    let now = Utc::now().timestamp();
+    let (since, changed) = {
+        let mut nodes = state.nodes.write().expect("nodes lock poisoned");
+        let Some(node) = nodes.get_mut(node_id) else {
+            return error(format!("Node not found: {node_id}"));
+        };
+        node.last_seen = Some(now);
+        let changed = node.agent_status != Some(status);
+        if changed {
+            node.agent_status = Some(status);
+            node.agent_status_since = Some(now);
+        }
+        (node.agent_status_since, changed)
+    };
+    // Repeated reports of the same status are not news to dashboards.
+    if changed {
+        let event = serde_json::json!({
+            "event": "node_status_changed",
+            "id": node_id,
+            "agent_status": status,
+            "agent_status_since": since,
+        })
+        .to_string();
+        let _ = state.tx.send(cx, event);
+    }
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({
+            "agent_status": status,
+            "agent_status_since": since,
+            "changed": changed,
+        })),
+        error: None,
+    }
+}
+
+fn handle_set_agent_card(
+    cx: &Cx,
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+) -> RpcResponse {
+    let error = |e: String| RpcResponse {
+        id: id.clone(),
+        result: None,
+        error: Some(e),
+    };
+    let mut params = params.unwrap_or(Value::Null);
+    let Some(node_id) = params.get("id").and_then(|v| v.as_str()).map(String::from) else {
+        return error("Missing params.id".into());
+    };
+    if registered_node_id.is_some_and(|caller| caller != node_id) {
+        return error("Unauthorized: cannot set another node's card".into());
+    }
+    // `card: null` removes the card.
+    let card = match params.get_mut("card").map(Value::take) {
+        None => return error("Missing params.card".into()),
+        Some(Value::Null) => None,
+        Some(card) => match AgentCard::from_value(card) {
+            Ok(card) => Some(card),
+            Err(e) => return error(e),
+        },
+    };
+
+    {
+        let mut nodes = state.nodes.write().expect("nodes lock poisoned");
+        let Some(node) = nodes.get_mut(&node_id) else {
+            return error(format!("Node not found: {node_id}"));
+        };
+        node.card.clone_from(&card);
+    }
+    // Cards can be large; dashboards fetch them with get_agent_card.
+    let event = serde_json::json!({ "event": "agent_card_updated", "id": node_id }).to_string();
+    let _ = state.tx.send(cx, event);
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "id": node_id, "card": card })),
+        error: None,
+    }
+}
+
+fn handle_get_agent_card(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+) -> RpcResponse {
+    let node_id = params
+        .as_ref()
+        .and_then(|p| p.get("id"))
+        .and_then(|v| v.as_str());
+    let Some(node_id) = node_id else {
+        return RpcResponse {
+            id,
+            result: None,
+            error: Some("Missing params.id".into()),
+        };
+    };
+    let nodes = state.nodes.read().expect("nodes lock poisoned");
+    let Some(node) = nodes.get(node_id) else {
+        return RpcResponse {
+            id,
+            result: None,
+            error: Some(format!("Node not found: {node_id}")),
+        };
+    };
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "id": node_id, "card": node.card })),
+        error: None,
+    }
+}
+
+fn handle_list_nodes(id: Option<String>, params: Option<Value>, state: &Registry) -> RpcResponse {
+    let filter = match CardFilter::from_params(params) {
+        Ok(filter) => filter,
+        Err(e) => {
+            return RpcResponse {
+                id,
+                result: None,
+                error: Some(e),
+            }
+        }
+    };
     let nodes: Vec<NodeInfo> = state
         .nodes
         .read()
         .expect("nodes lock poisoned in list_nodes")
         .values()
+        .filter(|node| filter.matches(node.card.as_ref()))
         .cloned()
         .collect();
     RpcResponse {
@@ -184,15 +526,55 @@ fn handle_list_directories(
     params: Option<Value>,
     state: &Registry,
 ) -> RpcResponse {
+    let params = params.unwrap_or_else(|| serde_json::json!({}));
     let target = params
-        .and_then(|p| p.get("path").and_then(|v| v.as_str().map(String::from)))
+        .get("path")
+        .and_then(|v| v.as_str())
         .map(PathBuf::from)
-        .unwrap_or_else(|| state.home_dir.clone());
+        .unwrap_or_else(|| state.roots.allowed()[0].clone());
+
+    let listing = fs_browser::ListOptions::from_params(&params)
+        .and_then(|options| fs_browser::list_directories(&target, &state.roots, &options));
+    match listing {
+        Ok(mut listing) => {
+            let nodes = state.nodes.read().expect("nodes lock poisoned");
+            let cwds: Vec<&std::path::Path> = nodes
+                .values()
+                .filter(|n| n.status == NodeStatus::Active)
+                .map(|n| std::path::Path::new(&n.cwd))
+                .collect();
+            let current = PathBuf::from(&listing.current);
+            for entry in &mut listing.entries {
+                let dir = current.join(&entry.name);
+                entry.agents = cwds.iter().filter(|cwd| cwd.starts_with(&dir)).count();
+            }
+            drop(nodes);
+            RpcResponse {
+                id,
+                result: Some(serde_json::json!(listing)),
+                error: None,
+            }
+        }
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
 
-    match fs_browser::list_directories(&target, &state.home_dir) {
-        Ok((current, directories)) => RpcResponse {
+fn handle_search_directories(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+) -> RpcResponse {
+    let params = params.unwrap_or_else(|| serde_json::json!({}));
+    match search::SearchOptions::from_params(&params)
+        .and_then(|options| search::search(&state.roots, &state.search_skip, &options))
+    {
+        Ok(found) => RpcResponse {
             id,
-            result: Some(serde_json::json!({ "current": current, "directories": directories })),
+            result: Some(serde_json::json!(found)),
             error: None,
         },
         Err(e) => RpcResponse {
@@ -203,24 +585,664 @@ fn handle_list_directories(
     }
 }
 
-fn handle_spawn_agent(id: Option<String>, params: Option<Value>, state: &Registry) -> RpcResponse {
-    let Some(params) = params else {
+fn handle_read_file(id: Option<String>, params: Option<Value>, state: &Registry) -> RpcResponse {
+    let params = params.unwrap_or_else(|| serde_json::json!({}));
+    let max_bytes = match params.get("max_bytes").map(|v| v.as_u64()) {
+        None => Ok(fs_browser::DEFAULT_READ_BYTES),
+        Some(Some(n)) if (1..=fs_browser::MAX_READ_BYTES).contains(&n) => Ok(n),
+        Some(_) => Err(format!(
+            "max_bytes must be between 1 and {}",
+            fs_browser::MAX_READ_BYTES
+        )),
+    };
+    let read = match params.get("path").and_then(|v| v.as_str()) {
+        Some(path) => max_bytes.and_then(|max_bytes| {
+            fs_browser::read_file(std::path::Path::new(path), &state.roots, max_bytes)
+        }),
+        None => Err("Missing params.path".to_string()),
+    };
+    match read {
+        Ok(file) => RpcResponse {
+            id,
+            result: Some(serde_json::json!(file)),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn handle_watch_directory(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    watch_client: Option<&WatchClient>,
+) -> RpcResponse {
+    let error = |e: String| RpcResponse {
+        id: id.clone(),
+        result: None,
+        error: Some(e),
+    };
+    let Some(client) = watch_client else {
+        return error("watch_directory needs a registry WebSocket connection".into());
+    };
+    let params = params.unwrap_or_else(|| serde_json::json!({}));
+    let node_id = params.get("node_id").and_then(|v| v.as_str());
+    let path = match (node_id, params.get("path").and_then(|v| v.as_str())) {
+        (Some(node_id), None) => {
+            let nodes = state.nodes.read().expect("nodes lock poisoned");
+            match nodes.get(node_id) {
+                Some(node) => node.cwd.clone(),
+                None => return error(format!("Node not found: {node_id}")),
+            }
+        }
+        (None, Some(path)) => path.to_string(),
+        _ => return error("Pass exactly one of params.node_id and params.path".into()),
+    };
+    let mut ignore = state.watch_ignore.clone();
+    if let Some(extra) = params.get("ignore") {
+        match serde_json::from_value::<Vec<String>>(extra.clone()) {
+            Ok(extra) => ignore.extend(extra),
+            Err(e) => return error(format!("Invalid ignore: {e}")),
+        }
+    }
+
+    let watched = std::fs::canonicalize(&path)
+        .map_err(|e| format!("Invalid path: {e}"))
+        .and_then(|dir| {
+            if !dir.is_dir() {
+                return Err("Path is not a directory".to_string());
+            }
+            state.roots.check(&dir)?;
+            let watch_id = state
+                .watches
+                .watch(client, &dir, node_id.map(String::from), &ignore)?;
+            Ok((watch_id, dir))
+        });
+    match watched {
+        Ok((watch_id, dir)) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({
+                "watch_id": watch_id,
+                "path": dir,
+                "node_id": node_id,
+            })),
+            error: None,
+        },
+        Err(e) => error(e),
+    }
+}
+
+fn handle_unwatch_directory(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    watch_client: Option<&WatchClient>,
+) -> RpcResponse {
+    let watch_id = params
+        .as_ref()
+        .and_then(|p| p.get("watch_id"))
+        .and_then(|v| v.as_str());
+    let unwatched = match (watch_client, watch_id) {
+        (_, None) => Err("Missing params.watch_id".to_string()),
+        (None, Some(watch_id)) => Err(format!("Unknown watch_id: {watch_id}")),
+        (Some(client), Some(watch_id)) => state.watches.unwatch(client.id(), watch_id),
+    };
+    match unwatched {
+        Ok(()) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({ "watch_id": watch_id })),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn handle_list_recent_directories(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+) -> RpcResponse {
+    let limit = params
+        .as_ref()
+        .and_then(|p| p.get("limit"))
+        .and_then(|v| v.as_u64())
+        .map_or(20, |n| n.min(recent::MAX_RECENT as u64) as usize);
+    let history = state.directories.lock().expect("directories lock poisoned");
+    let exists = |path: &str| std::path::Path::new(path).is_dir();
+    let recent: Vec<Value> = history
+        .recent(recent::now(), limit)
+        .into_iter()
+        .map(|(path, usage)| {
+            serde_json::json!({
+                "path": path,
+                "count": usage.count,
+                "last_used": usage.last_used,
+                "exists": exists(&path),
+            })
+        })
+        .collect();
+    let favorites: Vec<Value> = history
+        .favorites()
+        .iter()
+        .map(|path| serde_json::json!({ "path": path, "exists": exists(path) }))
+        .collect();
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "recent": recent, "favorites": favorites })),
+        error: None,
+    }
+}
+
+/// `add_favorite` (`add`) and `remove_favorite`.
+fn handle_favorite(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    add: bool,
+) -> RpcResponse {
+    let Some(path) = params
+        .as_ref()
+        .and_then(|p| p.get("path"))
+        .and_then(|v| v.as_str())
+    else {
         return RpcResponse {
             id,
             result: None,
-            error: Some("Missing params".into()),
+            error: Some("Missing params.path".into()),
         };
     };
+    let mut history = state.directories.lock().expect("directories lock poisoned");
+    let changed = if add {
+        history.add_favorite(path, &state.roots)
+    } else {
+        history.remove_favorite(path)
+    };
+    match changed {
+        Ok(path) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({ "path": path, "favorites": history.favorites() })),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn handle_list_roots(id: Option<String>, state: &Registry) -> RpcResponse {
+    let roots: Vec<Value> = state
+        .roots
+        .allowed()
+        .iter()
+        .map(|root| {
+            serde_json::json!({
+                "path": root,
+                "name": root.file_name().map(|n| n.to_string_lossy()).unwrap_or_default(),
+                "exists": root.is_dir(),
+            })
+        })
+        .collect();
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "roots": roots, "denied": state.roots.denied() })),
+        error: None,
+    }
+}
+
+/// With `wait_ms` the response is sent once the agent registers, exits or
+/// the wait runs out, through `reply` if there is one.
+fn handle_spawn_agent(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    reply: Option<&EventSink>,
+) -> Option<RpcResponse> {
+    let Some(params) = params else {
+        return Some(RpcResponse {
+            id,
+            result: None,
+            error: Some("Missing params".into()),
+        });
+    };
     let path_str = params.get("path").and_then(|v| v.as_str()).unwrap_or("");
     let new_folder = params
         .get("new_folder")
         .and_then(|v| v.as_str())
         .unwrap_or("");
+    let options = match spawn::SpawnOptions::from_params(&params)
+        .and_then(|o| o.validate(&state.spawn_env_allowlist).map(|_| o))
+    {
+        Ok(o) => o,
+        Err(e) => {
+            return Some(RpcResponse {
+                id,
+                result: None,
+                error: Some(e),
+            })
+        }
+    };
+
+    let applied = match params.get("template").and_then(|v| v.as_str()) {
+        Some(name) => state
+            .spawn_templates
+            .get(name)
+            .ok_or_else(|| format!("Unknown spawn template: {name}"))
+            .and_then(|template| {
+                let vars = match params.get("vars") {
+                    None | Some(Value::Null) => Default::default(),
+                    Some(vars) => serde_json::from_value(vars.clone())
+                        .map_err(|_| "vars must be an object of strings".to_string())?,
+                };
+                template.apply(path_str, new_folder, &vars, options)
+            }),
+        None => Ok(template::Applied {
+            path: path_str.to_string(),
+            new_folder: new_folder.to_string(),
+            options,
+        }),
+    };
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(e) => {
+            return Some(RpcResponse {
+                id,
+                result: None,
+                error: Some(e),
+            })
+        }
+    };
+    let waits = applied.options.wait_ms.is_some();
+    let state = state.clone();
+    let start = move || start_agent(&state, &applied.path, &applied.new_folder, applied.options);
+    if waits {
+        respond_later(id, reply, start)
+    } else {
+        Some(into_response(id, start()))
+    }
+}
+
+/// Launch and track an agent with already validated `options`, returning the
+/// `spawn_agent` result. Blocks for `options.wait_ms` if set.
+fn start_agent(
+    state: &Registry,
+    path_str: &str,
+    new_folder: &str,
+    mut options: spawn::SpawnOptions,
+) -> Result<Value, String> {
+    options.backend.get_or_insert(state.spawn_backend);
+    options.hidden = crate::sandbox::Hidden {
+        socket: state.config.unix_socket.clone(),
+        config: state.config.source.path.clone(),
+        data_dir: state.config.data_dir.clone(),
+    };
+    // Held until the agent is tracked, so concurrent spawns see each other.
+    let mut window = state
+        .spawn_window
+        .lock()
+        .expect("spawn window lock poisoned");
+    // The overall and rate limits are checked before creating anything; the
+    // per-directory one needs the resolved target, symlinks and all.
+    {
+        let children = state.children.lock().expect("children lock poisoned");
+        window.check(&state.spawn_limits, &children, None, Instant::now())?;
+    }
+    let target = spawn::resolve_target(path_str, new_folder, &state.roots, &options)?;
+    {
+        let children = state.children.lock().expect("children lock poisoned");
+        window.admit(
+            &state.spawn_limits,
+            &children,
+            Some(&target),
+            Instant::now(),
+        )?;
+    }
+    let spawn_id = spawn::new_spawn_id();
+    let waiter = options.wait_ms.map(|ms| {
+        (
+            children::wait_for(state, &spawn_id),
+            Duration::from_millis(ms),
+        )
+    });
+    let spawned = spawn::spawn_agent(
+        &state.spawn_command,
+        &target,
+        &options,
+        &spawn_id,
+        &state.spawn_rlimits,
+    )
+    .inspect_err(|_| children::stop_waiting(state, &spawn_id))?;
+
+    let pid = spawned.launched.pid();
+    state
+        .directories
+        .lock()
+        .expect("directories lock poisoned")
+        .record(
+            std::path::Path::new(&spawned.path),
+            &state.roots,
+            recent::now(),
+        );
+    if let Some(prompt) = options.prompt.take() {
+        state
+            .pending_prompts
+            .lock()
+            .expect("pending prompts lock poisoned")
+            .insert(spawn_id.clone(), prompt);
+    }
+    let mut result = serde_json::json!({
+        "status": "spawning",
+        "spawn_id": spawn_id,
+        "path": spawned.path,
+        "root": state.roots.root_of(std::path::Path::new(&spawned.path)),
+        "pid": pid,
+        "backend": spawned.backend,
+    });
+    if let Some(attach) = spawned.launched.attach() {
+        result["attach"] = attach.into();
+    }
+    let mut record = children::SpawnedProcess::new(
+        pid,
+        spawn_id.clone(),
+        spawned.backend,
+        spawned.command,
+        spawned.path,
+    );
+    record.secret = spawned.secret;
+    // A restart relaunches in the same directory with the same options, but
+    // no initial prompt and no new worktree.
+    record.options = spawn::SpawnOptions {
+        wait_ms: None,
+        worktree: None,
+        ..options
+    };
+    children::track(state, spawned.launched, record);
+    drop(window);
+
+    let Some((waiter, timeout)) = waiter else {
+        return Ok(result);
+    };
+    match waiter.recv_timeout(timeout) {
+        Ok(children::SpawnOutcome::Registered(node)) => {
+            result["status"] = "registered".into();
+            result["node"] = serde_json::json!(node);
+            Ok(result)
+        }
+        Ok(children::SpawnOutcome::Exited { status, stderr }) => {
+            let mut error = format!(
+                "Agent exited before registering ({})",
+                children::describe_exit(status)
+            );
+            if let Some(last) = stderr.last() {
+                error.push_str(": ");
+                error.push_str(last);
+            }
+            Err(error)
+        }
+        Err(_) => {
+            // Still starting; the caller can match `spawn_id` on node_joined.
+            children::stop_waiting(state, &spawn_id);
+            Ok(result)
+        }
+    }
+}
+
+/// Which spawned agent a `stop_agent` / `restart_agent` call targets, and
+/// how long it gets to exit.
+fn lifecycle_target(
+    params: Option<&Value>,
+    state: &Registry,
+) -> Result<(children::SpawnedProcess, Duration), String> {
+    let params = params.ok_or("Missing params")?;
+    let node_id = params.get("id").and_then(|v| v.as_str());
+    let spawn_id = params.get("spawn_id").and_then(|v| v.as_str());
+    let grace_ms = match params.get("grace_ms") {
+        None | Some(Value::Null) => children::DEFAULT_STOP_GRACE_MS,
+        Some(v) => v
+            .as_u64()
+            .ok_or("grace_ms must be a non-negative integer")?,
+    };
+    if grace_ms > children::MAX_STOP_GRACE_MS {
+        return Err(format!(
+            "grace_ms is too large (max {})",
+            children::MAX_STOP_GRACE_MS
+        ));
+    }
+    let child = children::owned_child(state, node_id, spawn_id)?;
+    Ok((child, Duration::from_millis(grace_ms)))
+}
 
-    match spawn::spawn_agent(path_str, new_folder, &state.home_dir) {
-        Ok(resolved_path) => RpcResponse {
+fn handle_stop_agent(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+) -> RpcResponse {
+    if registered_node_id.is_some() {
+        return RpcResponse {
             id,
-            result: Some(serde_json::json!({ "status": "spawning", "path": resolved_path })),
+            result: None,
+            error: Some("Unauthorized: agents cannot stop agents".into()),
+        };
+    }
+    let stopped = lifecycle_target(params.as_ref(), state).and_then(|(child, grace)| {
+        children::stop(state, child.pid, grace)?;
+        Ok(child)
+    });
+    match stopped {
+        Ok(child) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({
+                "status": "stopping",
+                "pid": child.pid,
+                "spawn_id": child.spawn_id,
+            })),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+/// How long past the grace period a restart waits for the old process to
+/// be gone, covering `SIGKILL` delivery and exit polling.
+const RESTART_EXIT_SLACK: Duration = Duration::from_secs(3);
+
+/// Waits for the old process to exit, so the response is sent through
+/// `reply` if there is one.
+fn handle_restart_agent(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+    reply: Option<&EventSink>,
+) -> Option<RpcResponse> {
+    if registered_node_id.is_some() {
+        return Some(RpcResponse {
+            id,
+            result: None,
+            error: Some("Unauthorized: agents cannot restart agents".into()),
+        });
+    }
+    let (child, grace) = match lifecycle_target(params.as_ref(), state) {
+        Ok(target) => target,
+        Err(e) => {
+            return Some(RpcResponse {
+                id,
+                result: None,
+                error: Some(e),
+            })
+        }
+    };
+    let state = state.clone();
+    respond_later(id, reply, move || {
+        children::stop(&state, child.pid, grace)?;
+        if !children::wait_untracked(&state, child.pid, grace + RESTART_EXIT_SLACK) {
+            return Err(format!("Agent {} did not exit", child.pid));
+        }
+        let mut result = start_agent(&state, &child.cwd, "", child.options)?;
+        result["previous_spawn_id"] = child.spawn_id.into();
+        Ok(result)
+    })
+}
+
+/// Configured spawn templates. Env values are left out since they may
+/// hold secrets; `vars` lists the placeholders a caller must fill in.
+fn handle_list_spawn_templates(id: Option<String>, state: &Registry) -> RpcResponse {
+    let templates: Vec<Value> = state
+        .spawn_templates
+        .iter()
+        .map(|(name, template)| {
+            let mut entry = serde_json::json!(template);
+            entry["name"] = name.as_str().into();
+            entry["env"] = serde_json::json!(template.env.keys().collect::<Vec<_>>());
+            entry["vars"] = serde_json::json!(template.vars());
+            entry
+        })
+        .collect();
+    RpcResponse {
+        id,
+        result: Some(serde_json::json!({ "templates": templates })),
+        error: None,
+    }
+}
+
+/// Whether a running agent has its working directory inside `dir`.
+fn dir_in_use(state: &Registry, dir: &std::path::Path) -> bool {
+    let inside = |cwd: &str| std::path::Path::new(cwd).starts_with(dir);
+    let spawned = state
+        .children
+        .lock()
+        .expect("children lock poisoned")
+        .values()
+        .any(|c| inside(&c.cwd));
+    spawned
+        || state
+            .nodes
+            .read()
+            .expect("nodes lock poisoned")
+            .values()
+            .any(|n| n.status == NodeStatus::Active && inside(&n.cwd))
+}
+
+fn handle_list_worktrees(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+) -> RpcResponse {
+    let path = params
+        .as_ref()
+        .and_then(|p| p.get("path"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    match worktree::list(path, &state.roots) {
+        Ok(worktrees) => {
+            let worktrees: Vec<Value> = worktrees
+                .into_iter()
+                .map(|w| {
+                    let in_use = dir_in_use(state, std::path::Path::new(&w.path));
+                    let mut entry = serde_json::json!(w);
+                    entry["in_use"] = in_use.into();
+                    entry
+                })
+                .collect();
+            RpcResponse {
+                id,
+                result: Some(serde_json::json!({ "worktrees": worktrees })),
+                error: None,
+            }
+        }
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn handle_node_git_status(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+) -> RpcResponse {
+    let params = params.unwrap_or_else(|| serde_json::json!({}));
+    let status = match params.get("id").and_then(|v| v.as_str()) {
+        None => Err("Missing params.id".to_string()),
+        Some(node_id) => {
+            let cwd = state
+                .nodes
+                .read()
+                .expect("nodes lock poisoned")
+                .get(node_id)
+                .map(|n| n.cwd.clone())
+                .ok_or_else(|| format!("Node not found: {node_id}"));
+            cwd.and_then(|cwd| {
+                let options = git_status::StatusOptions::from_params(&params)?;
+                git_status::status(&cwd, &state.roots, &options)
+            })
+        }
+    };
+    match status {
+        Ok(status) => RpcResponse {
+            id,
+            result: Some(serde_json::json!(status)),
+            error: None,
+        },
+        Err(e) => RpcResponse {
+            id,
+            result: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn handle_remove_worktree(
+    id: Option<String>,
+    params: Option<Value>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+) -> RpcResponse {
+    if registered_node_id.is_some() {
+        return RpcResponse {
+            id,
+            result: None,
+            error: Some("Unauthorized: agents cannot remove worktrees".into()),
+        };
+    }
+    let path = params
+        .as_ref()
+        .and_then(|p| p.get("path"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("");
+    let force = params
+        .as_ref()
+        .and_then(|p| p.get("force"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let removed = std::fs::canonicalize(path)
+        .map_err(|e| format!("Invalid path: {e}"))
+        .and_then(|dir| {
+            if dir_in_use(state, &dir) {
+                return Err("Worktree is in use by a running agent".into());
+            }
+            worktree::remove(path, force, &state.roots)
+        });
+    match removed {
+        Ok(()) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({ "status": "removed" })),
             error: None,
         },
         Err(e) => RpcResponse {
@@ -243,29 +1265,70 @@ fn handle_ping(id: Option<String>, state: &Registry) -> RpcResponse {
             "status": "healthy",
             "nodes": node_count,
             "version": env!("CARGO_PKG_VERSION"),
+            "sandbox": crate::sandbox::available_method(),
         })),
         error: None,
     }
 }
 
+fn handle_reload_config(
+    id: Option<String>,
+    state: &Registry,
+    registered_node_id: Option<&str>,
+    admin: bool,
+) -> RpcResponse {
+    // Admin-only: registered agents must not be able to change auth settings
+    if registered_node_id.is_some() {
+        return RpcResponse {
+            id,
+            result: None,
+            error: Some("Unauthorized: agents cannot reload config".into()),
+        };
+    }
+    if !admin {
+        return RpcResponse {
+            id,
+            result: None,
+            error: Some("Unauthorized: reload_config needs an admin token".into()),
+        };
+    }
+
+    match config::reload(state) {
+        Ok(reloaded) => RpcResponse {
+            id,
+            result: Some(serde_json::json!({
+                "status": "reloaded",
+                "node_ttl": reloaded.live.node_ttl,
+                "tokens": reloaded.live.tokens.len(),
+                "restart_required": reloaded.ignored,
+            })),
+            error: None,
+        },
+        Err(e) => {
+            warn!(error = %e, "Config reload failed");
+            RpcResponse {
+                id,
+                result: None,
+                error: Some(e),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state::AppState;
-    use asupersync::channel::broadcast;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock},
-    };
+    use crate::config::{ConfigLayer, ConfigSource, ServerConfig};
 
     fn make_registry() -> Registry {
-        let (tx, _) = broadcast::channel::<String>(16);
-        Arc::new(AppState {
-            nodes: RwLock::new(HashMap::new()),
-            tx,
-            secret_token: String::new(),
-            home_dir: dirs::home_dir().unwrap_or_else(|| PathBuf::from("/tmp")),
+        make_registry_with_source(ConfigSource::default())
+    }
+
+    fn make_registry_with_source(source: ConfigSource) -> Registry {
+        crate::create_state(&ServerConfig {
             node_ttl: 3600,
+            source,
+            ..Default::default()
         })
     }
 
@@ -365,9 +1428,207 @@ mod tests {
                 "port": 8082, "status": "active"
             })),
         };
-        dispatch(&cx, req, &reg, None);
-        assert!(!reg.nodes.read().unwrap().contains_key("host-old-session"));
-        assert!(reg.nodes.read().unwrap().contains_key("host-new-session"));
+        dispatch(&cx, req, &reg, None);
+        assert!(!reg.nodes.read().unwrap().contains_key("host-old-session"));
+        assert!(reg.nodes.read().unwrap().contains_key("host-new-session"));
+    }
+
+    #[test]
+    fn node_metadata_is_kept_and_updated() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let call = |method: &str, params: Value, caller: Option<&str>| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: method.into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, caller)
+        };
+        let register = serde_json::json!({
+            "id": "n1", "machine": "host", "cwd": "/project", "port": 8090,
+            "status": "active", "session_name": "refactor", "model": "sonnet",
+            "labels": { "team": "core" }, "theme": { "color": "teal" }
+        });
+        call("register", register, None);
+
+        let updated = call(
+            "update_node",
+            serde_json::json!({
+                "id": "n1", "model": null, "git_branch": "fix/login",
+                "labels": { "team": null, "review": "yes" }, "theme": "plain"
+            }),
+            Some("n1"),
+        );
+        let node = &updated.result.unwrap()["node"];
+        assert_eq!(node["session_name"], "refactor");
+        assert_eq!(node["git_branch"], "fix/login");
+        assert!(node.get("model").is_none());
+        assert_eq!(node["labels"], serde_json::json!({ "review": "yes" }));
+        assert_eq!(node["theme"], "plain");
+
+        // A reconnect that leaves fields out keeps them.
+        let reconnect = serde_json::json!({
+            "id": "n1", "machine": "host", "cwd": "/project", "port": 8090,
+            "status": "active", "pi_version": "0.9.0"
+        });
+        call("register", reconnect, None);
+        let nodes = call("list_nodes", Value::Null, None).result.unwrap();
+        assert_eq!(nodes[0]["labels"]["review"], "yes");
+        assert_eq!(nodes[0]["pi_version"], "0.9.0");
+        assert_eq!(nodes[0]["theme"], "plain");
+
+        let error = |params: Value, caller: Option<&str>| {
+            call("update_node", params, caller).error.unwrap()
+        };
+        assert_eq!(
+            error(serde_json::json!({ "id": "n1", "cwd": "/" }), None),
+            "Cannot change cwd with update_node"
+        );
+        assert!(error(serde_json::json!({ "id": "n1" }), Some("n2")).starts_with("Unauthorized"));
+        assert!(error(serde_json::json!({ "id": "n1", "labels": [] }), None).contains("labels"));
+        assert!(error(serde_json::json!({ "id": "n1", "model": 5 }), None)
+            .starts_with("Invalid node update"));
+        assert_eq!(
+            error(serde_json::json!({ "id": "n9" }), None),
+            "Node not found: n9"
+        );
+    }
+
+    #[test]
+    fn report_status_records_transitions() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let call = |method: &str, params: Value, caller: Option<&str>| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: method.into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, caller)
+        };
+        let register = serde_json::json!({
+            "id": "n1", "machine": "host", "cwd": "/project", "port": 8091,
+            "status": "active", "agent_status": "idle"
+        });
+        call("register", register, None);
+        let nodes = call("list_nodes", Value::Null, None).result.unwrap();
+        assert_eq!(nodes[0]["agent_status"], "idle");
+        assert!(nodes[0]["agent_status_since"].is_i64());
+
+        let report = |status: &str| {
+            call(
+                "report_status",
+                serde_json::json!({ "id": "n1", "status": status }),
+                Some("n1"),
+            )
+            .result
+            .unwrap()
+        };
+        assert_eq!(report("working")["changed"], true);
+        assert_eq!(report("working")["changed"], false);
+        let result = report("waiting_for_input");
+        assert_eq!(result["agent_status"], "waiting_for_input");
+        let nodes = call("list_nodes", Value::Null, None).result.unwrap();
+        assert_eq!(nodes[0]["agent_status"], "waiting_for_input");
+
+        // Only register sets it otherwise.
+        let update = serde_json::json!({ "id": "n1", "agent_status": "idle" });
+        assert!(call("update_node", update, None).error.is_some());
+
+        let error = |params: Value, caller: Option<&str>| {
+            call("report_status", params, caller).error.unwrap()
+        };
+        let idle = serde_json::json!({ "id": "n1", "status": "idle" });
+        assert!(error(idle, Some("n2")).starts_with("Unauthorized"));
+        let stuck = serde_json::json!({ "id": "n1", "status": "stuck" });
+        assert!(error(stuck, None).starts_with("Invalid status"));
+        assert_eq!(
+            error(serde_json::json!({ "id": "n1" }), None),
+            "Missing params.status"
+        );
+        assert_eq!(
+            error(serde_json::json!({ "id": "n9", "status": "idle" }), None),
+            "Node not found: n9"
+        );
+    }
+
+    #[test]
+    fn agent_cards_are_served_and_filtered() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let call = |method: &str, params: Value, caller: Option<&str>| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: method.into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, caller)
+        };
+        let register = |id: &str, port: u16, card: Value| {
+            let params = serde_json::json!({
+                "id": id, "machine": "host", "cwd": "/project", "port": port,
+                "status": "active", "card": card
+            });
+            call("register", params, None)
+        };
+        let card = serde_json::json!({
+            "tools": [{ "name": "bash" }], "skills": [{ "name": "harden" }],
+            "current_task": "Review the API", "project": "api"
+        });
+        register("n1", 8092, card.clone());
+        register("n2", 8093, Value::Null);
+        let invalid = register("n3", 8094, serde_json::json!({ "tools": [{}] }));
+        assert!(invalid.error.unwrap().starts_with("Invalid agent card"));
+
+        let result = call("get_agent_card", serde_json::json!({ "id": "n1" }), None).result;
+        assert_eq!(result.unwrap()["card"]["project"], "api");
+        let result = call("get_agent_card", serde_json::json!({ "id": "n2" }), None).result;
+        assert!(result.unwrap()["card"].is_null());
+
+        // The card is not part of the node, and survives a reconnect without one.
+        let nodes = call("list_nodes", Value::Null, None).result.unwrap();
+        assert!(nodes[0].get("card").is_none() && nodes[1].get("card").is_none());
+        register("n1", 8092, Value::Null);
+        let ids = |filter: Value| -> Vec<String> {
+            let nodes = call("list_nodes", filter, None).result.unwrap();
+            let mut ids: Vec<String> = nodes
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|n| n["id"].as_str().unwrap().to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(serde_json::json!({})), ["n1", "n2"]);
+        let filter = serde_json::json!({ "tool": "bash", "task": "api" });
+        assert_eq!(ids(filter), ["n1"]);
+
+        let skills = serde_json::json!({ "skills": [{ "name": "harden" }] });
+        let update = serde_json::json!({ "id": "n2", "card": skills });
+        let other = call("set_agent_card", update.clone(), Some("n1"));
+        assert!(other.error.unwrap().starts_with("Unauthorized"));
+        assert!(call("set_agent_card", update, Some("n2")).error.is_none());
+        assert_eq!(ids(serde_json::json!({ "skill": "harden" })), ["n1", "n2"]);
+        let clear = serde_json::json!({ "id": "n1", "card": null });
+        call("set_agent_card", clear, None);
+        assert_eq!(ids(serde_json::json!({ "skill": "harden" })), ["n2"]);
+
+        let error = |method: &str, params: Value| call(method, params, None).error.unwrap();
+        assert!(error("list_nodes", serde_json::json!({ "model": "x" })).contains("unknown field"));
+        assert_eq!(
+            error("set_agent_card", serde_json::json!({ "id": "n1" })),
+            "Missing params.card"
+        );
+        assert_eq!(
+            error("get_agent_card", serde_json::json!({ "id": "n9" })),
+            "Node not found: n9"
+        );
+        assert_eq!(
+            error("update_node", serde_json::json!({ "id": "n1", "card": {} })),
+            "Cannot change card with update_node"
+        );
     }
 
     #[test]
@@ -644,6 +1905,306 @@ mod tests {
         assert!(resp.error.is_some());
     }
 
+    #[test]
+    fn list_roots_reports_allowed_and_denied() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone(), "/nonexistent/hypi".into()],
+            denied_paths: vec![root.join("private")],
+            ..Default::default()
+        });
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "list_roots".into(),
+            params: None,
+        };
+        let result = dispatch(&cx, req, &reg, None).result.unwrap();
+        assert_eq!(result["roots"][0]["path"], serde_json::json!(root));
+        assert_eq!(result["roots"][0]["exists"], true);
+        assert_eq!(result["roots"][1]["name"], "hypi");
+        assert_eq!(result["roots"][1]["exists"], false);
+        assert_eq!(result["denied"], serde_json::json!([root.join("private")]));
+
+        let req = RpcRequest {
+            id: Some("2".into()),
+            method: "list_directories".into(),
+            params: Some(serde_json::json!({ "path": root })),
+        };
+        let result = dispatch(&cx, req, &reg, None).result.unwrap();
+        assert_eq!(result["root"], serde_json::json!(root));
+    }
+
+    #[test]
+    fn list_directories_counts_agents_per_entry() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_ls_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("busy/sub")).unwrap();
+        std::fs::create_dir_all(root.join("idle")).unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        let nodes = [
+            (8001, "a", "busy"),
+            (8002, "b", "busy/sub"),
+            (8003, "c", "idle"),
+        ];
+        for (port, id, cwd) in nodes {
+            let req = RpcRequest {
+                id: None,
+                method: "register".into(),
+                params: Some(serde_json::json!({
+                    "id": id,
+                    "machine": "localhost",
+                    "cwd": root.join(cwd),
+                    "port": port,
+                    "status": "active",
+                })),
+            };
+            dispatch(&cx, req, &reg, None);
+        }
+        reg.nodes.write().unwrap().get_mut("c").unwrap().status = NodeStatus::Offline;
+
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "list_directories".into(),
+            params: Some(serde_json::json!({ "path": root, "sort": "name" })),
+        };
+        let result = dispatch(&cx, req, &reg, None).result.unwrap();
+        assert_eq!(result["directories"], serde_json::json!(["busy", "idle"]));
+        assert_eq!(result["entries"][0]["agents"], 2);
+        assert_eq!(result["entries"][1]["agents"], 0);
+        assert_eq!(result["total"], 2);
+
+        let req = RpcRequest {
+            id: Some("2".into()),
+            method: "list_directories".into(),
+            params: Some(serde_json::json!({ "path": root, "sort": "size" })),
+        };
+        assert!(dispatch(&cx, req, &reg, None).error.is_some());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn search_directories_finds_by_name() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_search_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src/hyper-pi")).unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        let search = |params: Value| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: "search_directories".into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, None)
+        };
+        let result = search(serde_json::json!({ "query": "hyp" }))
+            .result
+            .unwrap();
+        assert_eq!(result["total"], 1);
+        assert_eq!(
+            result["results"][0]["path"],
+            serde_json::json!(root.join("src/hyper-pi"))
+        );
+        assert_eq!(
+            search(serde_json::json!({})).error.unwrap(),
+            "Missing query"
+        );
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn recent_and_favorite_directories() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_recent_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("api")).unwrap();
+        std::fs::create_dir_all(root.join("web")).unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        let call = |method: &str, params: Value| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: method.into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, None)
+        };
+        let api = root.join("api");
+        // Re-registering the same node is a reconnect, not a new use.
+        for _ in 0..2 {
+            let node = serde_json::json!({
+                "id": "n1",
+                "machine": "localhost",
+                "cwd": api,
+                "port": 8001,
+                "status": "active",
+            });
+            call("register", node);
+        }
+        call(
+            "add_favorite",
+            serde_json::json!({ "path": root.join("web") }),
+        );
+
+        let result = call("list_recent_directories", serde_json::json!({}))
+            .result
+            .unwrap();
+        assert_eq!(result["recent"][0]["path"], serde_json::json!(api));
+        assert_eq!(result["recent"][0]["count"], 1);
+        assert_eq!(
+            result["favorites"][0]["path"],
+            serde_json::json!(root.join("web"))
+        );
+        assert_eq!(result["favorites"][0]["exists"], true);
+
+        let removed = call(
+            "remove_favorite",
+            serde_json::json!({ "path": root.join("web") }),
+        );
+        assert_eq!(removed.result.unwrap()["favorites"], serde_json::json!([]));
+        let outside = call("add_favorite", serde_json::json!({ "path": "/" }));
+        assert!(outside.error.unwrap().contains("outside allowed roots"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn node_git_status_runs_in_node_cwd() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_gitst_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        worktree::git(&root, &["init", "-q", "-b", "main"]).unwrap();
+        std::fs::write(root.join("todo.md"), "x").unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        let call = |method: &str, params: Value| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: method.into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, None)
+        };
+        call(
+            "register",
+            serde_json::json!({
+                "id": "n1",
+                "machine": "localhost",
+                "cwd": root,
+                "port": 8001,
+                "status": "active",
+            }),
+        );
+
+        let result = call(
+            "node_git_status",
+            serde_json::json!({ "id": "n1", "diff": true }),
+        )
+        .result
+        .unwrap();
+        assert_eq!(result["branch"], "main");
+        assert_eq!(result["head"], Value::Null);
+        assert_eq!(result["files"][0]["path"], "todo.md");
+        assert_eq!(result["files"][0]["status"], "untracked");
+        assert_eq!(result["diff"], "");
+
+        let missing = call("node_git_status", serde_json::json!({ "id": "ghost" }));
+        assert_eq!(missing.error.unwrap(), "Node not found: ghost");
+        let too_big = call(
+            "node_git_status",
+            serde_json::json!({ "id": "n1", "max_diff_bytes": 0 }),
+        );
+        assert!(too_big.error.unwrap().starts_with("max_diff_bytes"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn read_file_validates_params() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_read_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("notes.md"), "0123456789").unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        let call = |params: Value| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: "read_file".into(),
+                params: Some(params),
+            };
+            dispatch(&cx, req, &reg, None)
+        };
+        let notes = root.join("notes.md");
+
+        let result = call(serde_json::json!({ "path": notes, "max_bytes": 4 }))
+            .result
+            .unwrap();
+        assert_eq!(result["content"], "0123");
+        assert_eq!(result["size"], 10);
+        assert_eq!(result["truncated"], true);
+        assert_eq!(result["root"], serde_json::json!(root));
+
+        let missing = call(serde_json::json!({}));
+        assert_eq!(missing.error.unwrap(), "Missing params.path");
+        let too_big = call(serde_json::json!({ "path": notes, "max_bytes": 1 << 30 }));
+        assert!(too_big.error.unwrap().starts_with("max_bytes must be"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn watch_directory_needs_a_connection_and_one_target() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let client = reg.watches.client(std::sync::Arc::new(|_: &str| true));
+        let call = |params: Value, client: Option<&WatchClient>| {
+            let req = RpcRequest {
+                id: Some("1".into()),
+                method: "watch_directory".into(),
+                params: Some(params),
+            };
+            let connection = Connection {
+                watch_client: client,
+                ..Default::default()
+            };
+            let resp = dispatch_on(&cx, req, &reg, None, connection).unwrap();
+            resp.error.unwrap()
+        };
+        let outside = serde_json::json!({ "path": "/" });
+        assert!(call(outside.clone(), None).contains("registry WebSocket"));
+        assert!(call(outside, Some(&client)).contains("outside allowed roots"));
+        assert_eq!(
+            call(serde_json::json!({ "node_id": "ghost" }), Some(&client)),
+            "Node not found: ghost"
+        );
+        assert!(call(serde_json::json!({}), Some(&client)).starts_with("Pass exactly one"));
+    }
+
     #[test]
     fn spawn_agent_missing_params_returns_error() {
         let cx = crate::ephemeral_cx();
@@ -673,6 +2234,460 @@ mod tests {
         assert!(resp.error.is_some());
     }
 
+    #[test]
+    fn spawn_agent_rejects_disallowed_env_before_creating_folder() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let parent = std::env::temp_dir().join("hypi_spawn_env_test");
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "spawn_agent".into(),
+            params: Some(serde_json::json!({
+                "path": parent,
+                "new_folder": "should_not_exist",
+                "env": { "LD_PRELOAD": "/tmp/evil.so" }
+            })),
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        assert_eq!(
+            resp.error.as_deref(),
+            Some("Environment variable not allowed: LD_PRELOAD")
+        );
+        assert!(!parent.join("should_not_exist").exists());
+        assert!(reg.pending_prompts.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    fn make_spawn_registry() -> (Registry, PathBuf) {
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            spawn_command: "sh".into(),
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+        (reg, root)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_wait_reports_early_exit() {
+        let cx = crate::ephemeral_cx();
+        let (reg, root) = make_spawn_registry();
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "spawn_agent".into(),
+            params: Some(serde_json::json!({
+                "path": root,
+                "args": ["-c", "echo 'no api key' >&2; exit 2"],
+                "wait_ms": 10_000
+            })),
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        let error = resp.error.unwrap();
+        assert!(
+            error.starts_with("Agent exited before registering"),
+            "{error}"
+        );
+        assert!(error.ends_with(": no api key"), "{error}");
+        assert!(reg.spawn_waiters.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_wait_replies_later_on_connections() {
+        let cx = crate::ephemeral_cx();
+        let (reg, root) = make_spawn_registry();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let reply: EventSink = std::sync::Arc::new(move |json: &str| {
+            tx.lock().unwrap().send(json.to_string()).is_ok()
+        });
+        let connection = Connection {
+            reply: Some(&reply),
+            ..Default::default()
+        };
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "spawn_agent".into(),
+            params: Some(serde_json::json!({
+                "path": root,
+                "args": ["-c", "sleep 0.2; exit 2"],
+                "wait_ms": 10_000
+            })),
+        };
+        assert!(dispatch_on(&cx, req, &reg, None, connection).is_none());
+
+        // The connection keeps answering while the spawn waits
+        let ping = RpcRequest {
+            id: Some("2".into()),
+            method: "ping".into(),
+            params: None,
+        };
+        assert!(dispatch_on(&cx, ping, &reg, None, connection).is_some());
+
+        let response = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], "1");
+        let error = response["error"].as_str().unwrap();
+        assert!(error.starts_with("Agent exited before registering"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_wait_returns_registered_node() {
+        let cx = crate::ephemeral_cx();
+        let (reg, root) = make_spawn_registry();
+
+        // Play the agent: register with the spawn ID and secret once the
+        // child is tracked, after an impostor tried the spawn ID alone.
+        let agent_reg = reg.clone();
+        let agent = std::thread::spawn(move || {
+            let (spawn_id, secret) = loop {
+                let children = agent_reg.children.lock().unwrap();
+                if let Some(child) = children.values().next() {
+                    break (child.spawn_id.clone(), child.secret.clone());
+                }
+                drop(children);
+                std::thread::sleep(Duration::from_millis(10));
+            };
+            let register = |id: &str, proof: Value| RpcRequest {
+                id: Some("r".into()),
+                method: "register".into(),
+                params: Some(serde_json::json!({
+                    "id": id,
+                    "machine": "localhost",
+                    "cwd": "/tmp",
+                    "port": 8123,
+                    "status": "active",
+                    "spawn_id": spawn_id,
+                    "spawn_secret": proof,
+                })),
+            };
+            let cx = crate::ephemeral_cx();
+            let impostor = dispatch(&cx, register("impostor", "guess".into()), &agent_reg, None);
+            assert!(impostor.error.is_none());
+            let stored = agent_reg.nodes.read().unwrap()["impostor"].clone();
+            assert!(stored.spawn_id.is_none());
+            assert!(stored.meta.extra.get("spawn_secret").is_none());
+            dispatch(
+                &cx,
+                register("spawned-node", secret.into()),
+                &agent_reg,
+                None,
+            )
+        });
+
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "spawn_agent".into(),
+            params: Some(serde_json::json!({
+                "path": root,
+                "args": ["-c", "sleep 1"],
+                "wait_ms": 10_000
+            })),
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        assert!(agent.join().unwrap().error.is_none());
+        let result = resp.result.unwrap();
+        assert_eq!(result["status"], "registered");
+        assert_eq!(result["node"]["id"], "spawned-node");
+        assert_eq!(result["node"]["spawn_id"], result["spawn_id"]);
+        assert_eq!(result["root"], serde_json::json!(root));
+    }
+
+    fn lifecycle_request(method: &str, params: Value) -> RpcRequest {
+        RpcRequest {
+            id: Some("1".into()),
+            method: method.into(),
+            params: Some(params),
+        }
+    }
+
+    #[test]
+    fn stop_agent_refuses_agents_and_foreign_nodes() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let req = lifecycle_request(
+            "register",
+            serde_json::json!({
+                "id": "manual", "machine": "localhost", "cwd": "/tmp",
+                "port": 8123, "status": "active", "pid": std::process::id()
+            }),
+        );
+        assert!(dispatch(&cx, req, &reg, None).error.is_none());
+
+        let req = lifecycle_request("stop_agent", serde_json::json!({ "id": "manual" }));
+        assert_eq!(
+            dispatch(&cx, req, &reg, None).error.as_deref(),
+            Some("Node manual was not spawned by the hypivisor")
+        );
+        let req = lifecycle_request("stop_agent", serde_json::json!({ "id": "manual" }));
+        assert_eq!(
+            dispatch(&cx, req, &reg, Some("other")).error.as_deref(),
+            Some("Unauthorized: agents cannot stop agents")
+        );
+        let req = lifecycle_request(
+            "restart_agent",
+            serde_json::json!({ "id": "manual", "grace_ms": 600_000 }),
+        );
+        assert!(dispatch(&cx, req, &reg, None)
+            .error
+            .unwrap()
+            .starts_with("grace_ms is too large"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_agent_relaunches_with_same_options() {
+        let cx = crate::ephemeral_cx();
+        let (reg, root) = make_spawn_registry();
+        let req = lifecycle_request(
+            "spawn_agent",
+            serde_json::json!({ "path": root, "args": ["-c", "exec sleep 30"] }),
+        );
+        let first = dispatch(&cx, req, &reg, None).result.unwrap();
+
+        let req = lifecycle_request(
+            "restart_agent",
+            serde_json::json!({ "spawn_id": first["spawn_id"], "grace_ms": 1000 }),
+        );
+        let second = dispatch(&cx, req, &reg, None).result.unwrap();
+        assert_eq!(second["status"], "spawning");
+        assert_eq!(second["previous_spawn_id"], first["spawn_id"]);
+        assert_ne!(second["pid"], first["pid"]);
+        let children = reg.children.lock().unwrap().clone();
+        assert_eq!(children.len(), 1);
+        let restarted = &children[&(second["pid"].as_u64().unwrap() as u32)];
+        assert_eq!(restarted.options.args, ["-c", "exec sleep 30"]);
+
+        let req = lifecycle_request(
+            "stop_agent",
+            serde_json::json!({ "spawn_id": second["spawn_id"] }),
+        );
+        assert_eq!(
+            dispatch(&cx, req, &reg, None).result.unwrap()["status"],
+            "stopping"
+        );
+        assert!(children::wait_untracked(
+            &reg,
+            restarted.pid,
+            Duration::from_secs(10)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_from_template() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let folder = format!("hypi-template-{}", std::process::id());
+        let template = crate::template::SpawnTemplate {
+            description: Some("Quick exit".into()),
+            path: Some(root.to_string_lossy().into()),
+            new_folder: Some("hypi-template-{run}".into()),
+            args: vec!["-c".into(), "exit 0".into()],
+            env: [("SECRET".to_string(), "x".to_string())].into(),
+            prompt: Some("Hello {who}".into()),
+            ..Default::default()
+        };
+        let reg = crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            spawn_command: "sh".into(),
+            allowed_roots: vec![root.clone()],
+            templates: [("quick".to_string(), template)].into(),
+            ..Default::default()
+        });
+
+        let listed = dispatch(
+            &cx,
+            lifecycle_request("list_spawn_templates", Value::Null),
+            &reg,
+            None,
+        );
+        let listed = &listed.result.unwrap()["templates"][0];
+        assert_eq!(listed["name"], "quick");
+        assert_eq!(listed["env"], serde_json::json!(["SECRET"]));
+        assert_eq!(listed["vars"], serde_json::json!(["run", "who"]));
+
+        let req = lifecycle_request(
+            "spawn_agent",
+            serde_json::json!({ "template": "quick", "vars": { "run": std::process::id().to_string() } }),
+        );
+        assert_eq!(
+            dispatch(&cx, req, &reg, None).error.as_deref(),
+            Some("Missing template variable: who")
+        );
+        assert!(!root.join(&folder).exists());
+
+        let req = lifecycle_request(
+            "spawn_agent",
+            serde_json::json!({
+                "template": "quick",
+                "vars": { "run": std::process::id().to_string(), "who": "there" }
+            }),
+        );
+        let result = dispatch(&cx, req, &reg, None).result.unwrap();
+        assert_eq!(
+            result["path"],
+            root.join(&folder).to_string_lossy().as_ref()
+        );
+        let _ = std::fs::remove_dir_all(root.join(&folder));
+
+        let req = lifecycle_request("spawn_agent", serde_json::json!({ "template": "nope" }));
+        assert_eq!(
+            dispatch(&cx, req, &reg, None).error.as_deref(),
+            Some("Unknown spawn template: nope")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_in_worktree_then_remove_it() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_rpc_wt_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let repo = root.join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+        for args in [
+            &["init", "-q"][..],
+            &[
+                "-c",
+                "user.name=t",
+                "-c",
+                "user.email=t@t",
+                "commit",
+                "-q",
+                "--allow-empty",
+                "-m",
+                "init",
+            ],
+        ] {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let reg = crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            spawn_command: "sh".into(),
+            allowed_roots: vec![root.clone()],
+            ..Default::default()
+        });
+
+        let req = lifecycle_request(
+            "spawn_agent",
+            serde_json::json!({
+                "path": repo,
+                "new_folder": "fix",
+                "worktree": { "branch": "fix/1" },
+                "args": ["-c", "exec sleep 30"]
+            }),
+        );
+        let spawned = dispatch(&cx, req, &reg, None).result.unwrap();
+        let worktree = root.join("fix");
+        assert_eq!(spawned["path"], worktree.to_string_lossy().as_ref());
+
+        let req = lifecycle_request("list_worktrees", serde_json::json!({ "path": repo }));
+        let listed = dispatch(&cx, req, &reg, None).result.unwrap();
+        let linked = &listed["worktrees"][1];
+        assert_eq!(linked["branch"], "fix/1");
+        assert_eq!(linked["in_use"], true);
+
+        let remove =
+            || lifecycle_request("remove_worktree", serde_json::json!({ "path": worktree }));
+        assert_eq!(
+            dispatch(&cx, remove(), &reg, None).error.as_deref(),
+            Some("Worktree is in use by a running agent")
+        );
+        assert!(dispatch(&cx, remove(), &reg, Some("agent")).error.is_some());
+
+        let pid = spawned["pid"].as_u64().unwrap() as u32;
+        children::stop(&reg, pid, Duration::from_secs(1)).unwrap();
+        assert!(children::wait_untracked(&reg, pid, Duration::from_secs(5)));
+        let removed = dispatch(&cx, remove(), &reg, None);
+        assert_eq!(removed.result.unwrap()["status"], "removed");
+        assert!(!worktree.exists());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_enforces_agent_limit() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let reg = crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            spawn_command: "sh".into(),
+            allowed_roots: vec![root.clone()],
+            max_spawned_agents: 1,
+            ..Default::default()
+        });
+        let spawn = || {
+            let req = lifecycle_request(
+                "spawn_agent",
+                serde_json::json!({ "path": root, "args": ["-c", "exec sleep 30"] }),
+            );
+            dispatch(&cx, req, &reg, None)
+        };
+        let first = spawn().result.unwrap();
+        let error = spawn().error.unwrap();
+        assert!(
+            error.starts_with(&format!("{}: ", crate::limits::AGENT_LIMIT)),
+            "{error}"
+        );
+        assert_eq!(reg.children.lock().unwrap().len(), 1);
+
+        let pid = first["pid"].as_u64().unwrap() as u32;
+        children::stop(&reg, pid, Duration::from_secs(1)).unwrap();
+        assert!(children::wait_untracked(&reg, pid, Duration::from_secs(5)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_agent_counts_new_folder_against_directory_limit() {
+        let cx = crate::ephemeral_cx();
+        let root = std::env::temp_dir().canonicalize().unwrap();
+        let folder = format!("hypi_dir_limit_{}", std::process::id());
+        let _ = std::fs::remove_dir_all(root.join(&folder));
+        let reg = crate::create_state(&ServerConfig {
+            node_ttl: 3600,
+            spawn_command: "sh".into(),
+            allowed_roots: vec![root.clone()],
+            max_agents_per_dir: 1,
+            ..Default::default()
+        });
+        let spawn = || {
+            let req = lifecycle_request(
+                "spawn_agent",
+                serde_json::json!({
+                    "path": root,
+                    "new_folder": folder,
+                    "args": ["-c", "exec sleep 30"]
+                }),
+            );
+            dispatch(&cx, req, &reg, None)
+        };
+        let first = spawn().result.unwrap();
+        let error = spawn().error.unwrap();
+        assert!(
+            error.starts_with(&format!("{}: ", crate::limits::DIRECTORY_LIMIT)),
+            "{error}"
+        );
+        assert_eq!(reg.children.lock().unwrap().len(), 1);
+
+        let pid = first["pid"].as_u64().unwrap() as u32;
+        children::stop(&reg, pid, Duration::from_secs(1)).unwrap();
+        assert!(children::wait_untracked(&reg, pid, Duration::from_secs(5)));
+        let _ = std::fs::remove_dir_all(root.join(&folder));
+    }
+
     #[test]
     fn ping_returns_health() {
         let cx = crate::ephemeral_cx();
@@ -688,6 +2703,7 @@ mod tests {
         assert_eq!(result["status"], "healthy");
         assert_eq!(result["nodes"], 0);
         assert!(result["version"].is_string());
+        assert!(result.get("sandbox").is_some());
     }
 
     #[test]
@@ -714,4 +2730,95 @@ mod tests {
         let resp = dispatch(&cx, req, &reg, None);
         assert_eq!(resp.result.unwrap()["nodes"], 2);
     }
+
+    // ── reload_config ──
+
+    #[test]
+    fn reload_config_applies_file_changes() {
+        let cx = crate::ephemeral_cx();
+        let path = std::env::temp_dir().join("hypi_test_rpc_reload.toml");
+        std::fs::write(&path, "node_ttl = 45\ntokens = [\"rotated\"]\n").unwrap();
+        let reg = make_registry_with_source(ConfigSource {
+            path: Some(path.clone()),
+            overrides: ConfigLayer::default(),
+        });
+
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "reload_config".into(),
+            params: None,
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        assert!(resp.error.is_none());
+        let result = resp.result.unwrap();
+        assert_eq!(result["status"], "reloaded");
+        assert_eq!(result["node_ttl"], 45);
+        assert_eq!(result["tokens"], 1);
+        assert_eq!(reg.live().node_ttl, 45);
+        assert_eq!(reg.live().tokens, vec!["rotated"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reload_config_invalid_file_keeps_current_settings() {
+        let cx = crate::ephemeral_cx();
+        let path = std::env::temp_dir().join("hypi_test_rpc_reload_bad.toml");
+        std::fs::write(&path, "node_ttl = [").unwrap();
+        let reg = make_registry_with_source(ConfigSource {
+            path: Some(path.clone()),
+            overrides: ConfigLayer::default(),
+        });
+
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "reload_config".into(),
+            params: None,
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        assert!(resp.error.unwrap().contains("Invalid config"));
+        assert_eq!(reg.live().node_ttl, 3600);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reload_config_without_file_returns_error() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "reload_config".into(),
+            params: None,
+        };
+        let resp = dispatch(&cx, req, &reg, None);
+        assert_eq!(resp.error.unwrap(), "No config file configured");
+    }
+
+    #[test]
+    fn reload_config_refused_for_agents() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "reload_config".into(),
+            params: None,
+        };
+        let resp = dispatch(&cx, req, &reg, Some("n1"));
+        assert!(resp.error.unwrap().contains("Unauthorized"));
+    }
+
+    #[test]
+    fn reload_config_refused_without_admin_rights() {
+        let cx = crate::ephemeral_cx();
+        let reg = make_registry();
+        let req = RpcRequest {
+            id: Some("1".into()),
+            method: "reload_config".into(),
+            params: None,
+        };
+        let resp = dispatch_on(&cx, req, &reg, None, Connection::default()).unwrap();
+        assert_eq!(
+            resp.error.unwrap(),
+            "Unauthorized: reload_config needs an admin token"
+        );
+    }
 }
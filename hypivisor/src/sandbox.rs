@@ -0,0 +1,372 @@
+//! Sandboxed spawns.
+//!
+//! A template with a `[templates.NAME.sandbox]` table runs its agents with
+//! `$HOME` read-only, except for the project directory and the `writable`
+//! paths (by default `~/.pi`, where pi keeps its sessions and settings).
+//! Network access can be turned off, which also cuts the agent off from its
+//! model provider and from the hypivisor.
+//!
+//! Sandboxed agents must not reach the parts of the hypivisor that could
+//! undo the sandbox, such as `spawn_agent` with another template. They run
+//! without `HYPI_TOKEN`, the hypivisor's Unix socket is covered with
+//! `/dev/null`, and `HYPIVISOR_SOCKET` points at the agents-only socket next
+//! to it, which serves registration and nothing else. The config file,
+//! which holds the tokens, is covered the same way and the data directory
+//! with an empty tmpfs. Agents get their own PID namespace, so they cannot
+//! read the hypivisor's environment or files through `/proc`. A TCP
+//! listener that accepts clients without a token is still fully open to an
+//! agent with network access; sandboxing does not change that.
+//!
+//! bubblewrap is used when installed. Otherwise the agent is started in
+//! unprivileged user, mount and PID namespaces via `unshare`, with a small
+//! shell script doing the mounts. Both only wrap the agent's command line,
+//! so sandboxing works with every spawn backend.
+
+use crate::listener::agents_socket_path;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+/// Error code prefix when a sandbox is required but cannot be provided.
+pub const UNAVAILABLE: &str = "sandbox_unavailable";
+
+/// Sandbox settings of a template.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SandboxSpec {
+    /// Allow network access.
+    pub network: bool,
+    /// Paths under `$HOME` that stay writable besides the project. `~/` is
+    /// expanded; missing paths are skipped.
+    pub writable: Vec<String>,
+}
+
+impl Default for SandboxSpec {
+    fn default() -> Self {
+        SandboxSpec {
+            network: true,
+            writable: vec!["~/.pi".into()],
+        }
+    }
+}
+
+/// The hypivisor's own files, kept out of sandboxes. Filled in by the
+/// hypivisor when it spawns an agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hidden {
+    /// Unix socket; agents get the agents-only socket next to it instead.
+    pub socket: Option<PathBuf>,
+    /// Config file.
+    pub config: Option<PathBuf>,
+    /// Data directory.
+    pub data_dir: Option<PathBuf>,
+}
+
+/// How agents get sandboxed on this host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxMethod {
+    Bubblewrap,
+    Namespaces,
+}
+
+/// The method available on this host, probed once.
+pub fn available_method() -> Option<SandboxMethod> {
+    static METHOD: OnceLock<Option<SandboxMethod>> = OnceLock::new();
+    *METHOD.get_or_init(|| {
+        if probe("bwrap", &["--dev-bind", "/", "/", "true"]) {
+            Some(SandboxMethod::Bubblewrap)
+        } else if probe(
+            "unshare",
+            &["--user", "--map-current-user", "--mount", "true"],
+        ) {
+            Some(SandboxMethod::Namespaces)
+        } else {
+            None
+        }
+    })
+}
+
+fn probe(program: &str, args: &[&str]) -> bool {
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Bind mounts `project` and the writable paths, hides the hypivisor's
+/// files (directories under a tmpfs, anything else under `/dev/null`), then
+/// makes `$HOME` read-only around them and runs the agent.
+const NAMESPACE_SCRIPT: &str = r#"set -e
+home=$1 project=$2
+shift 2
+while [ "$1" != -- ]; do mount --bind "$1" "$1"; shift; done
+shift
+while [ "$1" != -- ]; do
+  if [ -d "$1" ]; then mount -t tmpfs tmpfs "$1"; else mount --bind /dev/null "$1"; fi
+  shift
+done
+shift
+mount --rbind "$home" "$home"
+mount -o remount,bind,ro "$home"
+cd "$project"
+exec "$@""#;
+
+impl SandboxSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        for entry in &self.writable {
+            if !entry.starts_with("~/") && !Path::new(entry).is_absolute() {
+                return Err(format!(
+                    "sandbox writable paths must be absolute or start with ~/: {entry}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Paths to keep writable: the project, then existing `writable` entries.
+    fn writable_paths(&self, project: &Path, home: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![project.to_path_buf()];
+        for entry in &self.writable {
+            let path = match entry.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => PathBuf::from(entry),
+            };
+            if let Ok(path) = path.canonicalize() {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        paths
+    }
+
+    /// The command line that runs `program args` in the sandbox, with the
+    /// `hidden` files out of sight and the agents-only socket in place of
+    /// the hypivisor's. Files that do not exist are left out.
+    pub fn wrap(
+        &self,
+        method: SandboxMethod,
+        program: &str,
+        args: &[String],
+        project: &Path,
+        home: &Path,
+        hidden: &Hidden,
+    ) -> (String, Vec<String>) {
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        let writable = self.writable_paths(project, home);
+        let socket = hidden
+            .socket
+            .as_deref()
+            .filter(|s| s.symlink_metadata().is_ok());
+        let agents_socket = socket
+            .map(agents_socket_path)
+            .filter(|s| s.symlink_metadata().is_ok());
+        // The socket is left as given: canonicalizing follows a symlink to
+        // it, and the name the agent would use is the one to cover.
+        let files: Vec<PathBuf> = socket
+            .map(Path::to_path_buf)
+            .into_iter()
+            .chain(hidden.config.iter().filter_map(|p| p.canonicalize().ok()))
+            .collect();
+        let dirs: Vec<PathBuf> = hidden
+            .data_dir
+            .iter()
+            .filter_map(|p| p.canonicalize().ok())
+            .filter(|p| p.is_dir())
+            .collect();
+        let mut wrapped: Vec<String> = Vec::new();
+        let wrapper = match method {
+            SandboxMethod::Bubblewrap => {
+                wrapped.extend(["--dev-bind", "/", "/", "--ro-bind"].map(String::from));
+                wrapped.extend([path(home), path(home)]);
+                for dir in &writable {
+                    wrapped.extend(["--bind".into(), path(dir), path(dir)]);
+                }
+                for file in &files {
+                    wrapped.extend(["--ro-bind".into(), "/dev/null".into(), path(file)]);
+                }
+                for dir in &dirs {
+                    wrapped.extend(["--tmpfs".into(), path(dir)]);
+                }
+                wrapped.extend(["--unshare-pid", "--proc", "/proc"].map(String::from));
+                if !self.network {
+                    wrapped.push("--unshare-net".into());
+                }
+                wrapped.extend(["--die-with-parent".into(), "--chdir".into(), path(project)]);
+                "bwrap"
+            }
+            SandboxMethod::Namespaces => {
+                wrapped
+                    .extend(["--user", "--map-current-user", "--mount", "--pid"].map(String::from));
+                if !self.network {
+                    wrapped.push("--net".into());
+                }
+                wrapped.extend(
+                    [
+                        "--fork",
+                        "--mount-proc",
+                        "--kill-child=SIGTERM",
+                        "--",
+                        "sh",
+                        "-c",
+                        NAMESPACE_SCRIPT,
+                        "sh",
+                    ]
+                    .map(String::from),
+                );
+                wrapped.extend([path(home), path(project)]);
+                wrapped.extend(writable.iter().map(|dir| path(dir)));
+                wrapped.push("--".into());
+                wrapped.extend(files.iter().chain(&dirs).map(|p| path(p)));
+                "unshare"
+            }
+        };
+        // The token would open the full API over TCP.
+        wrapped.extend(["--", "env", "-u", "HYPI_TOKEN"].map(String::from));
+        match agents_socket {
+            Some(agents) => wrapped.push(format!("HYPIVISOR_SOCKET={}", path(&agents))),
+            None => wrapped.extend(["-u".into(), "HYPIVISOR_SOCKET".into()]),
+        }
+        wrapped.push(program.into());
+        wrapped.extend(args.iter().cloned());
+        (wrapper.into(), wrapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_sbx_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        for dir in ["proj", "other", ".pi", "state"] {
+            fs::create_dir_all(home.join(dir)).unwrap();
+        }
+        // Stand-ins for the hypivisor's sockets; only their existence counts.
+        fs::write(home.join("hypi.sock"), "main").unwrap();
+        fs::write(home.join("hypi.sock.agents"), "agents").unwrap();
+        fs::write(home.join("hypi.toml"), "tokens = [\"t\"]").unwrap();
+        fs::write(home.join("state/recent.json"), "[]").unwrap();
+        home
+    }
+
+    fn hidden(home: &Path) -> Hidden {
+        Hidden {
+            socket: Some(home.join("hypi.sock")),
+            config: Some(home.join("hypi.toml")),
+            data_dir: Some(home.join("state")),
+        }
+    }
+
+    #[test]
+    fn bubblewrap_command_line() {
+        let home = temp_home("bwrap");
+        let spec = SandboxSpec {
+            network: false,
+            ..Default::default()
+        };
+        let (program, args) = spec.wrap(
+            SandboxMethod::Bubblewrap,
+            "pi",
+            &["--model".into(), "x".into()],
+            &home.join("proj"),
+            &home,
+            &hidden(&home),
+        );
+        let h = home.to_string_lossy();
+        assert_eq!(program, "bwrap");
+        assert_eq!(
+            args,
+            [
+                "--dev-bind".to_string(),
+                "/".into(),
+                "/".into(),
+                "--ro-bind".into(),
+                h.to_string(),
+                h.to_string(),
+                "--bind".into(),
+                format!("{h}/proj"),
+                format!("{h}/proj"),
+                "--bind".into(),
+                format!("{h}/.pi"),
+                format!("{h}/.pi"),
+                "--ro-bind".into(),
+                "/dev/null".into(),
+                format!("{h}/hypi.sock"),
+                "--ro-bind".into(),
+                "/dev/null".into(),
+                format!("{h}/hypi.toml"),
+                "--tmpfs".into(),
+                format!("{h}/state"),
+                "--unshare-pid".into(),
+                "--proc".into(),
+                "/proc".into(),
+                "--unshare-net".into(),
+                "--die-with-parent".into(),
+                "--chdir".into(),
+                format!("{h}/proj"),
+                "--".into(),
+                "env".into(),
+                "-u".into(),
+                "HYPI_TOKEN".into(),
+                format!("HYPIVISOR_SOCKET={h}/hypi.sock.agents"),
+                "pi".into(),
+                "--model".into(),
+                "x".into(),
+            ]
+        );
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn namespaces_keep_only_project_writable() {
+        if available_method() != Some(SandboxMethod::Namespaces) {
+            return;
+        }
+        let home = temp_home("ns");
+        let spec = SandboxSpec {
+            writable: vec!["~/.pi".into(), "~/missing".into()],
+            ..Default::default()
+        };
+        let script = "touch ok ../.pi/ok && echo \"$(pwd)\" \"$(cat ../hypi.sock)\" \
+                      \"$(cat ../hypi.toml)\" \"$(ls ../state)\" \"$$\" \
+                      \"${HYPI_TOKEN-unset}\" \"$HYPIVISOR_SOCKET\"; touch ../other/bad";
+        let (program, args) = spec.wrap(
+            SandboxMethod::Namespaces,
+            "sh",
+            &["-c".into(), script.into()],
+            &home.join("proj"),
+            &home,
+            &hidden(&home),
+        );
+        let output = Command::new(program)
+            .args(args)
+            .env("HYPI_TOKEN", "secret")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let h = home.to_string_lossy();
+        assert_eq!(
+            stdout.trim(),
+            format!("{h}/proj    1 unset {h}/hypi.sock.agents")
+        );
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Read-only"));
+        assert!(home.join("proj/ok").exists());
+        assert!(home.join(".pi/ok").exists());
+        assert!(!home.join("other/bad").exists());
+        assert!(home.join("state/recent.json").exists());
+        let _ = fs::remove_dir_all(&home);
+    }
+}
@@ -0,0 +1,317 @@
+//! `search_directories`: find projects by name under the allowed roots.
+//!
+//! The roots are walked breadth-first to a bounded depth, skipping hidden
+//! directories, denied paths, symlinks and the configured `search_skip`
+//! names (`node_modules`, `target`, ...). Directory names are fuzzy-matched
+//! against the query, and git repositories rank above plain directories.
+//! The walk stops when its time budget runs out, returning what it found.
+
+use crate::fs_browser::Roots;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Directory names not descended into by default.
+pub const DEFAULT_SKIP: &[&str] = &[
+    "node_modules",
+    "target",
+    "dist",
+    "build",
+    "vendor",
+    "__pycache__",
+];
+
+pub const MAX_SEARCH_DEPTH: usize = 8;
+pub const MAX_SEARCH_LIMIT: usize = 500;
+pub const MAX_TIME_BUDGET_MS: u64 = 10_000;
+/// Matches kept in memory, however many the walk finds.
+const MAX_MATCHES: usize = 10_000;
+
+/// `search_directories` params.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    pub query: String,
+    /// Search below this directory instead of every allowed root.
+    pub path: Option<String>,
+    pub max_depth: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub time_budget_ms: u64,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            query: String::new(),
+            path: None,
+            max_depth: 4,
+            offset: 0,
+            limit: 50,
+            time_budget_ms: 2_000,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn from_params(params: &Value) -> Result<SearchOptions, String> {
+        let options: SearchOptions = serde_json::from_value(params.clone())
+            .map_err(|e| format!("Invalid search options: {e}"))?;
+        if options.query.trim().is_empty() {
+            return Err("Missing query".into());
+        }
+        if options.max_depth == 0 || options.max_depth > MAX_SEARCH_DEPTH {
+            return Err(format!(
+                "max_depth must be between 1 and {MAX_SEARCH_DEPTH}"
+            ));
+        }
+        if options.limit == 0 || options.limit > MAX_SEARCH_LIMIT {
+            return Err(format!("limit must be between 1 and {MAX_SEARCH_LIMIT}"));
+        }
+        if options.time_budget_ms > MAX_TIME_BUDGET_MS {
+            return Err(format!(
+                "time_budget_ms is too large (max {MAX_TIME_BUDGET_MS})"
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// One matching directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SearchHit {
+    pub path: String,
+    pub name: String,
+    /// The allowed root it was found under.
+    pub root: String,
+    pub git: bool,
+    pub score: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub results: Vec<SearchHit>,
+    /// Matches found, across all pages.
+    pub total: usize,
+    pub offset: usize,
+    /// The time budget ran out before the walk finished.
+    pub timed_out: bool,
+    /// Directories read.
+    pub scanned: usize,
+}
+
+/// How well `name` matches `query` (already lowercase), or `None`.
+/// Exact beats prefix beats substring beats scattered letters.
+fn match_score(query: &str, name: &str) -> Option<i64> {
+    let name = name.to_lowercase();
+    if name == query {
+        return Some(1000);
+    }
+    if name.starts_with(query) {
+        return Some(800);
+    }
+    if let Some(at) = name.find(query) {
+        return Some(600 - at.min(100) as i64);
+    }
+    // Every query character in order; fewer skipped characters is better.
+    let mut chars = name.chars();
+    let mut skipped = 0;
+    for q in query.chars() {
+        loop {
+            let c = chars.next()?;
+            if c == q {
+                break;
+            }
+            skipped += 1;
+        }
+    }
+    Some(300 - skipped.min(200))
+}
+
+/// Walk `roots` (or just `options.path`) looking for directories matching
+/// `options.query`.
+pub fn search(
+    roots: &Roots,
+    skip: &[String],
+    options: &SearchOptions,
+) -> Result<SearchResult, String> {
+    let deadline = Instant::now() + Duration::from_millis(options.time_budget_ms);
+    let query = options.query.trim().to_lowercase();
+
+    let starts: Vec<(PathBuf, PathBuf)> = match &options.path {
+        Some(path) => {
+            let canonical = fs::canonicalize(path).map_err(|e| format!("Invalid path: {}", e))?;
+            let root = roots.check(&canonical)?.to_path_buf();
+            vec![(canonical, root)]
+        }
+        // Roots nested in another root would be walked twice.
+        None => roots
+            .allowed()
+            .iter()
+            .filter(|r| !roots.allowed().iter().any(|o| o != *r && r.starts_with(o)))
+            .filter(|r| roots.contains(r))
+            .map(|r| (r.clone(), r.clone()))
+            .collect(),
+    };
+
+    let mut queue: VecDeque<(PathBuf, usize, usize)> = VecDeque::new();
+    for (i, (start, _)) in starts.iter().enumerate() {
+        queue.push_back((start.clone(), 0, i));
+    }
+    let mut hits = Vec::new();
+    let mut scanned = 0;
+    let mut timed_out = false;
+
+    while let Some((dir, depth, start)) = queue.pop_front() {
+        if Instant::now() >= deadline {
+            timed_out = true;
+            break;
+        }
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        scanned += 1;
+        let mut children: Vec<(String, PathBuf)> = entries
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let skipped = name.starts_with('.') || skip.contains(&name);
+                (!skipped).then(|| (name, e.path()))
+            })
+            .filter(|(_, path)| roots.contains(path))
+            .collect();
+        children.sort();
+
+        for (name, path) in children {
+            let git = path.join(".git").exists();
+            if let Some(score) = match_score(&query, &name) {
+                if hits.len() < MAX_MATCHES {
+                    let bonus = if git { 100 } else { 0 };
+                    hits.push(SearchHit {
+                        path: path.to_string_lossy().into_owned(),
+                        name,
+                        root: starts[start].1.to_string_lossy().into_owned(),
+                        git,
+                        score: score + bonus - 10 * depth as i64,
+                    });
+                }
+            }
+            if depth + 1 < options.max_depth {
+                queue.push_back((path, depth + 1, start));
+            }
+        }
+    }
+
+    hits.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.path.cmp(&b.path)));
+    let total = hits.len();
+    let results = hits
+        .into_iter()
+        .skip(options.offset)
+        .take(options.limit)
+        .collect();
+    Ok(SearchResult {
+        results,
+        total,
+        offset: options.offset,
+        timed_out,
+        scanned,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_skip() -> Vec<String> {
+        DEFAULT_SKIP.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn options(query: &str) -> SearchOptions {
+        SearchOptions {
+            query: query.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scores_exact_prefix_substring_then_fuzzy() {
+        let scores: Vec<_> = ["api", "api-server", "my-api", "a-p-i", "web"]
+            .iter()
+            .map(|name| match_score("api", name))
+            .collect();
+        assert!(scores[0] > scores[1]);
+        assert!(scores[1] > scores[2]);
+        assert!(scores[2] > scores[3]);
+        assert_eq!(scores[4], None);
+        assert_eq!(match_score("api", "API"), Some(1000));
+    }
+
+    #[test]
+    fn walks_roots_skipping_and_preferring_repos() {
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_search_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in [
+            "work/api-docs",
+            "work/api/.git",
+            "work/web/node_modules/api",
+            "work/.hidden/api",
+            "secret/api",
+            "a/b/c/d/api",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let roots = Roots::new(vec![root.clone()], vec![root.join("secret")]);
+
+        let found = search(&roots, &default_skip(), &options("api")).unwrap();
+        let names: Vec<_> = found
+            .results
+            .iter()
+            .map(|h| h.path.strip_prefix(&*root.to_string_lossy()).unwrap())
+            .collect();
+        assert_eq!(names, ["/work/api", "/work/api-docs"]);
+        assert!(found.results[0].git);
+        assert_eq!(found.results[0].root, root.to_string_lossy());
+        assert!(!found.timed_out);
+
+        let deep = SearchOptions {
+            max_depth: 5,
+            limit: 1,
+            offset: 1,
+            ..options("api")
+        };
+        let found = search(&roots, &default_skip(), &deep).unwrap();
+        assert_eq!(found.total, 3);
+        assert!(found.results[0].path.ends_with("a/b/c/d/api"));
+
+        let below = SearchOptions {
+            path: Some(root.join("work").to_string_lossy().into_owned()),
+            ..options("web")
+        };
+        let found = search(&roots, &default_skip(), &below).unwrap();
+        assert_eq!(found.total, 1);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn validates_options() {
+        let parse = |v: Value| SearchOptions::from_params(&v);
+        assert_eq!(parse(serde_json::json!({})).unwrap_err(), "Missing query");
+        assert!(parse(serde_json::json!({ "query": "x", "max_depth": 9 })).is_err());
+        assert!(parse(serde_json::json!({ "query": "x", "limit": 0 })).is_err());
+        assert!(parse(serde_json::json!({ "query": "x", "time_budget_ms": 60000 })).is_err());
+        assert_eq!(
+            parse(serde_json::json!({ "query": "x" })).unwrap().limit,
+            50
+        );
+    }
+}
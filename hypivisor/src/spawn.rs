@@ -1,13 +1,230 @@
-use std::{fs, path::PathBuf, process::Command};
+use crate::backend::{BackendKind, LaunchSpec, Launched};
+use crate::fs_browser::Roots;
+use crate::limits::ResourceLimits;
+use crate::sandbox::{self, Hidden, SandboxSpec};
+use crate::worktree::{self, WorktreeSpec};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::info;
 
+/// Most extra arguments a single spawn may pass.
+pub const MAX_SPAWN_ARGS: usize = 32;
+/// Largest initial prompt accepted, in bytes.
+pub const MAX_PROMPT_BYTES: usize = 64 * 1024;
+/// Longest a caller may block waiting for the agent to register.
+pub const MAX_WAIT_MS: u64 = 120_000;
+/// Environment variable carrying the spawn ID to the agent, which echoes it
+/// back in its `register` params.
+pub const SPAWN_ID_ENV: &str = "HYPI_SPAWN_ID";
+/// Environment variable carrying the spawn secret, which the agent echoes
+/// back with its spawn ID. Spawn IDs are public; only the agent knows the
+/// secret, so it proves the registration comes from the spawned process.
+pub const SPAWN_SECRET_ENV: &str = "HYPI_SPAWN_SECRET";
+
+/// Optional `spawn_agent` settings beyond the target directory.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpawnOptions {
+    /// `--model` for pi.
+    pub model: Option<String>,
+    /// `--provider` for pi.
+    pub provider: Option<String>,
+    /// `--continue`: resume the most recent session in the directory.
+    #[serde(rename = "continue")]
+    pub continue_session: bool,
+    /// `--session`: resume a specific session file.
+    pub session: Option<String>,
+    /// Extra arguments appended after the ones above.
+    pub args: Vec<String>,
+    /// Environment overrides; names must match `spawn_env_allowlist`.
+    pub env: BTreeMap<String, String>,
+    /// Sent to the agent as its first message once it registers.
+    pub prompt: Option<String>,
+    /// Block until the agent registers, up to this many milliseconds.
+    pub wait_ms: Option<u64>,
+    /// How to run the agent. Defaults to the configured `spawn_backend`.
+    pub backend: Option<BackendKind>,
+    /// Run the agent in a new git worktree named `new_folder`.
+    pub worktree: Option<WorktreeSpec>,
+    /// Run the agent sandboxed. Only templates can set this.
+    #[serde(skip)]
+    pub sandbox: Option<SandboxSpec>,
+    /// The hypivisor's own files, hidden from sandboxed agents. Filled in by
+    /// the hypivisor, never by callers.
+    #[serde(skip)]
+    pub hidden: Hidden,
+}
+
+impl SpawnOptions {
+    /// Read the options from `spawn_agent` params. `path` and `new_folder`
+    /// are handled separately and ignored here.
+    pub fn from_params(params: &Value) -> Result<SpawnOptions, String> {
+        serde_json::from_value(params.clone()).map_err(|e| format!("Invalid spawn options: {e}"))
+    }
+
+    /// Check every option, returning the first problem found.
+    pub fn validate(&self, env_allowlist: &[String]) -> Result<(), String> {
+        if let Some(model) = &self.model {
+            validate_flag_value("model", model)?;
+        }
+        if let Some(provider) = &self.provider {
+            validate_flag_value("provider", provider)?;
+        }
+        if let Some(session) = &self.session {
+            validate_flag_value("session", session)?;
+            if self.continue_session {
+                return Err("Cannot combine continue with session".into());
+            }
+        }
+        if self.args.len() > MAX_SPAWN_ARGS {
+            return Err(format!("Too many args (max {MAX_SPAWN_ARGS})"));
+        }
+        if self.args.iter().any(|a| a.contains('\0')) {
+            return Err("args must not contain NUL bytes".into());
+        }
+        for (name, value) in &self.env {
+            if !is_env_name(name) {
+                return Err(format!("Invalid environment variable name: {name}"));
+            }
+            if !env_allowed(name, env_allowlist) {
+                return Err(format!("Environment variable not allowed: {name}"));
+            }
+            if value.contains('\0') {
+                return Err(format!("Environment variable {name} contains a NUL byte"));
+            }
+        }
+        if let Some(prompt) = &self.prompt {
+            if prompt.trim().is_empty() {
+                return Err("prompt must not be empty".into());
+            }
+            if prompt.len() > MAX_PROMPT_BYTES {
+                return Err(format!("prompt is too long (max {MAX_PROMPT_BYTES} bytes)"));
+            }
+        }
+        if self.wait_ms.is_some_and(|ms| ms > MAX_WAIT_MS) {
+            return Err(format!("wait_ms is too large (max {MAX_WAIT_MS})"));
+        }
+        if let Some(worktree) = &self.worktree {
+            validate_flag_value("branch", &worktree.branch)?;
+            if let Some(base) = &worktree.base {
+                validate_flag_value("base", base)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Command-line arguments for pi, in a stable order.
+    pub fn cli_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(provider) = &self.provider {
+            args.extend(["--provider".to_string(), provider.clone()]);
+        }
+        if let Some(model) = &self.model {
+            args.extend(["--model".to_string(), model.clone()]);
+        }
+        if self.continue_session {
+            args.push("--continue".into());
+        }
+        if let Some(session) = &self.session {
+            args.extend(["--session".to_string(), session.clone()]);
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// Values given to a flag must be a single, non-flag token.
+fn validate_flag_value(name: &str, value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("{name} must not be empty"));
+    }
+    if value.starts_with('-') {
+        return Err(format!("{name} must not start with '-'"));
+    }
+    if value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!(
+            "{name} must not contain whitespace or control characters"
+        ));
+    }
+    Ok(())
+}
+
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Allowlist entries are exact names, or prefixes ending in `*`.
+pub fn env_allowed(name: &str, allowlist: &[String]) -> bool {
+    allowlist
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
+
+/// A fresh ID correlating a spawn with the node that later registers.
+/// Unique within this hypivisor run and unlikely to repeat across restarts.
+pub fn new_spawn_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("sp-{nanos:x}-{seq:x}")
+}
+
+/// A random secret for one spawn: 128 bits, hex encoded.
+pub fn new_spawn_secret() -> String {
+    let mut bytes = [0u8; 16];
+    let from_os = {
+        use std::io::Read;
+        fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes))
+    };
+    if from_os.is_err() {
+        // Each RandomState is keyed from OS randomness.
+        use std::hash::{BuildHasher, Hasher};
+        for chunk in bytes.chunks_mut(8) {
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u64(new_spawn_id().len() as u64);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A successfully started agent process. The caller owns `launched` and is
+/// responsible for reaping it.
+#[derive(Debug)]
+pub struct Spawned {
+    pub path: String,
+    /// Command line, for display.
+    pub command: String,
+    pub backend: BackendKind,
+    pub launched: Launched,
+    /// Handed to the agent as [`SPAWN_SECRET_ENV`].
+    pub secret: String,
+}
+
 /// Validate and prepare the target directory for spawning.
 /// Returns the canonicalized path on success.
 /// Separated from the actual spawn to enable unit testing of path validation.
 pub fn validate_spawn_path(
     path_str: &str,
     new_folder: &str,
-    home_dir: &PathBuf,
+    roots: &Roots,
 ) -> Result<PathBuf, String> {
     let mut target = PathBuf::from(path_str);
 
@@ -20,31 +237,116 @@ pub fn validate_spawn_path(
         if new_folder.is_empty() {
             return Err("Path does not exist".into());
         }
-        fs::create_dir_all(&target).map_err(|e| format!("Failed to create directory: {}", e))?;
+        // Check before creating anything.
+        if Path::new(new_folder)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err("new_folder must be a relative path without '..'".into());
+        }
+        let mut dir = fs::canonicalize(path_str).map_err(|e| format!("Invalid path: {}", e))?;
+        roots.check(&dir.join(new_folder))?;
+        // One component at a time, resolving each before going further, so
+        // nothing is created beneath a symlink that leads out of the roots.
+        for component in Path::new(new_folder).components() {
+            let next = dir.join(component);
+            roots.check(&next)?;
+            match fs::create_dir(&next) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(format!("Failed to create directory: {}", e)),
+            }
+            dir = fs::canonicalize(&next).map_err(|e| format!("Invalid path: {}", e))?;
+            roots.check(&dir)?;
+        }
     }
 
     let canonical = fs::canonicalize(&target).map_err(|e| format!("Invalid path: {}", e))?;
-
-    if !canonical.starts_with(home_dir) {
-        return Err("Path resolves outside home directory".into());
-    }
+    roots.check(&canonical)?;
 
     Ok(canonical)
 }
 
-/// Spawn a new `pi` process in the given directory.
+/// Resolve the directory an agent will run in.
 /// Creates `new_folder` as a subdirectory if provided and non-empty.
-/// Enforces that the final path is within `home_dir`.
-pub fn spawn_agent(path_str: &str, new_folder: &str, home_dir: &PathBuf) -> Result<String, String> {
-    let canonical = validate_spawn_path(path_str, new_folder, home_dir)?;
+/// Enforces that the final path is within one of `roots`. With
+/// `options.worktree`, `new_folder` is instead a new worktree next to the
+/// repository at `path_str`.
+pub fn resolve_target(
+    path_str: &str,
+    new_folder: &str,
+    roots: &Roots,
+    options: &SpawnOptions,
+) -> Result<PathBuf, String> {
+    match &options.worktree {
+        Some(spec) => worktree::create(path_str, new_folder, spec, roots),
+        None => validate_spawn_path(path_str, new_folder, roots),
+    }
+}
+
+/// Spawn `command` (normally `pi`) in `canonical`, as returned by
+/// [`resolve_target`].
+/// `options` must already have been validated. `spawn_id` is exported as
+/// [`SPAWN_ID_ENV`], with a new secret as [`SPAWN_SECRET_ENV`]; both take
+/// precedence over caller-supplied values.
+/// The backend is `options.backend`, or `direct` if unset, and the agent
+/// runs under `rlimits` where the backend allows.
+pub fn spawn_agent(
+    command: &str,
+    canonical: &Path,
+    options: &SpawnOptions,
+    spawn_id: &str,
+    rlimits: &ResourceLimits,
+) -> Result<Spawned, String> {
+    let mut program = command.to_string();
+    let mut args = options.cli_args();
+    if let Some(spec) = &options.sandbox {
+        let method = sandbox::available_method().ok_or_else(|| {
+            format!(
+                "{}: Sandboxing is not available on this host",
+                sandbox::UNAVAILABLE
+            )
+        })?;
+        let home = dirs::home_dir().ok_or("Cannot determine home directory")?;
+        (program, args) = spec.wrap(method, command, &args, canonical, &home, &options.hidden);
+    }
+    let secret = new_spawn_secret();
+    let env: Vec<(String, String)> = options
+        .env
+        .iter()
+        .filter(|(name, _)| ![SPAWN_ID_ENV, SPAWN_SECRET_ENV].contains(&name.as_str()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .chain([
+            (SPAWN_ID_ENV.to_string(), spawn_id.to_string()),
+            (SPAWN_SECRET_ENV.to_string(), secret.clone()),
+        ])
+        .collect();
+    let name = canonical
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let backend = options.backend.unwrap_or_default();
 
-    Command::new("pi")
-        .current_dir(&canonical)
-        .spawn()
-        .map_err(|e| format!("Failed to spawn: {}", e))?;
+    let launched = backend.backend().launch(&LaunchSpec {
+        program: &program,
+        args: &args,
+        env: &env,
+        cwd: canonical,
+        name: &name,
+        rlimits,
+    })?;
 
-    info!(path = %canonical.display(), "Agent spawned");
-    Ok(canonical.to_string_lossy().to_string())
+    info!(path = %canonical.display(), pid = launched.pid(), %backend, "Agent spawned");
+    Ok(Spawned {
+        path: canonical.to_string_lossy().to_string(),
+        command: std::iter::once(program)
+            .chain(args)
+            .collect::<Vec<_>>()
+            .join(" "),
+        backend,
+        launched,
+        secret,
+    })
 }
 
 #[cfg(test)]
@@ -54,7 +356,7 @@ mod tests {
 
     /// Create a uniquely-named test directory under $HOME.
     fn unique_test_dir(suffix: &str) -> (PathBuf, PathBuf) {
-        let home = dirs::home_dir().unwrap_or_else(|| std::env::temp_dir());
+        let home = dirs::home_dir().unwrap_or_else(std::env::temp_dir);
         let dir = home.join(format!(".hypi_test_sp_{}", suffix));
         let _ = fs::remove_dir_all(&dir);
         fs::create_dir_all(&dir).unwrap();
@@ -64,9 +366,9 @@ mod tests {
     #[test]
     fn rejects_path_outside_home() {
         let (_, home) = unique_test_dir("outside");
-        let result = validate_spawn_path("/usr", "", &home);
+        let result = validate_spawn_path("/usr", "", &Roots::new(vec![home.clone()], vec![]));
         assert!(result.is_err());
-        assert!(result.unwrap_err().contains("outside home"));
+        assert!(result.unwrap_err().contains("outside allowed roots"));
         let _ = fs::remove_dir_all(&home);
     }
 
@@ -74,7 +376,11 @@ mod tests {
     fn rejects_nonexistent_path_without_new_folder() {
         let (_, home) = unique_test_dir("nonexist");
         let nonexistent = home.join("does_not_exist");
-        let result = validate_spawn_path(nonexistent.to_str().unwrap(), "", &home);
+        let result = validate_spawn_path(
+            nonexistent.to_str().unwrap(),
+            "",
+            &Roots::new(vec![home.clone()], vec![]),
+        );
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("Path does not exist"));
         let _ = fs::remove_dir_all(&home);
@@ -83,7 +389,11 @@ mod tests {
     #[test]
     fn creates_new_folder_when_specified() {
         let (home, dir) = unique_test_dir("newfolder");
-        let result = validate_spawn_path(dir.to_str().unwrap(), "new_project", &home);
+        let result = validate_spawn_path(
+            dir.to_str().unwrap(),
+            "new_project",
+            &Roots::new(vec![home], vec![]),
+        );
         assert!(result.is_ok());
         let canonical = result.unwrap();
         assert!(canonical.ends_with("new_project"));
@@ -94,7 +404,11 @@ mod tests {
     #[test]
     fn trims_whitespace_from_new_folder() {
         let (home, dir) = unique_test_dir("trim");
-        let result = validate_spawn_path(dir.to_str().unwrap(), "  trimmed  ", &home);
+        let result = validate_spawn_path(
+            dir.to_str().unwrap(),
+            "  trimmed  ",
+            &Roots::new(vec![home], vec![]),
+        );
         assert!(result.is_ok());
         let canonical = result.unwrap();
         assert!(canonical.ends_with("trimmed"));
@@ -107,7 +421,11 @@ mod tests {
         let (home, dir) = unique_test_dir("empty");
         let subdir = dir.join("existing");
         fs::create_dir_all(&subdir).unwrap();
-        let result = validate_spawn_path(subdir.to_str().unwrap(), "", &home);
+        let result = validate_spawn_path(
+            subdir.to_str().unwrap(),
+            "",
+            &Roots::new(vec![home], vec![]),
+        );
         assert!(result.is_ok());
         let canonical = result.unwrap();
         assert!(canonical.ends_with("existing"));
@@ -120,7 +438,7 @@ mod tests {
         let subdir = dir.join("sub");
         fs::create_dir_all(&subdir).unwrap();
         let non_canonical = format!("{}/./sub", dir.to_str().unwrap());
-        let result = validate_spawn_path(&non_canonical, "", &home);
+        let result = validate_spawn_path(&non_canonical, "", &Roots::new(vec![home], vec![]));
         assert!(result.is_ok());
         let canonical = result.unwrap();
         assert!(!canonical.to_str().unwrap().contains("/./"));
@@ -134,7 +452,7 @@ mod tests {
         let subdir = dir.join("sub");
         fs::create_dir_all(&subdir).unwrap();
         let escape = format!("{}/../../etc", subdir.to_str().unwrap());
-        let result = validate_spawn_path(&escape, "", &dir);
+        let result = validate_spawn_path(&escape, "", &Roots::new(vec![dir.clone()], vec![]));
         assert!(result.is_err());
         let _ = fs::remove_dir_all(&dir);
     }
@@ -142,17 +460,189 @@ mod tests {
     #[test]
     fn new_folder_creates_nested_dirs() {
         let (home, dir) = unique_test_dir("nested");
-        let result = validate_spawn_path(dir.to_str().unwrap(), "a/b/c", &home);
+        let result = validate_spawn_path(
+            dir.to_str().unwrap(),
+            "a/b/c",
+            &Roots::new(vec![home], vec![]),
+        );
         assert!(result.is_ok());
         assert!(dir.join("a/b/c").exists());
         let _ = fs::remove_dir_all(&dir);
     }
 
+    #[test]
+    fn new_folder_outside_roots_is_not_created() {
+        let (_, dir) = unique_test_dir("escape");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("denied")).unwrap();
+        let roots = Roots::new(vec![root.clone()], vec![root.join("denied")]);
+        let root_str = root.to_str().unwrap();
+
+        assert!(validate_spawn_path(root_str, "../outside", &roots).is_err());
+        assert!(!dir.join("outside").exists());
+        assert_eq!(
+            validate_spawn_path(root_str, "denied/new", &roots).unwrap_err(),
+            "Path is inside a denied directory"
+        );
+        assert!(!root.join("denied/new").exists());
+        assert_eq!(
+            validate_spawn_path(dir.to_str().unwrap(), "new", &roots).unwrap_err(),
+            "Path resolves outside allowed roots"
+        );
+        assert!(!dir.join("new").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_folder_through_symlink_out_of_roots_is_not_created() {
+        let (_, dir) = unique_test_dir("symlink");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("inside")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("inside/link")).unwrap();
+        let roots = Roots::new(vec![root.clone()], vec![]);
+
+        let err = validate_spawn_path(root.to_str().unwrap(), "inside/link/evil/deeper", &roots)
+            .unwrap_err();
+        assert_eq!(err, "Path resolves outside allowed roots");
+        assert!(!dir.join("outside/evil").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn whitespace_only_new_folder_treated_as_empty() {
         let (home, dir) = unique_test_dir("wsonly");
-        let result = validate_spawn_path(dir.to_str().unwrap(), "   ", &home);
+        let result = validate_spawn_path(
+            dir.to_str().unwrap(),
+            "   ",
+            &Roots::new(vec![home], vec![]),
+        );
         assert!(result.is_ok());
         let _ = fs::remove_dir_all(&dir);
     }
+
+    fn options(json: serde_json::Value) -> Result<SpawnOptions, String> {
+        SpawnOptions::from_params(&json)
+    }
+
+    #[test]
+    fn options_build_pi_args_in_order() {
+        let opts = options(serde_json::json!({
+            "path": "/ignored",
+            "model": "claude-sonnet-4",
+            "provider": "anthropic",
+            "continue": true,
+            "args": ["--thinking", "high"]
+        }))
+        .unwrap();
+        assert!(opts.validate(&[]).is_ok());
+        assert_eq!(
+            opts.cli_args(),
+            [
+                "--provider",
+                "anthropic",
+                "--model",
+                "claude-sonnet-4",
+                "--continue",
+                "--thinking",
+                "high"
+            ]
+        );
+    }
+
+    #[test]
+    fn options_reject_wrong_types() {
+        let err = options(serde_json::json!({ "model": 5 })).unwrap_err();
+        assert!(err.starts_with("Invalid spawn options"));
+    }
+
+    #[test]
+    fn options_reject_flag_like_values() {
+        let opts = options(serde_json::json!({ "model": "--help" })).unwrap();
+        assert!(opts
+            .validate(&[])
+            .unwrap_err()
+            .contains("model must not start with '-'"));
+        let opts = options(serde_json::json!({ "provider": "a b" })).unwrap();
+        assert!(opts.validate(&[]).unwrap_err().contains("provider"));
+    }
+
+    #[test]
+    fn options_reject_continue_with_session() {
+        let opts = options(serde_json::json!({ "continue": true, "session": "s.jsonl" })).unwrap();
+        assert!(opts.validate(&[]).unwrap_err().contains("Cannot combine"));
+    }
+
+    #[test]
+    fn options_check_env_against_allowlist() {
+        let allow = vec!["PI_*".to_string(), "OPENAI_BASE_URL".to_string()];
+        let opts = options(serde_json::json!({
+            "env": { "PI_SOCKET_PORT": "9000", "OPENAI_BASE_URL": "http://x" }
+        }))
+        .unwrap();
+        assert!(opts.validate(&allow).is_ok());
+
+        let opts = options(serde_json::json!({ "env": { "LD_PRELOAD": "/x.so" } })).unwrap();
+        assert_eq!(
+            opts.validate(&allow).unwrap_err(),
+            "Environment variable not allowed: LD_PRELOAD"
+        );
+
+        let opts = options(serde_json::json!({ "env": { "PI-X": "1" } })).unwrap();
+        assert!(opts
+            .validate(&allow)
+            .unwrap_err()
+            .contains("Invalid environment variable"));
+    }
+
+    #[test]
+    fn options_check_prompt_and_args_limits() {
+        let opts = options(serde_json::json!({ "prompt": "   " })).unwrap();
+        assert!(opts
+            .validate(&[])
+            .unwrap_err()
+            .contains("prompt must not be empty"));
+
+        let long = "x".repeat(MAX_PROMPT_BYTES + 1);
+        let opts = options(serde_json::json!({ "prompt": long })).unwrap();
+        assert!(opts.validate(&[]).unwrap_err().contains("too long"));
+
+        let args: Vec<String> = (0..=MAX_SPAWN_ARGS).map(|i| i.to_string()).collect();
+        let opts = options(serde_json::json!({ "args": args })).unwrap();
+        assert!(opts.validate(&[]).unwrap_err().contains("Too many args"));
+
+        let opts = options(serde_json::json!({ "wait_ms": MAX_WAIT_MS + 1 })).unwrap();
+        assert!(opts.validate(&[]).unwrap_err().contains("wait_ms"));
+    }
+
+    #[test]
+    fn spawn_ids_are_unique() {
+        let a = new_spawn_id();
+        let b = new_spawn_id();
+        assert!(a.starts_with("sp-"));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn spawn_passes_args_and_env() {
+        let (home, dir) = unique_test_dir("argsenv");
+        let opts = options(serde_json::json!({
+            "args": ["-c", "echo \"$0 $PI_TEST_VALUE $HYPI_SPAWN_ID\" > out.txt"],
+            "env": { "PI_TEST_VALUE": "from-env" }
+        }))
+        .unwrap();
+        let roots = Roots::new(vec![home], vec![]);
+        let target = resolve_target(dir.to_str().unwrap(), "", &roots, &opts).unwrap();
+        let mut spawned =
+            spawn_agent("sh", &target, &opts, "sp-1", &ResourceLimits::default()).unwrap();
+        assert!(spawned.command.starts_with("sh -c echo"));
+        let Launched::Child { child, .. } = &mut spawned.launched else {
+            panic!("direct spawns are our children");
+        };
+        assert!(child.wait().unwrap().success());
+        let out = fs::read_to_string(dir.join("out.txt")).unwrap();
+        assert_eq!(out.trim(), "sh from-env sp-1");
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
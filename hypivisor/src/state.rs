@@ -1,9 +1,21 @@
+use crate::agent_card::AgentCard;
+use crate::backend::BackendKind;
+use crate::children::{SpawnOutcome, SpawnedProcess};
+use crate::config::{LiveConfig, Loaded, ServerConfig};
+use crate::fs_browser::Roots;
+use crate::limits::{ResourceLimits, SpawnLimits, SpawnWindow};
+#[cfg(unix)]
+use crate::pty::PtySession;
+use crate::recent::DirectoryHistory;
+use crate::template::SpawnTemplate;
+use crate::watch::Watches;
 use asupersync::channel::broadcast;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{mpsc, Arc, Mutex, RwLock},
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -13,6 +25,16 @@ pub enum NodeStatus {
     Offline,
 }
 
+/// What an agent is doing, as reported with `report_status`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentStatus {
+    Idle,
+    Working,
+    WaitingForInput,
+    Error,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NodeInfo {
     pub id: String,
@@ -30,14 +52,175 @@ pub struct NodeInfo {
     /// Set during registration, used for debugging and process management.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pid: Option<u32>,
+    /// ID handed to the agent by `spawn_agent`, echoed back on register.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spawn_id: Option<String>,
+    /// Last status the agent reported, and when it changed to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_status: Option<AgentStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_status_since: Option<i64>,
+    /// Served by `get_agent_card`; not sent with the node itself.
+    #[serde(skip)]
+    pub card: Option<AgentCard>,
+    #[serde(flatten)]
+    pub meta: NodeMeta,
+}
+
+/// Fields of `NodeInfo` that `update_node` cannot change.
+pub const FIXED_NODE_FIELDS: &[&str] = &[
+    "id",
+    "machine",
+    "cwd",
+    "port",
+    "status",
+    "offline_since",
+    "last_seen",
+    "pid",
+    "spawn_id",
+    "agent_status",
+    "agent_status_since",
+    "card",
+];
+
+/// Optional details about a node, given on register and changed with
+/// `update_node`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NodeMeta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pi_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_branch: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Fields the hypivisor does not know, kept and passed on as given.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl NodeMeta {
+    /// Apply an `update_node` patch: fields given replace the current ones
+    /// and `null` clears them. `labels` are merged key by key, with `null`
+    /// removing a label.
+    pub fn apply(&mut self, patch: &Map<String, Value>) -> Result<(), String> {
+        let mut fields = match serde_json::to_value(&*self) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        for (key, value) in patch {
+            if key == "labels" {
+                let labels = fields
+                    .entry("labels")
+                    .or_insert_with(|| Value::Object(Map::new()));
+                match (value, labels.as_object_mut()) {
+                    (Value::Null, _) => *labels = Value::Object(Map::new()),
+                    (Value::Object(changes), Some(labels)) => {
+                        for (name, label) in changes {
+                            if label.is_null() {
+                                labels.remove(name);
+                            } else {
+                                labels.insert(name.clone(), label.clone());
+                            }
+                        }
+                    }
+                    _ => return Err("labels must be an object".into()),
+                }
+            } else if value.is_null() {
+                fields.remove(key);
+            } else {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+        *self = serde_json::from_value(Value::Object(fields))
+            .map_err(|e| format!("Invalid node update: {e}"))?;
+        Ok(())
+    }
+
+    /// Keep what an earlier registration of the same node set and this one
+    /// leaves out, such as labels added by a dashboard.
+    pub fn inherit(&mut self, previous: &NodeMeta) {
+        let fields = [
+            (&mut self.session_name, &previous.session_name),
+            (&mut self.model, &previous.model),
+            (&mut self.provider, &previous.provider),
+            (&mut self.pi_version, &previous.pi_version),
+            (&mut self.git_branch, &previous.git_branch),
+        ];
+        for (field, previous) in fields {
+            if field.is_none() {
+                field.clone_from(previous);
+            }
+        }
+        for (name, label) in &previous.labels {
+            self.labels
+                .entry(name.clone())
+                .or_insert_with(|| label.clone());
+        }
+        for (key, value) in &previous.extra {
+            self.extra
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
 }
 
 pub struct AppState {
     pub nodes: RwLock<HashMap<String, NodeInfo>>,
     pub tx: broadcast::Sender<String>,
-    pub secret_token: String,
-    pub home_dir: PathBuf,
-    pub node_ttl: u64,
+    /// Directories `list_directories` and `spawn_agent` are confined to.
+    pub roots: Roots,
+    /// Directory names `search_directories` does not descend into.
+    pub search_skip: Vec<String>,
+    /// Recently used and favorite project directories.
+    pub directories: Mutex<DirectoryHistory>,
+    /// Names `watch_directory` does not watch.
+    pub watch_ignore: Vec<String>,
+    /// Active `watch_directory` subscriptions.
+    pub watches: Watches,
+    /// Executable launched by `spawn_agent`.
+    pub spawn_command: String,
+    /// Backend used when a `spawn_agent` call does not pick one.
+    pub spawn_backend: BackendKind,
+    /// Environment variables `spawn_agent` callers may override.
+    pub spawn_env_allowlist: Vec<String>,
+    /// Named `spawn_agent` presets, by name.
+    pub spawn_templates: BTreeMap<String, SpawnTemplate>,
+    /// Caps on running agents and spawn rate.
+    pub spawn_limits: SpawnLimits,
+    /// Recent spawns; held while a spawn is admitted and started.
+    pub spawn_window: Mutex<SpawnWindow>,
+    /// Resource limits for each spawned agent.
+    pub spawn_rlimits: ResourceLimits,
+    /// Initial prompts for spawned agents, keyed by spawn ID, delivered once
+    /// the agent registers.
+    pub pending_prompts: Mutex<HashMap<String, String>>,
+    /// `spawn_agent` calls blocked on `wait_ms`, keyed by spawn ID.
+    pub spawn_waiters: Mutex<HashMap<String, mpsc::Sender<SpawnOutcome>>>,
+    /// Agent processes started by `spawn_agent` that are still running.
+    pub children: Mutex<HashMap<u32, SpawnedProcess>>,
+    /// Terminals of agents spawned with the `pty` backend, keyed by spawn ID.
+    #[cfg(unix)]
+    pub ptys: Mutex<HashMap<String, Arc<PtySession>>>,
+    /// Settings that can be hot-reloaded (tokens, TTLs), with the config
+    /// last loaded.
+    pub live: RwLock<Loaded>,
+    /// The config the server started with. Reloads re-read its `source`.
+    pub config: ServerConfig,
+    /// Socket files this process created, removed again on shutdown.
+    pub socket_files: Mutex<Vec<PathBuf>>,
+}
+
+impl AppState {
+    /// Snapshot of the current hot-reloadable settings.
+    pub fn live(&self) -> LiveConfig {
+        self.live.read().expect("config lock poisoned").live.clone()
+    }
 }
 
 pub type Registry = Arc<AppState>;
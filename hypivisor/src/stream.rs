@@ -0,0 +1,218 @@
+//! Transport-agnostic listeners and connections: TCP or Unix domain socket.
+//!
+//! The WebSocket handlers only need `Read + Write` plus a few socket
+//! controls, so both transports are wrapped in one enum rather than making
+//! every handler generic.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// An accepted connection.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    /// `trusted` is inherited from the listener it was accepted on.
+    #[cfg(unix)]
+    Unix {
+        stream: UnixStream,
+        trusted: bool,
+    },
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix { stream, trusted } => stream.try_clone().map(|stream| Stream::Unix {
+                stream,
+                trusted: *trusted,
+            }),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix { stream, .. } => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix { stream, .. } => stream.shutdown(how),
+        }
+    }
+
+    /// Whether the peer came in through the owner-only socket the hypivisor
+    /// created itself, so its file permissions already vetted the peer and
+    /// the token check is skipped. Sockets inherited from systemd carry
+    /// whatever permissions the unit gave them and are not trusted.
+    pub fn is_trusted(&self) -> bool {
+        match self {
+            Stream::Tcp(_) => false,
+            #[cfg(unix)]
+            Stream::Unix { trusted, .. } => *trusted,
+        }
+    }
+
+    /// Peer description for logs.
+    pub fn peer_label(&self) -> String {
+        match self {
+            Stream::Tcp(s) => s
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| "0.0.0.0:0".into()),
+            #[cfg(unix)]
+            Stream::Unix { .. } => "unix".into(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix { stream, .. } => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix { stream, .. } => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix { stream, .. } => stream.flush(),
+        }
+    }
+}
+
+/// A bound listener.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    /// `trusted` is set only for the owner-only socket made by
+    /// [`crate::listener::bind_unix`].
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        trusted: bool,
+    },
+}
+
+impl Listener {
+    /// Block until the next connection arrives.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix { listener, trusted } => {
+                listener.accept().map(|(stream, _)| Stream::Unix {
+                    stream,
+                    trusted: *trusted,
+                })
+            }
+        }
+    }
+
+    /// Bound address for logs: `IP:PORT` or `unix:PATH`.
+    pub fn local_label(&self) -> String {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(|a| a.to_string()).unwrap_or_default(),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| format!("unix:{}", p.display())))
+                .unwrap_or_else(|| "unix".into()),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(l: TcpListener) -> Self {
+        Listener::Tcp(l)
+    }
+}
+
+/// An untrusted Unix listener: its clients must present a token.
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix {
+            listener,
+            trusted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_stream_is_not_trusted() {
+        let listener = Listener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        let addr = match &listener {
+            Listener::Tcp(l) => l.local_addr().unwrap(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => unreachable!(),
+        };
+        let _client = TcpStream::connect(addr).unwrap();
+        let accepted = listener.accept().unwrap();
+        assert!(!accepted.is_trusted());
+        assert!(accepted.peer_label().starts_with("127.0.0.1:"));
+        assert_eq!(listener.local_label(), addr.to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_stream_round_trips_and_carries_listener_trust() {
+        let dir = std::env::temp_dir().join(format!("hypi-stream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("s.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let listener = Listener::from(UnixListener::bind(&path).unwrap());
+        assert_eq!(listener.local_label(), format!("unix:{}", path.display()));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut accepted = listener.accept().unwrap();
+        assert!(!accepted.is_trusted());
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+        let trusted = Listener::Unix {
+            listener: UnixListener::bind(&path).unwrap(),
+            trusted: true,
+        };
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(trusted.accept().unwrap().is_trusted());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
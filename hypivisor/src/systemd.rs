@@ -0,0 +1,291 @@
+//! systemd integration: socket activation and `sd_notify`.
+//!
+//! Implements the small, stable parts of the protocol directly rather than
+//! linking libsystemd:
+//!
+//! - **Socket activation.** When `LISTEN_PID` is our PID, the `LISTEN_FDS`
+//!   descriptors starting at fd 3 are used instead of binding. Each socket's
+//!   `FileDescriptorName=` (from `LISTEN_FDNAMES`) may be a listener role
+//!   (`agents`, `dashboard`, `all`); anything else means `all`.
+//! - **Notifications.** `READY=1`, `STOPPING=1` and `WATCHDOG=1` are sent as
+//!   datagrams to `NOTIFY_SOCKET`. Without it, notifying is a no-op.
+//! - **Watchdog.** `WATCHDOG_USEC` sets the deadline. The cleanup loop pings
+//!   at half that, so a wedged cleanup thread gets the service restarted.
+
+use crate::listener::ListenerRole;
+use crate::stream::Listener;
+use std::{env, io, time::Duration};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+/// First inherited descriptor (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+
+/// An inherited socket: its descriptor and `FileDescriptorName=`, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InheritedFd {
+    pub fd: i32,
+    pub name: Option<String>,
+}
+
+impl InheritedFd {
+    /// Listener role named by the socket unit, defaulting to `all`.
+    pub fn role(&self) -> ListenerRole {
+        self.name
+            .as_deref()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Parse the socket-activation variables for process `pid`, using `lookup`
+/// to read them. Returns no descriptors if they were meant for another process.
+pub fn listen_fds_with(
+    lookup: impl Fn(&str) -> Option<String>,
+    pid: u32,
+) -> Result<Vec<InheritedFd>, String> {
+    let Some(listen_pid) = lookup("LISTEN_PID") else {
+        return Ok(Vec::new());
+    };
+    let listen_pid: u32 = listen_pid
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid LISTEN_PID: {e}"))?;
+    if listen_pid != pid {
+        return Ok(Vec::new());
+    }
+    let count: i32 = lookup("LISTEN_FDS")
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid LISTEN_FDS: {e}"))?;
+    let names: Vec<String> = lookup("LISTEN_FDNAMES")
+        .map(|n| n.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+    Ok((0..count)
+        .map(|i| InheritedFd {
+            fd: LISTEN_FDS_START + i,
+            name: names.get(i as usize).filter(|n| !n.is_empty()).cloned(),
+        })
+        .collect())
+}
+
+/// Take ownership of the sockets systemd passed in, if any.
+///
+/// The activation variables are removed afterwards so spawned agents don't
+/// see them, and each descriptor is re-opened close-on-exec so it doesn't
+/// leak into them either. Must be called before any other threads start.
+#[cfg(unix)]
+pub fn take_listeners() -> Result<Vec<(Listener, ListenerRole)>, String> {
+    let inherited = listen_fds_with(|key| env::var(key).ok(), std::process::id())?;
+    for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(key);
+    }
+    inherited
+        .iter()
+        .map(|fd| {
+            // SAFETY: LISTEN_PID names this process, so systemd handed these
+            // descriptors to us and nothing else in the process owns them.
+            unsafe { listener_from_fd(fd.fd) }.map(|l| (l, fd.role()))
+        })
+        .collect()
+}
+
+/// Wrap an inherited listening socket, detecting TCP vs Unix.
+///
+/// # Safety
+///
+/// `fd` must be an open listening socket owned by the caller. It is
+/// consumed (closed) whether or not this succeeds.
+#[cfg(unix)]
+pub unsafe fn listener_from_fd(fd: i32) -> Result<Listener, String> {
+    use std::net::TcpListener;
+    use std::os::unix::{
+        io::{FromRawFd, IntoRawFd},
+        net::UnixListener,
+    };
+
+    let tcp = TcpListener::from_raw_fd(fd);
+    // `try_clone` duplicates with close-on-exec set; dropping the original
+    // closes the inherited (inheritable) descriptor.
+    if tcp.local_addr().is_ok() {
+        return tcp
+            .try_clone()
+            .map(Listener::from)
+            .map_err(|e| format!("Cannot use inherited fd {fd}: {e}"));
+    }
+    let unix = UnixListener::from_raw_fd(tcp.into_raw_fd());
+    if unix.local_addr().is_err() {
+        return Err(format!("Inherited fd {fd} is not a TCP or Unix socket"));
+    }
+    unix.try_clone()
+        .map(Listener::from)
+        .map_err(|e| format!("Cannot use inherited fd {fd}: {e}"))
+}
+
+/// Watchdog deadline for process `pid`, if systemd enabled one for it.
+pub fn watchdog_interval_with(
+    lookup: impl Fn(&str) -> Option<String>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(wd_pid) = lookup("WATCHDOG_PID") {
+        if wd_pid.trim().parse::<u32>().ok() != Some(pid) {
+            return None;
+        }
+    }
+    let usec: u64 = lookup("WATCHDOG_USEC")?.trim().parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Watchdog deadline from the process environment.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_with(|key| env::var(key).ok(), std::process::id())
+}
+
+/// Send `state` to the datagram socket at `addr`. A leading `@` denotes a
+/// Linux abstract socket, as in `NOTIFY_SOCKET`.
+#[cfg(unix)]
+pub fn notify_to(addr: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = addr.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            return socket.send_to_addr(state.as_bytes(), &addr).map(|_| ());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract NOTIFY_SOCKET is Linux-only",
+            ));
+        }
+    }
+    socket.send_to(state.as_bytes(), addr).map(|_| ())
+}
+
+/// Send `state` to `NOTIFY_SOCKET`. Returns `Ok(false)` when not running
+/// under a notify-aware service manager.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(addr) if !addr.is_empty() => notify_to(&addr, state).map(|_| true),
+        _ => Ok(false),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |k| map.get(k).cloned()
+    }
+
+    #[test]
+    fn listen_fds_for_this_process() {
+        let lookup = vars(&[
+            ("LISTEN_PID", "42"),
+            ("LISTEN_FDS", "2"),
+            ("LISTEN_FDNAMES", "agents:"),
+        ]);
+        let fds = listen_fds_with(lookup, 42).unwrap();
+        assert_eq!(fds.len(), 2);
+        assert_eq!(fds[0].fd, 3);
+        assert_eq!(fds[0].role(), ListenerRole::Agents);
+        assert_eq!(fds[1].fd, 4);
+        assert_eq!(fds[1].name, None);
+        assert_eq!(fds[1].role(), ListenerRole::All);
+    }
+
+    #[test]
+    fn listen_fds_for_another_process_are_ignored() {
+        let lookup = vars(&[("LISTEN_PID", "41"), ("LISTEN_FDS", "1")]);
+        assert!(listen_fds_with(lookup, 42).unwrap().is_empty());
+        assert!(listen_fds_with(vars(&[]), 42).unwrap().is_empty());
+    }
+
+    #[test]
+    fn listen_fds_rejects_garbage() {
+        let lookup = vars(&[("LISTEN_PID", "42"), ("LISTEN_FDS", "two")]);
+        let err = listen_fds_with(lookup, 42).unwrap_err();
+        assert!(err.contains("LISTEN_FDS"));
+    }
+
+    #[test]
+    fn unknown_fd_name_means_all() {
+        let fd = InheritedFd {
+            fd: 3,
+            name: Some("hypivisor.socket".into()),
+        };
+        assert_eq!(fd.role(), ListenerRole::All);
+    }
+
+    #[test]
+    fn watchdog_interval_parsing() {
+        let lookup = vars(&[("WATCHDOG_USEC", "20000000"), ("WATCHDOG_PID", "42")]);
+        assert_eq!(
+            watchdog_interval_with(&lookup, 42),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(watchdog_interval_with(&lookup, 7), None);
+        assert_eq!(
+            watchdog_interval_with(vars(&[("WATCHDOG_USEC", "5000000")]), 7),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval_with(vars(&[("WATCHDOG_USEC", "0")]), 7),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_sends_datagram() {
+        let dir = env::temp_dir().join(format!("hypi-notify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inherited_tcp_and_unix_listeners_are_detected() {
+        use std::os::unix::io::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = unsafe { listener_from_fd(tcp.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        assert_eq!(listener.local_label(), addr.to_string());
+
+        let dir = env::temp_dir().join(format!("hypi-fd-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("s.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = unsafe { listener_from_fd(unix.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Unix { trusted: false, .. }));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
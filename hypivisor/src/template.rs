@@ -0,0 +1,336 @@
+//! Named spawn templates from the config file.
+//!
+//! A template presets what `spawn_agent` would otherwise take as params:
+//!
+//! ```toml
+//! [templates.reviewer]
+//! description = "Code reviewer for the API repo"
+//! path = "/home/me/src/api"
+//! new_folder = "review-{branch}"
+//! model = "claude-sonnet-4"
+//! prompt = "Review the changes on {branch}."
+//! ```
+//!
+//! `new_folder` and `prompt` may contain `{placeholders}`, filled from the
+//! caller's `vars` plus the built-ins `{date}` (`YYYY-MM-DD`) and `{time}`
+//! (`HHMMSS`). Anything in braces that is not an identifier is left alone,
+//! so prompts can still contain JSON.
+
+use crate::backend::BackendKind;
+use crate::sandbox::SandboxSpec;
+use crate::spawn::SpawnOptions;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Placeholders filled in without the caller supplying them.
+const BUILTIN_VARS: &[&str] = &["date", "time"];
+
+/// A named preset for `spawn_agent`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpawnTemplate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Directory to spawn in. If unset, the caller passes `path`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Subdirectory to create, with placeholders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_folder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Not subject to `spawn_env_allowlist`, since the config is trusted.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Initial prompt, with placeholders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BackendKind>,
+    /// Run agents from this template sandboxed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<SandboxSpec>,
+}
+
+/// Where and how to spawn once a template has been applied.
+#[derive(Debug)]
+pub struct Applied {
+    pub path: String,
+    pub new_folder: String,
+    pub options: SpawnOptions,
+}
+
+impl SpawnTemplate {
+    /// Check the template when the config is loaded.
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        let invalid = |e: String| format!("Invalid template '{name}': {e}");
+        if self.path.as_deref().is_some_and(|p| p.trim().is_empty()) {
+            return Err(invalid("path must not be empty".into()));
+        }
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.validate().map_err(invalid)?;
+        }
+        // Env names are still checked, but any name is allowed.
+        self.base_options()
+            .validate(&["*".to_string()])
+            .map_err(invalid)
+    }
+
+    /// Caller-supplied placeholders the template uses, sorted.
+    pub fn vars(&self) -> Vec<String> {
+        let mut vars = BTreeSet::new();
+        for text in [&self.new_folder, &self.prompt].into_iter().flatten() {
+            vars.extend(placeholders(text));
+        }
+        vars.retain(|v| !BUILTIN_VARS.contains(&v.as_str()));
+        vars.into_iter().collect()
+    }
+
+    fn base_options(&self) -> SpawnOptions {
+        SpawnOptions {
+            model: self.model.clone(),
+            provider: self.provider.clone(),
+            args: self.args.clone(),
+            env: self.env.clone(),
+            prompt: self.prompt.clone(),
+            backend: self.backend,
+            sandbox: self.sandbox.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Combine the template with a `spawn_agent` call. `path` and
+    /// `new_folder` are the caller's (empty if not given); `options` must
+    /// already have been validated. Caller settings win over the template's,
+    /// except that args are appended and env is merged.
+    pub fn apply(
+        &self,
+        path: &str,
+        new_folder: &str,
+        vars: &BTreeMap<String, String>,
+        options: SpawnOptions,
+    ) -> Result<Applied, String> {
+        let path = match (&self.path, path.is_empty()) {
+            (Some(_), false) => return Err("This template sets the path; omit path".into()),
+            (Some(template_path), true) => template_path.clone(),
+            (None, false) => path.to_string(),
+            (None, true) => return Err("This template needs a path".into()),
+        };
+        let now = Local::now();
+        let mut all_vars = vars.clone();
+        all_vars.insert("date".into(), now.format("%Y-%m-%d").to_string());
+        all_vars.insert("time".into(), now.format("%H%M%S").to_string());
+
+        let new_folder = if !new_folder.is_empty() {
+            new_folder.to_string()
+        } else if let Some(template_folder) = &self.new_folder {
+            let folder = expand(template_folder, &all_vars)?;
+            validate_folder_name(&folder)?;
+            folder
+        } else {
+            String::new()
+        };
+
+        let base = self.base_options();
+        let prompt = match options.prompt {
+            Some(prompt) => Some(prompt),
+            None => base.prompt.map(|p| expand(&p, &all_vars)).transpose()?,
+        };
+        let mut env = base.env;
+        env.extend(options.env);
+        let mut args = base.args;
+        args.extend(options.args);
+        let options = SpawnOptions {
+            model: options.model.or(base.model),
+            provider: options.provider.or(base.provider),
+            continue_session: options.continue_session,
+            session: options.session,
+            args,
+            env,
+            prompt,
+            wait_ms: options.wait_ms,
+            backend: options.backend.or(base.backend),
+            worktree: options.worktree,
+            sandbox: base.sandbox,
+            hidden: options.hidden,
+        };
+        Ok(Applied {
+            path,
+            new_folder,
+            options,
+        })
+    }
+}
+
+/// A placeholder is `{name}` where `name` is an identifier.
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split `text` into literal text and placeholder names.
+fn segments(text: &str) -> Vec<(&str, bool)> {
+    let mut out = Vec::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        let Some(len) = rest[open + 1..].find('}') else {
+            break;
+        };
+        let name = &rest[open + 1..open + 1 + len];
+        if !is_identifier(name) {
+            out.push((&rest[..=open], false));
+            rest = &rest[open + 1..];
+            continue;
+        }
+        out.push((&rest[..open], false));
+        out.push((name, true));
+        rest = &rest[open + len + 2..];
+    }
+    out.push((rest, false));
+    out
+}
+
+fn placeholders(text: &str) -> impl Iterator<Item = String> + '_ {
+    segments(text)
+        .into_iter()
+        .filter(|(_, is_var)| *is_var)
+        .map(|(name, _)| name.to_string())
+}
+
+/// Fill in every placeholder in `text`.
+fn expand(text: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    for (segment, is_var) in segments(text) {
+        if is_var {
+            let value = vars
+                .get(segment)
+                .ok_or_else(|| format!("Missing template variable: {segment}"))?;
+            out.push_str(value);
+        } else {
+            out.push_str(segment);
+        }
+    }
+    Ok(out)
+}
+
+/// An expanded `new_folder` must be a single, ordinary path component.
+fn validate_folder_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(format!("Invalid folder name from template: {name:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn reviewer() -> SpawnTemplate {
+        SpawnTemplate {
+            path: Some("/work/api".into()),
+            new_folder: Some("review-{branch}".into()),
+            model: Some("sonnet".into()),
+            args: vec!["--verbose".into()],
+            env: vars(&[("OPENAI_BASE_URL", "http://proxy")]),
+            prompt: Some("Review {branch} as of {date}. Reply {\"ok\": true}.".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expands_identifiers_only() {
+        let v = vars(&[("a", "1")]);
+        assert_eq!(expand("x{a}y{a}", &v).unwrap(), "x1y1");
+        assert_eq!(expand("{\"a\": {a}}", &v).unwrap(), "{\"a\": 1}");
+        assert_eq!(expand("{ a } {", &v).unwrap(), "{ a } {");
+        assert_eq!(
+            expand("{b}", &v).unwrap_err(),
+            "Missing template variable: b"
+        );
+    }
+
+    #[test]
+    fn lists_caller_vars() {
+        assert_eq!(reviewer().vars(), ["branch"]);
+    }
+
+    #[test]
+    fn applies_template_under_caller_options() {
+        let caller = SpawnOptions {
+            model: Some("opus".into()),
+            args: vec!["--x".into()],
+            env: vars(&[("PI_DEBUG", "1")]),
+            ..Default::default()
+        };
+        let applied = reviewer()
+            .apply("", "", &vars(&[("branch", "fix-7")]), caller)
+            .unwrap();
+        assert_eq!(applied.path, "/work/api");
+        assert_eq!(applied.new_folder, "review-fix-7");
+        let options = applied.options;
+        assert_eq!(options.model.as_deref(), Some("opus"));
+        assert_eq!(options.args, ["--verbose", "--x"]);
+        assert_eq!(options.env.len(), 2);
+        let prompt = options.prompt.unwrap();
+        assert!(prompt.starts_with("Review fix-7 as of 2"), "{prompt}");
+        assert!(prompt.ends_with("Reply {\"ok\": true}."), "{prompt}");
+    }
+
+    #[test]
+    fn rejects_bad_paths_and_folders() {
+        let template = reviewer();
+        let none = SpawnOptions::default;
+        assert!(template
+            .apply("/elsewhere", "", &vars(&[]), none())
+            .is_err());
+        assert!(template.apply("", "", &vars(&[]), none()).is_err());
+        let bare = SpawnTemplate {
+            new_folder: Some("{branch}".into()),
+            ..reviewer()
+        };
+        for bad in ["../up", "a/b", "..", ""] {
+            let err = bare
+                .apply("", "", &vars(&[("branch", bad)]), none())
+                .unwrap_err();
+            assert!(err.starts_with("Invalid folder name"), "{err}");
+        }
+        let open = SpawnTemplate::default();
+        assert_eq!(
+            open.apply("", "", &vars(&[]), none()).unwrap_err(),
+            "This template needs a path"
+        );
+        let applied = open.apply("/work", "mine", &vars(&[]), none()).unwrap();
+        assert_eq!(
+            (applied.path.as_str(), applied.new_folder.as_str()),
+            ("/work", "mine")
+        );
+    }
+
+    #[test]
+    fn validates_at_load() {
+        assert!(reviewer().validate("reviewer").is_ok());
+        let bad = SpawnTemplate {
+            model: Some("-x".into()),
+            ..reviewer()
+        };
+        assert_eq!(
+            bad.validate("reviewer").unwrap_err(),
+            "Invalid template 'reviewer': model must not start with '-'"
+        );
+    }
+}
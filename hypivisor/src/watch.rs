@@ -0,0 +1,660 @@
+//! `watch_directory`: push `fs_changed` events as files change under a
+//! project directory.
+//!
+//! Each watched directory gets one inotify instance and thread, shared by
+//! every subscription to it with the same ignore list. Subdirectories are
+//! watched too, including ones created later, except those named in
+//! `watch_ignore` (`.git`, `node_modules`, ...). Changes are collected until
+//! the tree has been quiet for `DEBOUNCE` (or `MAX_DELAY` has passed), then
+//! sent to each subscriber as one event. Each connection has its own sender
+//! thread, so a slow one only delays its own events. Subscriptions end with
+//! `unwatch_directory` or when the registry connection closes.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+/// Names not watched by default, at any depth.
+pub const DEFAULT_IGNORE: &[&str] = &[
+    ".git",
+    "node_modules",
+    "target",
+    "dist",
+    "build",
+    "__pycache__",
+    ".venv",
+];
+
+/// Most subscriptions one connection may hold.
+pub const MAX_WATCHES_PER_CLIENT: usize = 16;
+/// Quiet time before a batch of changes is sent.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Longest a change waits while the tree keeps changing.
+const MAX_DELAY: Duration = Duration::from_secs(2);
+/// Most changes in one event; the rest are dropped and `truncated` is set.
+const MAX_CHANGES: usize = 1000;
+/// Events queued for one connection before further ones are dropped.
+const CLIENT_QUEUE: usize = 64;
+/// Most directories one watcher adds to inotify.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const MAX_WATCHED_DIRS: usize = 20_000;
+
+/// Delivers an event to a connection; returns false once it is closed.
+pub type EventSink = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A registry connection that can hold watches.
+pub struct WatchClient {
+    id: u64,
+    sink: EventSink,
+}
+
+impl WatchClient {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Relative to the watched directory.
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+struct Subscriber {
+    client: u64,
+    node_id: Option<String>,
+    sink: EventSink,
+}
+
+type Subscribers = Arc<Mutex<HashMap<String, Subscriber>>>;
+
+struct Watcher {
+    /// By watch ID.
+    subscribers: Subscribers,
+    stop: Arc<AtomicBool>,
+}
+
+/// A canonical directory and the sorted names ignored under it.
+type WatcherKey = (PathBuf, Vec<String>);
+
+/// Every active watch, by directory and ignore list.
+#[derive(Default)]
+pub struct Watches {
+    next_client: AtomicU64,
+    next_watch: AtomicU64,
+    watchers: Mutex<HashMap<WatcherKey, Watcher>>,
+}
+
+impl Watches {
+    /// A new client sending its events to `sink`, from a thread of its own.
+    /// Events are dropped while `CLIENT_QUEUE` of them are waiting, and the
+    /// thread ends once the connection is closed or the client and all its
+    /// watches are gone.
+    pub fn client(&self, sink: EventSink) -> WatchClient {
+        let id = self.next_client.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::sync_channel::<String>(CLIENT_QUEUE);
+        let sender = std::thread::Builder::new()
+            .name("watch-send".into())
+            .spawn(move || {
+                while let Ok(event) = rx.recv() {
+                    if !sink(&event) {
+                        break;
+                    }
+                }
+            });
+        if let Err(e) = sender {
+            warn!(error = %e, "Failed to start watch sender");
+        }
+        WatchClient {
+            id,
+            sink: Arc::new(move |event: &str| match tx.try_send(event.to_string()) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    warn!(
+                        client = id,
+                        "Dropped fs_changed event for a slow connection"
+                    );
+                    true
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }),
+        }
+    }
+
+    /// Subscribe `client` to changes under `dir`, which must be canonical,
+    /// skipping names in `ignore`. Watches of the same directory share a
+    /// watcher only if they ignore the same names. Returns the watch ID.
+    pub fn watch(
+        &self,
+        client: &WatchClient,
+        dir: &Path,
+        node_id: Option<String>,
+        ignore: &[String],
+    ) -> Result<String, String> {
+        let mut watchers = self.watchers.lock().expect("watches lock poisoned");
+        let held = watchers
+            .values()
+            .map(|w| {
+                let subscribers = w.subscribers.lock().expect("watch lock poisoned");
+                subscribers
+                    .values()
+                    .filter(|s| s.client == client.id)
+                    .count()
+            })
+            .sum::<usize>();
+        if held >= MAX_WATCHES_PER_CLIENT {
+            return Err(format!("Too many watches (max {MAX_WATCHES_PER_CLIENT})"));
+        }
+
+        let mut ignore = ignore.to_vec();
+        ignore.sort();
+        ignore.dedup();
+        let key = (dir.to_path_buf(), ignore);
+        if !watchers.contains_key(&key) {
+            let watcher = Watcher {
+                subscribers: Arc::default(),
+                stop: Arc::default(),
+            };
+            start(dir, &key.1, &watcher)?;
+            watchers.insert(key.clone(), watcher);
+        }
+        let watch_id = format!("w-{}", self.next_watch.fetch_add(1, Ordering::Relaxed));
+        watchers[&key]
+            .subscribers
+            .lock()
+            .expect("watch lock poisoned")
+            .insert(
+                watch_id.clone(),
+                Subscriber {
+                    client: client.id,
+                    node_id,
+                    sink: client.sink.clone(),
+                },
+            );
+        Ok(watch_id)
+    }
+
+    /// End one of `client`'s watches.
+    pub fn unwatch(&self, client: u64, watch_id: &str) -> Result<(), String> {
+        let mut found = false;
+        self.remove(|id, s| {
+            let matched = id == watch_id && s.client == client;
+            found |= matched;
+            matched
+        });
+        if found {
+            Ok(())
+        } else {
+            Err(format!("Unknown watch_id: {watch_id}"))
+        }
+    }
+
+    /// End every watch of a client whose connection closed.
+    pub fn drop_client(&self, client: u64) {
+        self.remove(|_, s| s.client == client);
+    }
+
+    /// Remove matching subscriptions, stopping watchers left without any.
+    fn remove(&self, mut matches: impl FnMut(&str, &Subscriber) -> bool) {
+        let mut watchers = self.watchers.lock().expect("watches lock poisoned");
+        watchers.retain(|_, watcher| {
+            let mut subscribers = watcher.subscribers.lock().expect("watch lock poisoned");
+            subscribers.retain(|id, s| !matches(id, s));
+            if subscribers.is_empty() {
+                watcher.stop.store(true, Ordering::Relaxed);
+            }
+            !subscribers.is_empty()
+        });
+    }
+}
+
+/// Changes seen since the last event.
+#[derive(Default)]
+struct Batch {
+    changes: BTreeMap<String, ChangeKind>,
+    truncated: bool,
+}
+
+impl Batch {
+    fn add(&mut self, path: String, kind: ChangeKind) {
+        use ChangeKind::*;
+        let merged = match (self.changes.get(&path), kind) {
+            // Deleted and recreated, as editors do when saving.
+            (Some(Deleted), Created) => Modified,
+            (Some(Created), Modified) => Created,
+            (Some(Created), Deleted) => {
+                self.changes.remove(&path);
+                return;
+            }
+            (None, _) if self.changes.len() >= MAX_CHANGES => {
+                self.truncated = true;
+                return;
+            }
+            (_, kind) => kind,
+        };
+        self.changes.insert(path, merged);
+    }
+
+    /// Send the batch to every subscriber and start a new one.
+    fn flush(&mut self, dir: &Path, subscribers: &Subscribers) {
+        let batch = std::mem::take(self);
+        let changes: Vec<Change> = batch
+            .changes
+            .into_iter()
+            .map(|(path, kind)| Change { path, kind })
+            .collect();
+        // Sinks only queue the event for the connection's sender thread, so
+        // a slow connection does not hold up the others or this watcher.
+        let targets: Vec<(String, Option<String>, EventSink)> = subscribers
+            .lock()
+            .expect("watch lock poisoned")
+            .iter()
+            .map(|(id, s)| (id.clone(), s.node_id.clone(), s.sink.clone()))
+            .collect();
+        for (watch_id, node_id, sink) in targets {
+            let mut event = serde_json::json!({
+                "event": "fs_changed",
+                "watch_id": watch_id,
+                "path": dir,
+                "changes": changes,
+                "truncated": batch.truncated,
+            });
+            if let Some(node_id) = node_id {
+                event["node_id"] = node_id.into();
+            }
+            sink(&event.to_string());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start(_dir: &Path, _ignore: &[String], _watcher: &Watcher) -> Result<(), String> {
+    Err("watch_directory is only supported on Linux".into())
+}
+
+/// Watch `dir` on a new thread until `watcher.stop` is set.
+#[cfg(target_os = "linux")]
+fn start(dir: &Path, ignore: &[String], watcher: &Watcher) -> Result<(), String> {
+    use std::time::Instant;
+
+    let mut inotify = inotify::Inotify::new(dir, ignore)?;
+    let (dir, subscribers, stop) = (
+        dir.to_path_buf(),
+        watcher.subscribers.clone(),
+        watcher.stop.clone(),
+    );
+    std::thread::Builder::new()
+        .name("watch".into())
+        .spawn(move || {
+            let mut batch = Batch::default();
+            let mut first_change: Option<Instant> = None;
+            let mut last_change = Instant::now();
+            while !stop.load(Ordering::Relaxed) {
+                if inotify.read(Duration::from_millis(100), &mut batch) {
+                    last_change = Instant::now();
+                    first_change.get_or_insert(last_change);
+                }
+                let due = first_change.is_some_and(|first| {
+                    last_change.elapsed() >= DEBOUNCE || first.elapsed() >= MAX_DELAY
+                });
+                if due {
+                    first_change = None;
+                    if !batch.changes.is_empty() || batch.truncated {
+                        batch.flush(&dir, &subscribers);
+                    }
+                }
+            }
+        })
+        .map_err(|e| format!("Failed to start watcher: {e}"))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::{Batch, ChangeKind, MAX_WATCHED_DIRS};
+    use std::{
+        collections::{HashMap, VecDeque},
+        ffi::{CString, OsStr},
+        fs,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::ffi::{OsStrExt, OsStringExt},
+        },
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+    use tracing::warn;
+
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ONLYDIR
+        | libc::IN_DONT_FOLLOW;
+
+    pub(super) struct Inotify {
+        fd: OwnedFd,
+        root: PathBuf,
+        ignore: Vec<String>,
+        /// Watched directories relative to `root`, by watch descriptor.
+        dirs: HashMap<i32, PathBuf>,
+    }
+
+    impl Inotify {
+        pub(super) fn new(root: &Path, ignore: &[String]) -> Result<Inotify, String> {
+            // SAFETY: inotify_init1 has no preconditions; the descriptor it
+            // returns is owned by nothing else.
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(format!(
+                    "Cannot watch directory: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            let mut inotify = Inotify {
+                // SAFETY: `fd` was just opened and is not used elsewhere.
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                root: root.to_path_buf(),
+                ignore: ignore.to_vec(),
+                dirs: HashMap::new(),
+            };
+            if !inotify.add(Path::new("")) {
+                return Err(format!(
+                    "Cannot watch directory: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            inotify.add_tree(Path::new(""), None);
+            Ok(inotify)
+        }
+
+        fn ignored(&self, name: &OsStr) -> bool {
+            self.ignore.iter().any(|i| OsStr::new(i) == name)
+        }
+
+        fn add(&mut self, rel: &Path) -> bool {
+            let Ok(path) = CString::new(self.root.join(rel).into_os_string().into_vec()) else {
+                return false;
+            };
+            // SAFETY: `path` is a valid C string and `fd` is open.
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), MASK) };
+            if wd >= 0 {
+                self.dirs.insert(wd, rel.to_path_buf());
+            }
+            wd >= 0
+        }
+
+        /// Watch the subdirectories below `rel`. Entries found are recorded
+        /// in `batch` when given, for directories created after the watch
+        /// started whose contents may already have been written.
+        fn add_tree(&mut self, rel: &Path, mut batch: Option<&mut Batch>) {
+            let mut queue = VecDeque::from([rel.to_path_buf()]);
+            while let Some(dir) = queue.pop_front() {
+                let Ok(entries) = fs::read_dir(self.root.join(&dir)) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    if self.ignored(&name) {
+                        continue;
+                    }
+                    let child = dir.join(&name);
+                    if let Some(batch) = batch.as_deref_mut() {
+                        batch.add(child.to_string_lossy().into_owned(), ChangeKind::Created);
+                    }
+                    if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                        continue;
+                    }
+                    if self.dirs.len() >= MAX_WATCHED_DIRS {
+                        warn!(root = %self.root.display(), "Too many directories to watch");
+                        return;
+                    }
+                    if self.add(&child) {
+                        queue.push_back(child);
+                    }
+                }
+            }
+        }
+
+        /// Wait up to `timeout` for events and add them to `batch`. Returns
+        /// whether anything was added.
+        pub(super) fn read(&mut self, timeout: Duration, batch: &mut Batch) -> bool {
+            let mut poll = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: `poll` points to one valid pollfd.
+            if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) } <= 0 {
+                return false;
+            }
+            let mut buf = [0u8; 16 * 1024];
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut changed = false;
+            loop {
+                // SAFETY: `buf` is writable for its whole length.
+                let n =
+                    unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n <= 0 {
+                    return changed;
+                }
+                let mut offset = 0;
+                while offset + header <= n as usize {
+                    // SAFETY: the kernel writes whole events, each a header
+                    // followed by `len` bytes of name.
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(buf.as_ptr().add(offset).cast()) };
+                    let name = &buf[offset + header..offset + header + event.len as usize];
+                    offset += header + event.len as usize;
+                    let name = OsStr::from_bytes(name.split(|b| *b == 0).next().unwrap_or(&[]));
+                    changed |= self.handle(event.wd, event.mask, name, batch);
+                }
+            }
+        }
+
+        fn handle(&mut self, wd: i32, mask: u32, name: &OsStr, batch: &mut Batch) -> bool {
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                batch.truncated = true;
+                return true;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&wd);
+                return false;
+            }
+            let Some(dir) = self.dirs.get(&wd) else {
+                return false;
+            };
+            if name.is_empty() || self.ignored(name) {
+                return false;
+            }
+            let rel = dir.join(name);
+            let kind = if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                ChangeKind::Created
+            } else if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                ChangeKind::Deleted
+            } else {
+                ChangeKind::Modified
+            };
+            batch.add(rel.to_string_lossy().into_owned(), kind);
+            if kind == ChangeKind::Created && mask & libc::IN_ISDIR != 0 && self.add(&rel) {
+                self.add_tree(&rel, Some(batch));
+            }
+            true
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::{fs, time::Instant};
+
+    fn collecting_client(watches: &Watches) -> (WatchClient, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink_events = events.clone();
+        let client = watches.client(Arc::new(move |event: &str| {
+            sink_events
+                .lock()
+                .unwrap()
+                .push(serde_json::from_str(event).unwrap());
+            true
+        }));
+        (client, events)
+    }
+
+    /// All changes delivered so far, once `expected` of them have arrived.
+    fn wait_for_changes(events: &Mutex<Vec<serde_json::Value>>, expected: usize) -> Vec<Change> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let changes: Vec<Change> = events
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|e| e["changes"].as_array().unwrap().clone())
+                .map(|c| serde_json::from_value(c).unwrap())
+                .collect();
+            if changes.len() >= expected || Instant::now() > deadline {
+                return changes;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_watch_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("node_modules")).unwrap();
+        fs::write(dir.join("old.txt"), "x").unwrap();
+        dir
+    }
+
+    #[test]
+    fn reports_debounced_changes_in_new_directories() {
+        let dir = temp_dir("changes");
+        let watches = Watches::default();
+        let (client, events) = collecting_client(&watches);
+        let ignore = vec!["node_modules".to_string()];
+        let watch_id = watches
+            .watch(&client, &dir, Some("n1".into()), &ignore)
+            .unwrap();
+
+        fs::write(dir.join("a.txt"), "1").unwrap();
+        fs::write(dir.join("a.txt"), "2").unwrap();
+        fs::write(dir.join("node_modules/dep.js"), "").unwrap();
+        fs::remove_file(dir.join("old.txt")).unwrap();
+        fs::create_dir_all(dir.join("src/deep")).unwrap();
+        fs::write(dir.join("src/deep/lib.rs"), "").unwrap();
+
+        let changes = wait_for_changes(&events, 5);
+        let created = |path: &str| Change {
+            path: path.into(),
+            kind: ChangeKind::Created,
+        };
+        assert!(changes.contains(&created("a.txt")), "{changes:?}");
+        assert!(changes.contains(&created("src/deep/lib.rs")), "{changes:?}");
+        assert!(changes.contains(&Change {
+            path: "old.txt".into(),
+            kind: ChangeKind::Deleted,
+        }));
+        assert!(!changes.iter().any(|c| c.path.contains("node_modules")));
+        let first = events.lock().unwrap()[0].clone();
+        assert_eq!(first["watch_id"], watch_id.as_str());
+        assert_eq!(first["node_id"], "n1");
+        assert_eq!(first["path"], serde_json::json!(dir));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unwatch_and_disconnect_stop_events() {
+        let dir = temp_dir("unwatch");
+        let watches = Watches::default();
+        let (client, events) = collecting_client(&watches);
+        let (other, other_events) = collecting_client(&watches);
+        let watch_id = watches.watch(&client, &dir, None, &[]).unwrap();
+        watches.watch(&other, &dir, None, &[]).unwrap();
+
+        assert!(watches.unwatch(other.id(), &watch_id).is_err());
+        watches.unwatch(client.id(), &watch_id).unwrap();
+        assert!(watches.unwatch(client.id(), &watch_id).is_err());
+        fs::write(dir.join("a.txt"), "").unwrap();
+        assert_eq!(wait_for_changes(&other_events, 1).len(), 1);
+        assert!(events.lock().unwrap().is_empty());
+
+        watches.drop_client(other.id());
+        assert!(watches.watchers.lock().unwrap().is_empty());
+
+        for _ in 0..MAX_WATCHES_PER_CLIENT {
+            watches.watch(&client, &dir, None, &[]).unwrap();
+        }
+        assert!(watches
+            .watch(&client, &dir, None, &[])
+            .unwrap_err()
+            .starts_with("Too many watches"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn different_ignore_lists_get_their_own_watchers() {
+        let dir = temp_dir("ignore");
+        let watches = Watches::default();
+        let (skipping, skipping_events) = collecting_client(&watches);
+        let (all, all_events) = collecting_client(&watches);
+        let ignore = vec!["node_modules".to_string()];
+        watches.watch(&skipping, &dir, None, &ignore).unwrap();
+        watches.watch(&all, &dir, None, &[]).unwrap();
+        assert_eq!(watches.watchers.lock().unwrap().len(), 2);
+
+        fs::write(dir.join("node_modules/dep.js"), "").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let seen = wait_for_changes(&all_events, 2);
+        assert!(
+            seen.iter().any(|c| c.path == "node_modules/dep.js"),
+            "{seen:?}"
+        );
+        let skipped = wait_for_changes(&skipping_events, 1);
+        assert!(!skipped.iter().any(|c| c.path.contains("node_modules")));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn batches_merge_repeated_changes() {
+        use ChangeKind::*;
+        let mut batch = Batch::default();
+        batch.add("new".into(), Created);
+        batch.add("new".into(), Modified);
+        batch.add("saved".into(), Deleted);
+        batch.add("saved".into(), Created);
+        batch.add("temp".into(), Created);
+        batch.add("temp".into(), Deleted);
+        let changes: Vec<_> = batch.changes.into_iter().collect();
+        assert_eq!(
+            changes,
+            [
+                ("new".to_string(), Created),
+                ("saved".to_string(), Modified)
+            ]
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! Git worktrees for spawned agents.
+//!
+//! `spawn_agent` with a `worktree` option checks out a new branch into its
+//! own worktree and starts the agent there, so the main checkout is left
+//! alone. The worktree is created next to the repository, named
+//! `new_folder`, and is subject to the same `allowed_roots` confinement as
+//! any spawn path. Worktrees outlive their agents; `list_worktrees` and
+//! `remove_worktree` clean them up.
+
+use crate::fs_browser::Roots;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tracing::info;
+
+/// The `worktree` option of `spawn_agent`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorktreeSpec {
+    /// New branch to create and check out.
+    pub branch: String,
+    /// Commit to branch from. Defaults to the repository's `HEAD`.
+    #[serde(default)]
+    pub base: Option<String>,
+}
+
+/// One entry of `git worktree list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Worktree {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    /// Checked-out branch, without `refs/heads/`. `None` when detached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// The repository's main checkout, which cannot be removed.
+    pub main: bool,
+    pub locked: bool,
+    /// Its directory is gone; `git worktree prune` would drop it.
+    pub prunable: bool,
+}
+
+pub(crate) fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Top level of the repository containing `path`, which must be within
+/// `roots`.
+fn repo_root(path: &str, roots: &Roots) -> Result<PathBuf, String> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("Invalid path: {e}"))?;
+    roots.check(&canonical)?;
+    let top = git(&canonical, &["rev-parse", "--show-toplevel"])
+        .map_err(|_| "Path is not inside a git repository".to_string())?;
+    let top = fs::canonicalize(top.trim()).map_err(|e| format!("Invalid path: {e}"))?;
+    if !roots.contains(&top) {
+        return Err("Repository resolves outside allowed roots".into());
+    }
+    Ok(top)
+}
+
+/// Create a worktree for `spec.branch` next to the repository containing
+/// `path`, in a new directory `folder`. Returns its canonical path.
+pub fn create(
+    path: &str,
+    folder: &str,
+    spec: &WorktreeSpec,
+    roots: &Roots,
+) -> Result<PathBuf, String> {
+    let folder = folder.trim();
+    if folder.is_empty() {
+        return Err("A worktree needs new_folder".into());
+    }
+    if folder == "." || folder == ".." || folder.contains(['/', '\\']) {
+        return Err("new_folder must be a single directory name for a worktree".into());
+    }
+    let repo = repo_root(path, roots)?;
+    let parent = repo
+        .parent()
+        .ok_or("Cannot create a worktree next to the filesystem root")?;
+    if !roots.contains(parent) {
+        return Err("Worktree would be outside allowed roots".into());
+    }
+    let target = parent.join(folder);
+    // The folder itself may be a denied path even though its parent is not.
+    roots.check(&target)?;
+    if target.exists() {
+        return Err(format!("{} already exists", target.display()));
+    }
+    if spec.branch.starts_with('-')
+        || git(&repo, &["check-ref-format", "--branch", &spec.branch]).is_err()
+    {
+        return Err(format!("Invalid branch name: {}", spec.branch));
+    }
+
+    let target_str = target.to_string_lossy();
+    let mut args = vec!["worktree", "add", "-b", &spec.branch, &target_str];
+    if let Some(base) = &spec.base {
+        if base.starts_with('-') {
+            return Err(format!("Invalid base: {base}"));
+        }
+        args.push(base);
+    }
+    git(&repo, &args)?;
+    info!(repo = %repo.display(), worktree = %target.display(), branch = %spec.branch, "Worktree created");
+    // Something may have swapped a symlink in while git ran; never hand out
+    // a worktree that resolves outside the roots.
+    let canonical = fs::canonicalize(&target).map_err(|e| format!("Invalid path: {e}"))?;
+    if let Err(e) = roots.check(&canonical) {
+        let _ = git(&repo, &["worktree", "remove", "--force", &target_str]);
+        return Err(e);
+    }
+    Ok(canonical)
+}
+
+/// Every worktree of the repository containing `path`.
+pub fn list(path: &str, roots: &Roots) -> Result<Vec<Worktree>, String> {
+    let repo = repo_root(path, roots)?;
+    Ok(parse_porcelain(&git(
+        &repo,
+        &["worktree", "list", "--porcelain"],
+    )?))
+}
+
+fn parse_porcelain(text: &str) -> Vec<Worktree> {
+    let mut worktrees: Vec<Worktree> = Vec::new();
+    for line in text.lines() {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        if key == "worktree" {
+            worktrees.push(Worktree {
+                path: value.to_string(),
+                head: None,
+                branch: None,
+                main: worktrees.is_empty(),
+                locked: false,
+                prunable: false,
+            });
+            continue;
+        }
+        let Some(current) = worktrees.last_mut() else {
+            continue;
+        };
+        match key {
+            "HEAD" => current.head = Some(value.to_string()),
+            "branch" => current.branch = Some(value.trim_start_matches("refs/heads/").to_string()),
+            "locked" => current.locked = true,
+            "prunable" => current.prunable = true,
+            _ => {}
+        }
+    }
+    worktrees
+}
+
+/// Remove the linked worktree at `path`. `force` discards uncommitted
+/// changes. The branch is kept.
+pub fn remove(path: &str, force: bool, roots: &Roots) -> Result<(), String> {
+    let canonical = fs::canonicalize(path).map_err(|e| format!("Invalid path: {e}"))?;
+    let worktrees = list(path, roots)?;
+    let worktree = worktrees
+        .iter()
+        .find(|w| Path::new(&w.path) == canonical)
+        .ok_or("Path is not the top of a worktree")?;
+    if worktree.main {
+        return Err("Cannot remove the main worktree".into());
+    }
+    let main = Path::new(&worktrees[0].path);
+    let mut args = vec!["worktree", "remove"];
+    if force {
+        args.push("--force");
+    }
+    args.push(&worktree.path);
+    git(main, &args)?;
+    info!(worktree = %worktree.path, "Worktree removed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A repository with one commit in a fresh directory, which is also the
+    /// only allowed root.
+    fn temp_repo(name: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("hypi_wt_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let repo = root.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        git(&repo, &["init", "-q"]).unwrap();
+        git(
+            &repo,
+            &[
+                "-c",
+                "user.name=t",
+                "-c",
+                "user.email=t@t",
+                "commit",
+                "-q",
+                "--allow-empty",
+                "-m",
+                "init",
+            ],
+        )
+        .unwrap();
+        (root, repo)
+    }
+
+    fn spec(branch: &str) -> WorktreeSpec {
+        WorktreeSpec {
+            branch: branch.into(),
+            base: None,
+        }
+    }
+
+    #[test]
+    fn creates_lists_and_removes_worktree() {
+        let (root, repo) = temp_repo("cycle");
+        let roots = Roots::new(vec![root.clone()], vec![]);
+        let repo_str = repo.to_str().unwrap();
+
+        let created = create(repo_str, "feature", &spec("feat/x"), &roots).unwrap();
+        assert_eq!(created, root.join("feature"));
+
+        let worktrees = list(created.to_str().unwrap(), &roots).unwrap();
+        assert_eq!(worktrees.len(), 2);
+        assert!(worktrees[0].main);
+        assert_eq!(worktrees[1].branch.as_deref(), Some("feat/x"));
+
+        assert!(create(repo_str, "feature", &spec("other"), &roots)
+            .unwrap_err()
+            .ends_with("already exists"));
+        assert_eq!(
+            remove(repo_str, false, &roots).unwrap_err(),
+            "Cannot remove the main worktree"
+        );
+        remove(created.to_str().unwrap(), false, &roots).unwrap();
+        assert!(!created.exists());
+        assert_eq!(list(repo_str, &roots).unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let (root, repo) = temp_repo("bad");
+        let roots = Roots::new(vec![root.clone()], vec![]);
+        let repo_str = repo.to_str().unwrap();
+
+        for folder in ["", "a/b", ".."] {
+            assert!(create(repo_str, folder, &spec("b"), &roots).is_err());
+        }
+        assert_eq!(
+            create(repo_str, "wt", &spec("bad..name"), &roots).unwrap_err(),
+            "Invalid branch name: bad..name"
+        );
+        assert_eq!(
+            create(root.to_str().unwrap(), "wt", &spec("b"), &roots).unwrap_err(),
+            "Path is not inside a git repository"
+        );
+        // The worktree would land next to the repo, outside a root that is
+        // the repo itself.
+        assert_eq!(
+            create(
+                repo_str,
+                "wt",
+                &spec("b"),
+                &Roots::new(vec![repo.clone()], vec![])
+            )
+            .unwrap_err(),
+            "Worktree would be outside allowed roots"
+        );
+        assert!(!root.join("wt").exists());
+        // The folder itself is denied, though its parent is allowed.
+        let denied = Roots::new(vec![root.clone()], vec![root.join("secrets")]);
+        assert_eq!(
+            create(repo_str, "secrets", &spec("b"), &denied).unwrap_err(),
+            "Path is inside a denied directory"
+        );
+        assert!(!root.join("secrets").exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn parses_porcelain_output() {
+        let text = "worktree /src/api\nHEAD abc\nbranch refs/heads/main\n\n\
+                    worktree /src/fix\nHEAD def\ndetached\nlocked\n\n\
+                    worktree /src/gone\nHEAD 123\nbranch refs/heads/old\nprunable gitdir file points to non-existent location\n";
+        let worktrees = parse_porcelain(text);
+        assert_eq!(worktrees.len(), 3);
+        assert!(worktrees[0].main && !worktrees[1].main);
+        assert_eq!(worktrees[0].branch.as_deref(), Some("main"));
+        assert_eq!(worktrees[1].branch, None);
+        assert!(worktrees[1].locked);
+        assert!(worktrees[2].prunable);
+    }
+}
@@ -8,20 +8,34 @@ use futures_util::{SinkExt, StreamExt};
 use serde_json::{json, Value};
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::time::Duration;
 use tokio_tungstenite::tungstenite::Message;
 
 /// Start the hypivisor in-process on a random port.
 /// Returns (port, shutdown_handle).
 fn start_server(token: &str) -> (u16, Box<dyn FnOnce() + Send>) {
+    start_server_with_config(token, None)
+}
+
+/// Like `start_server`, but with a config file that can be hot-reloaded.
+fn start_server_with_config(
+    token: &str,
+    config_path: Option<PathBuf>,
+) -> (u16, Box<dyn FnOnce() + Send>) {
     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
     let port = listener.local_addr().unwrap().port();
 
-    let config = hypivisor::ServerConfig {
-        port,
-        node_ttl: 3600,
-        secret_token: token.to_string(),
+    let source = hypivisor::config::ConfigSource {
+        path: config_path,
+        overrides: hypivisor::config::ConfigLayer {
+            port: Some(port),
+            node_ttl: Some(3600),
+            tokens: Some(vec![token.to_string()]),
+            ..Default::default()
+        },
     };
+    let config = hypivisor::ServerConfig::load(&source).unwrap();
 
     let state = hypivisor::create_state(&config);
 
@@ -256,6 +270,152 @@ async fn registry_broadcast_node_joined() {
     ws2.close(None).await.ok();
 }
 
+#[tokio::test]
+async fn update_node_broadcasts_node_updated() {
+    let (port, _shutdown) = start_server("");
+
+    let mut agent = connect_ws(port, "/ws", "").await;
+    let mut dashboard = connect_ws(port, "/ws", "").await;
+    let _init1 = recv_json(&mut agent).await;
+    let _init2 = recv_json(&mut dashboard).await;
+
+    let _resp = send_rpc(
+        &mut agent,
+        "register",
+        Some(json!({
+            "id": "meta-node",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9993,
+            "status": "active",
+            "session_name": "docs"
+        })),
+    )
+    .await;
+    let joined = recv_json(&mut dashboard).await;
+    assert_eq!(joined["node"]["session_name"], "docs");
+
+    let resp = send_rpc(
+        &mut agent,
+        "update_node",
+        Some(json!({ "id": "meta-node", "labels": { "project": "site" } })),
+    )
+    .await;
+    assert!(resp.get("error").is_none());
+
+    let event = recv_json(&mut dashboard).await;
+    assert_eq!(event["event"], "node_updated");
+    assert_eq!(event["node"]["session_name"], "docs");
+    assert_eq!(event["node"]["labels"]["project"], "site");
+
+    agent.close(None).await.ok();
+    dashboard.close(None).await.ok();
+}
+
+#[tokio::test]
+async fn report_status_broadcasts_node_status_changed() {
+    let (port, _shutdown) = start_server("");
+
+    let mut agent = connect_ws(port, "/ws", "").await;
+    let mut dashboard = connect_ws(port, "/ws", "").await;
+    let _init1 = recv_json(&mut agent).await;
+    let _init2 = recv_json(&mut dashboard).await;
+
+    let _resp = send_rpc(
+        &mut agent,
+        "register",
+        Some(json!({
+            "id": "status-node",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9992,
+            "status": "active",
+            "agent_status": "idle"
+        })),
+    )
+    .await;
+    let joined = recv_json(&mut dashboard).await;
+    assert_eq!(joined["node"]["agent_status"], "idle");
+
+    for _ in 0..2 {
+        let resp = send_rpc(
+            &mut agent,
+            "report_status",
+            Some(json!({ "id": "status-node", "status": "working" })),
+        )
+        .await;
+        assert_eq!(resp["result"]["agent_status"], "working");
+    }
+    let resp = send_rpc(
+        &mut agent,
+        "report_status",
+        Some(json!({ "id": "status-node", "status": "error" })),
+    )
+    .await;
+    assert!(resp.get("error").is_none());
+
+    // The repeated "working" report is not broadcast again.
+    let event = recv_json(&mut dashboard).await;
+    assert_eq!(event["event"], "node_status_changed");
+    assert_eq!(event["id"], "status-node");
+    assert_eq!(event["agent_status"], "working");
+    let event = recv_json(&mut dashboard).await;
+    assert_eq!(event["agent_status"], "error");
+
+    agent.close(None).await.ok();
+    dashboard.close(None).await.ok();
+}
+
+#[tokio::test]
+async fn set_agent_card_broadcasts_agent_card_updated() {
+    let (port, _shutdown) = start_server("");
+
+    let mut agent = connect_ws(port, "/ws", "").await;
+    let mut dashboard = connect_ws(port, "/ws", "").await;
+    let _init1 = recv_json(&mut agent).await;
+    let _init2 = recv_json(&mut dashboard).await;
+
+    let _resp = send_rpc(
+        &mut agent,
+        "register",
+        Some(json!({
+            "id": "card-node",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9991,
+            "status": "active",
+            "card": { "tools": [{ "name": "bash" }] }
+        })),
+    )
+    .await;
+    let joined = recv_json(&mut dashboard).await;
+    assert!(joined["node"].get("card").is_none());
+
+    let resp = send_rpc(
+        &mut agent,
+        "set_agent_card",
+        Some(json!({ "id": "card-node", "card": { "current_task": "Write docs" } })),
+    )
+    .await;
+    assert!(resp.get("error").is_none());
+
+    let event = recv_json(&mut dashboard).await;
+    assert_eq!(event["event"], "agent_card_updated");
+    assert_eq!(event["id"], "card-node");
+
+    let resp = send_rpc(
+        &mut dashboard,
+        "get_agent_card",
+        Some(json!({ "id": "card-node" })),
+    )
+    .await;
+    assert_eq!(resp["result"]["card"]["current_task"], "Write docs");
+    assert_eq!(resp["result"]["card"]["tools"], json!([]));
+
+    agent.close(None).await.ok();
+    dashboard.close(None).await.ok();
+}
+
 #[tokio::test]
 async fn proxy_to_missing_node_returns_error() {
     let (port, _shutdown) = start_server("");
@@ -371,6 +531,319 @@ async fn registry_node_offline_on_disconnect() {
     dashboard.close(None).await.ok();
 }
 
+#[tokio::test]
+async fn reload_config_rotates_tokens_without_dropping_sessions() {
+    let path = std::env::temp_dir().join("hypi_test_integration_reload.toml");
+    let admin = "admin_tokens = [\"admin-token\"]\n";
+    std::fs::write(&path, format!("tokens = [\"old-token\"]\n{admin}")).unwrap();
+    let (port, _shutdown) = start_server_with_config("", Some(path.clone()));
+
+    let mut ws = connect_ws(port, "/ws", "old-token").await;
+    let _init = recv_json(&mut ws).await;
+    let mut admin_ws = connect_ws(port, "/ws", "admin-token").await;
+    let _init = recv_json(&mut admin_ws).await;
+
+    std::fs::write(&path, format!("tokens = [\"new-token\"]\n{admin}")).unwrap();
+    // Plain tokens may not reload the config
+    let resp = send_rpc(&mut ws, "reload_config", None).await;
+    assert!(resp["error"].as_str().unwrap().contains("Unauthorized"));
+    let resp = send_rpc(&mut admin_ws, "reload_config", None).await;
+    assert_eq!(resp["result"]["status"], "reloaded");
+
+    // The already-open session keeps working
+    let resp = send_rpc(&mut ws, "ping", None).await;
+    assert_eq!(resp["result"]["status"], "healthy");
+
+    // New connections must use the new token
+    let mut new_ws = connect_ws(port, "/ws", "new-token").await;
+    let init = recv_json(&mut new_ws).await;
+    assert_eq!(init["event"], "init");
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+    stream
+        .write_all(b"GET /ws?token=old-token HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGVzdA==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).contains("401"));
+
+    new_ws.close(None).await.ok();
+    admin_ws.close(None).await.ok();
+    ws.close(None).await.ok();
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Start an in-process server whose only listener is restricted to `role`.
+fn start_server_with_role(role: hypivisor::listener::ListenerRole) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = hypivisor::ServerConfig {
+        port,
+        node_ttl: 3600,
+        ..Default::default()
+    };
+    let state = hypivisor::create_state(&config);
+    std::thread::spawn(move || hypivisor::serve_with_role(listener, state, role));
+    port
+}
+
+#[tokio::test]
+async fn agents_listener_serves_registration_only() {
+    let port = start_server_with_role(hypivisor::listener::ListenerRole::Agents);
+    let mut ws = connect_ws(port, "/ws", "").await;
+
+    // No init roster is pushed; the first message is our RPC response
+    let resp = send_rpc(
+        &mut ws,
+        "register",
+        Some(json!({
+            "id": "agent-only",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9990,
+            "status": "active"
+        })),
+    )
+    .await;
+    assert_eq!(resp["result"]["status"], "registered");
+
+    let resp = send_rpc(&mut ws, "list_nodes", None).await;
+    assert!(resp["error"]
+        .as_str()
+        .unwrap()
+        .contains("not allowed on this listener"));
+
+    // The proxy route is hidden
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+    stream
+        .write_all(b"GET /ws/agent/agent-only HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGVzdA==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).contains("404"));
+
+    ws.close(None).await.ok();
+}
+
+#[tokio::test]
+async fn dashboard_listener_refuses_register() {
+    let port = start_server_with_role(hypivisor::listener::ListenerRole::Dashboard);
+    let mut ws = connect_ws(port, "/ws", "").await;
+    let init = recv_json(&mut ws).await;
+    assert_eq!(init["event"], "init");
+
+    let resp = send_rpc(
+        &mut ws,
+        "register",
+        Some(json!({
+            "id": "sneaky",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9991,
+            "status": "active"
+        })),
+    )
+    .await;
+    assert!(resp["error"].is_string());
+
+    let resp = send_rpc(&mut ws, "list_nodes", None).await;
+    assert_eq!(resp["result"].as_array().unwrap().len(), 0);
+
+    ws.close(None).await.ok();
+}
+
+/// The Unix socket needs no token even when the TCP listener does.
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_serves_registry_without_token() {
+    let dir = std::env::temp_dir().join(format!("hypi-it-unix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hypivisor.sock");
+    let config = hypivisor::ServerConfig {
+        node_ttl: 3600,
+        tokens: vec!["tcp-only".to_string()],
+        ..Default::default()
+    };
+    let state = hypivisor::create_state(&config);
+    let listener = hypivisor::listener::bind_unix(&path).unwrap();
+    std::thread::spawn(move || hypivisor::serve(listener, state));
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async("ws://localhost/ws", stream)
+        .await
+        .unwrap();
+
+    let next_json = |msg: Option<Result<Message, _>>| -> Value {
+        match msg {
+            Some(Ok(Message::Text(text))) => serde_json::from_str(text.as_ref()).unwrap(),
+            other => panic!("Expected text message, got: {other:?}"),
+        }
+    };
+    let init = next_json(ws.next().await);
+    assert_eq!(init["event"], "init");
+
+    let req = json!({
+        "id": "u1",
+        "method": "register",
+        "params": {
+            "id": "local-agent",
+            "machine": "127.0.0.1",
+            "cwd": "/tmp",
+            "port": 9992,
+            "status": "active"
+        }
+    });
+    ws.send(Message::Text(req.to_string().into()))
+        .await
+        .unwrap();
+    loop {
+        let msg = next_json(ws.next().await);
+        if msg["id"] == "u1" {
+            assert_eq!(msg["result"]["status"], "registered");
+            break;
+        }
+    }
+
+    ws.close(None).await.ok();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// SIGTERM runs the normal shutdown, which removes both socket files. This
+/// needs a real process, so the binary is started as a subprocess.
+#[cfg(unix)]
+#[test]
+fn sigterm_removes_socket_files() {
+    use std::time::Instant;
+
+    let dir = std::env::temp_dir().join(format!("hypi-it-sigterm-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hypivisor.sock");
+    let agents = hypivisor::listener::agents_socket_path(&path);
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_hypivisor"))
+        .args(["--listen", "127.0.0.1:0", "--unix-socket"])
+        .arg(&path)
+        .env("HOME", &dir)
+        .env_remove("HYPIVISOR_CONFIG")
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !(path.exists() && agents.exists()) {
+        assert!(Instant::now() < deadline, "sockets never appeared");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("hypivisor did not exit on SIGTERM");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "{status:?}");
+    assert!(!path.exists());
+    assert!(!agents.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Read binary frames until their combined text contains `needle`.
+#[cfg(unix)]
+async fn recv_terminal_until(
+    ws: &mut tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >,
+    needle: &str,
+) -> String {
+    let mut seen = Vec::new();
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), ws.next()).await {
+            Ok(Some(Ok(Message::Binary(data)))) => seen.extend_from_slice(&data),
+            Ok(Some(Ok(_))) => {}
+            other => panic!(
+                "terminal ended before {needle:?} ({other:?}), saw {:?}",
+                String::from_utf8_lossy(&seen)
+            ),
+        }
+        let text = String::from_utf8_lossy(&seen);
+        if text.contains(needle) {
+            return text.into_owned();
+        }
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn pty_terminal_streams_output_and_accepts_input() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let root = std::env::temp_dir().canonicalize().unwrap();
+    let config = hypivisor::ServerConfig {
+        port,
+        node_ttl: 3600,
+        spawn_command: "sh".into(),
+        spawn_backend: hypivisor::backend::BackendKind::Pty,
+        allowed_roots: vec![root.clone()],
+        ..Default::default()
+    };
+    let state = hypivisor::create_state(&config);
+    std::thread::spawn(move || hypivisor::serve(listener, state));
+
+    let mut ws = connect_ws(port, "/ws", "").await;
+    let _init = recv_json(&mut ws).await;
+    let resp = send_rpc(
+        &mut ws,
+        "spawn_agent",
+        Some(json!({
+            "path": root,
+            "args": ["-c", "echo ready; read line; echo \"got-$line\"; read line; stty size; sleep 1"]
+        })),
+    )
+    .await;
+    assert_eq!(resp["result"]["backend"], "pty");
+    let spawn_id = resp["result"]["spawn_id"].as_str().unwrap().to_string();
+
+    let mut first = connect_ws(port, &format!("/ws/pty/{spawn_id}"), "").await;
+    recv_terminal_until(&mut first, "ready").await;
+
+    // A second viewer starts with the scrollback.
+    let mut second = connect_ws(port, &format!("/ws/pty/{spawn_id}"), "").await;
+    recv_terminal_until(&mut second, "ready").await;
+
+    first
+        .send(Message::Binary(b"abc\n".to_vec().into()))
+        .await
+        .unwrap();
+    recv_terminal_until(&mut first, "got-abc").await;
+    recv_terminal_until(&mut second, "got-abc").await;
+
+    // Frames from one viewer are applied in order.
+    second
+        .send(Message::Text(
+            json!({ "type": "resize", "rows": 30, "cols": 100 })
+                .to_string()
+                .into(),
+        ))
+        .await
+        .unwrap();
+    second
+        .send(Message::Text(
+            json!({ "type": "input", "data": "\n" }).to_string().into(),
+        ))
+        .await
+        .unwrap();
+    recv_terminal_until(&mut first, "30 100").await;
+    recv_terminal_until(&mut second, "30 100").await;
+
+    let mut missing = connect_ws(port, "/ws/pty/sp-missing", "").await;
+    let err = recv_json(&mut missing).await;
+    assert_eq!(err["error"], "No terminal for this spawn ID");
+}
+
 #[tokio::test]
 async fn multiple_agents_same_cwd_both_register() {
     let (port, _shutdown) = start_server("");
@@ -428,6 +901,49 @@ async fn list_directories_via_rpc() {
     ws.close(None).await.ok();
 }
 
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn watch_directory_pushes_fs_changed_until_unwatched() {
+    let dir = PathBuf::from(std::env::var("HOME").unwrap())
+        .join(format!(".hypi_it_watch_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = dir.canonicalize().unwrap();
+
+    let (port, _shutdown) = start_server("");
+    let mut ws = connect_ws(port, "/ws", "").await;
+    let _init = recv_json(&mut ws).await;
+
+    let resp = send_rpc(&mut ws, "watch_directory", Some(json!({ "path": dir }))).await;
+    let watch_id = resp["result"]["watch_id"].as_str().unwrap().to_string();
+    assert_eq!(resp["result"]["path"], json!(dir));
+
+    std::fs::write(dir.join("notes.md"), "hi").unwrap();
+    let event = recv_json(&mut ws).await;
+    assert_eq!(event["event"], "fs_changed");
+    assert_eq!(event["watch_id"], watch_id.as_str());
+    assert_eq!(
+        event["changes"],
+        json!([{ "path": "notes.md", "kind": "created" }])
+    );
+
+    let resp = send_rpc(
+        &mut ws,
+        "unwatch_directory",
+        Some(json!({ "watch_id": watch_id })),
+    )
+    .await;
+    assert!(resp.get("error").is_none());
+    std::fs::write(dir.join("later.md"), "").unwrap();
+    let resp = send_rpc(&mut ws, "ping", None).await;
+    assert!(resp["result"].is_object());
+    let quiet = tokio::time::timeout(Duration::from_millis(600), ws.next()).await;
+    assert!(quiet.is_err(), "unexpected message: {quiet:?}");
+
+    ws.close(None).await.ok();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
 #[tokio::test]
 async fn unknown_rpc_method_returns_error() {
     let (port, _shutdown) = start_server("");
@@ -534,11 +1050,6 @@ fn handle_echo_client(mut stream: TcpStream) {
         .trim();
 
     // Compute accept key
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    let magic = format!("{key}258EAFA5-E914-47DA-95CA-5AB5DC11650A");
-    // Use SHA1 from the standard approach — for test purposes, just use a simple accept
-    // Actually we need a proper SHA1 accept key. Let's use a raw computation.
     let accept = compute_ws_accept(key);
 
     let response = format!(
@@ -629,8 +1140,6 @@ fn handle_echo_client(mut stream: TcpStream) {
 
 fn compute_ws_accept(key: &str) -> String {
     // SHA-1 of key + magic string, then base64
-    use std::io::Write;
-
     let magic = "258EAFA5-E914-47DA-95CA-5AB5DC11650A";
     let input = format!("{key}{magic}");
 
@@ -672,7 +1181,7 @@ fn sha1_hash(data: &[u8]) -> [u8; 20] {
         }
 
         let (mut a, mut b, mut c, mut d, mut e) = (h0, h1, h2, h3, h4);
-        for i in 0..80 {
+        for (i, wi) in w.iter().enumerate() {
             let (f, k) = match i {
                 0..=19 => ((b & c) | ((!b) & d), 0x5A827999u32),
                 20..=39 => (b ^ c ^ d, 0x6ED9EBA1u32),
@@ -684,7 +1193,7 @@ fn sha1_hash(data: &[u8]) -> [u8; 20] {
                 .wrapping_add(f)
                 .wrapping_add(e)
                 .wrapping_add(k)
-                .wrapping_add(w[i]);
+                .wrapping_add(*wi);
             e = d;
             d = c;
             c = b.rotate_left(30);
@@ -0,0 +1,33 @@
+import { describe, it, expect } from "vitest";
+import { buildAgentCard } from "./card.js";
+
+describe("buildAgentCard", () => {
+  it("lists tools, commands and skills apart", () => {
+    const card = buildAgentCard(
+      [{ name: "bash", description: "Run bash" }, { name: "read", description: "" }],
+      [
+        { name: "reload", description: "Reload extensions", source: "extension" },
+        { name: "skill:harden", description: "Harden app", source: "skill" },
+      ],
+      "/home/me/projects/site",
+    );
+    expect(card).toEqual({
+      tools: [{ name: "bash", description: "Run bash" }, { name: "read" }],
+      skills: [{ name: "harden", description: "Harden app" }],
+      commands: [{ name: "reload", description: "Reload extensions" }],
+      project: "site",
+    });
+  });
+
+  it("keeps within the limits the hypivisor checks", () => {
+    const tools = Array.from({ length: 1200 }, (_, i) => ({ name: `tool-${i}` }));
+    const card = buildAgentCard(
+      [...tools, { name: " " }],
+      [{ name: "long", description: "😀".repeat(5000), source: "prompt" }],
+      "/",
+    );
+    expect(card.tools).toHaveLength(1000);
+    expect(Array.from(card.commands[0].description!)).toHaveLength(4096);
+    expect(card.project).toBeUndefined();
+  });
+});
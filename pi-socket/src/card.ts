@@ -0,0 +1,48 @@
+import path from "node:path";
+import type { AgentCard, AgentCardItem } from "./types.js";
+
+/** Limits the hypivisor checks cards against; longer cards are refused. */
+const MAX_ITEMS = 1000;
+const MAX_NAME_CHARS = 200;
+const MAX_TEXT_CHARS = 4096;
+
+interface Named {
+  name: string;
+  description?: string;
+  source?: string;
+}
+
+/** Cut to `max` code points, which is what the hypivisor counts. */
+function clip(text: string, max: number): string {
+  const chars = Array.from(text);
+  return chars.length > max ? chars.slice(0, max).join("") : text;
+}
+
+function items(entries: Named[], prefix = ""): AgentCardItem[] {
+  return entries
+    .map(({ name, description }) => {
+      const bare = name.startsWith(prefix) ? name.slice(prefix.length) : name;
+      const item: AgentCardItem = { name: clip(bare, MAX_NAME_CHARS) };
+      if (description) item.description = clip(description, MAX_TEXT_CHARS);
+      return item;
+    })
+    .filter((item) => item.name.trim() !== "")
+    .slice(0, MAX_ITEMS);
+}
+
+/**
+ * Build the agent card sent on register: pi's tools, its slash commands
+ * with skills listed apart (without the `skill:` prefix), and the project
+ * directory name.
+ */
+export function buildAgentCard(tools: Named[], commands: Named[], cwd: string): AgentCard {
+  const skills = commands.filter((c) => c.source === "skill");
+  const card: AgentCard = {
+    tools: items(tools),
+    skills: items(skills, "skill:"),
+    commands: items(commands.filter((c) => c.source !== "skill")),
+  };
+  const project = path.basename(cwd);
+  if (project) card.project = clip(project, MAX_TEXT_CHARS);
+  return card;
+}
@@ -0,0 +1,37 @@
+import { describe, it, expect, vi } from "vitest";
+import { trackDialogs } from "./dialogs.js";
+
+describe("trackDialogs", () => {
+  it("reports waiting from the first dialog until the last is answered", async () => {
+    const answers: Array<(value: string) => void> = [];
+    const ui = {
+      input: vi.fn((_title: string) => new Promise<string>((resolve) => answers.push(resolve))),
+      select: vi.fn((_title: string, _options: string[]) => new Promise<string>((resolve) => answers.push(resolve))),
+      notify: vi.fn((_message: string) => {}),
+    };
+    const changes: boolean[] = [];
+    trackDialogs(ui, (waiting) => changes.push(waiting));
+    trackDialogs(ui, (waiting) => changes.push(waiting));
+
+    const first = ui.input("Name?");
+    const second = ui.select("Pick one", ["a", "b"]);
+    ui.notify("not a dialog");
+    expect(changes).toEqual([true]);
+
+    answers[0]("pi");
+    expect(await first).toBe("pi");
+    expect(changes).toEqual([true]);
+    answers[1]("b");
+    expect(await second).toBe("b");
+    expect(changes).toEqual([true, false]);
+  });
+
+  it("stops waiting when a dialog fails", async () => {
+    const ui = { confirm: vi.fn(() => Promise.reject(new Error("closed"))) };
+    const changes: boolean[] = [];
+    trackDialogs(ui, (waiting) => changes.push(waiting));
+
+    await expect(ui.confirm()).rejects.toThrow("closed");
+    expect(changes).toEqual([true, false]);
+  });
+});
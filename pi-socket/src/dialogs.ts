@@ -0,0 +1,29 @@
+/** Dialogs that block pi until the user answers. */
+const BLOCKING = ["select", "confirm", "input", "editor"] as const;
+
+const tracked = new WeakSet<object>();
+
+/**
+ * Wrap the blocking dialogs of pi's UI so `onChange(true)` is called when
+ * the first one opens and `onChange(false)` once the last one is answered.
+ * The UI object is shared by all extensions, so dialogs opened by pi or any
+ * other extension are seen too. Wrapping the same UI twice is a no-op.
+ */
+export function trackDialogs(ui: object, onChange: (waiting: boolean) => void): void {
+  if (tracked.has(ui)) return;
+  tracked.add(ui);
+  const methods = ui as Record<string, unknown>;
+  let open = 0;
+  for (const name of BLOCKING) {
+    const original = methods[name];
+    if (typeof original !== "function") continue;
+    methods[name] = async (...args: unknown[]) => {
+      if (open++ === 0) onChange(true);
+      try {
+        return await original.apply(ui, args);
+      } finally {
+        if (--open === 0) onChange(false);
+      }
+    };
+  }
+}
@@ -1,5 +1,8 @@
 import { describe, it, expect, vi, beforeEach } from "vitest";
 import type { ExtensionAPI } from "@mariozechner/pi-coding-agent";
+import os from "node:os";
+import fs from "node:fs";
+import path from "node:path";
 
 // Create mock instances that will be reused
 let mockWssInstance: any;
@@ -8,6 +11,8 @@ let mockHypivisorWsInstance: any;
 // Mock modules BEFORE importing the module under test
 vi.mock("./log.js");
 
+vi.mock("@mariozechner/pi-coding-agent", () => ({ VERSION: "0.0.0-test" }));
+
 vi.mock("portfinder", () => ({
   default: {
     getPortPromise: vi.fn(() => Promise.resolve(8080)),
@@ -35,6 +40,7 @@ vi.mock("ws", () => {
 
 import piSocket from "./index.js";
 import * as log from "./log.js";
+import { gitBranch } from "./meta.js";
 import { WebSocketServer, WebSocket } from "ws";
 
 describe("pi-socket/index.ts", () => {
@@ -1087,6 +1093,33 @@ describe("pi-socket/index.ts", () => {
       );
     });
 
+    it("prefers HYPIVISOR_SOCKET when the socket file exists", async () => {
+      const { WebSocket } = await import("ws");
+      const sock = path.join(os.tmpdir(), `hypi-test-${process.pid}.sock`);
+      fs.writeFileSync(sock, "");
+      process.env.HYPIVISOR_SOCKET = sock;
+      try {
+        piSocket(mockPi as ExtensionAPI);
+        await piEventHandlers["session_start"][0]({}, mockCtx);
+        expect(WebSocket).toHaveBeenCalledWith(`ws+unix://${sock}:/ws`);
+      } finally {
+        delete process.env.HYPIVISOR_SOCKET;
+        fs.rmSync(sock, { force: true });
+      }
+    });
+
+    it("falls back to TCP when HYPIVISOR_SOCKET is missing", async () => {
+      const { WebSocket } = await import("ws");
+      process.env.HYPIVISOR_SOCKET = path.join(os.tmpdir(), "hypi-no-such.sock");
+      try {
+        piSocket(mockPi as ExtensionAPI);
+        await piEventHandlers["session_start"][0]({}, mockCtx);
+        expect(WebSocket).toHaveBeenCalledWith(expect.stringContaining("ws://"));
+      } finally {
+        delete process.env.HYPIVISOR_SOCKET;
+      }
+    });
+
     it("registers hypivisor handlers", async () => {
       piSocket(mockPi as ExtensionAPI);
 
@@ -1116,6 +1149,9 @@ describe("pi-socket/index.ts", () => {
         "tool_execution_start",
         "tool_execution_update",
         "tool_execution_end",
+        "agent_start",
+        "agent_end",
+        "model_select",
         "session_shutdown",
       ];
 
@@ -1164,6 +1200,128 @@ describe("pi-socket/index.ts", () => {
     });
   });
 
+  describe("Agent status", () => {
+    const sentRpcs = () =>
+      mockHypivisorWsInstance.send.mock.calls.map((call: any[]) => JSON.parse(call[0]));
+
+    it("registers as idle and reports status changes", async () => {
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+      mockHypivisorWsInstance.openHandler();
+
+      piEventHandlers["agent_start"][0]({ type: "agent_start" }, mockCtx);
+      piEventHandlers["agent_start"][0]({ type: "agent_start" }, mockCtx);
+      piEventHandlers["agent_end"][0](
+        { type: "agent_end", messages: [{ role: "assistant", content: [], stopReason: "error" }] },
+        mockCtx,
+      );
+      piEventHandlers["agent_end"][0](
+        { type: "agent_end", messages: [{ role: "assistant", content: [], stopReason: "stop" }] },
+        mockCtx,
+      );
+
+      const rpcs = sentRpcs();
+      expect(rpcs[0].method).toBe("register");
+      expect(rpcs[0].params.agent_status).toBe("idle");
+      // Repeated agent_start is reported once.
+      expect(rpcs.slice(1).map((rpc: any) => rpc.params.status)).toEqual(["working", "error", "idle"]);
+      expect(rpcs[1]).toMatchObject({ method: "report_status", params: { id: "session-123" } });
+    });
+
+    it("registers with the current status after a reconnect", async () => {
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+
+      // Not connected yet: the change is remembered, not sent.
+      piEventHandlers["agent_start"][0]({ type: "agent_start" }, mockCtx);
+      expect(mockHypivisorWsInstance.send).not.toHaveBeenCalled();
+
+      mockHypivisorWsInstance.openHandler();
+      expect(sentRpcs()[0].params.agent_status).toBe("working");
+    });
+
+    it("reports waiting_for_input while a dialog is open", async () => {
+      let answer!: (ok: boolean) => void;
+      mockCtx.ui.confirm = vi.fn(() => new Promise<boolean>((resolve) => { answer = resolve; }));
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+      mockHypivisorWsInstance.openHandler();
+
+      piEventHandlers["agent_start"][0]({ type: "agent_start" }, mockCtx);
+      const confirmed = mockCtx.ui.confirm("Run rm -rf?", "Allow this command?");
+      answer(true);
+      expect(await confirmed).toBe(true);
+
+      expect(sentRpcs().slice(1).map((rpc: any) => rpc.params.status)).toEqual([
+        "working",
+        "waiting_for_input",
+        "working",
+      ]);
+    });
+  });
+
+  describe("Node details", () => {
+    const sentRpcs = () =>
+      mockHypivisorWsInstance.send.mock.calls.map((call: any[]) => JSON.parse(call[0]));
+
+    it("registers with session, model, version and branch", async () => {
+      mockCtx.model = { id: "claude-sonnet", provider: "anthropic" };
+      mockCtx.sessionManager.getSessionName = vi.fn(() => "fix login");
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+      mockHypivisorWsInstance.openHandler();
+
+      expect(sentRpcs()[0].params).toMatchObject({
+        session_name: "fix login",
+        model: "claude-sonnet",
+        provider: "anthropic",
+        pi_version: "0.0.0-test",
+      });
+      expect(sentRpcs()[0].params.git_branch).toBe(gitBranch(process.cwd()));
+    });
+
+    it("sends update_node when the model or session name changes", async () => {
+      mockCtx.model = { id: "claude-sonnet", provider: "anthropic" };
+      let sessionName: string | undefined;
+      mockCtx.sessionManager.getSessionName = vi.fn(() => sessionName);
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+      mockHypivisorWsInstance.openHandler();
+
+      piEventHandlers["model_select"][0](
+        { type: "model_select", model: { id: "gpt-5", provider: "openai" } },
+        mockCtx,
+      );
+      // Same model again: nothing to send.
+      piEventHandlers["model_select"][0](
+        { type: "model_select", model: { id: "gpt-5", provider: "openai" } },
+        mockCtx,
+      );
+      sessionName = "refactor";
+      piEventHandlers["agent_end"][0]({ type: "agent_end", messages: [] }, mockCtx);
+
+      const updates = sentRpcs().filter((rpc: any) => rpc.method === "update_node");
+      expect(updates.map((rpc: any) => rpc.params)).toEqual([
+        { id: "session-123", model: "gpt-5", provider: "openai" },
+        { id: "session-123", session_name: "refactor" },
+      ]);
+    });
+  });
+
+  describe("Agent card", () => {
+    it("registers with an agent card of tools, commands and skills", async () => {
+      piSocket(mockPi as ExtensionAPI);
+      await piEventHandlers["session_start"][0]({}, mockCtx);
+      mockHypivisorWsInstance.openHandler();
+
+      const card = JSON.parse(mockHypivisorWsInstance.send.mock.calls[0][0]).params.card;
+      expect(card.tools).toEqual([{ name: "bash", description: "Run bash" }]);
+      expect(card.skills).toEqual([{ name: "harden", description: "Harden app" }]);
+      expect(card.commands.map((c: any) => c.name)).toEqual(["help", "reload"]);
+      expect(card.project).toBe(path.basename(process.cwd()));
+    });
+  });
+
   // ────────────────────────────────────────────────────────────
   // CRITICAL INVARIANT: Hypivisor crash MUST NOT affect pi agent
   // ────────────────────────────────────────────────────────────
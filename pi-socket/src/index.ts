@@ -24,16 +24,20 @@
  * All operational events are logged to ~/.pi/logs/pi-socket.jsonl as
  * structured JSONL. Errors needing attention are marked needsHardening.
  */
-import type { ExtensionAPI } from "@mariozechner/pi-coding-agent";
+import { VERSION as piVersion } from "@mariozechner/pi-coding-agent";
+import type { ExtensionAPI, ExtensionContext } from "@mariozechner/pi-coding-agent";
 import { WebSocketServer, WebSocket } from "ws";
 import portfinder from "portfinder";
 import os from "node:os";
 import fs from "node:fs";
 import path from "node:path";
 import { buildInitState, getHistoryPage } from "./history.js";
+import { buildAgentCard } from "./card.js";
+import { trackDialogs } from "./dialogs.js";
+import { gitBranch, metaChanges, type NodeMeta } from "./meta.js";
 import { boundary } from "./safety.js";
 import * as log from "./log.js";
-import type { RpcRequest, FetchHistoryRequest, AbortRequest, ListCommandsRequest, CommandInfo, CommandsListResponse, ListFilesRequest, FileInfo, FilesListResponse, AttachFileRequest, AttachFileResponse } from "./types.js";
+import type { RpcRequest, NodeAgentStatus, FetchHistoryRequest, AbortRequest, ListCommandsRequest, CommandInfo, CommandsListResponse, ListFilesRequest, FileInfo, FilesListResponse, AttachFileRequest, AttachFileResponse } from "./types.js";
 
 export default function piSocket(pi: ExtensionAPI) {
   let nodeId = process.pid.toString(); // fallback until session provides UUID
@@ -45,12 +49,29 @@ export default function piSocket(pi: ExtensionAPI) {
   let shutdownRequested = false;
   let reconnectTimer: ReturnType<typeof setTimeout> | null = null;
   let heartbeatInterval: ReturnType<typeof setInterval> | null = null;
+  // Sent on register and on every change so the roster shows what we are doing.
+  let agentStatus: NodeAgentStatus = "idle";
+  // What the agent is doing apart from dialogs, and whether one is open.
+  let runStatus: NodeAgentStatus = "idle";
+  let waitingForInput = false;
+  // Session, model and branch details as last sent; update_node sends changes.
+  let sessionCtx: ExtensionContext | null = null;
+  let model: { id: string; provider: string } | undefined;
+  let sentMeta: NodeMeta = {};
 
   const startPort = parseInt(process.env.PI_SOCKET_PORT || "8080", 10);
   const reconnectMs = parseInt(process.env.PI_SOCKET_RECONNECT_MS || "5000", 10);
   const reconnectMaxMs = 5 * 60 * 1000; // cap at 5 minutes
   const hypivisorUrl = process.env.HYPIVISOR_WS || "ws://localhost:31415/ws";
   const hypiToken = process.env.HYPI_TOKEN || "";
+  // Unix socket of a hypivisor on this host. Preferred over TCP whenever the
+  // socket file exists; its permissions replace the token.
+  const hypivisorSocket = process.env.HYPIVISOR_SOCKET || "";
+  // Set when the hypivisor spawned us; echoed on register so the spawner
+  // can tell which node_joined is ours. The secret proves the ID is ours;
+  // without it the hypivisor ignores the claim.
+  const spawnId = process.env.HYPI_SPAWN_ID || "";
+  const spawnSecret = process.env.HYPI_SPAWN_SECRET || "";
 
   log.info("pi-socket", "extension loaded", { nodeId, hypivisorUrl, startPort });
 
@@ -61,6 +82,14 @@ export default function piSocket(pi: ExtensionAPI) {
     // Use the stable session ID so hypivisor can deduplicate across restarts
     nodeId = ctx.sessionManager.getSessionId();
     shutdownRequested = false;
+    agentStatus = runStatus = "idle";
+    waitingForInput = false;
+    sessionCtx = ctx;
+    model = ctx.model;
+    trackDialogs(ctx.ui, (waiting) => {
+      waitingForInput = waiting;
+      reportStatus(runStatus);
+    });
 
     // Close previous WSS if session restarts — prevents stale port registrations
     if (wss) {
@@ -325,6 +354,22 @@ export default function piSocket(pi: ExtensionAPI) {
   pi.on("tool_execution_update", (event) => broadcast(event));
   pi.on("tool_execution_end", (event) => broadcast(event));
 
+  // ── Agent status ────────────────────────────────────────────
+  pi.on("agent_start", () => reportStatus("working"));
+  pi.on("agent_end", (event) => {
+    const last = event.messages[event.messages.length - 1];
+    const failed = last?.role === "assistant" && last.stopReason === "error";
+    reportStatus(failed ? "error" : "idle");
+    // The agent may have switched branches or named the session.
+    reportMeta();
+  });
+
+  // ── Node details ────────────────────────────────────────────
+  pi.on("model_select", (event) => {
+    model = event.model;
+    reportMeta();
+  });
+
   // ── Shutdown ────────────────────────────────────────────────
   pi.on("session_shutdown", async () => {
     log.info("pi-socket", "shutting down", { nodeId });
@@ -375,6 +420,52 @@ export default function piSocket(pi: ExtensionAPI) {
     }
   }
 
+  /**
+   * Record a status change and tell the hypivisor, if connected. While a
+   * dialog is open the agent is `waiting_for_input`, whatever it was doing.
+   */
+  function reportStatus(next: NodeAgentStatus): void {
+    runStatus = next;
+    const status: NodeAgentStatus = waitingForInput ? "waiting_for_input" : next;
+    if (status === agentStatus) return;
+    agentStatus = status;
+    const ws = hypivisorWs;
+    if (!hypivisorConnected || !ws || ws.readyState !== WebSocket.OPEN) return;
+    const rpc: RpcRequest = {
+      id: "status",
+      method: "report_status",
+      params: { id: nodeId, status },
+    };
+    ws.send(JSON.stringify(rpc));
+  }
+
+  /** Session, model and branch details of this node as they are now. */
+  function currentMeta(): NodeMeta {
+    return {
+      session_name: sessionCtx?.sessionManager.getSessionName?.() || undefined,
+      model: model?.id,
+      provider: model?.provider,
+      pi_version: piVersion,
+      git_branch: gitBranch(process.cwd()),
+    };
+  }
+
+  /** Send the details that changed since last sent, if connected. */
+  function reportMeta(): void {
+    const ws = hypivisorWs;
+    if (!hypivisorConnected || !ws || ws.readyState !== WebSocket.OPEN) return;
+    const meta = currentMeta();
+    const changes = metaChanges(sentMeta, meta);
+    if (!changes) return;
+    sentMeta = meta;
+    const rpc: RpcRequest = {
+      id: "meta",
+      method: "update_node",
+      params: { id: nodeId, ...changes },
+    };
+    ws.send(JSON.stringify(rpc));
+  }
+
   // ── Hypivisor lifecycle ──────────────────────────────────────
 
   /** Cleanly close the hypivisor WebSocket and cancel any pending reconnect/heartbeat. */
@@ -406,9 +497,11 @@ export default function piSocket(pi: ExtensionAPI) {
   function connectToHypivisor(port: number): void {
     if (!hypivisorUrlValid || shutdownRequested) return;
 
-    const url = hypiToken
-      ? `${hypivisorUrl}?token=${encodeURIComponent(hypiToken)}`
-      : hypivisorUrl;
+    const url = hypivisorSocket && fs.existsSync(hypivisorSocket)
+      ? `ws+unix://${hypivisorSocket}:/ws`
+      : hypiToken
+        ? `${hypivisorUrl}?token=${encodeURIComponent(hypiToken)}`
+        : hypivisorUrl;
 
     let ws: WebSocket;
     try {
@@ -422,6 +515,7 @@ export default function piSocket(pi: ExtensionAPI) {
 
     ws.on("open", boundary("hypivisor.open", () => {
       if (ws.readyState !== WebSocket.OPEN) return;
+      sentMeta = currentMeta();
       const rpc: RpcRequest = {
         id: "reg",
         method: "register",
@@ -432,6 +526,10 @@ export default function piSocket(pi: ExtensionAPI) {
           port,
           status: "active",
           pid: process.pid,
+          agent_status: agentStatus,
+          card: buildAgentCard(pi.getAllTools(), pi.getCommands(), process.cwd()),
+          ...sentMeta,
+          ...(spawnId ? { spawn_id: spawnId, spawn_secret: spawnSecret } : {}),
         },
       };
       ws.send(JSON.stringify(rpc));
@@ -444,6 +542,8 @@ export default function piSocket(pi: ExtensionAPI) {
       heartbeatInterval = setInterval(() => {
         if (ws.readyState === WebSocket.OPEN) {
           ws.ping();
+          // Catches branch switches made outside the agent.
+          reportMeta();
         }
       }, 30_000);
     }));
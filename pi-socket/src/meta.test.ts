@@ -0,0 +1,49 @@
+import { describe, it, expect, afterEach } from "vitest";
+import fs from "node:fs";
+import os from "node:os";
+import path from "node:path";
+import { gitBranch, metaChanges } from "./meta.js";
+
+describe("gitBranch", () => {
+  const root = path.join(os.tmpdir(), `pi-socket-meta-${process.pid}`);
+
+  afterEach(() => {
+    fs.rmSync(root, { recursive: true, force: true });
+  });
+
+  it("reads the branch from HEAD of the enclosing repository", () => {
+    fs.mkdirSync(path.join(root, "repo/.git"), { recursive: true });
+    fs.mkdirSync(path.join(root, "repo/src/deep"), { recursive: true });
+    fs.writeFileSync(path.join(root, "repo/.git/HEAD"), "ref: refs/heads/feature/x\n");
+    expect(gitBranch(path.join(root, "repo/src/deep"))).toBe("feature/x");
+  });
+
+  it("follows the .git file of a worktree", () => {
+    fs.mkdirSync(path.join(root, "main/.git/worktrees/wt"), { recursive: true });
+    fs.mkdirSync(path.join(root, "wt"), { recursive: true });
+    fs.writeFileSync(path.join(root, "main/.git/worktrees/wt/HEAD"), "ref: refs/heads/wt-branch\n");
+    fs.writeFileSync(path.join(root, "wt/.git"), "gitdir: ../main/.git/worktrees/wt\n");
+    expect(gitBranch(path.join(root, "wt"))).toBe("wt-branch");
+  });
+
+  it("is undefined on a detached HEAD", () => {
+    fs.mkdirSync(path.join(root, "repo/.git"), { recursive: true });
+    fs.writeFileSync(path.join(root, "repo/.git/HEAD"), "0123456789abcdef0123456789abcdef01234567\n");
+    expect(gitBranch(path.join(root, "repo"))).toBeUndefined();
+  });
+});
+
+describe("metaChanges", () => {
+  it("lists changed fields and clears removed ones", () => {
+    expect(
+      metaChanges(
+        { model: "a", provider: "p", git_branch: "main" },
+        { model: "b", provider: "p" },
+      ),
+    ).toEqual({ model: "b", git_branch: null });
+  });
+
+  it("is null when nothing changed", () => {
+    expect(metaChanges({ model: "a" }, { model: "a" })).toBeNull();
+  });
+});
@@ -0,0 +1,54 @@
+import fs from "node:fs";
+import path from "node:path";
+
+/** Optional node fields pi-socket sends on register and keeps current with update_node. */
+export interface NodeMeta {
+  session_name?: string;
+  model?: string;
+  provider?: string;
+  pi_version?: string;
+  git_branch?: string;
+}
+
+/** The `.git` directory of the repository containing `cwd`, following worktree links. */
+function gitDir(cwd: string): string | undefined {
+  for (let dir = path.resolve(cwd); ; dir = path.dirname(dir)) {
+    const dotGit = path.join(dir, ".git");
+    try {
+      if (fs.statSync(dotGit).isDirectory()) return dotGit;
+      const link = /^gitdir:\s*(.+)$/m.exec(fs.readFileSync(dotGit, "utf-8"));
+      if (link) return path.resolve(dir, link[1].trim());
+    } catch {
+      // No .git here; keep looking upwards.
+    }
+    if (path.dirname(dir) === dir) return undefined;
+  }
+}
+
+/**
+ * Branch checked out in the repository containing `cwd`, read from HEAD
+ * without running git. Undefined outside a repository or on a detached HEAD.
+ */
+export function gitBranch(cwd: string): string | undefined {
+  const dir = gitDir(cwd);
+  if (!dir) return undefined;
+  try {
+    const head = fs.readFileSync(path.join(dir, "HEAD"), "utf-8").trim();
+    return head.startsWith("ref: refs/heads/") ? head.slice("ref: refs/heads/".length) : undefined;
+  } catch {
+    return undefined;
+  }
+}
+
+/**
+ * Fields of `next` that differ from `prev`, as update_node takes them:
+ * `null` clears a field. Null when nothing changed.
+ */
+export function metaChanges(prev: NodeMeta, next: NodeMeta): Record<string, string | null> | null {
+  const changes: Record<string, string | null> = {};
+  const keys = new Set([...Object.keys(prev), ...Object.keys(next)]) as Set<keyof NodeMeta>;
+  for (const key of keys) {
+    if (prev[key] !== next[key]) changes[key] = next[key] ?? null;
+  }
+  return Object.keys(changes).length > 0 ? changes : null;
+}
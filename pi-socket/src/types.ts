@@ -20,4 +20,7 @@ export type {
   HistoryPageResponse,
   SocketEvent,
   RpcRequest,
+  NodeAgentStatus,
+  AgentCard,
+  AgentCardItem,
 } from "hyper-pi-protocol";